@@ -0,0 +1,31 @@
+//! Converts a Hugging Face AQLM checkpoint into the runtime weight layout.
+//!
+//! Usage: `convert <checkpoint> <output_dir>`, where `checkpoint` is a
+//! `.safetensors` file, a `*.safetensors.index.json` shard index or a directory.
+
+use std::path::Path;
+use std::process::exit;
+use tools::convert::convert_checkpoint;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 3 {
+        eprintln!("usage: {} <checkpoint> <output_dir>", args[0]);
+        exit(2);
+    }
+
+    let output_dir = Path::new(&args[2]);
+    let report = convert_checkpoint(Path::new(&args[1]), output_dir).unwrap_or_else(|err| {
+        eprintln!("error: {err}");
+        exit(1);
+    });
+
+    for name in &report.skipped {
+        eprintln!("skipped {name}");
+    }
+    eprintln!(
+        "wrote {} tensors to {}",
+        report.written.len(),
+        output_dir.display()
+    );
+}
@@ -0,0 +1,209 @@
+//! Measures how much fusing the QKV and gate/up projections into one request
+//! per worker saves on the per-token latency of Llama 3.1 8B.
+//!
+//! Usage: `fused_latency [addr ...]`, connecting to `linear_worker` servers at
+//! the given addresses, or to two in-process workers when none are given.
+//! Layers hold random AQLM weights; only the timings are meaningful.
+
+use nn::linear::{ForwardError, Module};
+use nn::llama_config::LLAMA_3_1_8B_CONFIG;
+use std::process::exit;
+use std::time::{Duration, Instant};
+use worker_engine::parallel_aqlm::{set_handles, ParallelAQLMLinear, Split};
+use worker_engine::registry_rpc_handle::RPCLinearRegistryHandle;
+
+const N_THREAD_WORKERS: usize = 2;
+const HIDDEN_DIM: usize = 14336;
+const N_WARMUP: usize = 3;
+const N_ITERS: usize = 20;
+
+/// A cheap deterministic byte stream, good enough for weights nobody reads.
+fn pseudo_random(len: usize, seed: usize) -> Vec<u8> {
+    let mut state = seed as u64 * 6364136223846793005 + 1442695040888963407;
+    (0..len)
+        .map(|_| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 56) as u8
+        })
+        .collect()
+}
+
+async fn random_layer(
+    name: &str,
+    out_dim: usize,
+    in_dim: usize,
+    n_workers: usize,
+) -> ParallelAQLMLinear {
+    let in_group_dim = in_dim / 8;
+    let codebooks: Vec<f32> = pseudo_random(2 * 256 * 8, 0)
+        .into_iter()
+        .map(|value| value as f32 / 128.0 - 1.0)
+        .collect();
+    let scales = vec![0.01; out_dim];
+    let codes = pseudo_random(in_group_dim * 2 * out_dim, out_dim + in_dim);
+
+    ParallelAQLMLinear::new(
+        name.to_string(),
+        &codebooks,
+        &scales,
+        &codes,
+        out_dim,
+        in_group_dim,
+        n_workers,
+        Split::Rows,
+    )
+    .await
+    .unwrap_or_else(|err| panic!("{name}: {err}"))
+}
+
+/// The linear layers of one transformer block.
+struct Block {
+    q_proj: ParallelAQLMLinear,
+    k_proj: ParallelAQLMLinear,
+    v_proj: ParallelAQLMLinear,
+    o_proj: ParallelAQLMLinear,
+    gate_proj: ParallelAQLMLinear,
+    up_proj: ParallelAQLMLinear,
+    down_proj: ParallelAQLMLinear,
+}
+
+impl Block {
+    async fn new(n_workers: usize) -> Self {
+        let config = &LLAMA_3_1_8B_CONFIG;
+        let (dim, kv_dim) = (config.dim, config.dim / config.n_heads * config.n_kv_heads);
+        Self {
+            q_proj: random_layer("q_proj", dim, dim, n_workers).await,
+            k_proj: random_layer("k_proj", kv_dim, dim, n_workers).await,
+            v_proj: random_layer("v_proj", kv_dim, dim, n_workers).await,
+            o_proj: random_layer("o_proj", dim, dim, n_workers).await,
+            gate_proj: random_layer("gate_proj", HIDDEN_DIM, dim, n_workers).await,
+            up_proj: random_layer("up_proj", HIDDEN_DIM, dim, n_workers).await,
+            down_proj: random_layer("down_proj", dim, HIDDEN_DIM, n_workers).await,
+        }
+    }
+
+    /// One request per layer and worker.
+    async fn forward_unfused(&mut self, x: &[f32], h: &[f32]) -> Result<(), ForwardError> {
+        self.q_proj.forward(x).await?;
+        self.k_proj.forward(x).await?;
+        self.v_proj.forward(x).await?;
+        self.o_proj.forward(x).await?;
+        self.gate_proj.forward(x).await?;
+        self.up_proj.forward(x).await?;
+        self.down_proj.forward(h).await?;
+        Ok(())
+    }
+
+    /// QKV and gate/up each sent as one request per worker.
+    async fn forward_fused(&mut self, x: &[f32], h: &[f32]) -> Result<(), ForwardError> {
+        ParallelAQLMLinear::forward_batch(
+            &mut [&mut self.q_proj, &mut self.k_proj, &mut self.v_proj],
+            x,
+        )
+        .await?;
+        self.o_proj.forward(x).await?;
+        ParallelAQLMLinear::forward_batch(&mut [&mut self.gate_proj, &mut self.up_proj], x).await?;
+        self.down_proj.forward(h).await?;
+        Ok(())
+    }
+
+    async fn async_drop(&mut self) {
+        for layer in [
+            &mut self.q_proj,
+            &mut self.k_proj,
+            &mut self.v_proj,
+            &mut self.o_proj,
+            &mut self.gate_proj,
+            &mut self.up_proj,
+            &mut self.down_proj,
+        ] {
+            let _ = layer.async_drop().await;
+        }
+    }
+}
+
+/// The mean time per block over `N_ITERS` runs, after `N_WARMUP` untimed ones.
+async fn time_block(mut run: impl AsyncFnMut()) -> Duration {
+    for _ in 0..N_WARMUP {
+        run().await;
+    }
+    let start = Instant::now();
+    for _ in 0..N_ITERS {
+        run().await;
+    }
+    start.elapsed() / N_ITERS as u32
+}
+
+fn report(label: &str, per_block: Duration) {
+    let per_token = per_block * LLAMA_3_1_8B_CONFIG.n_layers as u32;
+    println!(
+        "{label:>8}: {:8.2} ms per block, {:8.2} ms per token",
+        per_block.as_secs_f64() * 1e3,
+        per_token.as_secs_f64() * 1e3
+    );
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).is_some_and(|arg| arg.starts_with('-')) {
+        eprintln!("usage: {} [addr ...]", args[0]);
+        exit(2);
+    }
+
+    let fail = |err: &dyn std::fmt::Display| -> ! {
+        eprintln!("error: {err}");
+        exit(1);
+    };
+
+    let mut handles = Vec::new();
+    if args.len() > 1 {
+        for addr in &args[1..] {
+            let mut handle = RPCLinearRegistryHandle::connect(addr.as_str())
+                .await
+                .unwrap_or_else(|err| fail(&format!("{addr}: {err}")));
+            handle
+                .handshake()
+                .await
+                .unwrap_or_else(|err| fail(&format!("{addr}: {err}")));
+            handles.push(handle);
+        }
+    } else {
+        handles.extend((0..N_THREAD_WORKERS).map(|_| RPCLinearRegistryHandle::spawn_thread()));
+    }
+    let n_workers = handles.len();
+    set_handles(handles).await;
+    log::info!("uploading one block to {n_workers} workers");
+
+    let mut block = Block::new(n_workers).await;
+    let dim = LLAMA_3_1_8B_CONFIG.dim;
+    let x: Vec<f32> = (0..dim).map(|idx| (idx % 7) as f32 / 7.0).collect();
+    let h: Vec<f32> = (0..HIDDEN_DIM).map(|idx| (idx % 5) as f32 / 5.0).collect();
+
+    let unfused = time_block(async || {
+        block
+            .forward_unfused(&x, &h)
+            .await
+            .unwrap_or_else(|err| fail(&err))
+    })
+    .await;
+    let fused = time_block(async || {
+        block
+            .forward_fused(&x, &h)
+            .await
+            .unwrap_or_else(|err| fail(&err))
+    })
+    .await;
+    report("unfused", unfused);
+    report("fused", fused);
+    println!(
+        "saved {:.1}%",
+        100.0 * (1.0 - fused.as_secs_f64() / unfused.as_secs_f64())
+    );
+
+    block.async_drop().await;
+}
@@ -0,0 +1,31 @@
+//! Hosts linear layers for a remote loader over TCP.
+//!
+//! Usage: `linear_worker [addr]`, listening on `0.0.0.0:7070` by default. Each
+//! connected client gets a registry of its own, dropped when it disconnects.
+
+use std::net::TcpListener;
+use std::process::exit;
+use worker_engine::tcp_transport::serve;
+
+const DEFAULT_ADDR: &str = "0.0.0.0:7070";
+
+fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() > 2 {
+        eprintln!("usage: {} [addr]", args[0]);
+        exit(2);
+    }
+    let addr = args.get(1).map(String::as_str).unwrap_or(DEFAULT_ADDR);
+
+    let fail = |err: &dyn std::fmt::Display| -> ! {
+        eprintln!("error: {err}");
+        exit(1);
+    };
+
+    let listener = TcpListener::bind(addr).unwrap_or_else(|err| fail(&err));
+    log::info!("listening on {}", listener.local_addr().unwrap());
+
+    serve(listener).unwrap_or_else(|err| fail(&err));
+}
@@ -0,0 +1,69 @@
+//! Generates the weight manifest of a local weights directory.
+//!
+//! Usage: `manifest <weights_dir> [output]`, `output` defaults to `<weights_dir>/manifest.json`.
+
+use memmap2::Mmap;
+use state_dict::manifest::{Manifest, MANIFEST_FILENAME};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+fn build_manifest(dir: &Path) -> Result<Manifest, String> {
+    let mut paths: Vec<PathBuf> = dir
+        .read_dir()
+        .map_err(|err| format!("{}: {err}", dir.display()))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file())
+        .collect();
+    paths.sort();
+
+    let mut manifest = Manifest::default();
+
+    for path in paths {
+        let filename = path.file_name().unwrap().to_string_lossy().to_string();
+        if filename == MANIFEST_FILENAME {
+            continue;
+        }
+
+        let file = File::open(&path).map_err(|err| format!("{filename}: {err}"))?;
+        // Safety: weight files must not be modified while the manifest is generated.
+        let data = unsafe { Mmap::map(&file) }.map_err(|err| format!("{filename}: {err}"))?;
+
+        manifest
+            .add_file(&filename, &data)
+            .map_err(|err| err.to_string())?;
+        eprintln!("{filename}: {} bytes", data.len());
+    }
+
+    Ok(manifest)
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 2 || args.len() > 3 {
+        eprintln!("usage: {} <weights_dir> [output]", args[0]);
+        exit(2);
+    }
+
+    let dir = Path::new(&args[1]);
+    let output = args
+        .get(2)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| dir.join(MANIFEST_FILENAME));
+
+    let manifest = build_manifest(dir).unwrap_or_else(|err| {
+        eprintln!("error: {err}");
+        exit(1);
+    });
+
+    if let Err(err) = std::fs::write(&output, manifest.to_json()) {
+        eprintln!("error: {}: {err}", output.display());
+        exit(1);
+    }
+
+    eprintln!(
+        "wrote {} entries to {}",
+        manifest.files.len(),
+        output.display()
+    );
+}
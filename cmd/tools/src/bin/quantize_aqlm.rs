@@ -0,0 +1,73 @@
+//! Quantizes 2-D tensors into 2x8 AQLM `codebooks`, `scales` and `codes_120`.
+//!
+//! Usage: `quantize_aqlm [--iters N] [--beam N] [--calib FILE] <checkpoint> <output_dir> <tensor>...`,
+//! where `checkpoint` is a `.safetensors` file, a shard index or a directory, and
+//! each `tensor` is a name such as `model.layers.0.mlp.up_proj.weight`. The
+//! calibration file holds, under the same names, `[n_samples, in_dim]` input
+//! activations of each layer.
+
+use state_dict::mmap_state_dict::MmapStateDict;
+use std::fs;
+use std::path::Path;
+use std::process::exit;
+use tools::quantize_aqlm::{quantize_tensor, AqlmConfig};
+
+fn main() {
+    let mut args = std::env::args();
+    let program = args.next().unwrap_or_default();
+    let usage = || -> ! {
+        eprintln!(
+            "usage: {program} [--iters N] [--beam N] [--calib FILE] <checkpoint> <output_dir> <tensor>..."
+        );
+        exit(2);
+    };
+
+    let mut config = AqlmConfig::default();
+    let mut calibration_path = None;
+    let mut positional = Vec::new();
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "--iters" => config.n_iters = value().parse().unwrap_or_else(|_| usage()),
+            "--beam" => config.beam_size = value().parse().unwrap_or_else(|_| usage()),
+            "--calib" => calibration_path = Some(value()),
+            _ => positional.push(arg),
+        }
+    }
+    if positional.len() < 3 {
+        usage();
+    }
+
+    let fail = |err: &dyn std::fmt::Display| -> ! {
+        eprintln!("error: {err}");
+        exit(1);
+    };
+
+    let state_dict =
+        MmapStateDict::open(Path::new(&positional[0])).unwrap_or_else(|err| fail(&err));
+    let calibration = calibration_path
+        .map(|path| MmapStateDict::open(Path::new(&path)).unwrap_or_else(|err| fail(&err)));
+    let output_dir = Path::new(&positional[1]);
+    fs::create_dir_all(output_dir).unwrap_or_else(|err| fail(&err));
+
+    for name in &positional[2..] {
+        let activations = calibration
+            .as_ref()
+            .map(|calibration| calibration.get_f32(name).unwrap_or_else(|err| fail(&err)).0);
+
+        let (quantized, error) = quantize_tensor(
+            &state_dict,
+            name,
+            activations.as_deref(),
+            output_dir,
+            &config,
+        )
+        .unwrap_or_else(|err| fail(&err));
+        eprintln!(
+            "{name} [{}, {}]: {error}",
+            quantized.out_dim,
+            quantized.in_group_dim * 8
+        );
+    }
+}
@@ -0,0 +1,46 @@
+//! Quantizes 2-D tensors to `weight_int8` + `weight_max_values`.
+//!
+//! Usage: `quantize_int8 [--per-row] <checkpoint> <output_dir> <tensor>...`, where
+//! `checkpoint` is a `.safetensors` file, a shard index or a directory, and each
+//! `tensor` is a name such as `lm_head.weight`.
+
+use nn::matrix_int8::Int8Scaling;
+use state_dict::mmap_state_dict::MmapStateDict;
+use std::fs;
+use std::path::Path;
+use std::process::exit;
+use tools::quantize_int8::quantize_tensor;
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().collect();
+    let program = args.remove(0);
+
+    let scaling = match args.iter().position(|arg| arg == "--per-row") {
+        Some(idx) => {
+            args.remove(idx);
+            Int8Scaling::PerRow
+        }
+        None => Int8Scaling::PerColumn,
+    };
+
+    if args.len() < 3 {
+        eprintln!("usage: {program} [--per-row] <checkpoint> <output_dir> <tensor>...");
+        exit(2);
+    }
+
+    let fail = |err: &dyn std::fmt::Display| -> ! {
+        eprintln!("error: {err}");
+        exit(1);
+    };
+
+    let state_dict = MmapStateDict::open(Path::new(&args[0])).unwrap_or_else(|err| fail(&err));
+    let output_dir = Path::new(&args[1]);
+    fs::create_dir_all(output_dir).unwrap_or_else(|err| fail(&err));
+
+    for name in &args[2..] {
+        let (quantized, error) = quantize_tensor(&state_dict, name, output_dir, scaling)
+            .unwrap_or_else(|err| fail(&err));
+        let (n_rows, n_cols) = quantized.shape;
+        eprintln!("{name} [{n_rows}, {n_cols}]: {error}");
+    }
+}
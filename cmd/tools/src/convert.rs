@@ -0,0 +1,309 @@
+//! Converts a Hugging Face AQLM checkpoint into the per-tensor layout that
+//! `state_dict` loads:
+//!
+//! - `codes` `[out, in_group, 2]` int8 become `codes_120` `[in_group, 2, out]` u8,
+//! - `codebooks` and `scales` become f32, `scales` flattened to `[out]`,
+//! - the embeddings and `lm_head` are quantized to `weight_int8` and
+//!   per-column `weight_max_values`,
+//! - the layernorms become f32,
+//! - `tokenizer.model` is copied over if it is found next to the checkpoint.
+
+use crate::quantize_aqlm::QuantizedAqlm;
+use crate::quantize_int8::quantize_int8;
+use crate::writer::write_f32;
+use nn::matrix_int8::Int8Scaling;
+use state_dict::error::LoadError;
+use state_dict::from_state_dict::check_shape;
+use state_dict::mmap_state_dict::MmapStateDict;
+use std::fs;
+use std::path::Path;
+
+/// Tensors that are stored as int8 instead of f32.
+pub const INT8_TENSORS: [&str; 2] = ["model.embed_tokens.", "lm_head."];
+
+const TOKENIZER_FILENAME: &str = "tokenizer.model";
+
+#[derive(Debug, Default)]
+pub struct ConvertReport {
+    pub written: Vec<String>,
+    pub skipped: Vec<String>,
+}
+
+/// Permutes upstream `[out, in_group, 2]` codes into `codes_120` `[in_group, 2, out]`.
+/// Upstream stores 8-bit codes as int8, the runtime as u8.
+pub fn permute_codes(codes: &[i8], out_dim: usize, in_group_dim: usize) -> Vec<u8> {
+    assert_eq!(codes.len(), out_dim * in_group_dim * 2);
+
+    let mut output = vec![0u8; codes.len()];
+    for out_idx in 0..out_dim {
+        for in_group_idx in 0..in_group_dim {
+            for codebook_idx in 0..2 {
+                output[in_group_idx * 2 * out_dim + codebook_idx * out_dim + out_idx] =
+                    codes[(out_idx * in_group_dim + in_group_idx) * 2 + codebook_idx] as u8;
+            }
+        }
+    }
+    output
+}
+
+fn convert_aqlm(
+    state_dict: &MmapStateDict,
+    prefix: &str,
+    output_dir: &Path,
+    report: &mut ConvertReport,
+) -> Result<(), LoadError> {
+    let codebooks_name = format!("{prefix}codebooks");
+    let (codebooks, codebooks_shape) = state_dict.get_f32(&codebooks_name)?;
+    check_shape(&codebooks_name, &codebooks_shape, &[2, 256, 1, 8])?;
+
+    let codes_name = format!("{prefix}codes");
+    let (codes, codes_shape) = state_dict.get_i8(&codes_name)?;
+    if codes_shape.len() != 3 || codes_shape[2] != 2 {
+        return Err(LoadError::corrupted(
+            &codes_name,
+            format!("expected shape [out_dim, in_group_dim, 2], got {codes_shape:?}"),
+        ));
+    }
+    let (out_dim, in_group_dim) = (codes_shape[0], codes_shape[1]);
+
+    let scales_name = format!("{prefix}scales");
+    let (scales, scales_shape) = state_dict.get_f32(&scales_name)?;
+    check_shape(&scales_name, &scales_shape, &[out_dim, 1, 1, 1])?;
+
+    let quantized = QuantizedAqlm {
+        codebooks: codebooks.into_owned(),
+        scales: scales.into_owned(),
+        codes: permute_codes(&codes, out_dim, in_group_dim),
+        out_dim,
+        in_group_dim,
+    };
+    report.written.extend(quantized.write(output_dir, prefix)?);
+    Ok(())
+}
+
+fn convert_int8(
+    state_dict: &MmapStateDict,
+    prefix: &str,
+    output_dir: &Path,
+    report: &mut ConvertReport,
+) -> Result<(), LoadError> {
+    let name = format!("{prefix}weight");
+    let (weight, shape) = state_dict.get_f32(&name)?;
+    if shape.len() != 2 {
+        return Err(LoadError::corrupted(
+            &name,
+            format!("expected a matrix, got shape {shape:?}"),
+        ));
+    }
+
+    let quantized = quantize_int8(&weight, (shape[0], shape[1]), Int8Scaling::PerColumn);
+    report.written.extend(quantized.write(output_dir, prefix)?);
+    Ok(())
+}
+
+/// Converts the checkpoint at `input` (a `.safetensors` file, a shard index or a
+/// directory) into one `{tensor_name}.safetensors` file per tensor in `output_dir`.
+pub fn convert_checkpoint(input: &Path, output_dir: &Path) -> Result<ConvertReport, LoadError> {
+    let state_dict = MmapStateDict::open(input)?;
+    fs::create_dir_all(output_dir)
+        .map_err(|err| LoadError::io(&output_dir.display().to_string(), err))?;
+
+    let mut names = state_dict.names();
+    names.sort();
+
+    let mut report = ConvertReport::default();
+
+    for name in names {
+        if let Some(prefix) = name.strip_suffix("codes") {
+            convert_aqlm(&state_dict, prefix, output_dir, &mut report)?;
+        } else if name.ends_with("codebooks") || name.ends_with("scales") {
+            // Converted along with the codes.
+        } else if let Some(prefix) = INT8_TENSORS
+            .iter()
+            .find(|prefix| name == format!("{prefix}weight"))
+        {
+            convert_int8(&state_dict, prefix, output_dir, &mut report)?;
+        } else if name.ends_with("norm.weight") {
+            let (weight, shape) = state_dict.get_f32(name)?;
+            write_f32(output_dir, name, shape, &weight)?;
+            report.written.push(name.to_string());
+        } else {
+            report.skipped.push(name.to_string());
+        }
+    }
+
+    copy_tokenizer(input, output_dir, &mut report)?;
+
+    Ok(report)
+}
+
+/// The runtime loads the tiktoken `tokenizer.model`, which HF repos keep in `original/`.
+fn copy_tokenizer(
+    input: &Path,
+    output_dir: &Path,
+    report: &mut ConvertReport,
+) -> Result<(), LoadError> {
+    let input_dir = if input.is_dir() {
+        input
+    } else {
+        input.parent().unwrap_or(Path::new("."))
+    };
+
+    let candidates = [
+        input_dir.join(TOKENIZER_FILENAME),
+        input_dir.join("original").join(TOKENIZER_FILENAME),
+    ];
+    if let Some(path) = candidates.iter().find(|path| path.is_file()) {
+        fs::copy(path, output_dir.join(TOKENIZER_FILENAME))
+            .map_err(|err| LoadError::io(TOKENIZER_FILENAME, err))?;
+        report.written.push(TOKENIZER_FILENAME.to_string());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use half::f16;
+    use nn::linear::Module;
+    use nn::linear_aqlm::LinearAQLM;
+    use nn::linear_int8::LinearINT8;
+    use nn::test_util::pseudo_random;
+    use safetensors::tensor::TensorView;
+    use safetensors::Dtype;
+    use state_dict::from_state_dict::FromStateDict;
+    use std::collections::HashMap;
+
+    const OUT_DIM: usize = 3;
+    const IN_GROUP_DIM: usize = 2;
+
+    fn f16_bytes(values: &[f32]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|v| f16::from_f32(*v).to_le_bytes())
+            .collect()
+    }
+
+    /// Writes a one-layer upstream checkpoint, returning the dense weights it encodes.
+    fn write_upstream_checkpoint(path: &Path) -> HashMap<&'static str, Vec<f32>> {
+        let codebooks = pseudo_random(2 * 256 * 8, 1);
+        let scales = vec![0.5, 1.0, 2.0];
+        let codes: Vec<i8> = (0..OUT_DIM * IN_GROUP_DIM * 2)
+            .map(|idx| (idx * 37 % 256) as u8 as i8)
+            .collect();
+        let lm_head = pseudo_random(5 * 4, 2);
+        let norm = vec![1.0, 2.0, 3.0, 4.0];
+
+        // Reference dequantization of the upstream layout.
+        let mut q_proj = vec![0f32; OUT_DIM * IN_GROUP_DIM * 8];
+        for out_idx in 0..OUT_DIM {
+            for in_group_idx in 0..IN_GROUP_DIM {
+                for codebook_idx in 0..2 {
+                    let code =
+                        codes[(out_idx * IN_GROUP_DIM + in_group_idx) * 2 + codebook_idx] as u8;
+                    for k in 0..8 {
+                        q_proj[out_idx * IN_GROUP_DIM * 8 + in_group_idx * 8 + k] += codebooks
+                            [(codebook_idx * 256 + code as usize) * 8 + k]
+                            * scales[out_idx];
+                    }
+                }
+            }
+        }
+
+        let codebooks_bytes = f16_bytes(&codebooks);
+        let scales_bytes = f16_bytes(&scales);
+        let lm_head_bytes = f16_bytes(&lm_head);
+        let norm_bytes = f16_bytes(&norm);
+        let tensors = [
+            (
+                "model.layers.0.self_attn.q_proj.codebooks",
+                TensorView::new(Dtype::F16, vec![2, 256, 1, 8], &codebooks_bytes).unwrap(),
+            ),
+            (
+                "model.layers.0.self_attn.q_proj.scales",
+                TensorView::new(Dtype::F16, vec![OUT_DIM, 1, 1, 1], &scales_bytes).unwrap(),
+            ),
+            (
+                "model.layers.0.self_attn.q_proj.codes",
+                TensorView::new(
+                    Dtype::I8,
+                    vec![OUT_DIM, IN_GROUP_DIM, 2],
+                    bytemuck::cast_slice(&codes),
+                )
+                .unwrap(),
+            ),
+            (
+                "lm_head.weight",
+                TensorView::new(Dtype::F16, vec![5, 4], &lm_head_bytes).unwrap(),
+            ),
+            (
+                "model.norm.weight",
+                TensorView::new(Dtype::F16, vec![4], &norm_bytes).unwrap(),
+            ),
+        ];
+        safetensors::serialize_to_file(tensors, &None, path).unwrap();
+
+        // Round the reference through f16, like the checkpoint.
+        let round = |values: Vec<f32>| -> Vec<f32> {
+            values
+                .into_iter()
+                .map(|v| f16::from_f32(v).to_f32())
+                .collect()
+        };
+        HashMap::from([("q_proj", q_proj), ("lm_head", round(lm_head))])
+    }
+
+    fn matvec(weight: &[f32], x: &[f32]) -> Vec<f32> {
+        weight
+            .chunks_exact(x.len())
+            .map(|row| row.iter().zip(x).map(|(a, b)| a * b).sum())
+            .collect()
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32], tolerance: f32) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() <= tolerance, "{actual:?} != {expected:?}");
+        }
+    }
+
+    #[test]
+    fn test_permute_codes() {
+        // out_dim = 2, in_group_dim = 1: [[a0, a1]], [[b0, b1]] -> [[a0, b0], [a1, b1]]
+        let codes = [1i8, 2, 3, -1];
+        assert_eq!(permute_codes(&codes, 2, 1), vec![1, 3, 2, 255]);
+    }
+
+    #[test]
+    fn test_convert_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("model.safetensors");
+        let output = dir.path().join("converted");
+        let reference = write_upstream_checkpoint(&input);
+
+        let report = convert_checkpoint(&input, &output).unwrap();
+        assert_eq!(report.written.len(), 6);
+        assert!(report.skipped.is_empty());
+
+        let state_dict = &MmapStateDict::open(&output).unwrap();
+
+        let mut q_proj = block_on(LinearAQLM::from_state_dict(
+            &state_dict,
+            "model.layers.0.self_attn.q_proj.",
+        ))
+        .unwrap();
+        let x = pseudo_random(IN_GROUP_DIM * 8, 3);
+        let output = block_on(q_proj.forward(&x)).unwrap();
+        assert_close(output.data(), &matvec(&reference["q_proj"], &x), 1e-2);
+
+        let mut lm_head = block_on(LinearINT8::from_state_dict(&state_dict, "lm_head.")).unwrap();
+        let x = pseudo_random(4, 4);
+        let output = block_on(lm_head.forward(&x)).unwrap();
+        assert_close(output.data(), &matvec(&reference["lm_head"], &x), 5e-2);
+
+        let (norm, _) = state_dict.get_f32("model.norm.weight").unwrap();
+        assert_eq!(norm.as_ref(), &[1.0, 2.0, 3.0, 4.0]);
+    }
+}
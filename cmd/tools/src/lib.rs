@@ -0,0 +1,5 @@
+pub mod convert;
+pub mod quantize_aqlm;
+pub mod quantize_int8;
+pub mod reconstruction;
+pub mod writer;
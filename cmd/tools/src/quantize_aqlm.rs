@@ -0,0 +1,640 @@
+//! Quantizes dense weights into the 2x8 AQLM scheme that `LinearAQLM` runs:
+//! each group of 8 inputs of an output row is the sum of one codeword from each
+//! of two 256-entry codebooks, times a per-row scale.
+//!
+//! The codebooks start from residual k-means. They are then refined by
+//! alternating a beam search over codes with closed-form codebook and scale
+//! updates. With calibration activations, the error on input `i` is weighted by
+//! `mean(x_i^2)`, a diagonal approximation of the AQLM `XX^T` objective.
+
+use crate::reconstruction::ReconstructionError;
+use crate::writer::{write_f32, write_tensor};
+use nn::linear_aqlm::LinearAQLM;
+use safetensors::Dtype;
+use state_dict::error::LoadError;
+use state_dict::mmap_state_dict::MmapStateDict;
+use std::borrow::Cow;
+use std::path::Path;
+use std::thread;
+
+pub const N_CODEBOOKS: usize = 2;
+pub const CODEBOOK_SIZE: usize = 256;
+pub const GROUP_SIZE: usize = 8;
+
+#[derive(Debug, Clone, Copy)]
+pub struct AqlmConfig {
+    /// Rounds of code search and codebook updates after initialization.
+    pub n_iters: usize,
+    pub kmeans_iters: usize,
+    /// First-codeword candidates tried per group in the code search.
+    pub beam_size: usize,
+    pub seed: u64,
+}
+
+impl Default for AqlmConfig {
+    fn default() -> Self {
+        Self {
+            n_iters: 10,
+            kmeans_iters: 10,
+            beam_size: 8,
+            seed: 0,
+        }
+    }
+}
+
+pub struct QuantizedAqlm {
+    /// `[2, 256, 1, 8]`
+    pub codebooks: Vec<f32>,
+    /// `[out_dim]`
+    pub scales: Vec<f32>,
+    /// `codes_120`, `[in_group_dim, 2, out_dim]`
+    pub codes: Vec<u8>,
+    pub out_dim: usize,
+    pub in_group_dim: usize,
+}
+
+impl QuantizedAqlm {
+    pub fn linear(&self) -> LinearAQLM<'_> {
+        LinearAQLM::new(
+            Cow::Borrowed(&self.codebooks),
+            Cow::Borrowed(&self.scales),
+            Cow::Borrowed(&self.codes),
+            self.out_dim,
+            self.in_group_dim,
+        )
+    }
+
+    /// Row-major `[out_dim, in_group_dim * 8]` weights.
+    pub fn dequantize(&self) -> Vec<f32> {
+        self.linear().dequantize().into_data().into_owned()
+    }
+
+    /// Writes `{prefix}codebooks`, `{prefix}scales` and `{prefix}codes_120`,
+    /// returning their names.
+    pub fn write(&self, output_dir: &Path, prefix: &str) -> Result<[String; 3], LoadError> {
+        let codebooks_name = format!("{prefix}codebooks");
+        let scales_name = format!("{prefix}scales");
+        let codes_name = format!("{prefix}codes_120");
+
+        write_f32(
+            output_dir,
+            &codebooks_name,
+            vec![N_CODEBOOKS, CODEBOOK_SIZE, 1, GROUP_SIZE],
+            &self.codebooks,
+        )?;
+        write_f32(output_dir, &scales_name, vec![self.out_dim], &self.scales)?;
+        write_tensor(
+            output_dir,
+            &codes_name,
+            Dtype::U8,
+            vec![self.in_group_dim, N_CODEBOOKS, self.out_dim],
+            &self.codes,
+        )?;
+
+        Ok([codebooks_name, scales_name, codes_name])
+    }
+}
+
+/// Quantizes a row-major `[out_dim, in_dim]` matrix. `calibration` holds
+/// `[n_samples, in_dim]` input activations of the layer.
+pub fn quantize_aqlm(
+    weight: &[f32],
+    shape: (usize, usize),
+    calibration: Option<&[f32]>,
+    config: &AqlmConfig,
+) -> QuantizedAqlm {
+    let (out_dim, in_dim) = shape;
+    assert_eq!(weight.len(), out_dim * in_dim);
+    assert!(in_dim.is_multiple_of(GROUP_SIZE));
+
+    let importance = match calibration {
+        Some(calibration) => input_importance(calibration, in_dim),
+        None => vec![1.0; in_dim],
+    };
+
+    let mut problem = Problem {
+        weight,
+        importance,
+        in_group_dim: in_dim / GROUP_SIZE,
+        scales: weight
+            .chunks_exact(in_dim)
+            .map(|row| {
+                let rms = (row.iter().map(|v| v * v).sum::<f32>() / in_dim as f32).sqrt();
+                if rms > 0.0 {
+                    rms
+                } else {
+                    1.0
+                }
+            })
+            .collect(),
+        vectors: Vec::new(),
+    };
+    problem.normalize();
+
+    let n_vectors = problem.n_vectors();
+    let mut rng = SplitMix64(config.seed);
+
+    // Residual k-means: the second codebook quantizes what the first one misses.
+    let (first, first_codes) = problem.kmeans(&problem.vectors, &mut rng, config.kmeans_iters);
+    let residuals = problem.residuals(&problem.vectors, &first, &first_codes);
+    let (second, second_codes) = problem.kmeans(&residuals, &mut rng, config.kmeans_iters);
+
+    let mut codebooks = [first, second];
+    let mut codes: Vec<[u8; 2]> = (0..n_vectors)
+        .map(|idx| [first_codes[idx], second_codes[idx]])
+        .collect();
+
+    for _ in 0..config.n_iters {
+        codes = par_map(n_vectors, |idx| {
+            problem.search_codes(idx, &codebooks, codes[idx], config.beam_size)
+        });
+
+        for codebook_idx in 0..N_CODEBOOKS {
+            let other = 1 - codebook_idx;
+            let other_codes: Vec<u8> = codes.iter().map(|code| code[other]).collect();
+            let targets = problem.residuals(&problem.vectors, &codebooks[other], &other_codes);
+            let assignment: Vec<u8> = codes.iter().map(|code| code[codebook_idx]).collect();
+            codebooks[codebook_idx] =
+                problem.update_centroids(&targets, &assignment, &codebooks[codebook_idx]);
+        }
+
+        problem.update_scales(&codebooks, &codes);
+    }
+
+    let mut codes_120 = vec![0u8; n_vectors * N_CODEBOOKS];
+    for (idx, code) in codes.iter().enumerate() {
+        let (out_idx, in_group_idx) = (idx / problem.in_group_dim, idx % problem.in_group_dim);
+        for (codebook_idx, code) in code.iter().enumerate() {
+            codes_120[in_group_idx * N_CODEBOOKS * out_dim + codebook_idx * out_dim + out_idx] =
+                *code;
+        }
+    }
+
+    QuantizedAqlm {
+        codebooks: codebooks.concat(),
+        scales: problem.scales,
+        codes: codes_120,
+        out_dim,
+        in_group_dim: problem.in_group_dim,
+    }
+}
+
+/// Mean squared activation of each input, normalized to a mean of 1. Inputs
+/// that are never active keep a small weight, so their codewords stay defined.
+fn input_importance(calibration: &[f32], in_dim: usize) -> Vec<f32> {
+    assert!(!calibration.is_empty() && calibration.len().is_multiple_of(in_dim));
+
+    let mut importance = vec![0f32; in_dim];
+    for sample in calibration.chunks_exact(in_dim) {
+        for (importance, x) in importance.iter_mut().zip(sample) {
+            *importance += x * x;
+        }
+    }
+
+    let mean = importance.iter().sum::<f32>() / in_dim as f32;
+    if mean == 0.0 {
+        return vec![1.0; in_dim];
+    }
+    importance.iter().map(|v| (v / mean).max(1e-6)).collect()
+}
+
+/// One layer being quantized. Vector `idx` is the group `idx % in_group_dim`
+/// of row `idx / in_group_dim`, divided by the row scale.
+struct Problem<'a> {
+    weight: &'a [f32],
+    importance: Vec<f32>,
+    in_group_dim: usize,
+    scales: Vec<f32>,
+    vectors: Vec<f32>,
+}
+
+impl Problem<'_> {
+    fn n_vectors(&self) -> usize {
+        self.weight.len() / GROUP_SIZE
+    }
+
+    fn normalize(&mut self) {
+        let in_dim = self.in_group_dim * GROUP_SIZE;
+        self.vectors = self
+            .weight
+            .iter()
+            .enumerate()
+            .map(|(idx, v)| v / self.scales[idx / in_dim])
+            .collect();
+    }
+
+    fn vector<'b>(&self, vectors: &'b [f32], idx: usize) -> &'b [f32] {
+        &vectors[idx * GROUP_SIZE..(idx + 1) * GROUP_SIZE]
+    }
+
+    /// Per-dimension weights of the error on vector `idx`, without the row scale.
+    fn importance(&self, idx: usize) -> &[f32] {
+        let in_group_idx = idx % self.in_group_dim;
+        &self.importance[in_group_idx * GROUP_SIZE..(in_group_idx + 1) * GROUP_SIZE]
+    }
+
+    fn residuals(&self, vectors: &[f32], codebook: &[f32], codes: &[u8]) -> Vec<f32> {
+        vectors
+            .chunks_exact(GROUP_SIZE)
+            .zip(codes)
+            .flat_map(|(vector, code)| {
+                vector
+                    .iter()
+                    .zip(codeword(codebook, *code))
+                    .map(|(v, c)| v - c)
+            })
+            .collect()
+    }
+
+    fn kmeans(&self, targets: &[f32], rng: &mut SplitMix64, n_iters: usize) -> (Vec<f32>, Vec<u8>) {
+        let n_vectors = self.n_vectors();
+        let mut centroids: Vec<f32> = (0..CODEBOOK_SIZE)
+            .flat_map(|_| self.vector(targets, rng.next_below(n_vectors)).to_vec())
+            .collect();
+
+        let mut assignment = self.assign(targets, &centroids);
+        for _ in 0..n_iters {
+            centroids = self.update_centroids(targets, &assignment, &centroids);
+            assignment = self.assign(targets, &centroids);
+        }
+        (centroids, assignment)
+    }
+
+    fn assign(&self, targets: &[f32], codebook: &[f32]) -> Vec<u8> {
+        par_map(self.n_vectors(), |idx| {
+            nearest(codebook, self.vector(targets, idx), self.importance(idx)).0
+        })
+    }
+
+    /// Weighted mean of the targets assigned to each codeword. Codewords
+    /// without targets keep their previous value.
+    fn update_centroids(&self, targets: &[f32], assignment: &[u8], previous: &[f32]) -> Vec<f32> {
+        let mut sums = vec![0f64; CODEBOOK_SIZE * GROUP_SIZE];
+        let mut weights = vec![0f64; CODEBOOK_SIZE * GROUP_SIZE];
+
+        for (idx, code) in assignment.iter().enumerate() {
+            let row_weight = self.scales[idx / self.in_group_dim].powi(2) as f64;
+            let offset = *code as usize * GROUP_SIZE;
+            for (dim, (v, importance)) in self
+                .vector(targets, idx)
+                .iter()
+                .zip(self.importance(idx))
+                .enumerate()
+            {
+                let weight = row_weight * *importance as f64;
+                sums[offset + dim] += weight * *v as f64;
+                weights[offset + dim] += weight;
+            }
+        }
+
+        sums.iter()
+            .zip(&weights)
+            .zip(previous)
+            .map(|((sum, weight), previous)| match *weight > 0.0 {
+                true => (sum / weight) as f32,
+                false => *previous,
+            })
+            .collect()
+    }
+
+    /// Beam search for the codes of vector `idx`: the `beam_size` nearest
+    /// codewords of one codebook, each completed by the best codeword of the
+    /// other, trying both codebooks first. Never returns codes worse than `current`.
+    fn search_codes(
+        &self,
+        idx: usize,
+        codebooks: &[Vec<f32>; 2],
+        current: [u8; 2],
+        beam_size: usize,
+    ) -> [u8; 2] {
+        let vector = self.vector(&self.vectors, idx);
+        let importance = self.importance(idx);
+
+        let error = |codes: [u8; 2]| -> f32 {
+            let reconstructed: Vec<f32> = codeword(&codebooks[0], codes[0])
+                .iter()
+                .zip(codeword(&codebooks[1], codes[1]))
+                .map(|(a, b)| a + b)
+                .collect();
+            weighted_distance(vector, &reconstructed, importance)
+        };
+
+        let mut best = (error(current), current);
+
+        for first in 0..N_CODEBOOKS {
+            let second = 1 - first;
+
+            let mut candidates: Vec<(f32, u8)> = (0..CODEBOOK_SIZE)
+                .map(|code| {
+                    let code = code as u8;
+                    let distance =
+                        weighted_distance(vector, codeword(&codebooks[first], code), importance);
+                    (distance, code)
+                })
+                .collect();
+            let beam_size = beam_size.clamp(1, CODEBOOK_SIZE);
+            candidates.select_nth_unstable_by(beam_size - 1, |a, b| a.0.total_cmp(&b.0));
+
+            for (_, code) in &candidates[..beam_size] {
+                let residual: Vec<f32> = vector
+                    .iter()
+                    .zip(codeword(&codebooks[first], *code))
+                    .map(|(v, c)| v - c)
+                    .collect();
+                let (other_code, distance) = nearest(&codebooks[second], &residual, importance);
+
+                if distance < best.0 {
+                    let mut codes = [0u8; 2];
+                    codes[first] = *code;
+                    codes[second] = other_code;
+                    best = (distance, codes);
+                }
+            }
+        }
+
+        best.1
+    }
+
+    /// Least-squares scale of each row for the current codes, weighted by importance.
+    fn update_scales(&mut self, codebooks: &[Vec<f32>; 2], codes: &[[u8; 2]]) {
+        let in_dim = self.in_group_dim * GROUP_SIZE;
+
+        for (out_idx, row) in self.weight.chunks_exact(in_dim).enumerate() {
+            let mut numerator = 0f64;
+            let mut denominator = 0f64;
+
+            for in_group_idx in 0..self.in_group_dim {
+                let idx = out_idx * self.in_group_dim + in_group_idx;
+                let code = codes[idx];
+                let decoded = codeword(&codebooks[0], code[0])
+                    .iter()
+                    .zip(codeword(&codebooks[1], code[1]))
+                    .map(|(a, b)| a + b);
+                let group = &row[in_group_idx * GROUP_SIZE..(in_group_idx + 1) * GROUP_SIZE];
+
+                for ((w, u), importance) in group.iter().zip(decoded).zip(self.importance(idx)) {
+                    numerator += (*importance * w * u) as f64;
+                    denominator += (*importance * u * u) as f64;
+                }
+            }
+
+            if denominator > 0.0 && numerator != 0.0 {
+                self.scales[out_idx] = (numerator / denominator) as f32;
+            }
+        }
+
+        self.normalize();
+    }
+}
+
+fn codeword(codebook: &[f32], code: u8) -> &[f32] {
+    &codebook[code as usize * GROUP_SIZE..(code as usize + 1) * GROUP_SIZE]
+}
+
+fn weighted_distance(a: &[f32], b: &[f32], weights: &[f32]) -> f32 {
+    a.iter()
+        .zip(b)
+        .zip(weights)
+        .map(|((a, b), w)| w * (a - b) * (a - b))
+        .sum()
+}
+
+fn nearest(codebook: &[f32], vector: &[f32], weights: &[f32]) -> (u8, f32) {
+    codebook
+        .chunks_exact(GROUP_SIZE)
+        .enumerate()
+        .map(|(code, codeword)| (code as u8, weighted_distance(vector, codeword, weights)))
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .unwrap()
+}
+
+/// Maps `f` over `0..n` on all available cores, keeping the order.
+fn par_map<T: Send>(n: usize, f: impl Fn(usize) -> T + Sync) -> Vec<T> {
+    let n_threads = thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk_size = n.div_ceil(n_threads).max(1);
+
+    thread::scope(|scope| {
+        let f = &f;
+        let handles: Vec<_> = (0..n)
+            .step_by(chunk_size)
+            .map(|begin| {
+                scope.spawn(move || {
+                    (begin..(begin + chunk_size).min(n))
+                        .map(f)
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect()
+    })
+}
+
+/// Small deterministic generator for the k-means initialization.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    fn next_below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+/// Quantizes the 2-D tensor `name` from `state_dict` and writes it to
+/// `output_dir` under the prefix `name` without its trailing `weight`.
+pub fn quantize_tensor(
+    state_dict: &MmapStateDict,
+    name: &str,
+    calibration: Option<&[f32]>,
+    output_dir: &Path,
+    config: &AqlmConfig,
+) -> Result<(QuantizedAqlm, ReconstructionError), LoadError> {
+    let (data, shape) = state_dict.get_f32(name)?;
+    let [out_dim, in_dim] = shape[..] else {
+        return Err(LoadError::corrupted(
+            name,
+            format!("expected a matrix, got shape {shape:?}"),
+        ));
+    };
+    if !in_dim.is_multiple_of(GROUP_SIZE) {
+        return Err(LoadError::corrupted(
+            name,
+            format!("input dimension {in_dim} is not a multiple of {GROUP_SIZE}"),
+        ));
+    }
+    if let Some(calibration) = calibration {
+        if calibration.is_empty() || !calibration.len().is_multiple_of(in_dim) {
+            return Err(LoadError::corrupted(
+                name,
+                format!(
+                    "{} calibration values do not split into rows of {in_dim}",
+                    calibration.len()
+                ),
+            ));
+        }
+    }
+
+    let prefix = name.strip_suffix("weight").unwrap_or(name);
+    let quantized = quantize_aqlm(&data, (out_dim, in_dim), calibration, config);
+    let error = ReconstructionError::between(&data, &quantized.dequantize());
+    quantized.write(output_dir, prefix)?;
+
+    Ok((quantized, error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use nn::linear::Module;
+    use safetensors::tensor::TensorView;
+    use state_dict::from_state_dict::FromStateDict;
+
+    fn gaussian(n: usize, seed: u64) -> Vec<f32> {
+        let mut rng = SplitMix64(seed);
+        let mut uniform = || (rng.next_u64() >> 11) as f32 / (1u64 << 53) as f32;
+        (0..n)
+            .map(|_| {
+                let (u1, u2) = (uniform().max(1e-7), uniform());
+                (-2.0 * u1.ln()).sqrt() * (2.0 * std::f32::consts::PI * u2).cos()
+            })
+            .collect()
+    }
+
+    fn matvec(weight: &[f32], x: &[f32]) -> Vec<f32> {
+        weight
+            .chunks_exact(x.len())
+            .map(|row| row.iter().zip(x).map(|(a, b)| a * b).sum())
+            .collect()
+    }
+
+    fn output_error(weight: &[f32], reconstructed: &[f32], inputs: &[f32], in_dim: usize) -> f32 {
+        inputs
+            .chunks_exact(in_dim)
+            .flat_map(|x| {
+                matvec(weight, x)
+                    .into_iter()
+                    .zip(matvec(reconstructed, x))
+                    .map(|(a, b)| (a - b) * (a - b))
+            })
+            .sum()
+    }
+
+    const OUT_DIM: usize = 48;
+    const IN_DIM: usize = 64;
+
+    fn test_config(n_iters: usize) -> AqlmConfig {
+        AqlmConfig {
+            n_iters,
+            kmeans_iters: 3,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_iterations_reduce_error() {
+        let weight = gaussian(OUT_DIM * IN_DIM, 1);
+
+        let error = |n_iters| {
+            let config = test_config(n_iters);
+            let quantized = quantize_aqlm(&weight, (OUT_DIM, IN_DIM), None, &config);
+            ReconstructionError::between(&weight, &quantized.dequantize())
+        };
+
+        let initial = error(0);
+        let refined = error(3);
+        assert!(refined.mse < initial.mse, "{refined} vs {initial}");
+        assert!(refined.relative < 0.5, "{refined}");
+    }
+
+    #[test]
+    fn test_linear_matches_dequantized() {
+        let weight = gaussian(OUT_DIM * IN_DIM, 2);
+        let config = test_config(1);
+        let quantized = quantize_aqlm(&weight, (OUT_DIM, IN_DIM), None, &config);
+
+        let x = gaussian(IN_DIM, 3);
+        let output = block_on(quantized.linear().forward(&x)).unwrap();
+        for (a, b) in output
+            .data()
+            .iter()
+            .zip(matvec(&quantized.dequantize(), &x))
+        {
+            assert!((a - b).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_calibration_weights_active_inputs() {
+        let weight = gaussian(OUT_DIM * IN_DIM, 4);
+        // Only the first quarter of the inputs is ever active.
+        let inputs: Vec<f32> = gaussian(16 * IN_DIM, 5)
+            .into_iter()
+            .enumerate()
+            .map(|(idx, v)| if idx % IN_DIM < IN_DIM / 4 { v } else { 0.0 })
+            .collect();
+
+        let config = test_config(3);
+        let plain = quantize_aqlm(&weight, (OUT_DIM, IN_DIM), None, &config);
+        let calibrated = quantize_aqlm(&weight, (OUT_DIM, IN_DIM), Some(&inputs), &config);
+
+        let plain_error = output_error(&weight, &plain.dequantize(), &inputs, IN_DIM);
+        let calibrated_error = output_error(&weight, &calibrated.dequantize(), &inputs, IN_DIM);
+        assert!(
+            calibrated_error < plain_error / 2.0,
+            "{calibrated_error} vs {plain_error}"
+        );
+    }
+
+    #[test]
+    fn test_quantize_tensor_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("model.safetensors");
+        let output = dir.path().join("quantized");
+        std::fs::create_dir_all(&output).unwrap();
+
+        let weight = gaussian(OUT_DIM * IN_DIM, 6);
+        let view = TensorView::new(
+            Dtype::F32,
+            vec![OUT_DIM, IN_DIM],
+            bytemuck::cast_slice(&weight),
+        )
+        .unwrap();
+        let name = "model.layers.0.mlp.up_proj.weight";
+        safetensors::serialize_to_file([(name, view)], &None, &input).unwrap();
+
+        let state_dict = MmapStateDict::open(&input).unwrap();
+        let config = test_config(1);
+        let (quantized, error) =
+            quantize_tensor(&state_dict, name, None, &output, &config).unwrap();
+        assert!(error.relative < 0.5);
+
+        let quantized_state_dict = &MmapStateDict::open(&output).unwrap();
+        let mut linear = block_on(LinearAQLM::from_state_dict(
+            &quantized_state_dict,
+            "model.layers.0.mlp.up_proj.",
+        ))
+        .unwrap();
+        assert_eq!(linear.shape(), (OUT_DIM, IN_DIM));
+
+        let x = gaussian(IN_DIM, 7);
+        let output = block_on(linear.forward(&x)).unwrap();
+        for (a, b) in output
+            .data()
+            .iter()
+            .zip(matvec(&quantized.dequantize(), &x))
+        {
+            assert!((a - b).abs() < 1e-3);
+        }
+    }
+}
@@ -0,0 +1,233 @@
+//! Quantizes dense matrices into the `weight_int8` + `weight_max_values` pair
+//! that `MatrixInt8` loads, where `value = int8 * max_value / 127`.
+//!
+//! Per-column max values are stored with shape `[n_cols]`, per-row ones with
+//! shape `[n_rows, 1]`, which is how the loaders tell the two apart.
+
+use crate::reconstruction::ReconstructionError;
+use crate::writer::{write_f32, write_tensor};
+use nn::matrix_int8::{Int8Scaling, MatrixInt8};
+use safetensors::Dtype;
+use state_dict::error::LoadError;
+use state_dict::mmap_state_dict::MmapStateDict;
+use std::borrow::Cow;
+use std::path::Path;
+
+pub struct QuantizedInt8 {
+    pub max_values: Vec<f32>,
+    pub int8_values: Vec<i8>,
+    pub shape: (usize, usize),
+    pub scaling: Int8Scaling,
+}
+
+impl QuantizedInt8 {
+    pub fn matrix(&self) -> MatrixInt8<'_> {
+        MatrixInt8::with_scaling(
+            Cow::Borrowed(&self.max_values),
+            Cow::Borrowed(&self.int8_values),
+            self.scaling,
+        )
+    }
+
+    pub fn max_values_shape(&self) -> Vec<usize> {
+        match self.scaling {
+            Int8Scaling::PerColumn => vec![self.shape.1],
+            Int8Scaling::PerRow => vec![self.shape.0, 1],
+        }
+    }
+
+    /// Writes `{prefix}weight_max_values` and `{prefix}weight_int8`, returning their names.
+    pub fn write(&self, output_dir: &Path, prefix: &str) -> Result<[String; 2], LoadError> {
+        let max_values_name = format!("{prefix}weight_max_values");
+        let int8_name = format!("{prefix}weight_int8");
+
+        write_f32(
+            output_dir,
+            &max_values_name,
+            self.max_values_shape(),
+            &self.max_values,
+        )?;
+        write_tensor(
+            output_dir,
+            &int8_name,
+            Dtype::I8,
+            vec![self.shape.0, self.shape.1],
+            bytemuck::cast_slice(&self.int8_values),
+        )?;
+
+        Ok([max_values_name, int8_name])
+    }
+}
+
+/// Quantizes a row-major `[n_rows, n_cols]` matrix to int8 with one scale per
+/// column or per row.
+pub fn quantize_int8(data: &[f32], shape: (usize, usize), scaling: Int8Scaling) -> QuantizedInt8 {
+    let (n_rows, n_cols) = shape;
+    assert_eq!(data.len(), n_rows * n_cols);
+
+    let max_values: Vec<f32> = match scaling {
+        Int8Scaling::PerColumn => {
+            let mut max_values = vec![0f32; n_cols];
+            for row in data.chunks_exact(n_cols) {
+                for (max_value, v) in max_values.iter_mut().zip(row) {
+                    *max_value = max_value.max(v.abs());
+                }
+            }
+            max_values
+        }
+        Int8Scaling::PerRow => data
+            .chunks_exact(n_cols)
+            .map(|row| row.iter().fold(0f32, |max_value, v| max_value.max(v.abs())))
+            .collect(),
+    };
+
+    let int8_values = data
+        .iter()
+        .enumerate()
+        .map(|(idx, v)| {
+            let max_value = match scaling {
+                Int8Scaling::PerColumn => max_values[idx % n_cols],
+                Int8Scaling::PerRow => max_values[idx / n_cols],
+            };
+            if max_value == 0.0 {
+                0
+            } else {
+                (v * 127.0 / max_value).round().clamp(-127.0, 127.0) as i8
+            }
+        })
+        .collect();
+
+    QuantizedInt8 {
+        max_values,
+        int8_values,
+        shape,
+        scaling,
+    }
+}
+
+/// Compares `data` with the matrix `quantized` dequantizes to.
+pub fn reconstruction_error(data: &[f32], quantized: &QuantizedInt8) -> ReconstructionError {
+    let (n_rows, n_cols) = quantized.shape;
+    assert_eq!(data.len(), n_rows * n_cols);
+
+    let reconstructed = quantized.matrix().dequantize();
+
+    ReconstructionError::between(data, reconstructed.data())
+}
+
+/// Quantizes the 2-D tensor `name` (f32, f16 or bf16) from `state_dict` and
+/// writes it to `output_dir` under the prefix `name` without its trailing `weight`.
+pub fn quantize_tensor(
+    state_dict: &MmapStateDict,
+    name: &str,
+    output_dir: &Path,
+    scaling: Int8Scaling,
+) -> Result<(QuantizedInt8, ReconstructionError), LoadError> {
+    let (data, shape) = state_dict.get_f32(name)?;
+    let [n_rows, n_cols] = shape[..] else {
+        return Err(LoadError::corrupted(
+            name,
+            format!("expected a matrix, got shape {shape:?}"),
+        ));
+    };
+
+    let prefix = name.strip_suffix("weight").unwrap_or(name);
+    let quantized = quantize_int8(&data, (n_rows, n_cols), scaling);
+    let error = reconstruction_error(&data, &quantized);
+    quantized.write(output_dir, prefix)?;
+
+    Ok((quantized, error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use nn::linear::Module;
+    use nn::linear_int8::LinearINT8;
+    use safetensors::tensor::TensorView;
+    use state_dict::from_state_dict::FromStateDict;
+
+    #[test]
+    fn test_quantize_per_column() {
+        let quantized = quantize_int8(&[1.0, -4.0, -0.5, 2.0], (2, 2), Int8Scaling::PerColumn);
+        assert_eq!(quantized.max_values, vec![1.0, 4.0]);
+        assert_eq!(quantized.int8_values, vec![127, -127, -64, 64]);
+        assert_eq!(quantized.max_values_shape(), vec![2]);
+    }
+
+    #[test]
+    fn test_quantize_per_row() {
+        let quantized = quantize_int8(&[1.0, -4.0, -0.5, 0.0], (2, 2), Int8Scaling::PerRow);
+        assert_eq!(quantized.max_values, vec![4.0, 0.5]);
+        assert_eq!(quantized.int8_values, vec![32, -127, -127, 0]);
+        assert_eq!(quantized.max_values_shape(), vec![2, 1]);
+    }
+
+    #[test]
+    fn test_reconstruction_error() {
+        let data = [1.0, -4.0, -0.5, 2.0];
+        let quantized = quantize_int8(&data, (2, 2), Int8Scaling::PerColumn);
+        let error = reconstruction_error(&data, &quantized);
+        assert!(error.max_abs <= 4.0 / 127.0 / 2.0 + 1e-6);
+        assert!(error.relative < 1e-2);
+
+        let zeros = quantize_int8(&[0.0; 4], (2, 2), Int8Scaling::PerRow);
+        assert_eq!(zeros.int8_values, vec![0; 4]);
+        assert_eq!(reconstruction_error(&[0.0; 4], &zeros).relative, 0.0);
+    }
+
+    #[test]
+    fn test_quantize_tensor_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("model.safetensors");
+        let output = dir.path().join("quantized");
+        std::fs::create_dir_all(&output).unwrap();
+
+        // Rows of very different magnitude, where per-row scaling is more accurate.
+        let weight: Vec<f32> = (0..6 * 4)
+            .map(|idx| ((idx * 7) % 11) as f32 - 5.0)
+            .enumerate()
+            .map(|(idx, v)| v * 10f32.powi((idx / 4) as i32 - 3))
+            .collect();
+        let view = TensorView::new(Dtype::F32, vec![6, 4], bytemuck::cast_slice(&weight)).unwrap();
+        safetensors::serialize_to_file([("lm_head.weight", view)], &None, &input).unwrap();
+
+        let state_dict = MmapStateDict::open(&input).unwrap();
+        let (per_column, _) = quantize_tensor(
+            &state_dict,
+            "lm_head.weight",
+            &output,
+            Int8Scaling::PerColumn,
+        )
+        .unwrap();
+        let (per_row, error) =
+            quantize_tensor(&state_dict, "lm_head.weight", &output, Int8Scaling::PerRow).unwrap();
+        assert_eq!(error, reconstruction_error(&weight, &per_row));
+
+        // The smallest row is lost entirely with per-column scaling.
+        let row_error = |quantized: &QuantizedInt8| -> f32 {
+            let row = quantized.matrix().get_row(0);
+            row.iter().zip(&weight).map(|(a, b)| (a - b).abs()).sum()
+        };
+        assert!(row_error(&per_row) < row_error(&per_column) / 10.0);
+
+        let quantized_state_dict = &MmapStateDict::open(&output).unwrap();
+        let mut lm_head = block_on(LinearINT8::from_state_dict(
+            &quantized_state_dict,
+            "lm_head.",
+        ))
+        .unwrap();
+        assert_eq!(lm_head.shape(), (6, 4));
+
+        let x = [1.0, -2.0, 0.5, 3.0];
+        let output = block_on(lm_head.forward(&x)).unwrap();
+        for (row, actual) in weight.chunks_exact(4).zip(output.data().iter()) {
+            let expected: f32 = row.iter().zip(&x).map(|(a, b)| a * b).sum();
+            // Each weight is off by at most half a quantization step.
+            let row_max = row.iter().fold(0f32, |max_value, v| max_value.max(v.abs()));
+            let tolerance = x.iter().map(|v| v.abs()).sum::<f32>() * row_max / 254.0;
+            assert!((actual - expected).abs() <= tolerance * 1.01);
+        }
+    }
+}
@@ -0,0 +1,47 @@
+//! Error between a matrix and its quantized reconstruction.
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReconstructionError {
+    pub mse: f32,
+    pub max_abs: f32,
+    /// Frobenius norm of the error relative to the norm of the original matrix.
+    pub relative: f32,
+}
+
+impl ReconstructionError {
+    pub fn between(original: &[f32], reconstructed: &[f32]) -> Self {
+        assert_eq!(original.len(), reconstructed.len());
+
+        let mut squared_error = 0f64;
+        let mut squared_norm = 0f64;
+        let mut max_abs = 0f32;
+
+        for (v, reconstructed) in original.iter().zip(reconstructed) {
+            let error = (v - reconstructed).abs();
+            squared_error += (error as f64).powi(2);
+            squared_norm += (*v as f64).powi(2);
+            max_abs = max_abs.max(error);
+        }
+
+        Self {
+            mse: (squared_error / original.len().max(1) as f64) as f32,
+            max_abs,
+            relative: match squared_norm {
+                0.0 => 0.0,
+                _ => (squared_error / squared_norm).sqrt() as f32,
+            },
+        }
+    }
+}
+
+impl fmt::Display for ReconstructionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "mse {:.3e}, max abs {:.3e}, relative {:.3e}",
+            self.mse, self.max_abs, self.relative
+        )
+    }
+}
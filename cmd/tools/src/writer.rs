@@ -0,0 +1,37 @@
+//! Writes tensors in the one-file-per-tensor layout that `state_dict` loads.
+
+use safetensors::tensor::TensorView;
+use safetensors::Dtype;
+use state_dict::error::LoadError;
+use std::path::Path;
+
+/// Writes `data` as `{output_dir}/{name}.safetensors`.
+pub fn write_tensor(
+    output_dir: &Path,
+    name: &str,
+    dtype: Dtype,
+    shape: Vec<usize>,
+    data: &[u8],
+) -> Result<(), LoadError> {
+    let view =
+        TensorView::new(dtype, shape, data).map_err(|err| LoadError::corrupted(name, err))?;
+    let path = output_dir.join(format!("{name}.safetensors"));
+
+    safetensors::serialize_to_file([(name, view)], &None, &path)
+        .map_err(|err| LoadError::io(name, err))
+}
+
+pub fn write_f32(
+    output_dir: &Path,
+    name: &str,
+    shape: Vec<usize>,
+    data: &[f32],
+) -> Result<(), LoadError> {
+    write_tensor(
+        output_dir,
+        name,
+        Dtype::F32,
+        shape,
+        bytemuck::cast_slice(data),
+    )
+}
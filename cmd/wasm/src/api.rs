@@ -1,20 +1,24 @@
 use generator::Generator;
 use log::info;
 use tokenizer::{Llama3Tokenizer, Message};
-use wasm_bindgen::prelude::wasm_bindgen;
+use wasm_bindgen::prelude::{wasm_bindgen, JsError};
 use web_time::Instant;
-use worker_engine::parallel_aqlm::ParallelAQLMLinear;
+use worker_engine::parallel_attention::ParallelAQLMAttention;
 use worker_engine::parallel_int8::ParallelINT8Linear;
+use worker_engine::parallel_mlp::ParallelAQLMMLP;
 
 #[wasm_bindgen]
 pub struct LlamaAPI {
-    pub(crate) generator: Generator<ParallelAQLMLinear, ParallelINT8Linear>,
+    pub(crate) generator:
+        Generator<'static, ParallelINT8Linear, ParallelAQLMMLP, ParallelAQLMAttention>,
     pub(crate) tokenizer: Llama3Tokenizer,
 }
 
 #[wasm_bindgen]
 impl LlamaAPI {
-    pub async fn set_prefix(&mut self, messages: Vec<String>) {
+    /// Fails if a worker does; the next call then starts over from the
+    /// whole dialog.
+    pub async fn set_prefix(&mut self, messages: Vec<String>) -> Result<(), JsError> {
         let messages: Vec<Message> = messages
             .iter()
             .map(|message| serde_json::from_str(message).unwrap())
@@ -22,12 +26,16 @@ impl LlamaAPI {
 
         self.generator
             .set_tokens(&self.tokenizer.encode_dialog_prompt(&messages))
-            .await;
+            .await
+            .map_err(|err| JsError::new(&err.to_string()))
     }
 
-    pub async fn next(&mut self) -> Vec<String> {
+    pub async fn next(&mut self) -> Result<Vec<String>, JsError> {
         let begin = Instant::now();
-        self.generator.next_token().await;
+        self.generator
+            .next_token()
+            .await
+            .map_err(|err| JsError::new(&err.to_string()))?;
 
         let output = self.tokenizer.decode_dialog(self.generator.tokens());
 
@@ -40,7 +48,7 @@ impl LlamaAPI {
 
         info!("Seconds per token: {}", begin.elapsed().as_secs_f64());
 
-        output
+        Ok(output)
     }
 
     pub fn is_finished(&self) -> bool {
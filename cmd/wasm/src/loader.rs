@@ -1,43 +1,119 @@
 use crate::api::LlamaAPI;
 use crate::loader::StatusMessage::Cyanide;
+use futures::stream::{self, StreamExt};
 use generator::Generator;
 use nn::embedding::EmbeddingINT8;
 use nn::layernorm::LayerNorm;
 use nn::llama::{Llama, LlamaSubmodules};
 use nn::llama_block::LlamaBlock;
-use nn::llama_config::LLAMA_3_1_8B_CONFIG;
-use state_dict::from_state_dict::{get_file_by_name, FromStateDict, FromStateDictConf};
+use nn::llama_config::{LlamaConfig, LLAMA_3_1_8B_CONFIG};
+use serde::Serialize;
+use state_dict::download::{
+    reset_progress, set_fetch_limits, set_progress_callback, FetchLimits, Progress,
+};
+use state_dict::error::LoadError;
+use state_dict::from_state_dict::{
+    get_file_by_name, FromStateDict, FromStateDictConf, RemoteWeights,
+};
+use state_dict::weights_source::{set_weights_source, WeightsLayout, WeightsSource};
 use std::mem;
 use std::option::Option;
+use std::rc::Rc;
 use tokenizer::Llama3Tokenizer;
 use tokio::sync::mpsc;
-use wasm_bindgen::prelude::wasm_bindgen;
+use wasm_bindgen::prelude::{wasm_bindgen, JsError};
 use web_sys::Worker;
-use worker_engine::parallel_aqlm::{set_handles, ParallelAQLMLinear};
+use worker_engine::parallel_aqlm::{set_handles, set_weight_source};
+use worker_engine::parallel_attention::ParallelAQLMAttention;
 use worker_engine::parallel_int8::ParallelINT8Linear;
+use worker_engine::parallel_mlp::ParallelAQLMMLP;
 use worker_engine::registry_rpc_handle::RPCLinearRegistryHandle;
+use worker_engine::transport::WebWorkerTransport;
 
 #[wasm_bindgen]
 pub struct LlamaLoader {
     handles: Vec<RPCLinearRegistryHandle>,
+    response_senders: Vec<mpsc::Sender<Vec<u8>>>,
     status_tx: mpsc::Sender<StatusMessage>,
     status_rx: Option<mpsc::Receiver<StatusMessage>>,
+    parallel_layers: usize,
+    weights_source: WeightsSource,
 }
 
+const DEFAULT_PARALLEL_LAYERS: usize = 4;
+
 #[wasm_bindgen]
 impl LlamaLoader {
     pub fn new(workers: Vec<Worker>) -> Self {
-        let handles: Vec<_> = workers
+        let transports: Vec<_> = workers.into_iter().map(WebWorkerTransport::new).collect();
+        let response_senders = transports
+            .iter()
+            .map(|transport| transport.get_sender())
+            .collect();
+        let handles = transports
             .into_iter()
             .map(RPCLinearRegistryHandle::new)
             .collect();
 
+        LlamaLoader {
+            response_senders,
+            ..Self::with_handles(handles)
+        }
+    }
+
+    /// Downloads the weights from `{prefix}{filename}{suffix}` URLs. `layout` is
+    /// `per_tensor`, `single_file` or `sharded`; the last two take the name of
+    /// the safetensors file or of the shard index as `filename`.
+    pub fn set_weights_source(
+        &mut self,
+        prefix: &str,
+        suffix: &str,
+        layout: &str,
+        filename: Option<String>,
+    ) -> Result<(), JsError> {
+        let layout = match (layout, filename) {
+            ("per_tensor", _) => WeightsLayout::PerTensorFiles,
+            ("single_file", Some(filename)) => WeightsLayout::SingleFile(filename),
+            ("sharded", Some(filename)) => WeightsLayout::Sharded(filename),
+            (layout, _) => {
+                return Err(JsError::new(&format!(
+                    "unknown weights layout {layout:?}, or no file name for it"
+                )))
+            }
+        };
+
+        self.weights_source = WeightsSource::new(prefix, suffix, layout);
+        Ok(())
+    }
+
+    /// How many layers are fetched at once.
+    pub fn set_parallel_layers(&mut self, parallel_layers: usize) {
+        self.parallel_layers = parallel_layers;
+    }
+
+    /// Caps the requests and the requested bytes in flight across all layers.
+    pub fn set_fetch_limits(&mut self, max_requests: usize, max_bytes: usize) {
+        set_fetch_limits(FetchLimits {
+            max_requests,
+            max_bytes,
+        });
+    }
+}
+
+impl LlamaLoader {
+    /// Loads onto workers behind any transport, such as `TcpTransport` on
+    /// native targets, instead of web workers. There are no worker response
+    /// senders then: the transports receive their responses themselves.
+    pub fn with_handles(handles: Vec<RPCLinearRegistryHandle>) -> Self {
         let (status_tx, status_rx) = mpsc::channel(1);
 
         LlamaLoader {
             handles,
+            response_senders: Vec::new(),
             status_tx,
             status_rx: Some(status_rx),
+            parallel_layers: DEFAULT_PARALLEL_LAYERS,
+            weights_source: WeightsSource::default(),
         }
     }
 }
@@ -45,10 +121,10 @@ impl LlamaLoader {
 #[wasm_bindgen]
 impl LlamaLoader {
     pub fn get_worker_response_senders(&self) -> Vec<WorkerResponseSender> {
-        self.handles
+        self.response_senders
             .iter()
-            .map(|handle| WorkerResponseSender {
-                response_tx: handle.get_sender(),
+            .map(|response_tx| WorkerResponseSender {
+                response_tx: response_tx.clone(),
             })
             .collect()
     }
@@ -59,80 +135,107 @@ impl LlamaLoader {
         DownloadStatusSender { status_rx }
     }
 
-    pub async fn into_llama_api(mut self) -> LlamaAPI {
-        let llama_api = self.do_into_llama_api().await;
+    /// Loads the model. On failure the error is reported through the status
+    /// channel, the status stream is closed and the error is returned to JS.
+    pub async fn into_llama_api(self) -> Result<LlamaAPI, JsError> {
+        self.load()
+            .await
+            .map_err(|err| JsError::new(&err.to_string()))
+    }
+}
 
-        // let llama_api = llama_api.unwrap();
+impl LlamaLoader {
+    /// `into_llama_api` for Rust callers, which do not go through JS errors.
+    pub async fn load(mut self) -> Result<LlamaAPI, LoadError> {
+        // Nobody listens if the status stream was never taken.
+        self.status_rx = None;
+        let status_tx = self.status_tx.clone();
+        reset_progress();
+        set_progress_callback(Some(Rc::new(move |progress: &Progress| {
+            // Progress is best-effort: updates are dropped while the UI is busy.
+            let _ = status_tx.try_send(StatusMessage::Download(progress.clone()));
+        })));
+
+        let llama_api = self.do_into_llama_api().await;
+        set_progress_callback(None);
 
         match llama_api {
-            Ok(api) => api,
+            Ok(api) => Ok(api),
             Err(err) => {
-                self.send_str_status(err.to_string()).await;
+                log::error!("Failed to load llama: {err}");
 
-                let (status_tx, mut status_rx) = mpsc::channel(1);
-
-                // TODO: return something
-                status_rx.recv().await.unwrap();
-                status_tx.send(1).await.unwrap();
+                self.send_status(StatusMessage::Error {
+                    message: err.to_string(),
+                })
+                .await;
+                self.send_status(Cyanide).await;
 
-                panic!("{}", err);
+                Err(err)
             }
         }
     }
 
     async fn send_loading_status(&mut self, layer_idx: usize, n_layers: usize) {
-        self.send_str_status(format!("Loading llama: {}/{}", layer_idx + 1, n_layers))
-            .await;
+        self.send_status(StatusMessage::Loading {
+            step: layer_idx + 1,
+            n_steps: n_layers,
+        })
+        .await;
     }
 
-    async fn do_into_llama_api(&mut self) -> anyhow::Result<LlamaAPI> {
+    async fn do_into_llama_api(&mut self) -> Result<LlamaAPI, LoadError> {
+        for (worker_idx, handle) in self.handles.iter_mut().enumerate() {
+            handle
+                .handshake()
+                .await
+                .map_err(|err| LoadError::backend(&format!("linear worker {worker_idx}"), err))?;
+        }
         set_handles(mem::take(&mut self.handles)).await;
+        set_weights_source(self.weights_source.clone());
+        set_weight_source(Some(Rc::new(RemoteWeights)));
 
         let generator = {
             let config = &LLAMA_3_1_8B_CONFIG;
-
-            let mut blocks = Vec::new();
-
             let n_layers = config.n_layers;
 
-            for layer_idx in 0..n_layers / 2 {
-                self.send_loading_status(layer_idx, n_layers + 2).await;
-
-                blocks.push(
-                    LlamaBlock::<ParallelAQLMLinear>::from_state_dict(
-                        &format!("model.layers.{i}.", i = layer_idx),
-                        config.clone(),
-                    )
-                    .await?,
-                );
-            }
-
-            let embed_tokens = EmbeddingINT8::from_state_dict("model.embed_tokens.").await?;
-            self.send_loading_status(n_layers / 2, n_layers + 2).await;
-
-            let norm = LayerNorm::from_state_dict("model.norm.", config.norm_eps).await?;
-
-            let lm_head = ParallelINT8Linear::from_state_dict("lm_head.").await?;
-            self.send_loading_status(n_layers / 2 + 1, n_layers + 2)
-                .await;
+            // The embeddings and the head go in the middle, so that the first
+            // half of the blocks shows up in the progress as early as possible.
+            let steps: Vec<LoadStep> = (0..n_layers / 2)
+                .map(LoadStep::Block)
+                .chain([LoadStep::EmbedTokens, LoadStep::Norm, LoadStep::LmHead])
+                .chain((n_layers / 2..n_layers).map(LoadStep::Block))
+                .collect();
+            let n_steps = steps.len();
+
+            // `buffered` runs up to `parallel_layers` steps at once, but yields
+            // them in order.
+            let mut loaded = stream::iter(steps)
+                .map(|step| step.load(config))
+                .buffered(self.parallel_layers.max(1));
 
-            for layer_idx in n_layers / 2..n_layers {
-                self.send_loading_status(layer_idx + 2, n_layers + 2).await;
-
-                blocks.push(
-                    LlamaBlock::<ParallelAQLMLinear>::from_state_dict(
-                        &format!("model.layers.{i}.", i = layer_idx),
-                        config.clone(),
-                    )
-                    .await?,
-                );
+            let mut blocks = Vec::new();
+            let mut embed_tokens = None;
+            let mut norm = None;
+            let mut lm_head = None;
+
+            let mut step_idx = 0;
+            while let Some(module) = loaded.next().await {
+                match module? {
+                    LoadedModule::Block(block) => blocks.push(*block),
+                    LoadedModule::EmbedTokens(module) => embed_tokens = Some(module),
+                    LoadedModule::Norm(module) => norm = Some(module),
+                    LoadedModule::LmHead(module) => lm_head = Some(module),
+                }
+
+                self.send_loading_status(step_idx, n_steps).await;
+                step_idx += 1;
             }
 
             let submodules = LlamaSubmodules {
-                embed_tokens,
+                embed_tokens: embed_tokens.unwrap(),
                 blocks,
-                norm,
-                lm_head,
+                norm: norm.unwrap(),
+                lm_head: lm_head.unwrap(),
             };
 
             let llama = Llama::new(submodules);
@@ -142,7 +245,8 @@ impl LlamaLoader {
         let tokenizer = {
             let tokenizer_data = get_file_by_name("tokenizer.model").await?;
 
-            Llama3Tokenizer::from_data(tokenizer_data)?
+            Llama3Tokenizer::from_data(tokenizer_data)
+                .map_err(|err| LoadError::corrupted("tokenizer.model", err))?
         };
 
         self.send_status(Cyanide).await;
@@ -153,13 +257,47 @@ impl LlamaLoader {
         })
     }
 
-    async fn send_str_status(&mut self, status: String) {
-        self.send_status(StatusMessage::Message(status.to_string()))
-            .await
+    async fn send_status(&mut self, status: StatusMessage) {
+        // Statuses are dropped once nobody listens.
+        let _ = self.status_tx.send(status).await;
     }
+}
 
-    async fn send_status(&mut self, status: StatusMessage) {
-        self.status_tx.send(status).await.unwrap()
+enum LoadStep {
+    Block(usize),
+    EmbedTokens,
+    Norm,
+    LmHead,
+}
+
+enum LoadedModule {
+    Block(Box<LlamaBlock<'static, ParallelAQLMMLP, ParallelAQLMAttention>>),
+    EmbedTokens(EmbeddingINT8<'static>),
+    Norm(LayerNorm<'static>),
+    LmHead(ParallelINT8Linear),
+}
+
+impl LoadStep {
+    async fn load(self, config: &LlamaConfig) -> Result<LoadedModule, LoadError> {
+        Ok(match self {
+            LoadStep::Block(layer_idx) => LoadedModule::Block(Box::new(
+                LlamaBlock::from_state_dict(
+                    &RemoteWeights,
+                    &format!("model.layers.{layer_idx}."),
+                    config.clone(),
+                )
+                .await?,
+            )),
+            LoadStep::EmbedTokens => LoadedModule::EmbedTokens(
+                EmbeddingINT8::from_state_dict(&RemoteWeights, "model.embed_tokens.").await?,
+            ),
+            LoadStep::Norm => LoadedModule::Norm(
+                LayerNorm::from_state_dict(&RemoteWeights, "model.norm.", config.norm_eps).await?,
+            ),
+            LoadStep::LmHead => LoadedModule::LmHead(
+                ParallelINT8Linear::from_state_dict(&RemoteWeights, "lm_head.").await?,
+            ),
+        })
     }
 }
 
@@ -170,13 +308,12 @@ pub struct DownloadStatusSender {
 
 #[wasm_bindgen]
 impl DownloadStatusSender {
+    /// Next status update as JSON, tagged by `type`: `loading`, `download`,
+    /// `error` or `cyanide`, the last one meaning that no more updates will follow.
     pub async fn get_status(&mut self) -> String {
         let message = self.status_rx.recv().await.unwrap_or(Cyanide);
 
-        match message {
-            StatusMessage::Message(output) => output,
-            Cyanide => "cyanide".to_string(),
-        }
+        serde_json::to_string(&message).unwrap()
     }
 }
 
@@ -185,8 +322,12 @@ pub struct WorkerResponseSender {
     response_tx: mpsc::Sender<Vec<u8>>,
 }
 
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
 enum StatusMessage {
-    Message(String),
+    Loading { step: usize, n_steps: usize },
+    Download(Progress),
+    Error { message: String },
     Cyanide,
 }
 
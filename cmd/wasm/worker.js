@@ -2,6 +2,26 @@ import init, {LlamaLoader} from './pkg/wasm.js';
 
 let LLAMA = undefined;
 
+function formatBytes(n_bytes) {
+    return `${(n_bytes / 1e9).toFixed(2)} GB`;
+}
+
+function formatLoadingStatus(step, download) {
+    let parts = [];
+    if (step !== undefined) {
+        parts.push(`Loading llama: ${step.step}/${step.n_steps}`);
+    }
+    if (download !== undefined) {
+        if (download.overall_total !== null) {
+            let percent = (100 * download.overall_done / download.overall_total).toFixed(1);
+            parts.push(`${formatBytes(download.overall_done)} / ${formatBytes(download.overall_total)} (${percent}%)`);
+        } else {
+            parts.push(`${formatBytes(download.overall_done)} downloaded`);
+        }
+    }
+    return parts.join(', ');
+}
+
 onmessage = async (e) => {
     let oldMessages = e.data;
 
@@ -27,16 +47,28 @@ onmessage = async (e) => {
         }
 
         let status_sender = loader.get_download_status_sender();
+        let loadError = undefined;
         let status_callback = (async () => {
+            let step = undefined;
+            let download = undefined;
             while (true) {
-                let newMessages = oldMessages.slice();
-                let status = await status_sender.get_status();
-                if (status === "cyanide") {
+                let status = JSON.parse(await status_sender.get_status());
+                if (status.type === "cyanide") {
                     break
                 }
+                if (status.type === "loading") {
+                    step = status;
+                } else if (status.type === "download") {
+                    download = status;
+                } else if (status.type === "error") {
+                    loadError = status.message;
+                    continue
+                }
+
+                let newMessages = oldMessages.slice();
                 newMessages.push({
                     'role': 'Assistant',
-                    'content': status,
+                    'content': formatLoadingStatus(step, download),
                 })
                 postMessage({
                     'messages': newMessages,
@@ -45,7 +77,21 @@ onmessage = async (e) => {
             }
         })();
 
-        LLAMA = await loader.into_llama_api();
+        try {
+            LLAMA = await loader.into_llama_api();
+        } catch (err) {
+            await status_callback
+            let newMessages = oldMessages.slice();
+            newMessages.push({
+                'role': 'Assistant',
+                'content': `Failed to load llama: ${loadError ?? err.message}`,
+            })
+            postMessage({
+                'messages': newMessages,
+                'is_finished': true,
+            });
+            return
+        }
 
         await status_callback
     }
@@ -74,25 +120,38 @@ onmessage = async (e) => {
         'content': 'You are a helpful chat assistant.',
     }].concat(e.data);
 
-    await LLAMA.set_prefix(allMessages.map(msg => {
-        return JSON.stringify(msg);
-    }));
-
-    while (true) {
-        let newMessages = (await LLAMA.next()).map(msg => {
-            return JSON.parse(msg);
-        }).filter(msg => {
-            return msg.role !== "System";
-        });
-        let is_finished = LLAMA.is_finished();
-
+    try {
+        await LLAMA.set_prefix(allMessages.map(msg => {
+            return JSON.stringify(msg);
+        }));
+
+        while (true) {
+            let newMessages = (await LLAMA.next()).map(msg => {
+                return JSON.parse(msg);
+            }).filter(msg => {
+                return msg.role !== "System";
+            });
+            let is_finished = LLAMA.is_finished();
+
+            postMessage({
+                'messages': newMessages,
+                'is_finished': is_finished,
+            });
+
+            if (is_finished) {
+                break
+            }
+        }
+    } catch (err) {
+        // The generator starts over from the whole dialog on the next message.
+        let newMessages = oldMessages.slice();
+        newMessages.push({
+            'role': 'Assistant',
+            'content': `Failed to generate: ${err.message}`,
+        })
         postMessage({
             'messages': newMessages,
-            'is_finished': is_finished,
+            'is_finished': true,
         });
-
-        if (is_finished) {
-            break
-        }
     }
 };
@@ -1,25 +1,28 @@
 // use log::info;
 use nn::functional::softmax_one_row;
-use nn::linear::Module;
+use nn::linear::{ForwardError, Module};
 use nn::llama::Llama;
 use rand::random;
 // use web_time::Instant;
 
-pub struct Generator<BlockLinearType, HeadLinearType>
+pub struct Generator<'a, HeadLinearType, MLPType, AttentionType>
 where
-    BlockLinearType: Module,
     HeadLinearType: Module,
+    MLPType: Module,
+    AttentionType: Module,
 {
-    model: Llama<BlockLinearType, HeadLinearType>,
+    model: Llama<'a, HeadLinearType, MLPType, AttentionType>,
     tokens: Vec<usize>,
 }
 
-impl<BlockLinearType, HeadLinearType> Generator<BlockLinearType, HeadLinearType>
+impl<'a, HeadLinearType, MLPType, AttentionType>
+    Generator<'a, HeadLinearType, MLPType, AttentionType>
 where
-    BlockLinearType: Module,
     HeadLinearType: Module,
+    MLPType: Module,
+    AttentionType: Module,
 {
-    pub fn new(model: Llama<BlockLinearType, HeadLinearType>) -> Self {
+    pub fn new(model: Llama<'a, HeadLinearType, MLPType, AttentionType>) -> Self {
         Self {
             model,
             tokens: Vec::new(),
@@ -27,15 +30,19 @@ where
     }
 }
 
-impl<BlockLinearType, HeadLinearType> Generator<BlockLinearType, HeadLinearType>
+impl<HeadLinearType, MLPType, AttentionType> Generator<'_, HeadLinearType, MLPType, AttentionType>
 where
-    BlockLinearType: Module,
     HeadLinearType: Module,
+    MLPType: Module,
+    AttentionType: Module,
 {
-    pub async fn next_token(&mut self) -> usize {
+    /// Samples the next token. A failed forward leaves the KV caches out of
+    /// step with the tokens, so the generator starts over from no tokens.
+    pub async fn next_token(&mut self) -> Result<usize, ForwardError> {
         // let begin = Instant::now();
 
-        let logits = self.model.forward(*self.tokens.last().unwrap()).await;
+        let token = *self.tokens.last().unwrap();
+        let logits = self.forward_or_clear(token).await?;
 
         // let model_time = begin.elapsed().as_secs_f64();
 
@@ -47,7 +54,15 @@ where
 
         // info!("Model time: {}, Sample time: {}", model_time, sample_time);
 
-        new_token
+        Ok(new_token)
+    }
+
+    async fn forward_or_clear(&mut self, token: usize) -> Result<Vec<f32>, ForwardError> {
+        let logits = self.model.forward(token).await;
+        if logits.is_err() {
+            self.clear();
+        }
+        logits
     }
 
     fn top_p(mut logits: Vec<f32>) -> Vec<f32> {
@@ -98,16 +113,18 @@ where
         n_tokens - 1
     }
 
-    pub async fn add_tokens(&mut self, tokens: &[usize]) {
+    /// Like `next_token`, starts over from no tokens if a forward fails.
+    pub async fn add_tokens(&mut self, tokens: &[usize]) -> Result<(), ForwardError> {
         for token in tokens {
-            if let Some(token) = self.tokens.last() {
-                self.model.forward(*token).await;
+            if let Some(&last) = self.tokens.last() {
+                self.forward_or_clear(last).await?;
             }
             self.tokens.push(*token);
         }
+        Ok(())
     }
 
-    pub async fn set_tokens(&mut self, tokens: &[usize]) {
+    pub async fn set_tokens(&mut self, tokens: &[usize]) -> Result<(), ForwardError> {
         assert!(tokens.starts_with(&self.tokens));
         self.add_tokens(&tokens[self.tokens.len()..tokens.len()])
             .await
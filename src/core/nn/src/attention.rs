@@ -1,8 +1,10 @@
 use crate::functional::softmax_row;
-use crate::linear::Module;
+use crate::linear::{ForwardError, Module};
+use async_trait::async_trait;
 use tensorlib::functional::{cat_row, linear};
 use tensorlib::matrix::{Matrix, OwnedMatrix};
 
+#[derive(Debug, Clone)]
 pub struct AttentionConfig {
     pub dim: usize,
     pub head_dim: usize,
@@ -45,13 +47,22 @@ where
     pub fn new(submodules: AttentionSubmodules<LinearType>, config: AttentionConfig) -> Self {
         Attention { submodules, config }
     }
+
+    pub fn submodules(&self) -> &AttentionSubmodules<LinearType> {
+        &self.submodules
+    }
+
+    pub fn config(&self) -> &AttentionConfig {
+        &self.config
+    }
 }
 
-impl<LinearType> Attention<LinearType>
+#[async_trait(?Send)]
+impl<LinearType> Module for Attention<LinearType>
 where
     LinearType: Module,
 {
-    pub async fn forward(&mut self, x: &[f32]) -> Matrix {
+    async fn forward(&mut self, x: &[f32]) -> Result<OwnedMatrix, ForwardError> {
         let config = &self.config;
         let (head_dim, n_heads, n_kv_heads, rope_theta) = (
             config.head_dim,
@@ -64,9 +75,20 @@ where
         assert_eq!(self.submodules.v_proj.n_cached_tokens(), n_cached_tokens,);
         let n_tokens = n_cached_tokens + 1;
 
-        let tokens_q_proj = self.submodules.q_proj.forward(x).await;
-        let tokens_k_proj = self.submodules.k_proj.forward(x).await;
-        let tokens_v_proj = self.submodules.v_proj.forward(x).await;
+        let submodules = &mut self.submodules;
+        let mut outputs = LinearType::forward_batch(
+            &mut [
+                &mut submodules.q_proj,
+                &mut submodules.k_proj.inner,
+                &mut submodules.v_proj.inner,
+            ],
+            x,
+        )
+        .await?
+        .into_iter();
+        let tokens_q_proj = outputs.next().unwrap();
+        let tokens_k_proj = submodules.k_proj.push(outputs.next().unwrap());
+        let tokens_v_proj = submodules.v_proj.push(outputs.next().unwrap());
 
         assert_eq!(tokens_q_proj.shape(), (1, head_dim * n_heads));
         assert_eq!(tokens_k_proj.shape(), (n_tokens, head_dim * n_kv_heads));
@@ -104,12 +126,29 @@ where
 
         // TODO: matrix!!!
         let q_last_kv = cat_row(&qkv_heads).into_data().into_owned();
-        let output_last = self.submodules.o_proj.forward(&q_last_kv).await;
+        let output_last = self.submodules.o_proj.forward(&q_last_kv).await?;
         assert_eq!(output_last.shape(), (1, x.len()));
 
-        output_last
+        Ok(output_last)
     }
 
+    fn shape(&self) -> (usize, usize) {
+        (
+            self.submodules.o_proj.shape().0,
+            self.submodules.q_proj.shape().1,
+        )
+    }
+
+    fn clear_cache(&mut self) {
+        self.submodules.v_proj.cache.clear();
+        self.submodules.k_proj.cache.clear();
+    }
+}
+
+impl<LinearType> Attention<LinearType>
+where
+    LinearType: Module,
+{
     fn get_proj_head(config: &AttentionConfig, tokens_proj: &Matrix, head: usize) -> OwnedMatrix {
         let n_tokens = tokens_proj.n_rows();
         let head_dim = config.head_dim;
@@ -121,11 +160,6 @@ where
             head * head_dim,
         )
     }
-
-    pub fn clear_cache(&mut self) {
-        self.submodules.v_proj.cache.clear();
-        self.submodules.k_proj.cache.clear();
-    }
 }
 
 pub struct RotaryEmbeddingConfig {
@@ -153,15 +187,19 @@ impl<LinearType: Module> CachedAttentionLinear<LinearType> {
             emb_config,
         }
     }
+
+    pub fn inner(&self) -> &LinearType {
+        &self.inner
+    }
 }
 
 impl<LinearType: Module> CachedAttentionLinear<LinearType> {
-    async fn forward<'a, 'b>(&'a mut self, x: &'b [f32]) -> Matrix<'a> {
+    /// Appends the projection of a new token, computed by `inner`, and returns
+    /// the projections of every token so far.
+    fn push(&mut self, new_data: OwnedMatrix) -> Matrix<'_> {
         let out_dim = self.inner.shape().0;
         let cached_tokens = self.cache.len() / out_dim;
 
-        let new_data = self.inner.forward(x).await;
-
         let new_data = match &self.emb_config {
             None => new_data,
             Some(conf) => {
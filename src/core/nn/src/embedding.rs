@@ -9,6 +9,10 @@ impl<'a> EmbeddingINT8<'a> {
         Self { weight }
     }
 
+    pub fn weight(&self) -> &MatrixInt8<'a> {
+        &self.weight
+    }
+
     pub fn forward(&mut self, x: usize) -> Vec<f32> {
         self.weight.get_row(x)
     }
@@ -9,6 +9,14 @@ impl<'a> LayerNorm<'a> {
     pub fn new(weight: Cow<'a, [f32]>, norm_eps: f32) -> Self {
         LayerNorm { weight, norm_eps }
     }
+
+    pub fn weight(&self) -> &[f32] {
+        &self.weight
+    }
+
+    pub fn norm_eps(&self) -> f32 {
+        self.norm_eps
+    }
 }
 
 impl LayerNorm<'_> {
@@ -5,9 +5,12 @@ pub mod functional;
 pub mod layernorm;
 pub mod linear;
 pub mod linear_aqlm;
+pub mod linear_gguf;
 pub mod linear_int8;
 pub mod llama;
 pub mod llama_block;
 pub mod llama_config;
 pub mod matrix_int8;
 pub mod mlp;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
@@ -1,6 +1,11 @@
 use async_trait::async_trait;
+use std::error::Error;
 use tensorlib::matrix::{Matrix, OwnedMatrix};
 
+/// Why a forward failed, e.g. because the worker running a module went away.
+/// Modules that compute locally never fail.
+pub type ForwardError = Box<dyn Error>;
+
 #[async_trait(?Send)]
 pub trait Forward {
     async fn forward(&mut self, x: &Matrix) -> OwnedMatrix;
@@ -8,6 +13,27 @@ pub trait Forward {
 
 #[async_trait(?Send)]
 pub trait Module {
-    async fn forward(&mut self, x: &[f32]) -> OwnedMatrix;
+    async fn forward(&mut self, x: &[f32]) -> Result<OwnedMatrix, ForwardError>;
     fn shape(&self) -> (usize, usize);
+
+    /// Forgets the tokens seen so far. Only modules that keep state between
+    /// tokens, like the KV cache of attention, need to override this.
+    fn clear_cache(&mut self) {}
+
+    /// Runs several modules on the same input, returning the outputs in
+    /// order. Modules living on remote workers override this to share round
+    /// trips.
+    async fn forward_batch(
+        modules: &mut [&mut Self],
+        x: &[f32],
+    ) -> Result<Vec<OwnedMatrix>, ForwardError>
+    where
+        Self: Sized,
+    {
+        let mut outputs = Vec::with_capacity(modules.len());
+        for module in modules.iter_mut() {
+            outputs.push(module.forward(x).await?);
+        }
+        Ok(outputs)
+    }
 }
@@ -1,4 +1,4 @@
-use crate::linear::Module;
+use crate::linear::{ForwardError, Module};
 use async_trait::async_trait;
 use std::borrow::Cow;
 use tensorlib::functional::linear;
@@ -29,11 +29,48 @@ impl<'a> LinearAQLM<'a> {
             in_group_dim,
         }
     }
+
+    pub fn codebooks(&self) -> &[f32] {
+        &self.codebooks
+    }
+
+    pub fn scales(&self) -> &[f32] {
+        &self.scales
+    }
+
+    /// Codes in the `codes_120` layout, `[in_group_dim, 2, out_dim]`.
+    pub fn codes(&self) -> &[u8] {
+        &self.codes
+    }
+
+    /// The dense `[out_dim, in_dim]` weight, including the scales.
+    pub fn dequantize(&self) -> OwnedMatrix {
+        let in_dim = self.in_group_dim * 8;
+        let mut weight = vec![0f32; self.out_dim * in_dim];
+
+        for in_group_idx in 0..self.in_group_dim {
+            for codebook_idx in 0..2 {
+                for out_idx in 0..self.out_dim {
+                    let code = self.codes
+                        [in_group_idx * (2 * self.out_dim) + codebook_idx * self.out_dim + out_idx]
+                        as usize;
+                    let codeword = &self.codebooks[(codebook_idx * 256 + code) * 8..][..8];
+                    let group = &mut weight[out_idx * in_dim + in_group_idx * 8..][..8];
+
+                    for (v, c) in group.iter_mut().zip(codeword) {
+                        *v += c * self.scales[out_idx];
+                    }
+                }
+            }
+        }
+
+        OwnedMatrix::from_vec((self.out_dim, in_dim), weight)
+    }
 }
 
 #[async_trait(?Send)]
 impl Module for LinearAQLM<'_> {
-    async fn forward(&mut self, x: &[f32]) -> OwnedMatrix {
+    async fn forward(&mut self, x: &[f32]) -> Result<OwnedMatrix, ForwardError> {
         let x = Matrix::from_slice((1, x.len()), x);
 
         assert_eq!(x.n_rows(), 1);
@@ -57,7 +94,7 @@ impl Module for LinearAQLM<'_> {
             self.in_group_dim,
         );
         let output = OwnedMatrix::from_vec((self.out_dim, batch_size), output).transpose();
-        output.multiply_row(&self.scales)
+        Ok(output.multiply_row(&self.scales))
     }
 
     fn shape(&self) -> (usize, usize) {
@@ -133,3 +170,42 @@ pub unsafe fn get_lut_0123(
             + code_idx,
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::pseudo_random;
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_forward_matches_dequantize() {
+        let (out_dim, in_group_dim) = (5, 3);
+        let mut linear = LinearAQLM::new(
+            Cow::Owned(pseudo_random(2 * 256 * 8, 1)),
+            Cow::Owned(vec![0.5, 1.0, 1.5, 2.0, -1.0]),
+            Cow::Owned(
+                (0..out_dim * in_group_dim * 2)
+                    .map(|idx| (idx * 37 % 256) as u8)
+                    .collect(),
+            ),
+            out_dim,
+            in_group_dim,
+        );
+
+        let weight = linear.dequantize();
+        assert_eq!(weight.shape(), linear.shape());
+
+        let x = pseudo_random(in_group_dim * 8, 2);
+        let output = block_on(linear.forward(&x)).unwrap();
+        assert_eq!(output.shape(), (1, out_dim));
+
+        for (row, actual) in weight
+            .data()
+            .chunks_exact(x.len())
+            .zip(output.data().iter())
+        {
+            let expected: f32 = row.iter().zip(&x).map(|(a, b)| a * b).sum();
+            assert!((actual - expected).abs() < 1e-4, "{actual} != {expected}");
+        }
+    }
+}
@@ -0,0 +1,375 @@
+use crate::linear::{ForwardError, Module};
+use async_trait::async_trait;
+use half::f16;
+use std::borrow::Cow;
+use tensorlib::matrix::OwnedMatrix;
+
+/// Tensor types of GGUF files, with the ids ggml uses for them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GgmlType {
+    F32,
+    F16,
+    Q4_0,
+    Q8_0,
+    Q4K,
+    Q6K,
+}
+
+const QK_K: usize = 256;
+
+impl GgmlType {
+    pub fn from_id(id: u32) -> Option<Self> {
+        match id {
+            0 => Some(Self::F32),
+            1 => Some(Self::F16),
+            2 => Some(Self::Q4_0),
+            8 => Some(Self::Q8_0),
+            12 => Some(Self::Q4K),
+            14 => Some(Self::Q6K),
+            _ => None,
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        match self {
+            Self::F32 => 0,
+            Self::F16 => 1,
+            Self::Q4_0 => 2,
+            Self::Q8_0 => 8,
+            Self::Q4K => 12,
+            Self::Q6K => 14,
+        }
+    }
+
+    /// Number of values in one block.
+    pub fn block_size(&self) -> usize {
+        match self {
+            Self::F32 | Self::F16 => 1,
+            Self::Q4_0 | Self::Q8_0 => 32,
+            Self::Q4K | Self::Q6K => QK_K,
+        }
+    }
+
+    /// Number of bytes in one block.
+    pub fn type_size(&self) -> usize {
+        match self {
+            Self::F32 => 4,
+            Self::F16 => 2,
+            Self::Q4_0 => 2 + 16,
+            Self::Q8_0 => 2 + 32,
+            Self::Q4K => 2 + 2 + 12 + QK_K / 2,
+            Self::Q6K => QK_K / 2 + QK_K / 4 + QK_K / 16 + 2,
+        }
+    }
+
+    /// Number of bytes taken by `n_values` values, which must be a whole number of blocks.
+    /// `None` as well if the size overflows.
+    pub fn row_size(&self, n_values: usize) -> Option<usize> {
+        if !n_values.is_multiple_of(self.block_size()) {
+            return None;
+        }
+        (n_values / self.block_size()).checked_mul(self.type_size())
+    }
+}
+
+fn read_f16(data: &[u8]) -> f32 {
+    f16::from_le_bytes([data[0], data[1]]).to_f32()
+}
+
+/// Decodes the 6-bit scale and min of sub-block `j` of a Q4_K block.
+fn get_scale_min_k4(j: usize, scales: &[u8]) -> (f32, f32) {
+    let (scale, min) = if j < 4 {
+        (scales[j] & 63, scales[j + 4] & 63)
+    } else {
+        (
+            (scales[j + 4] & 0xF) | ((scales[j - 4] >> 6) << 4),
+            (scales[j + 4] >> 4) | ((scales[j] >> 6) << 4),
+        )
+    };
+    (scale as f32, min as f32)
+}
+
+/// Dequantizes one block of `ggml_type` into `output`, which holds `block_size` values.
+fn dequantize_block(ggml_type: GgmlType, block: &[u8], output: &mut [f32]) {
+    match ggml_type {
+        GgmlType::F32 => output[0] = f32::from_le_bytes([block[0], block[1], block[2], block[3]]),
+        GgmlType::F16 => output[0] = read_f16(block),
+        GgmlType::Q4_0 => {
+            let d = read_f16(block);
+            for (j, q) in block[2..18].iter().enumerate() {
+                output[j] = ((q & 0xF) as i32 - 8) as f32 * d;
+                output[j + 16] = ((q >> 4) as i32 - 8) as f32 * d;
+            }
+        }
+        GgmlType::Q8_0 => {
+            let d = read_f16(block);
+            for (v, q) in output.iter_mut().zip(&block[2..34]) {
+                *v = *q as i8 as f32 * d;
+            }
+        }
+        GgmlType::Q4K => {
+            let d = read_f16(block);
+            let dmin = read_f16(&block[2..]);
+            let scales = &block[4..16];
+            let qs = &block[16..16 + QK_K / 2];
+
+            for (chunk_idx, (q, output)) in qs
+                .chunks_exact(32)
+                .zip(output.chunks_exact_mut(64))
+                .enumerate()
+            {
+                let (scale1, min1) = get_scale_min_k4(2 * chunk_idx, scales);
+                let (scale2, min2) = get_scale_min_k4(2 * chunk_idx + 1, scales);
+                for (l, q) in q.iter().enumerate() {
+                    output[l] = d * scale1 * (q & 0xF) as f32 - dmin * min1;
+                    output[l + 32] = d * scale2 * (q >> 4) as f32 - dmin * min2;
+                }
+            }
+        }
+        GgmlType::Q6K => {
+            let ql = &block[..QK_K / 2];
+            let qh = &block[QK_K / 2..QK_K / 2 + QK_K / 4];
+            let scales = &block[QK_K / 2 + QK_K / 4..QK_K / 2 + QK_K / 4 + QK_K / 16];
+            let d = read_f16(&block[QK_K / 2 + QK_K / 4 + QK_K / 16..]);
+
+            for half_idx in 0..2 {
+                let ql = &ql[half_idx * 64..];
+                let qh = &qh[half_idx * 32..];
+                let scales = &scales[half_idx * 8..];
+                let output = &mut output[half_idx * 128..];
+
+                for l in 0..32 {
+                    let is = l / 16;
+                    let q1 = ((ql[l] & 0xF) | ((qh[l] & 3) << 4)) as i32 - 32;
+                    let q2 = ((ql[l + 32] & 0xF) | (((qh[l] >> 2) & 3) << 4)) as i32 - 32;
+                    let q3 = ((ql[l] >> 4) | (((qh[l] >> 4) & 3) << 4)) as i32 - 32;
+                    let q4 = ((ql[l + 32] >> 4) | (((qh[l] >> 6) & 3) << 4)) as i32 - 32;
+
+                    output[l] = d * (scales[is] as i8) as f32 * q1 as f32;
+                    output[l + 32] = d * (scales[is + 2] as i8) as f32 * q2 as f32;
+                    output[l + 64] = d * (scales[is + 4] as i8) as f32 * q3 as f32;
+                    output[l + 96] = d * (scales[is + 6] as i8) as f32 * q4 as f32;
+                }
+            }
+        }
+    }
+}
+
+/// Dequantizes a row of `output.len()` values stored as `ggml_type`.
+pub fn dequantize_row(ggml_type: GgmlType, data: &[u8], output: &mut [f32]) {
+    let (block_size, type_size) = (ggml_type.block_size(), ggml_type.type_size());
+    assert_eq!(Some(data.len()), ggml_type.row_size(output.len()));
+
+    for (block, output) in data
+        .chunks_exact(type_size)
+        .zip(output.chunks_exact_mut(block_size))
+    {
+        dequantize_block(ggml_type, block, output);
+    }
+}
+
+fn dot_row(ggml_type: GgmlType, data: &[u8], x: &[f32]) -> f32 {
+    let (block_size, type_size) = (ggml_type.block_size(), ggml_type.type_size());
+    let mut block_values = [0f32; QK_K];
+
+    data.chunks_exact(type_size)
+        .zip(x.chunks_exact(block_size))
+        .map(|(block, x)| {
+            let block_values = &mut block_values[..block_size];
+            dequantize_block(ggml_type, block, block_values);
+            block_values.iter().zip(x).map(|(a, b)| a * b).sum::<f32>()
+        })
+        .sum()
+}
+
+/// Linear layer over a row-major `[out_dim, in_dim]` GGUF tensor, dequantized
+/// block by block during the forward pass.
+pub struct LinearGGUF<'a> {
+    data: Cow<'a, [u8]>,
+    ggml_type: GgmlType,
+    out_dim: usize,
+    in_dim: usize,
+}
+
+impl<'a> LinearGGUF<'a> {
+    pub fn new(data: Cow<'a, [u8]>, ggml_type: GgmlType, out_dim: usize, in_dim: usize) -> Self {
+        let row_size = ggml_type.row_size(in_dim).unwrap();
+        assert_eq!(data.len(), out_dim * row_size);
+        Self {
+            data,
+            ggml_type,
+            out_dim,
+            in_dim,
+        }
+    }
+
+    pub fn ggml_type(&self) -> GgmlType {
+        self.ggml_type
+    }
+
+    pub fn dequantize(&self) -> OwnedMatrix {
+        let mut weight = vec![0f32; self.out_dim * self.in_dim];
+        let row_size = self.data.len() / self.out_dim;
+
+        for (row, output) in self
+            .data
+            .chunks_exact(row_size)
+            .zip(weight.chunks_exact_mut(self.in_dim))
+        {
+            dequantize_row(self.ggml_type, row, output);
+        }
+
+        OwnedMatrix::from_vec((self.out_dim, self.in_dim), weight)
+    }
+}
+
+#[async_trait(?Send)]
+impl Module for LinearGGUF<'_> {
+    async fn forward(&mut self, x: &[f32]) -> Result<OwnedMatrix, ForwardError> {
+        assert_eq!(x.len(), self.in_dim);
+        let row_size = self.data.len() / self.out_dim;
+
+        let output = self
+            .data
+            .chunks_exact(row_size)
+            .map(|row| dot_row(self.ggml_type, row, x))
+            .collect();
+
+        Ok(OwnedMatrix::from_vec((1, self.out_dim), output))
+    }
+
+    fn shape(&self) -> (usize, usize) {
+        (self.out_dim, self.in_dim)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::pseudo_random;
+    use futures::executor::block_on;
+
+    fn f16_bytes(v: f32) -> [u8; 2] {
+        f16::from_f32(v).to_le_bytes()
+    }
+
+    fn quantize_q8_0(values: &[f32]) -> Vec<u8> {
+        values
+            .chunks_exact(32)
+            .flat_map(|block| {
+                let d = block.iter().fold(0f32, |max, v| max.max(v.abs())) / 127.0;
+                let mut bytes = f16_bytes(d).to_vec();
+                let d = f16::from_f32(d).to_f32();
+                bytes.extend(block.iter().map(|v| (v / d).round() as i8 as u8));
+                bytes
+            })
+            .collect()
+    }
+
+    fn quantize_q4_0(values: &[f32]) -> Vec<u8> {
+        values
+            .chunks_exact(32)
+            .flat_map(|block| {
+                let d = block.iter().fold(0f32, |max, v| max.max(v.abs())) / 7.0;
+                let mut bytes = f16_bytes(d).to_vec();
+                let d = f16::from_f32(d).to_f32();
+                let q = |v: f32| ((v / d).round() as i32 + 8).clamp(0, 15) as u8;
+                bytes.extend((0..16).map(|j| q(block[j]) | (q(block[j + 16]) << 4)));
+                bytes
+            })
+            .collect()
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32], tolerance: f32) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() <= tolerance, "{actual:?} != {expected:?}");
+        }
+    }
+
+    #[test]
+    fn test_q8_0_and_q4_0_round_trip() {
+        let values = pseudo_random(64, 1);
+        let mut output = vec![0f32; 64];
+
+        dequantize_row(GgmlType::Q8_0, &quantize_q8_0(&values), &mut output);
+        assert_close(&output, &values, 1.0 / 127.0);
+
+        dequantize_row(GgmlType::Q4_0, &quantize_q4_0(&values), &mut output);
+        assert_close(&output, &values, 1.0 / 7.0);
+    }
+
+    #[test]
+    fn test_q4_k_block() {
+        let mut block = vec![0u8; GgmlType::Q4K.type_size()];
+        block[0..2].copy_from_slice(&f16_bytes(0.5));
+        block[2..4].copy_from_slice(&f16_bytes(0.25));
+        // Sub-block 0: scale 3, min 2. Sub-block 5: scale 1 | 2 << 4, min 4 | 1 << 4.
+        block[4] = 3;
+        block[4 + 4] = 2;
+        block[4 + 1] = 2 << 6;
+        block[4 + 5] = 1 << 6;
+        block[4 + 9] = 1 | (4 << 4);
+        // Values 0 and 1 come from sub-block 0, values 128 and 160 from the third chunk.
+        block[16] = 0x0A;
+        block[16 + 64] = 0x5F;
+
+        let mut output = vec![0f32; QK_K];
+        dequantize_row(GgmlType::Q4K, &block, &mut output);
+
+        assert_eq!(output[0], 0.5 * 3.0 * 10.0 - 0.25 * 2.0);
+        assert_eq!(output[1], -0.25 * 2.0);
+        assert_eq!(output[128], 0.0);
+        assert_eq!(output[160], 0.5 * 33.0 * 5.0 - 0.25 * 20.0);
+    }
+
+    #[test]
+    fn test_q6_k_block() {
+        let mut block = vec![0u8; GgmlType::Q6K.type_size()];
+        let (ql, rest) = block.split_at_mut(QK_K / 2);
+        let (qh, rest) = rest.split_at_mut(QK_K / 4);
+        let (scales, d) = rest.split_at_mut(QK_K / 16);
+        d.copy_from_slice(&f16_bytes(0.5));
+        scales.iter_mut().enumerate().for_each(|(idx, s)| {
+            *s = (idx as i8 - 8) as u8;
+        });
+        // Value 0: low bits 5, high bits 2 -> 37 - 32 = 5.
+        ql[0] = 5;
+        qh[0] = 2;
+        // Value 224 (second half, q4 of l = 0): high nibble of ql[96], bits 6-7 of qh[32].
+        ql[96] = 0xF0;
+        qh[32] = 3 << 6;
+
+        let mut output = vec![0f32; QK_K];
+        dequantize_row(GgmlType::Q6K, &block, &mut output);
+
+        assert_eq!(output[0], 0.5 * -8.0 * 5.0);
+        assert_eq!(output[1], 0.5 * -8.0 * -32.0);
+        assert_eq!(output[224], 0.5 * 6.0 * (63.0 - 32.0));
+    }
+
+    #[test]
+    fn test_forward_matches_dequantize() {
+        let (out_dim, in_dim) = (3, 64);
+        let weight = pseudo_random(out_dim * in_dim, 2);
+        let x = pseudo_random(in_dim, 3);
+
+        let f32_bytes: Vec<u8> = weight.iter().flat_map(|v| v.to_le_bytes()).collect();
+        for (ggml_type, data) in [
+            (GgmlType::F32, f32_bytes),
+            (GgmlType::Q8_0, quantize_q8_0(&weight)),
+            (GgmlType::Q4_0, quantize_q4_0(&weight)),
+        ] {
+            let mut linear = LinearGGUF::new(Cow::Owned(data), ggml_type, out_dim, in_dim);
+            let dense = linear.dequantize();
+            let expected: Vec<f32> = dense
+                .data()
+                .chunks_exact(in_dim)
+                .map(|row| row.iter().zip(&x).map(|(a, b)| a * b).sum())
+                .collect();
+
+            let output = block_on(linear.forward(&x)).unwrap();
+            assert_close(output.data(), &expected, 1e-4);
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use crate::linear::Module;
+use crate::linear::{ForwardError, Module};
 use crate::matrix_int8::MatrixInt8;
 use async_trait::async_trait;
 use tensorlib::matrix::OwnedMatrix;
@@ -9,8 +9,8 @@ pub struct LinearINT8<'a> {
 
 #[async_trait(?Send)]
 impl Module for LinearINT8<'_> {
-    async fn forward(&mut self, x: &[f32]) -> OwnedMatrix {
-        self.weight.matmul(x)
+    async fn forward(&mut self, x: &[f32]) -> Result<OwnedMatrix, ForwardError> {
+        Ok(self.weight.matmul(x))
     }
 
     fn shape(&self) -> (usize, usize) {
@@ -22,4 +22,12 @@ impl<'a> LinearINT8<'a> {
     pub fn new(weight: MatrixInt8<'a>) -> Self {
         Self { weight }
     }
+
+    pub fn weight(&self) -> &MatrixInt8<'a> {
+        &self.weight
+    }
+
+    pub fn dequantize(&self) -> OwnedMatrix {
+        self.weight.dequantize()
+    }
 }
@@ -1,43 +1,57 @@
+use crate::attention::Attention;
 use crate::embedding::EmbeddingINT8;
 use crate::layernorm::LayerNorm;
-use crate::linear::Module;
+use crate::linear::{ForwardError, Module};
 use crate::llama_block::LlamaBlock;
+use crate::mlp::MLP;
 
-pub struct LlamaSubmodules<BlockLinearType, HeadLinearType>
+pub struct LlamaSubmodules<'a, HeadLinearType, MLPType, AttentionType>
 where
-    BlockLinearType: Module,
     HeadLinearType: Module,
+    MLPType: Module,
+    AttentionType: Module,
 {
-    pub embed_tokens: EmbeddingINT8<'static>,
-    pub blocks: Vec<LlamaBlock<BlockLinearType>>,
-    pub norm: LayerNorm<'static>,
+    pub embed_tokens: EmbeddingINT8<'a>,
+    pub blocks: Vec<LlamaBlock<'a, MLPType, AttentionType>>,
+    pub norm: LayerNorm<'a>,
     pub lm_head: HeadLinearType,
 }
 
-pub struct Llama<BlockLinearType, HeadLinearType>
+pub struct Llama<'a, HeadLinearType, MLPType, AttentionType>
 where
-    BlockLinearType: Module,
     HeadLinearType: Module,
+    MLPType: Module,
+    AttentionType: Module,
 {
-    submodules: LlamaSubmodules<BlockLinearType, HeadLinearType>,
+    submodules: LlamaSubmodules<'a, HeadLinearType, MLPType, AttentionType>,
 }
 
-impl<BlockLinearType, HeadLinearType> Llama<BlockLinearType, HeadLinearType>
+/// A model whose blocks are `DefaultLlamaBlock`s of `BlockLinearType`.
+pub type DefaultLlama<'a, BlockLinearType, HeadLinearType> =
+    Llama<'a, HeadLinearType, MLP<BlockLinearType>, Attention<BlockLinearType>>;
+
+impl<'a, HeadLinearType, MLPType, AttentionType> Llama<'a, HeadLinearType, MLPType, AttentionType>
 where
-    BlockLinearType: Module,
     HeadLinearType: Module,
+    MLPType: Module,
+    AttentionType: Module,
 {
-    pub fn new(submodules: LlamaSubmodules<BlockLinearType, HeadLinearType>) -> Self {
+    pub fn new(submodules: LlamaSubmodules<'a, HeadLinearType, MLPType, AttentionType>) -> Self {
         Self { submodules }
     }
+
+    pub fn submodules(&self) -> &LlamaSubmodules<'a, HeadLinearType, MLPType, AttentionType> {
+        &self.submodules
+    }
 }
 
-impl<BlockLinearType, HeadLinearType> Llama<BlockLinearType, HeadLinearType>
+impl<HeadLinearType, MLPType, AttentionType> Llama<'_, HeadLinearType, MLPType, AttentionType>
 where
-    BlockLinearType: Module,
     HeadLinearType: Module,
+    MLPType: Module,
+    AttentionType: Module,
 {
-    pub async fn forward(&mut self, token: usize) -> Vec<f32> {
+    pub async fn forward(&mut self, token: usize) -> Result<Vec<f32>, ForwardError> {
         let (embed_tokens, blocks, norm, lm_head) = (
             &mut self.submodules.embed_tokens,
             &mut self.submodules.blocks,
@@ -47,16 +61,16 @@ where
         let mut x = embed_tokens.forward(token);
 
         for block in blocks {
-            x = block.forward(x).await;
+            x = block.forward(x).await?;
         }
 
         let x = norm.forward(x);
         // let x = Matrix::from_vec((1, x.len()), x);
 
-        let x = lm_head.forward(&x).await;
+        let x = lm_head.forward(&x).await?;
         assert_eq!(x.n_rows(), 1);
 
-        x.into_data().into_owned()
+        Ok(x.into_data().into_owned())
     }
 
     pub fn clear_cache(&mut self) {
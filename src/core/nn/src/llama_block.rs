@@ -1,46 +1,60 @@
 use crate::attention::Attention;
 use crate::layernorm::LayerNorm;
-use crate::linear::Module;
+use crate::linear::{ForwardError, Module};
 use crate::mlp::MLP;
 use tensorlib::functional::add_rows;
 
-pub struct LlamaBlockSubmodules<LinearType>
+pub struct LlamaBlockSubmodules<'a, MLPType, AttentionType>
 where
-    LinearType: Module,
+    MLPType: Module,
+    AttentionType: Module,
 {
-    pub input_layernorm: LayerNorm<'static>,
-    pub attention: Attention<LinearType>,
-    pub post_attention_layernorm: LayerNorm<'static>,
-    pub mlp: MLP<LinearType>,
+    pub input_layernorm: LayerNorm<'a>,
+    pub attention: AttentionType,
+    pub post_attention_layernorm: LayerNorm<'a>,
+    pub mlp: MLPType,
 }
 
-pub struct LlamaBlock<LinearType>
+/// `MLPType` and `AttentionType` are anything that maps a row to a row of the
+/// same width, see `DefaultLlamaBlock` for those built from linear layers. The
+/// layer norms may borrow their weights for `'a`, e.g. from a memory-mapped file.
+pub struct LlamaBlock<'a, MLPType, AttentionType>
 where
-    LinearType: Module,
+    MLPType: Module,
+    AttentionType: Module,
 {
-    submodules: LlamaBlockSubmodules<LinearType>,
+    submodules: LlamaBlockSubmodules<'a, MLPType, AttentionType>,
 }
 
-impl<LinearType> LlamaBlock<LinearType>
+/// A block whose MLP and attention run on the given linear layers.
+pub type DefaultLlamaBlock<'a, LinearType> = LlamaBlock<'a, MLP<LinearType>, Attention<LinearType>>;
+
+impl<'a, MLPType, AttentionType> LlamaBlock<'a, MLPType, AttentionType>
 where
-    LinearType: Module,
+    MLPType: Module,
+    AttentionType: Module,
 {
-    pub fn new(submodules: LlamaBlockSubmodules<LinearType>) -> Self {
+    pub fn new(submodules: LlamaBlockSubmodules<'a, MLPType, AttentionType>) -> Self {
         Self { submodules }
     }
+
+    pub fn submodules(&self) -> &LlamaBlockSubmodules<'a, MLPType, AttentionType> {
+        &self.submodules
+    }
 }
 
-impl<LinearType> LlamaBlock<LinearType>
+impl<MLPType, AttentionType> LlamaBlock<'_, MLPType, AttentionType>
 where
-    LinearType: Module,
+    MLPType: Module,
+    AttentionType: Module,
 {
-    pub async fn forward(&mut self, x: Vec<f32>) -> Vec<f32> {
+    pub async fn forward(&mut self, x: Vec<f32>) -> Result<Vec<f32>, ForwardError> {
         let x = {
             let residual = x.clone();
 
             let x = self.submodules.input_layernorm.forward(x);
 
-            let x = self.submodules.attention.forward(&x).await;
+            let x = self.submodules.attention.forward(&x).await?;
 
             add_rows(residual, x.data())
         };
@@ -50,13 +64,14 @@ where
 
             let x = self.submodules.post_attention_layernorm.forward(x);
 
-            let x = self.submodules.mlp.forward(&x).await;
+            let x = self.submodules.mlp.forward(&x).await?;
 
-            add_rows(residual, &x)
+            Ok(add_rows(residual, x.data()))
         }
     }
 
     pub fn clear_cache(&mut self) {
         self.submodules.attention.clear_cache();
+        self.submodules.mlp.clear_cache();
     }
 }
@@ -1,17 +1,37 @@
 use std::borrow::Cow;
 use tensorlib::matrix::OwnedMatrix;
 
+/// Which axis of the matrix `max_values` runs along.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Int8Scaling {
+    /// One max value per column: `value = int8 * max_values[col] / 127`.
+    #[default]
+    PerColumn,
+    /// One max value per row: `value = int8 * max_values[row] / 127`.
+    PerRow,
+}
+
 pub struct MatrixInt8<'a> {
     max_values: Cow<'a, [f32]>,
     int8_values: Cow<'a, [i8]>,
+    scaling: Int8Scaling,
 }
 
 impl<'a> MatrixInt8<'a> {
     pub fn new(max_values: Cow<'a, [f32]>, int8_values: Cow<'a, [i8]>) -> Self {
+        Self::with_scaling(max_values, int8_values, Int8Scaling::PerColumn)
+    }
+
+    pub fn with_scaling(
+        max_values: Cow<'a, [f32]>,
+        int8_values: Cow<'a, [i8]>,
+        scaling: Int8Scaling,
+    ) -> Self {
         assert_eq!(int8_values.len() % max_values.len(), 0);
         Self {
             max_values,
             int8_values,
+            scaling,
         }
     }
 }
@@ -22,25 +42,49 @@ impl MatrixInt8<'_> {
     }
 
     pub fn n_cols(&self) -> usize {
-        self.max_values.len()
+        match self.scaling {
+            Int8Scaling::PerColumn => self.max_values.len(),
+            Int8Scaling::PerRow => self.int8_values.len() / self.max_values.len(),
+        }
     }
 
     pub fn n_rows(&self) -> usize {
-        self.int8_values.len() / self.max_values.len()
+        match self.scaling {
+            Int8Scaling::PerColumn => self.int8_values.len() / self.max_values.len(),
+            Int8Scaling::PerRow => self.max_values.len(),
+        }
+    }
+
+    pub fn scaling(&self) -> Int8Scaling {
+        self.scaling
+    }
+
+    pub fn max_values(&self) -> &[f32] {
+        &self.max_values
+    }
+
+    pub fn int8_values(&self) -> &[i8] {
+        &self.int8_values
     }
 }
 
 impl<'a> MatrixInt8<'a> {
-    pub fn get_row(&mut self, row_idx: usize) -> Vec<f32> {
+    pub fn get_row(&self, row_idx: usize) -> Vec<f32> {
         let n_cols = self.n_cols();
 
         let output = &self.int8_values[row_idx * n_cols..(row_idx + 1) * n_cols];
 
-        output
-            .iter()
-            .zip(self.max_values.iter())
-            .map(|(v, max_value)| (*v as f32) * max_value / 127f32)
-            .collect()
+        match self.scaling {
+            Int8Scaling::PerColumn => output
+                .iter()
+                .zip(self.max_values.iter())
+                .map(|(v, max_value)| (*v as f32) * max_value / 127f32)
+                .collect(),
+            Int8Scaling::PerRow => {
+                let scale = self.max_values[row_idx] / 127f32;
+                output.iter().map(|v| (*v as f32) * scale).collect()
+            }
+        }
     }
 
     pub fn get_rows(&mut self, rows_idx: &[usize]) -> OwnedMatrix {
@@ -48,6 +92,14 @@ impl<'a> MatrixInt8<'a> {
 
         OwnedMatrix::from_vec((rows_idx.len(), self.n_cols()), data)
     }
+
+    pub fn dequantize(&self) -> OwnedMatrix {
+        let data: Vec<f32> = (0..self.n_rows())
+            .flat_map(|idx| self.get_row(idx))
+            .collect();
+
+        OwnedMatrix::from_vec(self.shape(), data)
+    }
 }
 
 impl<'a> MatrixInt8<'a> {
@@ -61,7 +113,17 @@ impl<'a> MatrixInt8<'a> {
         assert_eq!(x.len(), self.n_cols());
         let n_cols = self.n_cols();
 
-        assert_eq!(self.max_values.len(), n_cols);
+        if self.scaling == Int8Scaling::PerRow {
+            return self
+                .int8_values
+                .chunks_exact(n_cols)
+                .zip(self.max_values.iter())
+                .map(|(row, max_value)| {
+                    let dot: f32 = row.iter().zip(x).map(|(a, b)| (*a as f32) * *b).sum();
+                    dot * max_value / 127f32
+                })
+                .collect();
+        }
 
         let x: Vec<f32> = x
             .iter()
@@ -80,3 +142,70 @@ impl<'a> MatrixInt8<'a> {
             .collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_per_column_and_per_row_agree() {
+        // [[1, 2], [3, 4]] quantized both ways.
+        let mut per_column = MatrixInt8::new(
+            Cow::Owned(vec![3.0, 4.0]),
+            Cow::Owned(vec![42, 64, 127, 127]),
+        );
+        let mut per_row = MatrixInt8::with_scaling(
+            Cow::Owned(vec![2.0, 4.0]),
+            Cow::Owned(vec![64, 127, 95, 127]),
+            Int8Scaling::PerRow,
+        );
+        assert_eq!(per_column.shape(), (2, 2));
+        assert_eq!(per_row.shape(), (2, 2));
+
+        let x = [1.0, -1.0];
+        let expected = [-1.0, -1.0];
+        for (a, b) in per_column.matmul_row(&x).iter().zip(expected) {
+            assert!((a - b).abs() < 0.05);
+        }
+        for (a, b) in per_row.matmul_row(&x).iter().zip(expected) {
+            assert!((a - b).abs() < 0.05);
+        }
+        for (a, b) in per_row.get_row(1).iter().zip([3.0, 4.0]) {
+            assert!((a - b).abs() < 0.05);
+        }
+    }
+
+    #[test]
+    fn test_matmul_matches_dequantize() {
+        for scaling in [Int8Scaling::PerColumn, Int8Scaling::PerRow] {
+            let n_max_values = match scaling {
+                Int8Scaling::PerColumn => 5,
+                Int8Scaling::PerRow => 3,
+            };
+            let mut matrix = MatrixInt8::with_scaling(
+                Cow::Owned((0..n_max_values).map(|idx| 0.5 + idx as f32).collect()),
+                Cow::Owned((0..15).map(|idx| (idx * 37 % 255) as u8 as i8).collect()),
+                scaling,
+            );
+            let weight = matrix.dequantize();
+            assert_eq!(weight.shape(), (3, 5));
+
+            let x = [0.5, -1.0, 2.0, 0.25, -0.75];
+            let output = matrix.matmul_row(&x);
+            for (row, actual) in weight.data().chunks_exact(5).zip(output) {
+                let expected: f32 = row.iter().zip(&x).map(|(a, b)| a * b).sum();
+                assert!((actual - expected).abs() < 1e-4);
+            }
+        }
+    }
+
+    #[test]
+    fn test_per_row_shape() {
+        let matrix = MatrixInt8::with_scaling(
+            Cow::Owned(vec![1.0, 1.0, 1.0]),
+            Cow::Owned(vec![0; 6]),
+            Int8Scaling::PerRow,
+        );
+        assert_eq!(matrix.shape(), (3, 2));
+    }
+}
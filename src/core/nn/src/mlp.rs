@@ -1,5 +1,7 @@
 use crate::functional::silu;
-use crate::linear::Module;
+use crate::linear::{ForwardError, Module};
+use async_trait::async_trait;
+use tensorlib::matrix::OwnedMatrix;
 
 pub struct MLPSubmodules<LinearType>
 where
@@ -24,29 +26,41 @@ where
     pub fn new(submodules: MLPSubmodules<LinearType>) -> Self {
         Self { submodules }
     }
+
+    pub fn submodules(&self) -> &MLPSubmodules<LinearType> {
+        &self.submodules
+    }
 }
 
-impl<LinearType> MLP<LinearType>
+/// The feed-forward block, `down_proj(silu(gate_proj(x)) * up_proj(x))`.
+#[async_trait(?Send)]
+impl<LinearType> Module for MLP<LinearType>
 where
     LinearType: Module,
 {
-    pub async fn forward(&mut self, x: &[f32]) -> Vec<f32> {
-        // let x = Matrix::from_slice((1, x.len()), x);
-
+    async fn forward(&mut self, x: &[f32]) -> Result<OwnedMatrix, ForwardError> {
         let x = {
-            let gate = silu(self.submodules.gate_proj.forward(x).await);
-            self.submodules.up_proj.forward(x).await.multiply(&gate)
+            let submodules = &mut self.submodules;
+            let mut outputs = LinearType::forward_batch(
+                &mut [&mut submodules.gate_proj, &mut submodules.up_proj],
+                x,
+            )
+            .await?
+            .into_iter();
+            let (gate, up) = (outputs.next().unwrap(), outputs.next().unwrap());
+
+            up.multiply(&silu(gate))
         }
         .into_data()
         .into_owned();
 
-        // let x = Matrix::from_vec((1, x.len()), x);
+        self.submodules.down_proj.forward(&x).await
+    }
 
-        self.submodules
-            .down_proj
-            .forward(&x)
-            .await
-            .into_data()
-            .into_owned()
+    fn shape(&self) -> (usize, usize) {
+        (
+            self.submodules.down_proj.shape().0,
+            self.submodules.gate_proj.shape().1,
+        )
     }
 }
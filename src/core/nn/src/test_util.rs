@@ -0,0 +1,9 @@
+//! Helpers shared by the tests of this crate and of the crates built on it,
+//! which get them with the `test-util` feature.
+
+/// `n` deterministic values in `[-1, 1]`, varying with `seed`.
+pub fn pseudo_random(n: usize, seed: usize) -> Vec<f32> {
+    (0..n)
+        .map(|idx| (((idx + seed) * 7919) % 201) as f32 / 100.0 - 1.0)
+        .collect()
+}
@@ -0,0 +1,347 @@
+//! Content-addressed cache of downloaded weight files.
+//!
+//! Entries are keyed by the SHA-256 of the URL (plus byte range) and the file
+//! checksum from the manifest, so a new manifest never serves stale data.
+//! Without a manifest, entries are keyed by URL alone.
+
+use crate::manifest::sha256_hex;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::ops::Range;
+use std::rc::Rc;
+
+pub trait WeightCache {
+    fn get(&self, key: &str) -> Option<Vec<u8>>;
+    fn put(&self, key: &str, data: &[u8]);
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    pub hit_bytes: usize,
+}
+
+thread_local! {
+    static WEIGHT_CACHE: RefCell<Option<Rc<dyn WeightCache>>> = const { RefCell::new(None) };
+    static CACHE_STATS: Cell<CacheStats> = const {
+        Cell::new(CacheStats {
+            hits: 0,
+            misses: 0,
+            hit_bytes: 0,
+        })
+    };
+}
+
+pub fn set_weight_cache(cache: Option<Rc<dyn WeightCache>>) {
+    WEIGHT_CACHE.set(cache);
+}
+
+pub fn cache_stats() -> CacheStats {
+    CACHE_STATS.get()
+}
+
+pub fn reset_cache_stats() {
+    CACHE_STATS.set(CacheStats::default());
+}
+
+pub fn cache_key(url: &str, range: Option<&Range<usize>>, checksum: Option<&str>) -> String {
+    let range = range.map_or(String::new(), |range| {
+        format!("#{}-{}", range.start, range.end)
+    });
+    sha256_hex(format!("{url}{range}\n{}", checksum.unwrap_or("")).as_bytes())
+}
+
+pub(crate) fn cache_get(key: &str) -> Option<Vec<u8>> {
+    let cache = WEIGHT_CACHE.with_borrow(|cache| cache.clone())?;
+    let data = cache.get(key);
+
+    let mut stats = CACHE_STATS.get();
+    match &data {
+        Some(data) => {
+            stats.hits += 1;
+            stats.hit_bytes += data.len();
+        }
+        None => stats.misses += 1,
+    }
+    CACHE_STATS.set(stats);
+
+    data
+}
+
+pub(crate) fn cache_put(key: &str, data: &[u8]) {
+    if let Some(cache) = WEIGHT_CACHE.with_borrow(|cache| cache.clone()) {
+        cache.put(key, data);
+    }
+}
+
+/// Least-recently-used bookkeeping shared by the cache implementations.
+#[derive(Default)]
+struct LruIndex {
+    entries: HashMap<String, (usize, u64)>,
+    total_size: usize,
+    tick: u64,
+}
+
+impl LruIndex {
+    fn touch(&mut self, key: &str) {
+        self.tick += 1;
+        if let Some(entry) = self.entries.get_mut(key) {
+            entry.1 = self.tick;
+        }
+    }
+
+    /// Adds an entry and returns the keys evicted to stay within `max_size`.
+    fn insert(&mut self, key: &str, size: usize, max_size: usize) -> Vec<String> {
+        self.remove(key);
+        self.tick += 1;
+        self.entries.insert(key.to_string(), (size, self.tick));
+        self.total_size += size;
+
+        let mut evicted = Vec::new();
+        while self.total_size > max_size {
+            let Some(oldest) = self
+                .entries
+                .iter()
+                .filter(|(other, _)| other.as_str() != key)
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(oldest, _)| oldest.clone())
+            else {
+                break;
+            };
+            self.remove(&oldest);
+            evicted.push(oldest);
+        }
+        evicted
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some((size, _)) = self.entries.remove(key) {
+            self.total_size -= size;
+        }
+    }
+}
+
+/// In-memory cache, for repeated loads within one session.
+pub struct MemoryWeightCache {
+    max_size: usize,
+    data: RefCell<HashMap<String, Vec<u8>>>,
+    index: RefCell<LruIndex>,
+}
+
+impl MemoryWeightCache {
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            max_size,
+            data: RefCell::new(HashMap::new()),
+            index: RefCell::new(LruIndex::default()),
+        }
+    }
+
+    pub fn total_size(&self) -> usize {
+        self.index.borrow().total_size
+    }
+}
+
+impl WeightCache for MemoryWeightCache {
+    fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let data = self.data.borrow().get(key).cloned()?;
+        self.index.borrow_mut().touch(key);
+        Some(data)
+    }
+
+    fn put(&self, key: &str, data: &[u8]) {
+        if data.len() > self.max_size {
+            return;
+        }
+
+        let evicted = self
+            .index
+            .borrow_mut()
+            .insert(key, data.len(), self.max_size);
+
+        let mut cache_data = self.data.borrow_mut();
+        for key in evicted {
+            cache_data.remove(&key);
+        }
+        cache_data.insert(key.to_string(), data.to_vec());
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use fs_cache::FsWeightCache;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod fs_cache {
+    use super::{LruIndex, WeightCache};
+    use std::cell::RefCell;
+    use std::fs::{self, File, FileTimes};
+    use std::io;
+    use std::path::{Path, PathBuf};
+    use std::time::SystemTime;
+
+    /// Cache directory with one file per entry. Recency is kept in file
+    /// modification times, so eviction order survives restarts.
+    pub struct FsWeightCache {
+        dir: PathBuf,
+        max_size: usize,
+        index: RefCell<LruIndex>,
+    }
+
+    impl FsWeightCache {
+        pub fn open(dir: impl AsRef<Path>, max_size: usize) -> io::Result<Self> {
+            let dir = dir.as_ref().to_path_buf();
+            fs::create_dir_all(&dir)?;
+
+            let mut entries = Vec::new();
+            for entry in fs::read_dir(&dir)? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                let key = entry.file_name().to_string_lossy().to_string();
+
+                if !metadata.is_file() || key.ends_with(".tmp") {
+                    continue;
+                }
+                entries.push((metadata.modified()?, key, metadata.len() as usize));
+            }
+            entries.sort();
+
+            let cache = Self {
+                dir,
+                max_size,
+                index: RefCell::new(LruIndex::default()),
+            };
+
+            for (_, key, size) in entries {
+                cache.insert_index(&key, size);
+            }
+
+            Ok(cache)
+        }
+
+        pub fn total_size(&self) -> usize {
+            self.index.borrow().total_size
+        }
+
+        fn path(&self, key: &str) -> PathBuf {
+            self.dir.join(key)
+        }
+
+        fn insert_index(&self, key: &str, size: usize) {
+            let evicted = self.index.borrow_mut().insert(key, size, self.max_size);
+            for key in evicted {
+                if let Err(err) = fs::remove_file(self.path(&key)) {
+                    log::warn!("failed to evict {key} from the weight cache: {err}");
+                }
+            }
+        }
+
+        fn write(&self, key: &str, data: &[u8]) -> io::Result<()> {
+            // Write to a temporary file first, so that a crash never leaves a truncated entry.
+            let tmp_path = self.path(&format!("{key}.tmp"));
+            fs::write(&tmp_path, data)?;
+            fs::rename(&tmp_path, self.path(key))
+        }
+    }
+
+    impl WeightCache for FsWeightCache {
+        fn get(&self, key: &str) -> Option<Vec<u8>> {
+            let path = self.path(key);
+            let data = fs::read(&path).ok()?;
+
+            let touched = File::options()
+                .write(true)
+                .open(&path)
+                .and_then(|file| file.set_times(FileTimes::new().set_modified(SystemTime::now())));
+            if let Err(err) = touched {
+                log::warn!("failed to update {key} in the weight cache: {err}");
+            }
+            self.index.borrow_mut().touch(key);
+
+            Some(data)
+        }
+
+        fn put(&self, key: &str, data: &[u8]) {
+            if data.len() > self.max_size {
+                return;
+            }
+
+            match self.write(key, data) {
+                Ok(()) => self.insert_index(key, data.len()),
+                Err(err) => log::warn!("failed to store {key} in the weight cache: {err}"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_key() {
+        let key = cache_key("http://a/b.safetensors", None, Some("abc"));
+        assert_eq!(key, cache_key("http://a/b.safetensors", None, Some("abc")));
+        assert_ne!(key, cache_key("http://a/b.safetensors", None, Some("abd")));
+        assert_ne!(
+            key,
+            cache_key("http://a/b.safetensors", Some(&(0..8)), Some("abc"))
+        );
+        assert_ne!(key, cache_key("http://a/c.safetensors", None, Some("abc")));
+    }
+
+    #[test]
+    fn test_memory_cache_evicts_least_recently_used() {
+        let cache = MemoryWeightCache::new(10);
+        cache.put("a", &[1; 4]);
+        cache.put("b", &[2; 4]);
+        assert_eq!(cache.get("a"), Some(vec![1; 4]));
+
+        cache.put("c", &[3; 4]);
+        assert_eq!(cache.get("b"), None);
+        assert_eq!(cache.get("a"), Some(vec![1; 4]));
+        assert_eq!(cache.get("c"), Some(vec![3; 4]));
+        assert_eq!(cache.total_size(), 8);
+
+        cache.put("too_big", &[0; 11]);
+        assert_eq!(cache.get("too_big"), None);
+    }
+
+    #[test]
+    fn test_fs_cache() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let cache = FsWeightCache::open(dir.path(), 10).unwrap();
+        cache.put("a", &[1; 4]);
+        cache.put("b", &[2; 4]);
+        assert_eq!(cache.get("a"), Some(vec![1; 4]));
+        cache.put("c", &[3; 4]);
+        assert_eq!(cache.get("b"), None);
+        drop(cache);
+
+        let cache = FsWeightCache::open(dir.path(), 10).unwrap();
+        assert_eq!(cache.total_size(), 8);
+        assert_eq!(cache.get("a"), Some(vec![1; 4]));
+        assert_eq!(cache.get("c"), Some(vec![3; 4]));
+    }
+
+    #[test]
+    fn test_cache_stats() {
+        set_weight_cache(Some(Rc::new(MemoryWeightCache::new(100))));
+        reset_cache_stats();
+
+        assert_eq!(cache_get("a"), None);
+        cache_put("a", &[1, 2, 3]);
+        assert_eq!(cache_get("a"), Some(vec![1, 2, 3]));
+        set_weight_cache(None);
+
+        assert_eq!(
+            cache_stats(),
+            CacheStats {
+                hits: 1,
+                misses: 1,
+                hit_bytes: 3,
+            }
+        );
+    }
+}
@@ -0,0 +1,403 @@
+//! Chunked downloads that resume with Range requests after a failure, with
+//! exponential backoff between attempts and byte-level progress reporting.
+
+use crate::error::LoadError;
+use crate::from_state_dict::{check_response, fetch_url_range};
+use serde::Serialize;
+use std::cell::{Cell, RefCell};
+use std::future::Future;
+use std::ops::Range;
+use std::rc::Rc;
+use tokio::sync::Semaphore;
+
+pub const CHUNK_SIZE: usize = 16 << 20;
+
+const CHUNK_ATTEMPTS: u32 = 6;
+const INITIAL_BACKOFF_MS: u32 = 500;
+const MAX_BACKOFF_MS: u32 = 16_000;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Progress {
+    pub file: String,
+    pub file_done: usize,
+    /// `None` until the server has told us the file size.
+    pub file_total: Option<usize>,
+    pub overall_done: usize,
+    /// Known when the weights source has a manifest, or after [`set_expected_total_bytes`].
+    pub overall_total: Option<usize>,
+}
+
+pub type ProgressCallback = Rc<dyn Fn(&Progress)>;
+
+/// Caps on the requests in flight at once, shared by every download of the thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FetchLimits {
+    pub max_requests: usize,
+    /// Total size of the byte ranges requested at once. A single chunk larger
+    /// than this is still let through, alone.
+    pub max_bytes: usize,
+}
+
+impl Default for FetchLimits {
+    fn default() -> Self {
+        Self {
+            max_requests: 6,
+            max_bytes: 8 * CHUNK_SIZE,
+        }
+    }
+}
+
+struct FetchLimiter {
+    limits: FetchLimits,
+    requests: Semaphore,
+    bytes: Semaphore,
+    /// `limits.max_bytes`, clamped to what one `acquire_many` can take.
+    max_byte_permits: u32,
+}
+
+impl FetchLimiter {
+    fn new(limits: FetchLimits) -> Self {
+        let max_byte_permits = u32::try_from(limits.max_bytes).unwrap_or(u32::MAX).max(1);
+        Self {
+            limits,
+            requests: Semaphore::new(limits.max_requests.max(1)),
+            bytes: Semaphore::new(max_byte_permits as usize),
+            max_byte_permits,
+        }
+    }
+
+    /// The byte permits a request for `n_bytes` takes.
+    fn byte_permits(&self, n_bytes: usize) -> u32 {
+        u32::try_from(n_bytes)
+            .unwrap_or(u32::MAX)
+            .clamp(1, self.max_byte_permits)
+    }
+}
+
+thread_local! {
+    static PROGRESS_CALLBACK: RefCell<Option<ProgressCallback>> = const { RefCell::new(None) };
+    static OVERALL_DONE: Cell<usize> = const { Cell::new(0) };
+    static OVERALL_TOTAL: Cell<Option<usize>> = const { Cell::new(None) };
+    static FETCH_LIMITER: RefCell<Rc<FetchLimiter>> =
+        RefCell::new(Rc::new(FetchLimiter::new(FetchLimits::default())));
+}
+
+/// Requests already in flight keep their permits from the previous limits.
+pub fn set_fetch_limits(limits: FetchLimits) {
+    FETCH_LIMITER.set(Rc::new(FetchLimiter::new(limits)));
+}
+
+pub fn fetch_limits() -> FetchLimits {
+    FETCH_LIMITER.with_borrow(|limiter| limiter.limits)
+}
+
+pub fn set_progress_callback(callback: Option<ProgressCallback>) {
+    PROGRESS_CALLBACK.set(callback);
+}
+
+pub fn set_expected_total_bytes(total: Option<usize>) {
+    OVERALL_TOTAL.set(total);
+}
+
+pub fn reset_progress() {
+    OVERALL_DONE.set(0);
+}
+
+pub(crate) fn report_progress(
+    file: &str,
+    file_done: usize,
+    file_total: Option<usize>,
+    new_bytes: usize,
+) {
+    OVERALL_DONE.set(OVERALL_DONE.get() + new_bytes);
+
+    let Some(callback) = PROGRESS_CALLBACK.with_borrow(|callback| callback.clone()) else {
+        return;
+    };
+
+    callback(&Progress {
+        file: file.to_string(),
+        file_done,
+        file_total,
+        overall_done: OVERALL_DONE.get(),
+        overall_total: OVERALL_TOTAL.get(),
+    });
+}
+
+#[cfg(target_arch = "wasm32")]
+pub async fn sleep_ms(ms: u32) {
+    gloo_timers::future::TimeoutFuture::new(ms).await
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn sleep_ms(ms: u32) {
+    tokio::time::sleep(std::time::Duration::from_millis(ms as u64)).await
+}
+
+pub fn backoff_ms(attempt: u32) -> u32 {
+    INITIAL_BACKOFF_MS
+        .saturating_mul(1 << attempt.min(16))
+        .min(MAX_BACKOFF_MS)
+}
+
+/// Runs `get` up to `attempts` times, sleeping with exponential backoff in between.
+/// Errors that a retry cannot fix are returned right away.
+pub async fn with_backoff<T, F, Fut>(attempts: u32, get: F) -> Result<T, LoadError>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<T, LoadError>>,
+{
+    let mut attempt = 0;
+    loop {
+        match get().await {
+            Err(err) if err.is_retryable() && attempt + 1 < attempts => {
+                log::warn!("{err}, retrying in {}ms", backoff_ms(attempt));
+                sleep_ms(backoff_ms(attempt)).await;
+                attempt += 1;
+            }
+            output => return output,
+        }
+    }
+}
+
+/// Parses the file size out of a `Content-Range: bytes 0-1023/4096` header.
+fn parse_content_range_size(header: &str) -> Option<usize> {
+    header.rsplit_once('/')?.1.trim().parse().ok()
+}
+
+struct Chunk {
+    data: Vec<u8>,
+    file_size: Option<usize>,
+}
+
+async fn fetch_chunk(url: &str, range: Range<usize>) -> Result<Chunk, LoadError> {
+    let limiter = FETCH_LIMITER.with_borrow(|limiter| limiter.clone());
+    let n_bytes = limiter.byte_permits(range.len());
+    let _bytes_permit = limiter.bytes.acquire_many(n_bytes).await.unwrap();
+    let _request_permit = limiter.requests.acquire().await.unwrap();
+
+    let response = fetch_url_range(url, range.clone()).await;
+
+    // Asking for bytes past the end of a file whose size we did not know.
+    if response
+        .as_ref()
+        .is_ok_and(|response| response.status == 416)
+    {
+        return Ok(Chunk {
+            data: Vec::new(),
+            file_size: None,
+        });
+    }
+
+    let response = check_response(url, response)?;
+
+    if response.status == 206 {
+        if response.bytes.len() > range.len() {
+            return Err(LoadError::corrupted(
+                url,
+                format!(
+                    "expected at most {} bytes, got {}",
+                    range.len(),
+                    response.bytes.len()
+                ),
+            ));
+        }
+        return Ok(Chunk {
+            file_size: response
+                .headers
+                .get("content-range")
+                .and_then(parse_content_range_size),
+            data: response.bytes,
+        });
+    }
+
+    // Servers that ignore the Range header answer with the whole file,
+    // so everything from `range.start` on is returned at once.
+    let file_size = response.bytes.len();
+    let mut data = response.bytes;
+    if file_size < range.start {
+        return Err(LoadError::corrupted(
+            url,
+            format!("file is shorter than requested range {:?}", range),
+        ));
+    }
+    data.drain(..range.start);
+
+    Ok(Chunk {
+        data,
+        file_size: Some(file_size),
+    })
+}
+
+/// Downloads `range` of `url`, or the whole file if `range` is `None`, in
+/// [`CHUNK_SIZE`] pieces. A failed piece is retried with backoff without
+/// throwing away what was already downloaded. Progress is reported under `file`.
+pub async fn download(
+    file: &str,
+    url: &str,
+    range: Option<Range<usize>>,
+) -> Result<Vec<u8>, LoadError> {
+    let start = range.as_ref().map_or(0, |range| range.start);
+    let mut end = range.map(|range| range.end);
+    let mut data = Vec::new();
+
+    loop {
+        let offset = start + data.len();
+        if end.is_some_and(|end| offset >= end) {
+            break;
+        }
+
+        let chunk_end = end.map_or(offset + CHUNK_SIZE, |end| end.min(offset + CHUNK_SIZE));
+        let chunk = with_backoff(CHUNK_ATTEMPTS, || fetch_chunk(url, offset..chunk_end)).await?;
+
+        if end.is_none() {
+            end = chunk.file_size;
+        }
+
+        let mut chunk_data = chunk.data;
+        if let Some(end) = end {
+            chunk_data.truncate(end.saturating_sub(offset));
+        }
+
+        let is_short = chunk_data.len() < chunk_end - offset;
+        if chunk_data.is_empty() && end.is_some() {
+            return Err(LoadError::corrupted(
+                url,
+                format!("download stalled at byte {offset}"),
+            ));
+        }
+
+        let new_bytes = chunk_data.len();
+        data.extend(chunk_data);
+        report_progress(file, data.len(), end.map(|end| end - start), new_bytes);
+
+        // Without a known size, a short chunk marks the end of the file.
+        if is_short && end.is_none() {
+            break;
+        }
+    }
+
+    if let Some(end) = end {
+        if data.len() != end - start {
+            return Err(LoadError::corrupted(
+                url,
+                format!("expected {} bytes, got {}", end - start, data.len()),
+            ));
+        }
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_content_range_size() {
+        assert_eq!(parse_content_range_size("bytes 0-1023/4096"), Some(4096));
+        assert_eq!(parse_content_range_size("bytes 0-1023/*"), None);
+        assert_eq!(parse_content_range_size("garbage"), None);
+    }
+
+    #[test]
+    fn test_backoff_ms() {
+        assert_eq!(backoff_ms(0), INITIAL_BACKOFF_MS);
+        assert_eq!(backoff_ms(1), 2 * INITIAL_BACKOFF_MS);
+        assert_eq!(backoff_ms(30), MAX_BACKOFF_MS);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_with_backoff_retries_network_errors() {
+        let calls = Cell::new(0);
+        let output = with_backoff(3, || async {
+            calls.set(calls.get() + 1);
+            match calls.get() {
+                1 => Err(LoadError::network("file", "connection reset")),
+                _ => Ok(calls.get()),
+            }
+        })
+        .await;
+
+        assert_eq!(output, Ok(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_with_backoff_gives_up() {
+        let calls = Cell::new(0);
+        let output: Result<(), _> = with_backoff(3, || async {
+            calls.set(calls.get() + 1);
+            Err(LoadError::network("file", "connection reset"))
+        })
+        .await;
+
+        assert!(output.is_err());
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_with_backoff_does_not_retry_missing_tensors() {
+        let calls = Cell::new(0);
+        let output: Result<(), _> = with_backoff(3, || async {
+            calls.set(calls.get() + 1);
+            Err(LoadError::MissingTensor {
+                name: "tensor".to_string(),
+            })
+        })
+        .await;
+
+        assert!(output.is_err());
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_limits() {
+        set_fetch_limits(FetchLimits {
+            max_requests: 2,
+            max_bytes: 100,
+        });
+        let limiter = FETCH_LIMITER.with_borrow(|limiter| limiter.clone());
+
+        let _first = limiter.requests.acquire().await.unwrap();
+        let _second = limiter.requests.acquire().await.unwrap();
+        assert!(limiter.requests.try_acquire().is_err());
+
+        let _bytes = limiter.bytes.acquire_many(60).await.unwrap();
+        assert!(limiter.bytes.try_acquire_many(60).is_err());
+        assert!(limiter.bytes.try_acquire_many(40).is_ok());
+
+        set_fetch_limits(FetchLimits::default());
+        assert_eq!(fetch_limits(), FetchLimits::default());
+
+        // Limits past what a semaphore counts in one go still hold.
+        let limiter = FetchLimiter::new(FetchLimits {
+            max_requests: 1,
+            max_bytes: usize::MAX,
+        });
+        assert_eq!(limiter.byte_permits(0), 1);
+        assert_eq!(limiter.byte_permits(usize::MAX), u32::MAX);
+        let _bytes = limiter.bytes.acquire_many(u32::MAX).await.unwrap();
+        assert!(limiter.bytes.try_acquire().is_err());
+    }
+
+    #[test]
+    fn test_progress_callback() {
+        let reports = Rc::new(RefCell::new(Vec::new()));
+        let callback_reports = reports.clone();
+        set_progress_callback(Some(Rc::new(move |progress: &Progress| {
+            callback_reports.borrow_mut().push(progress.clone());
+        })));
+        set_expected_total_bytes(Some(30));
+        reset_progress();
+
+        report_progress("a", 10, Some(10), 10);
+        report_progress("b", 5, None, 5);
+        set_progress_callback(None);
+
+        let reports = reports.borrow();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[1].file, "b");
+        assert_eq!(reports[1].overall_done, 15);
+        assert_eq!(reports[1].overall_total, Some(30));
+    }
+}
@@ -0,0 +1,113 @@
+use crate::owned_tensor::Dtype;
+use thiserror::Error;
+
+/// Error raised while loading weights. `name` is the tensor (or file) being loaded.
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum LoadError {
+    #[error("{name}: tensor not found")]
+    MissingTensor { name: String },
+
+    #[error("{name}: expected dtype {expected:?}, got {actual:?}")]
+    DtypeMismatch {
+        name: String,
+        expected: Dtype,
+        actual: Dtype,
+    },
+
+    #[error("{name}: expected shape {expected:?}, got {actual:?}")]
+    ShapeMismatch {
+        name: String,
+        expected: Vec<usize>,
+        actual: Vec<usize>,
+    },
+
+    #[error("{name}: unsupported dtype {dtype}")]
+    UnsupportedDtype { name: String, dtype: String },
+
+    #[error("{name}: network error: {message}")]
+    Network { name: String, message: String },
+
+    /// A file or tensor the manifest does not know about, which no download can fix.
+    #[error("{name}: not listed in the manifest")]
+    NotInManifest { name: String },
+
+    #[error("{name}: corrupted file: {message}")]
+    Corrupted { name: String, message: String },
+
+    #[error("{name}: io error: {message}")]
+    Io { name: String, message: String },
+
+    #[error("{name}: backend error: {message}")]
+    Backend { name: String, message: String },
+
+    #[error("{name}: serialization error: {message}")]
+    Serialization { name: String, message: String },
+}
+
+impl LoadError {
+    pub fn name(&self) -> &str {
+        match self {
+            LoadError::MissingTensor { name }
+            | LoadError::DtypeMismatch { name, .. }
+            | LoadError::ShapeMismatch { name, .. }
+            | LoadError::UnsupportedDtype { name, .. }
+            | LoadError::Network { name, .. }
+            | LoadError::NotInManifest { name }
+            | LoadError::Corrupted { name, .. }
+            | LoadError::Io { name, .. }
+            | LoadError::Backend { name, .. }
+            | LoadError::Serialization { name, .. } => name,
+        }
+    }
+
+    /// Whether downloading again may fix the error.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            LoadError::Network { .. } | LoadError::Corrupted { .. }
+        )
+    }
+
+    pub fn corrupted(name: &str, message: impl ToString) -> Self {
+        LoadError::Corrupted {
+            name: name.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    pub fn network(name: &str, message: impl ToString) -> Self {
+        LoadError::Network {
+            name: name.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    pub fn io(name: &str, message: impl ToString) -> Self {
+        LoadError::Io {
+            name: name.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    pub fn backend(name: &str, message: impl ToString) -> Self {
+        LoadError::Backend {
+            name: name.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    pub fn serialization(name: &str, message: impl ToString) -> Self {
+        LoadError::Serialization {
+            name: name.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    pub fn shape_mismatch(name: &str, expected: Vec<usize>, actual: Vec<usize>) -> Self {
+        LoadError::ShapeMismatch {
+            name: name.to_string(),
+            expected,
+            actual,
+        }
+    }
+}
@@ -1,126 +1,268 @@
-use crate::owned_tensor::{get_f32_data, get_i8_data, get_u8_data, Dtype, OwnedTensor};
-use anyhow::anyhow;
+use crate::cache::{cache_get, cache_key, cache_put};
+use crate::download::{download, report_progress, with_backoff};
+use crate::error::LoadError;
+use crate::manifest::{get_manifest, Manifest};
+use crate::owned_tensor::{
+    bytes_to_f32, get_f32_data, get_i8_data, get_u16_data, get_u8_data, Dtype, OwnedTensor, Tensor,
+};
+use crate::remote_safetensors::{get_tensor_from_file, get_tensor_from_shards};
+use crate::weights_source::{weights_source, WeightsLayout};
 use async_trait::async_trait;
 use nn::attention::{Attention, AttentionConfig, AttentionSubmodules, CachedAttentionLinear};
 use nn::embedding::EmbeddingINT8;
 use nn::layernorm::LayerNorm;
 use nn::linear::Module;
 use nn::linear_aqlm::LinearAQLM;
+use nn::linear_gguf::LinearGGUF;
 use nn::linear_int8::LinearINT8;
+use nn::llama::{Llama, LlamaSubmodules};
 use nn::llama_block::{LlamaBlock, LlamaBlockSubmodules};
 use nn::llama_config::LlamaConfig;
-use nn::matrix_int8::MatrixInt8;
+use nn::matrix_int8::{Int8Scaling, MatrixInt8};
 use nn::mlp::{MLPSubmodules, MLP};
 use safetensors::tensor::TensorView;
 use safetensors::SafeTensors;
 use std::borrow::Cow;
+use std::ops::Range;
 use tokio::join;
 use tokio::sync::mpsc;
 
+/// A source of named tensors for `FromStateDict` loaders, whose tensors may
+/// borrow from it for `'a`. Sources that hand out owned tensors implement the
+/// trait for every `'a`, so the modules loaded from them are `'static`.
 #[async_trait(?Send)]
-pub trait FromStateDictConf<ConfigType>: Sized {
-    async fn from_state_dict(prefix: &str, config: ConfigType) -> anyhow::Result<Self>;
+pub trait TensorProvider<'a> {
+    async fn get_tensor(&self, name: &str) -> Result<Tensor<'a>, LoadError>;
+}
+
+/// Tensors fetched over the network from the configured weights source.
+pub struct RemoteWeights;
+
+#[async_trait(?Send)]
+impl<'a> TensorProvider<'a> for RemoteWeights {
+    async fn get_tensor(&self, name: &str) -> Result<Tensor<'a>, LoadError> {
+        get_tensor(name).await.map(Tensor::from)
+    }
+}
+
+#[async_trait(?Send)]
+pub trait FromStateDictConf<'a, ConfigType>: Sized {
+    async fn from_state_dict(
+        source: &dyn TensorProvider<'a>,
+        prefix: &str,
+        config: ConfigType,
+    ) -> Result<Self, LoadError>;
 }
 
 #[async_trait(?Send)]
-pub trait FromStateDict: Sized {
-    async fn from_state_dict(prefix: &str) -> anyhow::Result<Self>;
+pub trait FromStateDict<'a>: Sized {
+    async fn from_state_dict(
+        source: &dyn TensorProvider<'a>,
+        prefix: &str,
+    ) -> Result<Self, LoadError>;
 }
 
 #[async_trait(?Send)]
-impl FromStateDictConf<f32> for LayerNorm<'static> {
-    async fn from_state_dict(prefix: &str, norm_eps: f32) -> anyhow::Result<Self> {
+impl<'a> FromStateDictConf<'a, f32> for LayerNorm<'a> {
+    async fn from_state_dict(
+        source: &dyn TensorProvider<'a>,
+        prefix: &str,
+        norm_eps: f32,
+    ) -> Result<Self, LoadError> {
         Ok(LayerNorm::new(
-            Cow::Owned(load_f32_data(&format!("{prefix}weight")).await?.0),
+            load_f32_data(source, &format!("{prefix}weight")).await?.0,
             norm_eps,
         ))
     }
 }
 
 #[async_trait(?Send)]
-impl FromStateDict for MatrixInt8<'static> {
-    async fn from_state_dict(prefix: &str) -> anyhow::Result<Self> {
-        let max_values = load_f32_data(&format!("{prefix}weight_max_values"))
+impl<'a> FromStateDict<'a> for MatrixInt8<'a> {
+    async fn from_state_dict(
+        source: &dyn TensorProvider<'a>,
+        prefix: &str,
+    ) -> Result<Self, LoadError> {
+        let (max_values, max_values_shape) =
+            load_f32_data(source, &format!("{prefix}weight_max_values")).await?;
+        let int8_values = load_i8_data(source, &format!("{prefix}weight_int8"))
             .await?
             .0;
-        let int8_values = load_i8_data(&format!("{prefix}weight_int8")).await?.0;
 
-        let max_values = Cow::Owned(max_values);
-        let int8_values = Cow::Owned(int8_values);
+        let scaling = check_int8_shape(prefix, &max_values, &max_values_shape, &int8_values)?;
 
-        Ok(MatrixInt8::new(max_values, int8_values))
+        Ok(MatrixInt8::with_scaling(max_values, int8_values, scaling))
     }
 }
 
+/// Quantizes a dense `[n_tokens, dim]` embedding table to int8 with a scale
+/// per token, one row at a time, so the f32 table is never materialized.
+fn quantize_embedding(tensor: Tensor<'_>) -> Result<MatrixInt8<'static>, LoadError> {
+    tensor.check_size()?;
+    let [n_tokens, dim] = tensor.shape[..] else {
+        return Err(LoadError::corrupted(
+            &tensor.name,
+            format!("expected a matrix, got shape {:?}", tensor.shape),
+        ));
+    };
+    let row_size = tensor
+        .dtype
+        .data_size(dim)
+        .filter(|row_size| *row_size > 0)
+        .ok_or_else(|| LoadError::corrupted(&tensor.name, format!("rows of {dim} values")))?;
+
+    let mut max_values = Vec::with_capacity(n_tokens);
+    let mut int8_values = Vec::with_capacity(n_tokens * dim);
+
+    for row in tensor.data.chunks_exact(row_size) {
+        let row_values = bytes_to_f32(row, tensor.dtype).ok_or(LoadError::DtypeMismatch {
+            name: tensor.name.clone(),
+            expected: Dtype::F32,
+            actual: tensor.dtype,
+        })?;
+
+        let max_value = row_values.iter().fold(0f32, |max, v| max.max(v.abs()));
+        max_values.push(max_value);
+        int8_values.extend(row_values.iter().map(|v| match max_value {
+            0.0 => 0,
+            _ => (v * 127.0 / max_value).round().clamp(-127.0, 127.0) as i8,
+        }));
+    }
+
+    Ok(MatrixInt8::with_scaling(
+        Cow::Owned(max_values),
+        Cow::Owned(int8_values),
+        Int8Scaling::PerRow,
+    ))
+}
+
+/// Embeddings stored as a dense `{prefix}weight`, e.g. in GGUF files, are
+/// quantized on load.
 #[async_trait(?Send)]
-impl FromStateDict for EmbeddingINT8<'static> {
-    async fn from_state_dict(prefix: &str) -> anyhow::Result<Self> {
-        let matrix = MatrixInt8::from_state_dict(prefix).await?;
+impl<'a> FromStateDict<'a> for EmbeddingINT8<'a> {
+    async fn from_state_dict(
+        source: &dyn TensorProvider<'a>,
+        prefix: &str,
+    ) -> Result<Self, LoadError> {
+        let max_values_name = format!("{prefix}weight_max_values");
+
+        let matrix = match MatrixInt8::from_state_dict(source, prefix).await {
+            Err(LoadError::MissingTensor { name }) if name == max_values_name => {
+                quantize_embedding(source.get_tensor(&format!("{prefix}weight")).await?)?
+            }
+            matrix => matrix?,
+        };
+
         Ok(EmbeddingINT8::new(matrix))
     }
 }
 
 #[async_trait(?Send)]
-impl FromStateDict for LinearINT8<'static> {
-    async fn from_state_dict(prefix: &str) -> anyhow::Result<Self> {
-        let matrix = MatrixInt8::from_state_dict(prefix).await?;
+impl<'a> FromStateDict<'a> for LinearINT8<'a> {
+    async fn from_state_dict(
+        source: &dyn TensorProvider<'a>,
+        prefix: &str,
+    ) -> Result<Self, LoadError> {
+        let matrix = MatrixInt8::from_state_dict(source, prefix).await?;
         Ok(LinearINT8::new(matrix))
     }
 }
 
 #[async_trait(?Send)]
-impl FromStateDict for LinearAQLM<'static> {
-    async fn from_state_dict(prefix: &str) -> anyhow::Result<Self> {
-        let (codebooks, codebooks_shape) = load_f32_data(&format!("{prefix}codebooks")).await?;
-        assert_eq!(codebooks_shape, vec![2, 256, 1, 8]);
-
-        let (scales, _) = load_f32_data(&format!("{prefix}scales")).await?;
-        let (codes, codes_shape) = load_u8_data(&format!("{prefix}codes_120")).await?;
-
-        let out_dim = codes_shape[2];
-        let in_group_dim = codes_shape[0];
+impl<'a> FromStateDict<'a> for LinearAQLM<'a> {
+    async fn from_state_dict(
+        source: &dyn TensorProvider<'a>,
+        prefix: &str,
+    ) -> Result<Self, LoadError> {
+        let (codebooks, codebooks_shape) =
+            load_f32_data(source, &format!("{prefix}codebooks")).await?;
+        let (scales, _) = load_f32_data(source, &format!("{prefix}scales")).await?;
+        let (codes, codes_shape) = load_u8_data(source, &format!("{prefix}codes_120")).await?;
+
+        let (out_dim, in_group_dim) =
+            check_aqlm_shapes(prefix, &codebooks_shape, &scales, &codes_shape)?;
 
         Ok(LinearAQLM::new(
-            Cow::Owned(codebooks),
-            Cow::Owned(scales),
-            Cow::Owned(codes),
+            codebooks,
+            scales,
+            codes,
             out_dim,
             in_group_dim,
         ))
     }
 }
 
+/// Runs `{prefix}weight` in whatever ggml type it is stored in.
+#[async_trait(?Send)]
+impl<'a> FromStateDict<'a> for LinearGGUF<'a> {
+    async fn from_state_dict(
+        source: &dyn TensorProvider<'a>,
+        prefix: &str,
+    ) -> Result<Self, LoadError> {
+        let tensor = source.get_tensor(&format!("{prefix}weight")).await?;
+        tensor.check_size()?;
+
+        let ggml_type = tensor
+            .dtype
+            .ggml_type()
+            .ok_or_else(|| LoadError::UnsupportedDtype {
+                name: tensor.name.clone(),
+                dtype: format!("{:?}", tensor.dtype),
+            })?;
+        let (out_dim, in_dim) = match tensor.shape[..] {
+            [out_dim, in_dim] if ggml_type.row_size(in_dim).is_some() => (out_dim, in_dim),
+            _ => {
+                return Err(LoadError::corrupted(
+                    &tensor.name,
+                    format!(
+                        "expected whole {ggml_type:?} rows, got shape {:?}",
+                        tensor.shape
+                    ),
+                ))
+            }
+        };
+
+        Ok(LinearGGUF::new(tensor.data, ggml_type, out_dim, in_dim))
+    }
+}
+
 #[async_trait(?Send)]
-impl<LinearType> FromStateDict for MLP<LinearType>
+impl<'a, LinearType> FromStateDict<'a> for MLP<LinearType>
 where
-    LinearType: Module + FromStateDict,
+    LinearType: Module + FromStateDict<'a>,
 {
-    async fn from_state_dict(prefix: &str) -> anyhow::Result<Self> {
+    async fn from_state_dict(
+        source: &dyn TensorProvider<'a>,
+        prefix: &str,
+    ) -> Result<Self, LoadError> {
         Ok(MLP::new(MLPSubmodules {
-            up_proj: LinearType::from_state_dict(&format!("{prefix}up_proj.")).await?,
-            gate_proj: LinearType::from_state_dict(&format!("{prefix}gate_proj.")).await?,
-            down_proj: LinearType::from_state_dict(&format!("{prefix}down_proj.")).await?,
+            up_proj: LinearType::from_state_dict(source, &format!("{prefix}up_proj.")).await?,
+            gate_proj: LinearType::from_state_dict(source, &format!("{prefix}gate_proj.")).await?,
+            down_proj: LinearType::from_state_dict(source, &format!("{prefix}down_proj.")).await?,
         }))
     }
 }
 
 #[async_trait(?Send)]
-impl<LinearType> FromStateDictConf<AttentionConfig> for Attention<LinearType>
+impl<'a, LinearType> FromStateDictConf<'a, AttentionConfig> for Attention<LinearType>
 where
-    LinearType: Module + FromStateDict,
+    LinearType: Module + FromStateDict<'a>,
 {
-    async fn from_state_dict(prefix: &str, config: AttentionConfig) -> anyhow::Result<Self> {
+    async fn from_state_dict(
+        source: &dyn TensorProvider<'a>,
+        prefix: &str,
+        config: AttentionConfig,
+    ) -> Result<Self, LoadError> {
         let weights = AttentionSubmodules {
             v_proj: CachedAttentionLinear::new(
-                LinearType::from_state_dict(&format!("{prefix}v_proj.")).await?,
+                LinearType::from_state_dict(source, &format!("{prefix}v_proj.")).await?,
                 None,
             ),
-            q_proj: LinearType::from_state_dict(&format!("{prefix}q_proj.")).await?,
+            q_proj: LinearType::from_state_dict(source, &format!("{prefix}q_proj.")).await?,
             k_proj: CachedAttentionLinear::new(
-                LinearType::from_state_dict(&format!("{prefix}k_proj.")).await?,
+                LinearType::from_state_dict(source, &format!("{prefix}k_proj.")).await?,
                 Some(config.get_emb_config()),
             ),
-            o_proj: LinearType::from_state_dict(&format!("{prefix}o_proj.")).await?,
+            o_proj: LinearType::from_state_dict(source, &format!("{prefix}o_proj.")).await?,
         };
 
         Ok(Attention::new(weights, config))
@@ -128,20 +270,28 @@ where
 }
 
 #[async_trait(?Send)]
-impl<LinearType> FromStateDictConf<LlamaConfig> for LlamaBlock<LinearType>
+impl<'a, MLPType, AttentionType> FromStateDictConf<'a, LlamaConfig>
+    for LlamaBlock<'a, MLPType, AttentionType>
 where
-    LinearType: Module + FromStateDict,
+    MLPType: Module + FromStateDict<'a>,
+    AttentionType: Module + FromStateDictConf<'a, AttentionConfig>,
 {
-    async fn from_state_dict(prefix: &str, config: LlamaConfig) -> anyhow::Result<Self> {
+    async fn from_state_dict(
+        source: &dyn TensorProvider<'a>,
+        prefix: &str,
+        config: LlamaConfig,
+    ) -> Result<Self, LoadError> {
         let input_layernorm_prefix = format!("{prefix}input_layernorm.");
-        let input_layernorm = LayerNorm::from_state_dict(&input_layernorm_prefix, config.norm_eps);
+        let input_layernorm =
+            LayerNorm::from_state_dict(source, &input_layernorm_prefix, config.norm_eps);
         let attention_prefix = format!("{prefix}self_attn.");
-        let attention = Attention::from_state_dict(&attention_prefix, config.to_attention_config());
+        let attention =
+            AttentionType::from_state_dict(source, &attention_prefix, config.to_attention_config());
         let post_attention_layernorm_prefix = format!("{prefix}post_attention_layernorm.");
         let post_attention_layernorm =
-            LayerNorm::from_state_dict(&post_attention_layernorm_prefix, config.norm_eps);
+            LayerNorm::from_state_dict(source, &post_attention_layernorm_prefix, config.norm_eps);
         let mlp_prefix = format!("{prefix}mlp.");
-        let mlp = MLP::from_state_dict(&mlp_prefix);
+        let mlp = MLPType::from_state_dict(source, &mlp_prefix);
 
         let (input_layernorm, attention, post_attention_layernorm, mlp) =
             join!(input_layernorm, attention, post_attention_layernorm, mlp,);
@@ -164,100 +314,538 @@ where
     }
 }
 
+#[async_trait(?Send)]
+impl<'a, HeadLinearType, MLPType, AttentionType> FromStateDictConf<'a, LlamaConfig>
+    for Llama<'a, HeadLinearType, MLPType, AttentionType>
+where
+    HeadLinearType: Module + FromStateDict<'a>,
+    MLPType: Module + FromStateDict<'a>,
+    AttentionType: Module + FromStateDictConf<'a, AttentionConfig>,
+{
+    async fn from_state_dict(
+        source: &dyn TensorProvider<'a>,
+        prefix: &str,
+        config: LlamaConfig,
+    ) -> Result<Self, LoadError> {
+        let mut blocks = Vec::with_capacity(config.n_layers);
+        for layer_idx in 0..config.n_layers {
+            let block_prefix = format!("{prefix}model.layers.{layer_idx}.");
+            blocks.push(LlamaBlock::from_state_dict(source, &block_prefix, config.clone()).await?);
+        }
+
+        let embed_tokens_prefix = format!("{prefix}model.embed_tokens.");
+        let norm_prefix = format!("{prefix}model.norm.");
+        let submodules = LlamaSubmodules {
+            embed_tokens: EmbeddingINT8::from_state_dict(source, &embed_tokens_prefix).await?,
+            blocks,
+            norm: LayerNorm::from_state_dict(source, &norm_prefix, config.norm_eps).await?,
+            lm_head: HeadLinearType::from_state_dict(source, &format!("{prefix}lm_head.")).await?,
+        };
+
+        Ok(Llama::new(submodules))
+    }
+}
+
+pub fn check_shape(name: &str, actual: &[usize], expected: &[usize]) -> Result<(), LoadError> {
+    if actual != expected {
+        return Err(LoadError::shape_mismatch(
+            name,
+            expected.to_vec(),
+            actual.to_vec(),
+        ));
+    }
+    Ok(())
+}
+
+/// Validates AQLM tensor shapes, returning `(out_dim, in_group_dim)`.
+pub fn check_aqlm_shapes(
+    prefix: &str,
+    codebooks_shape: &[usize],
+    scales: &[f32],
+    codes_shape: &[usize],
+) -> Result<(usize, usize), LoadError> {
+    check_shape(
+        &format!("{prefix}codebooks"),
+        codebooks_shape,
+        &[2, 256, 1, 8],
+    )?;
+
+    if codes_shape.len() != 3 || codes_shape[1] != 2 {
+        return Err(LoadError::corrupted(
+            &format!("{prefix}codes_120"),
+            format!("expected shape [in_group_dim, 2, out_dim], got {codes_shape:?}"),
+        ));
+    }
+
+    let out_dim = codes_shape[2];
+    let in_group_dim = codes_shape[0];
+
+    check_shape(&format!("{prefix}scales"), &[scales.len()], &[out_dim])?;
+
+    Ok((out_dim, in_group_dim))
+}
+
+/// Checks that `weight_int8` splits evenly by `weight_max_values` and picks the
+/// scaling from the max values shape: `[n_rows, 1]` is per-row, anything else
+/// is per-column.
+pub fn check_int8_shape(
+    prefix: &str,
+    max_values: &[f32],
+    max_values_shape: &[usize],
+    int8_values: &[i8],
+) -> Result<Int8Scaling, LoadError> {
+    if max_values.is_empty() || !int8_values.len().is_multiple_of(max_values.len()) {
+        return Err(LoadError::corrupted(
+            &format!("{prefix}weight_int8"),
+            format!(
+                "{} values do not split into rows of {}",
+                int8_values.len(),
+                max_values.len()
+            ),
+        ));
+    }
+    Ok(match max_values_shape {
+        [_, 1] => Int8Scaling::PerRow,
+        _ => Int8Scaling::PerColumn,
+    })
+}
+
 pub async fn fetch_url(url: &str) -> ehttp::Result<ehttp::Response> {
+    fetch(ehttp::Request::get(url)).await
+}
+
+pub async fn fetch_url_range(url: &str, range: Range<usize>) -> ehttp::Result<ehttp::Response> {
+    let mut request = ehttp::Request::get(url);
+    request
+        .headers
+        .insert("Range", format!("bytes={}-{}", range.start, range.end - 1));
+
+    fetch(request).await
+}
+
+async fn fetch(request: ehttp::Request) -> ehttp::Result<ehttp::Response> {
     let (tx, mut rx) = mpsc::unbounded_channel();
 
-    let request = ehttp::Request::get(url);
     ehttp::fetch(request, move |result: ehttp::Result<ehttp::Response>| {
-        tx.send(result).unwrap();
+        let _ = tx.send(result);
     });
 
-    rx.recv().await.unwrap()
+    rx.recv()
+        .await
+        .unwrap_or_else(|| Err("request was dropped".to_string()))
 }
 
-pub async fn get_data(url: &str) -> anyhow::Result<Vec<u8>> {
-    let response = fetch_url(url).await.unwrap();
+pub(crate) fn check_response(
+    url: &str,
+    response: ehttp::Result<ehttp::Response>,
+) -> Result<ehttp::Response, LoadError> {
+    let response = response.map_err(|err| LoadError::network(url, err))?;
 
     if !response.ok {
-        return Err(anyhow!("{} ({})", response.status_text, response.status));
+        return Err(LoadError::network(
+            url,
+            format!("{} ({})", response.status_text, response.status),
+        ));
     }
 
-    Ok(response.bytes)
+    Ok(response)
 }
 
-const LOCAL_PREFIX: &str = "http://localhost:8000/aqlm-f32/";
-const LOCAL_SUFFIX: &str = "";
-const PROD_PREFIX: &str =
-    "https://huggingface.co/galqiwi/llama-3.1-aqlm-pv-2x8-f32-int8-emb/resolve/main/";
-const PROD_SUFFIX: &str = "?download=true";
+pub async fn get_data(url: &str) -> Result<Vec<u8>, LoadError> {
+    download(url, url, None).await
+}
+
+pub async fn get_data_range(url: &str, range: Range<usize>) -> Result<Vec<u8>, LoadError> {
+    download(url, url, Some(range)).await
+}
 
 fn get_url_by_name(filename: &str) -> String {
-    const LOCAL: bool = false;
+    weights_source().url(filename)
+}
 
-    let (prefix, suffix) = match LOCAL {
-        true => (LOCAL_PREFIX, LOCAL_SUFFIX),
-        false => (PROD_PREFIX, PROD_SUFFIX),
-    };
+/// How many times a download is started over when it fails verification.
+const VERIFY_ATTEMPTS: u32 = 3;
+
+/// Downloads a file, checking it against the manifest of the weights source if there is one.
+pub async fn get_file_by_name(filename: &str) -> Result<Vec<u8>, LoadError> {
+    let manifest = get_manifest().await?;
+
+    get_verified(
+        filename,
+        None,
+        manifest.as_deref(),
+        |data| match &manifest {
+            Some(manifest) => manifest.verify_file(filename, data),
+            None => Ok(()),
+        },
+    )
+    .await
+}
 
-    format!("{prefix}{filename}{suffix}")
+/// Downloads a file bypassing both the manifest and the weight cache.
+pub(crate) async fn get_file_by_name_unverified(filename: &str) -> Result<Vec<u8>, LoadError> {
+    download(filename, &get_url_by_name(filename), None).await
 }
 
-pub async fn get_file_by_name(filename: &str) -> anyhow::Result<Vec<u8>> {
-    for _retry_idx in 0..2 {
-        let output = get_data(&get_url_by_name(filename)).await;
+pub async fn get_file_range_by_name(
+    filename: &str,
+    range: Range<usize>,
+) -> Result<Vec<u8>, LoadError> {
+    get_verified_file_range(filename, range, |_| Ok(())).await
+}
 
-        if output.is_ok() {
-            return output;
+pub(crate) async fn get_verified_file_range<V>(
+    filename: &str,
+    range: Range<usize>,
+    verify: V,
+) -> Result<Vec<u8>, LoadError>
+where
+    V: Fn(&[u8]) -> Result<(), LoadError>,
+{
+    let manifest = get_manifest().await?;
+    get_verified(filename, Some(range), manifest.as_deref(), verify).await
+}
+
+/// Fetches `range` of a file, or all of it, from the weight cache or else from
+/// the network, starting the download over if `verify` fails.
+async fn get_verified<V>(
+    filename: &str,
+    range: Option<Range<usize>>,
+    manifest: Option<&Manifest>,
+    verify: V,
+) -> Result<Vec<u8>, LoadError>
+where
+    V: Fn(&[u8]) -> Result<(), LoadError>,
+{
+    let url = get_url_by_name(filename);
+    let checksum = manifest.and_then(|manifest| manifest.file_sha256(filename));
+    let key = cache_key(&url, range.as_ref(), checksum);
+
+    if let Some(data) = cache_get(&key) {
+        match verify(&data) {
+            Ok(()) => {
+                report_progress(filename, data.len(), Some(data.len()), data.len());
+                return Ok(data);
+            }
+            Err(err) => log::warn!("ignoring cached copy: {err}"),
         }
     }
 
-    let output = get_data(&get_url_by_name(filename)).await;
+    let data = with_backoff(VERIFY_ATTEMPTS, || async {
+        let data = download(filename, &url, range.clone()).await?;
+        verify(&data)?;
+        Ok(data)
+    })
+    .await?;
+
+    cache_put(&key, &data);
 
-    output.map_err(|err| anyhow!("huggingface error when loading: {}", err))
+    Ok(data)
 }
 
-fn tensor_view_to_owned_tensor(value: TensorView) -> OwnedTensor {
-    let data = value.data().to_vec();
-    assert_eq!(data.capacity(), data.len());
+pub(crate) fn convert_dtype(name: &str, dtype: safetensors::Dtype) -> Result<Dtype, LoadError> {
+    match dtype {
+        safetensors::Dtype::F32 => Ok(Dtype::F32),
+        safetensors::Dtype::F16 => Ok(Dtype::F16),
+        safetensors::Dtype::BF16 => Ok(Dtype::BF16),
+        safetensors::Dtype::U8 => Ok(Dtype::U8),
+        safetensors::Dtype::I8 => Ok(Dtype::I8),
+        safetensors::Dtype::U16 => Ok(Dtype::U16),
+        safetensors::Dtype::I16 => Ok(Dtype::I16),
+        dtype => Err(LoadError::UnsupportedDtype {
+            name: name.to_string(),
+            dtype: format!("{:?}", dtype),
+        }),
+    }
+}
+
+pub(crate) fn to_safetensors_dtype(
+    name: &str,
+    dtype: Dtype,
+) -> Result<safetensors::Dtype, LoadError> {
+    match dtype {
+        Dtype::F32 => Ok(safetensors::Dtype::F32),
+        Dtype::F16 => Ok(safetensors::Dtype::F16),
+        Dtype::BF16 => Ok(safetensors::Dtype::BF16),
+        Dtype::U8 => Ok(safetensors::Dtype::U8),
+        Dtype::I8 => Ok(safetensors::Dtype::I8),
+        Dtype::U16 => Ok(safetensors::Dtype::U16),
+        Dtype::I16 => Ok(safetensors::Dtype::I16),
+        Dtype::Q4_0 | Dtype::Q8_0 | Dtype::Q4K | Dtype::Q6K => Err(LoadError::UnsupportedDtype {
+            name: name.to_string(),
+            dtype: format!("{dtype:?}"),
+        }),
+    }
+}
 
+pub(crate) fn tensor_view_to_owned_tensor(
+    name: &str,
+    value: TensorView,
+) -> Result<OwnedTensor, LoadError> {
+    let data = value.data().to_vec();
     let shape = value.shape().to_vec();
-    assert_eq!(shape.capacity(), shape.len());
+    let dtype = convert_dtype(name, value.dtype())?;
+
+    Ok(OwnedTensor {
+        name: name.to_string(),
+        data,
+        shape,
+        dtype,
+    })
+}
 
-    let dtype = value.dtype();
+/// Reads tensor `name` from a safetensors file. Files holding a single tensor
+/// are accepted regardless of the name stored inside.
+pub fn read_named_tensor(data: &[u8], name: &str) -> Result<OwnedTensor, LoadError> {
+    let tensors = SafeTensors::deserialize(data).map_err(|err| LoadError::corrupted(name, err))?;
 
-    let dtype = match dtype {
-        safetensors::Dtype::F32 => Dtype::F32,
-        safetensors::Dtype::U8 => Dtype::U8,
-        safetensors::Dtype::I8 => Dtype::I8,
-        _ => unimplemented!(),
-    };
+    if let Ok(tensor) = tensors.tensor(name) {
+        return tensor_view_to_owned_tensor(name, tensor);
+    }
 
-    OwnedTensor { data, shape, dtype }
+    match tensors.tensors().pop() {
+        Some((_, tensor)) if tensors.len() == 1 => tensor_view_to_owned_tensor(name, tensor),
+        _ => Err(LoadError::MissingTensor {
+            name: name.to_string(),
+        }),
+    }
 }
 
-pub fn read_owned_tensor(data: &[u8]) -> anyhow::Result<OwnedTensor> {
-    let mut tensors = SafeTensors::deserialize(data)
-        .map_err(|err| anyhow!("failed to parse tensor: {}", err))?
-        .tensors();
-    assert_eq!(tensors.len(), 1);
+pub async fn get_tensor(path: &str) -> Result<OwnedTensor, LoadError> {
+    match weights_source().layout {
+        WeightsLayout::PerTensorFiles => {
+            let filename = format!("{path}.safetensors");
+            let tensor = read_named_tensor(&get_file_by_name(&filename).await?, path)?;
+
+            if let Some(manifest) = get_manifest().await? {
+                manifest.verify_tensor_info(&filename, &tensor)?;
+            }
 
-    let (_, tensor) = tensors.pop().ok_or_else(|| anyhow!("no tensor found"))?;
+            Ok(tensor)
+        }
+        WeightsLayout::SingleFile(filename) => get_tensor_from_file(&filename, path).await,
+        WeightsLayout::Sharded(index_filename) => {
+            get_tensor_from_shards(&index_filename, path).await
+        }
+    }
+}
 
-    Ok(tensor_view_to_owned_tensor(tensor))
+pub async fn load_f32_data<'a>(
+    source: &dyn TensorProvider<'a>,
+    path: &str,
+) -> Result<(Cow<'a, [f32]>, Vec<usize>), LoadError> {
+    get_f32_data(source.get_tensor(path).await?)
 }
 
-pub async fn get_tensor(path: &str) -> anyhow::Result<OwnedTensor> {
-    read_owned_tensor(&get_file_by_name(&format!("{path}.safetensors")).await?)
+pub async fn load_u8_data<'a>(
+    source: &dyn TensorProvider<'a>,
+    path: &str,
+) -> Result<(Cow<'a, [u8]>, Vec<usize>), LoadError> {
+    get_u8_data(source.get_tensor(path).await?)
 }
 
-pub async fn load_f32_data(path: &str) -> anyhow::Result<(Vec<f32>, Vec<usize>)> {
-    Ok(get_f32_data(get_tensor(path).await?))
+pub async fn load_i8_data<'a>(
+    source: &dyn TensorProvider<'a>,
+    path: &str,
+) -> Result<(Cow<'a, [i8]>, Vec<usize>), LoadError> {
+    get_i8_data(source.get_tensor(path).await?)
 }
 
-pub async fn load_u8_data(path: &str) -> anyhow::Result<(Vec<u8>, Vec<usize>)> {
-    Ok(get_u8_data(get_tensor(path).await?))
+pub async fn load_u16_data<'a>(
+    source: &dyn TensorProvider<'a>,
+    path: &str,
+) -> Result<(Cow<'a, [u16]>, Vec<usize>), LoadError> {
+    get_u16_data(source.get_tensor(path).await?)
 }
 
-pub async fn load_i8_data(path: &str) -> anyhow::Result<(Vec<i8>, Vec<usize>)> {
-    Ok(get_i8_data(get_tensor(path).await?))
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::state_dict::StateDict;
+    use nn::llama_block::DefaultLlamaBlock;
+
+    pub(crate) const CONFIG: LlamaConfig = LlamaConfig {
+        dim: 16,
+        n_layers: 1,
+        n_heads: 2,
+        n_kv_heads: 1,
+        norm_eps: 1e-5,
+        rope_theta: 10000.0,
+    };
+    const FFN_DIM: usize = 32;
+
+    pub(crate) fn pseudo_random(n: usize, seed: usize) -> Vec<f32> {
+        (0..n)
+            .map(|idx| (((idx + seed) * 7919) % 201) as f32 / 200.0 - 0.5)
+            .collect()
+    }
+
+    pub(crate) fn insert_int8(
+        state_dict: &mut StateDict,
+        prefix: &str,
+        out_dim: usize,
+        in_dim: usize,
+    ) {
+        let int8_values: Vec<i8> = (0..out_dim * in_dim)
+            .map(|idx| (idx * 37 % 255) as i8)
+            .collect();
+        state_dict.insert_f32(
+            &format!("{prefix}weight_max_values"),
+            &[out_dim, 1],
+            &pseudo_random(out_dim, out_dim + in_dim),
+        );
+        state_dict.insert_i8(
+            &format!("{prefix}weight_int8"),
+            &[out_dim, in_dim],
+            &int8_values,
+        );
+    }
+
+    pub(crate) fn insert_aqlm(
+        state_dict: &mut StateDict,
+        prefix: &str,
+        out_dim: usize,
+        in_dim: usize,
+    ) {
+        let codes: Vec<u8> = (0..out_dim * in_dim / 4)
+            .map(|idx| (idx * 37 % 256) as u8)
+            .collect();
+        state_dict.insert_f32(
+            &format!("{prefix}codebooks"),
+            &[2, 256, 1, 8],
+            &pseudo_random(2 * 256 * 8, in_dim),
+        );
+        state_dict.insert_f32(
+            &format!("{prefix}scales"),
+            &[out_dim],
+            &pseudo_random(out_dim, out_dim),
+        );
+        state_dict.insert_u8(
+            &format!("{prefix}codes_120"),
+            &[in_dim / 8, 2, out_dim],
+            &codes,
+        );
+    }
+
+    pub(crate) fn block_state_dict(
+        insert_linear: fn(&mut StateDict, &str, usize, usize),
+    ) -> StateDict {
+        let mut state_dict = StateDict::new();
+        insert_block(&mut state_dict, "block.", insert_linear);
+        state_dict
+    }
+
+    pub(crate) fn insert_block(
+        state_dict: &mut StateDict,
+        prefix: &str,
+        insert_linear: fn(&mut StateDict, &str, usize, usize),
+    ) {
+        let (dim, kv_dim) = (CONFIG.dim, CONFIG.dim / CONFIG.n_heads * CONFIG.n_kv_heads);
+
+        for name in ["input_layernorm", "post_attention_layernorm"] {
+            state_dict.insert_f32(&format!("{prefix}{name}.weight"), &[dim], &vec![1.0; dim]);
+        }
+        for (name, out_dim, in_dim) in [
+            ("self_attn.q_proj", dim, dim),
+            ("self_attn.k_proj", kv_dim, dim),
+            ("self_attn.v_proj", kv_dim, dim),
+            ("self_attn.o_proj", dim, dim),
+            ("mlp.up_proj", FFN_DIM, dim),
+            ("mlp.gate_proj", FFN_DIM, dim),
+            ("mlp.down_proj", dim, FFN_DIM),
+        ] {
+            insert_linear(state_dict, &format!("{prefix}{name}."), out_dim, in_dim);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_load_int8_linear() {
+        let mut state_dict = StateDict::new();
+        insert_int8(&mut state_dict, "linear.", 3, 4);
+
+        let linear = LinearINT8::from_state_dict(&state_dict, "linear.")
+            .await
+            .unwrap();
+        assert_eq!(linear.shape(), (3, 4));
+
+        let max_values = pseudo_random(3, 7);
+        let int8_values = state_dict.get("linear.weight_int8").unwrap();
+        for (idx, v) in linear.dequantize().data().iter().enumerate() {
+            let expected = int8_values.data[idx] as i8 as f32 * max_values[idx / 4] / 127.0;
+            assert!((v - expected).abs() < 1e-6);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_load_llama_block() {
+        let state_dict = block_state_dict(insert_int8);
+        let mut block: DefaultLlamaBlock<LinearINT8> =
+            LlamaBlock::from_state_dict(&state_dict, "block.", CONFIG)
+                .await
+                .unwrap();
+        let output = block.forward(pseudo_random(CONFIG.dim, 1)).await.unwrap();
+        assert_eq!(output.len(), CONFIG.dim);
+        assert!(output.iter().all(|v| v.is_finite()));
+
+        let state_dict = block_state_dict(insert_aqlm);
+        let mut block: DefaultLlamaBlock<LinearAQLM> =
+            LlamaBlock::from_state_dict(&state_dict, "block.", CONFIG)
+                .await
+                .unwrap();
+        let output = block.forward(pseudo_random(CONFIG.dim, 1)).await.unwrap();
+        assert_eq!(output.len(), CONFIG.dim);
+        assert!(output.iter().all(|v| v.is_finite()));
+    }
+
+    #[tokio::test]
+    async fn test_load_f16_aqlm() {
+        let mut state_dict = StateDict::new();
+        insert_aqlm(&mut state_dict, "linear.", 16, 32);
+        let reference = LinearAQLM::from_state_dict(&state_dict, "linear.")
+            .await
+            .unwrap();
+
+        // Published checkpoints store codebooks and scales in fp16.
+        for name in ["linear.codebooks", "linear.scales"] {
+            let tensor = state_dict.remove(name).unwrap();
+            let (values, shape) = get_f32_data(tensor.into()).unwrap();
+            state_dict.insert(OwnedTensor {
+                name: name.to_string(),
+                data: values
+                    .iter()
+                    .flat_map(|v| half::f16::from_f32(*v).to_le_bytes())
+                    .collect(),
+                shape,
+                dtype: Dtype::F16,
+            });
+        }
+        let linear = LinearAQLM::from_state_dict(&state_dict, "linear.")
+            .await
+            .unwrap();
+
+        let (expected, output) = (reference.dequantize(), linear.dequantize());
+        assert!(expected
+            .data()
+            .iter()
+            .zip(output.data().iter())
+            .all(|(a, b)| (a - b).abs() < 1e-3));
+    }
+
+    #[tokio::test]
+    async fn test_load_errors() {
+        let mut state_dict = block_state_dict(insert_aqlm);
+        state_dict.remove("block.mlp.down_proj.scales");
+
+        let result = MLP::<LinearAQLM>::from_state_dict(&state_dict, "block.mlp.").await;
+        assert_eq!(
+            result.err(),
+            Some(LoadError::MissingTensor {
+                name: "block.mlp.down_proj.scales".to_string()
+            })
+        );
+
+        state_dict.insert_f32("block.mlp.down_proj.scales", &[3], &[1.0; 3]);
+        let result = MLP::<LinearAQLM>::from_state_dict(&state_dict, "block.mlp.").await;
+        assert!(matches!(result, Err(LoadError::ShapeMismatch { .. })));
+    }
 }
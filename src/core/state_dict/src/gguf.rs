@@ -0,0 +1,632 @@
+//! Reader for GGUF files: metadata, tensor directory and the tokenizer.
+//!
+//! Parsing works on a byte slice, so it runs over a memory-mapped file natively
+//! and over a downloaded buffer in the browser.
+
+use crate::error::LoadError;
+use nn::linear_gguf::GgmlType;
+use nn::llama_config::LlamaConfig;
+use std::collections::BTreeMap;
+
+const GGUF_MAGIC: &[u8; 4] = b"GGUF";
+const DEFAULT_ALIGNMENT: usize = 32;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GgufValue {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    U64(u64),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+    Bool(bool),
+    String(String),
+    Array(Vec<GgufValue>),
+}
+
+impl GgufValue {
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Self::U8(v) => Some(v as u64),
+            Self::U16(v) => Some(v as u64),
+            Self::U32(v) => Some(v as u64),
+            Self::U64(v) => Some(v),
+            Self::I8(v) => u64::try_from(v).ok(),
+            Self::I16(v) => u64::try_from(v).ok(),
+            Self::I32(v) => u64::try_from(v).ok(),
+            Self::I64(v) => u64::try_from(v).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_f32(&self) -> Option<f32> {
+        match *self {
+            Self::F32(v) => Some(v),
+            Self::F64(v) => Some(v as f32),
+            _ => self.as_u64().map(|v| v as f32),
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[GgufValue]> {
+        match self {
+            Self::Array(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GgufTensorInfo {
+    /// Row-major shape, i.e. the GGUF dimensions reversed.
+    pub shape: Vec<usize>,
+    pub ggml_type: GgmlType,
+    /// Byte range relative to the start of the file.
+    pub begin: usize,
+    pub end: usize,
+}
+
+pub struct GgufFile {
+    pub version: u32,
+    pub metadata: BTreeMap<String, GgufValue>,
+    pub tensors: BTreeMap<String, GgufTensorInfo>,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+    name: &'a str,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], LoadError> {
+        let bytes = self
+            .position
+            .checked_add(n)
+            .and_then(|end| self.data.get(self.position..end))
+            .ok_or_else(|| LoadError::corrupted(self.name, "unexpected end of GGUF header"))?;
+        self.position += n;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], LoadError> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    fn u32(&mut self) -> Result<u32, LoadError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, LoadError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    fn usize(&mut self) -> Result<usize, LoadError> {
+        let value = self.u64()?;
+        usize::try_from(value)
+            .map_err(|_| LoadError::corrupted(self.name, format!("{value} does not fit in usize")))
+    }
+
+    fn string(&mut self) -> Result<String, LoadError> {
+        let len = self.usize()?;
+        String::from_utf8(self.bytes(len)?.to_vec())
+            .map_err(|err| LoadError::corrupted(self.name, err))
+    }
+
+    fn value(&mut self, value_type: u32) -> Result<GgufValue, LoadError> {
+        Ok(match value_type {
+            0 => GgufValue::U8(self.array::<1>()?[0]),
+            1 => GgufValue::I8(self.array::<1>()?[0] as i8),
+            2 => GgufValue::U16(u16::from_le_bytes(self.array()?)),
+            3 => GgufValue::I16(i16::from_le_bytes(self.array()?)),
+            4 => GgufValue::U32(self.u32()?),
+            5 => GgufValue::I32(i32::from_le_bytes(self.array()?)),
+            6 => GgufValue::F32(f32::from_le_bytes(self.array()?)),
+            7 => GgufValue::Bool(self.array::<1>()?[0] != 0),
+            8 => GgufValue::String(self.string()?),
+            9 => {
+                let element_type = self.u32()?;
+                let len = self.usize()?;
+                // Every element takes at least one byte, which bounds bogus lengths.
+                if len > self.data.len() - self.position {
+                    return Err(LoadError::corrupted(self.name, "GGUF array is too long"));
+                }
+                GgufValue::Array(
+                    (0..len)
+                        .map(|_| self.value(element_type))
+                        .collect::<Result<_, _>>()?,
+                )
+            }
+            10 => GgufValue::U64(self.u64()?),
+            11 => GgufValue::I64(i64::from_le_bytes(self.array()?)),
+            12 => GgufValue::F64(f64::from_le_bytes(self.array()?)),
+            _ => {
+                return Err(LoadError::corrupted(
+                    self.name,
+                    format!("unknown GGUF value type {value_type}"),
+                ))
+            }
+        })
+    }
+}
+
+impl GgufFile {
+    /// Parses the header of the GGUF file `data`; `name` is used in errors.
+    pub fn parse(name: &str, data: &[u8]) -> Result<Self, LoadError> {
+        let mut reader = Reader {
+            data,
+            position: 0,
+            name,
+        };
+
+        if reader.bytes(4)? != GGUF_MAGIC {
+            return Err(LoadError::corrupted(name, "not a GGUF file"));
+        }
+        let version = reader.u32()?;
+        if !(2..=3).contains(&version) {
+            return Err(LoadError::corrupted(
+                name,
+                format!("unsupported GGUF version {version}"),
+            ));
+        }
+
+        let n_tensors = reader.usize()?;
+        let n_metadata = reader.usize()?;
+
+        let mut metadata = BTreeMap::new();
+        for _ in 0..n_metadata {
+            let key = reader.string()?;
+            let value_type = reader.u32()?;
+            metadata.insert(key, reader.value(value_type)?);
+        }
+
+        let mut tensor_headers = Vec::new();
+        for _ in 0..n_tensors {
+            let tensor_name = reader.string()?;
+            let n_dims = reader.u32()?;
+            let mut shape = (0..n_dims)
+                .map(|_| reader.usize())
+                .collect::<Result<Vec<_>, _>>()?;
+            shape.reverse();
+
+            let type_id = reader.u32()?;
+            let ggml_type =
+                GgmlType::from_id(type_id).ok_or_else(|| LoadError::UnsupportedDtype {
+                    name: tensor_name.clone(),
+                    dtype: format!("ggml type {type_id}"),
+                })?;
+            let offset = reader.usize()?;
+
+            tensor_headers.push((tensor_name, shape, ggml_type, offset));
+        }
+
+        let alignment = match metadata.get("general.alignment") {
+            Some(value) => value
+                .as_u64()
+                .and_then(|alignment| usize::try_from(alignment).ok())
+                .filter(|alignment| *alignment > 0)
+                .ok_or_else(|| LoadError::corrupted(name, "invalid general.alignment"))?,
+            None => DEFAULT_ALIGNMENT,
+        };
+        let data_offset = reader
+            .position
+            .checked_next_multiple_of(alignment)
+            .ok_or_else(|| LoadError::corrupted(name, "data offset overflows"))?;
+
+        let mut tensors = BTreeMap::new();
+        for (tensor_name, shape, ggml_type, offset) in tensor_headers {
+            let n_values = shape
+                .iter()
+                .try_fold(1usize, |n_values, dim| n_values.checked_mul(*dim))
+                .ok_or_else(|| {
+                    LoadError::corrupted(&tensor_name, format!("shape {shape:?} overflows"))
+                })?;
+            let size = ggml_type.row_size(n_values).ok_or_else(|| {
+                LoadError::corrupted(
+                    &tensor_name,
+                    format!("{n_values} values do not fit whole {ggml_type:?} blocks"),
+                )
+            })?;
+            let range = data_offset
+                .checked_add(offset)
+                .and_then(|begin| Some((begin, begin.checked_add(size)?)))
+                .filter(|(_, end)| *end <= data.len());
+            let Some((begin, end)) = range else {
+                return Err(LoadError::corrupted(
+                    &tensor_name,
+                    "tensor is out of file bounds",
+                ));
+            };
+
+            tensors.insert(
+                tensor_name,
+                GgufTensorInfo {
+                    shape,
+                    ggml_type,
+                    begin,
+                    end,
+                },
+            );
+        }
+
+        Ok(Self {
+            version,
+            metadata,
+            tensors,
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Result<&GgufValue, LoadError> {
+        self.metadata
+            .get(key)
+            .ok_or_else(|| LoadError::corrupted(key, "missing GGUF metadata"))
+    }
+
+    fn get_usize(&self, key: &str) -> Result<usize, LoadError> {
+        self.get(key)?
+            .as_u64()
+            .map(|v| v as usize)
+            .ok_or_else(|| LoadError::corrupted(key, "expected an integer"))
+    }
+
+    fn get_f32(&self, key: &str) -> Result<f32, LoadError> {
+        self.get(key)?
+            .as_f32()
+            .ok_or_else(|| LoadError::corrupted(key, "expected a number"))
+    }
+
+    pub fn architecture(&self) -> Result<&str, LoadError> {
+        self.get("general.architecture")?
+            .as_str()
+            .ok_or_else(|| LoadError::corrupted("general.architecture", "expected a string"))
+    }
+
+    pub fn llama_config(&self) -> Result<LlamaConfig, LoadError> {
+        let arch = self.architecture()?;
+        if arch != "llama" {
+            return Err(LoadError::corrupted(
+                "general.architecture",
+                format!("unsupported architecture {arch}"),
+            ));
+        }
+
+        let n_heads = self.get_usize("llama.attention.head_count")?;
+        Ok(LlamaConfig {
+            dim: self.get_usize("llama.embedding_length")?,
+            n_layers: self.get_usize("llama.block_count")?,
+            n_heads,
+            n_kv_heads: match self.metadata.contains_key("llama.attention.head_count_kv") {
+                true => self.get_usize("llama.attention.head_count_kv")?,
+                false => n_heads,
+            },
+            norm_eps: self.get_f32("llama.attention.layer_norm_rms_epsilon")?,
+            rope_theta: match self.metadata.contains_key("llama.rope.freq_base") {
+                true => self.get_f32("llama.rope.freq_base")?,
+                false => 10000.0,
+            },
+        })
+    }
+
+    pub fn tokenizer(&self) -> Result<GgufTokenizer, LoadError> {
+        let strings = |key: &str| -> Result<Vec<String>, LoadError> {
+            match self.metadata.get(key) {
+                None => Ok(Vec::new()),
+                Some(value) => value
+                    .as_array()
+                    .and_then(|values| {
+                        values
+                            .iter()
+                            .map(|v| v.as_str().map(str::to_string))
+                            .collect()
+                    })
+                    .ok_or_else(|| LoadError::corrupted(key, "expected an array of strings")),
+            }
+        };
+        let token_id = |key: &str| -> Result<Option<usize>, LoadError> {
+            match self.metadata.contains_key(key) {
+                true => self.get_usize(key).map(Some),
+                false => Ok(None),
+            }
+        };
+
+        let tokens = strings("tokenizer.ggml.tokens")?;
+        let token_types = match self.metadata.get("tokenizer.ggml.token_type") {
+            None => vec![TOKEN_TYPE_NORMAL; tokens.len()],
+            Some(value) => value
+                .as_array()
+                .and_then(|values| values.iter().map(|v| v.as_u64()).collect())
+                .ok_or_else(|| {
+                    LoadError::corrupted("tokenizer.ggml.token_type", "expected integers")
+                })?,
+        };
+
+        Ok(GgufTokenizer {
+            model: self
+                .get("tokenizer.ggml.model")?
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            tokens,
+            token_types,
+            merges: strings("tokenizer.ggml.merges")?,
+            bos_token_id: token_id("tokenizer.ggml.bos_token_id")?,
+            eos_token_id: token_id("tokenizer.ggml.eos_token_id")?,
+        })
+    }
+
+    pub fn tensor_info(&self, name: &str) -> Result<&GgufTensorInfo, LoadError> {
+        self.tensors
+            .get(name)
+            .ok_or_else(|| LoadError::MissingTensor {
+                name: name.to_string(),
+            })
+    }
+}
+
+pub const TOKEN_TYPE_NORMAL: u64 = 1;
+
+/// Tokenizer data stored in the GGUF metadata.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GgufTokenizer {
+    /// `gpt2` for byte-level BPE vocabularies such as Llama 3, `llama` for SentencePiece.
+    pub model: String,
+    pub tokens: Vec<String>,
+    pub token_types: Vec<u64>,
+    pub merges: Vec<String>,
+    pub bos_token_id: Option<usize>,
+    pub eos_token_id: Option<usize>,
+}
+
+impl GgufTokenizer {
+    /// Byte sequences and ranks of the normal tokens of a `gpt2` vocabulary,
+    /// in the form tiktoken takes. Tokens are stored with the GPT-2
+    /// byte-to-unicode mapping, which this reverses.
+    pub fn mergeable_ranks(&self) -> Result<Vec<(Vec<u8>, usize)>, LoadError> {
+        if self.model != "gpt2" {
+            return Err(LoadError::corrupted(
+                "tokenizer.ggml.model",
+                format!("no byte-level vocabulary for {} tokenizers", self.model),
+            ));
+        }
+
+        let unicode_to_byte = unicode_to_byte();
+        self.tokens
+            .iter()
+            .zip(&self.token_types)
+            .enumerate()
+            .filter(|(_, (_, token_type))| **token_type == TOKEN_TYPE_NORMAL)
+            .map(|(rank, (token, _))| {
+                let bytes = token
+                    .chars()
+                    .map(|c| unicode_to_byte.get(&c).copied())
+                    .collect::<Option<Vec<u8>>>()
+                    .ok_or_else(|| {
+                        LoadError::corrupted(
+                            "tokenizer.ggml.tokens",
+                            format!("token {rank} is not byte-level"),
+                        )
+                    })?;
+                Ok((bytes, rank))
+            })
+            .collect()
+    }
+}
+
+/// Inverse of the GPT-2 `bytes_to_unicode` table: printable bytes map to
+/// themselves, the others to code points from 256 up.
+fn unicode_to_byte() -> BTreeMap<char, u8> {
+    let printable = |b: u8| matches!(b, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF);
+
+    let mut next = 256u32;
+    (0..=255u8)
+        .map(|b| {
+            let c = if printable(b) {
+                b as char
+            } else {
+                next += 1;
+                char::from_u32(next - 1).unwrap()
+            };
+            (c, b)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Minimal GGUF writer for tests.
+    #[derive(Default)]
+    pub(crate) struct GgufBuilder {
+        metadata: Vec<u8>,
+        n_metadata: u64,
+        tensors: Vec<(String, Vec<usize>, GgmlType, Vec<u8>)>,
+    }
+
+    fn write_string(output: &mut Vec<u8>, value: &str) {
+        output.extend((value.len() as u64).to_le_bytes());
+        output.extend(value.as_bytes());
+    }
+
+    impl GgufBuilder {
+        fn key(&mut self, key: &str, value_type: u32) -> &mut Vec<u8> {
+            self.n_metadata += 1;
+            write_string(&mut self.metadata, key);
+            self.metadata.extend(value_type.to_le_bytes());
+            &mut self.metadata
+        }
+
+        pub(crate) fn u32(&mut self, key: &str, value: u32) -> &mut Self {
+            self.key(key, 4).extend(value.to_le_bytes());
+            self
+        }
+
+        pub(crate) fn f32(&mut self, key: &str, value: f32) -> &mut Self {
+            self.key(key, 6).extend(value.to_le_bytes());
+            self
+        }
+
+        pub(crate) fn string(&mut self, key: &str, value: &str) -> &mut Self {
+            write_string(self.key(key, 8), value);
+            self
+        }
+
+        pub(crate) fn strings(&mut self, key: &str, values: &[&str]) -> &mut Self {
+            let output = self.key(key, 9);
+            output.extend(8u32.to_le_bytes());
+            output.extend((values.len() as u64).to_le_bytes());
+            for value in values {
+                write_string(output, value);
+            }
+            self
+        }
+
+        pub(crate) fn i32s(&mut self, key: &str, values: &[i32]) -> &mut Self {
+            let output = self.key(key, 9);
+            output.extend(5u32.to_le_bytes());
+            output.extend((values.len() as u64).to_le_bytes());
+            for value in values {
+                output.extend(value.to_le_bytes());
+            }
+            self
+        }
+
+        /// Adds a tensor with a row-major `shape`.
+        pub(crate) fn tensor(
+            &mut self,
+            name: &str,
+            shape: &[usize],
+            ggml_type: GgmlType,
+            data: Vec<u8>,
+        ) -> &mut Self {
+            self.tensors
+                .push((name.to_string(), shape.to_vec(), ggml_type, data));
+            self
+        }
+
+        pub(crate) fn f32_tensor(
+            &mut self,
+            name: &str,
+            shape: &[usize],
+            data: &[f32],
+        ) -> &mut Self {
+            let bytes = data.iter().flat_map(|v| v.to_le_bytes()).collect();
+            self.tensor(name, shape, GgmlType::F32, bytes)
+        }
+
+        pub(crate) fn build(&self) -> Vec<u8> {
+            let mut output = GGUF_MAGIC.to_vec();
+            output.extend(3u32.to_le_bytes());
+            output.extend((self.tensors.len() as u64).to_le_bytes());
+            output.extend(self.n_metadata.to_le_bytes());
+            output.extend(&self.metadata);
+
+            let mut offset = 0;
+            for (name, shape, ggml_type, data) in &self.tensors {
+                write_string(&mut output, name);
+                output.extend((shape.len() as u32).to_le_bytes());
+                for dim in shape.iter().rev() {
+                    output.extend((*dim as u64).to_le_bytes());
+                }
+                output.extend(ggml_type.id().to_le_bytes());
+                output.extend((offset as u64).to_le_bytes());
+                offset = (offset + data.len()).next_multiple_of(DEFAULT_ALIGNMENT);
+            }
+
+            for (_, _, _, data) in &self.tensors {
+                output.resize(output.len().next_multiple_of(DEFAULT_ALIGNMENT), 0);
+                output.extend(data);
+            }
+            output
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let data = GgufBuilder::default()
+            .string("general.architecture", "llama")
+            .u32("llama.embedding_length", 64)
+            .u32("llama.block_count", 2)
+            .u32("llama.attention.head_count", 4)
+            .u32("llama.attention.head_count_kv", 2)
+            .f32("llama.attention.layer_norm_rms_epsilon", 1e-5)
+            .f32_tensor("output_norm.weight", &[3], &[1.0, 2.0, 3.0])
+            .f32_tensor("token_embd.weight", &[2, 2], &[1.0, 2.0, 3.0, 4.0])
+            .build();
+
+        let file = GgufFile::parse("model.gguf", &data).unwrap();
+        assert_eq!(file.version, 3);
+
+        let config = file.llama_config().unwrap();
+        assert_eq!(
+            (
+                config.dim,
+                config.n_layers,
+                config.n_heads,
+                config.n_kv_heads
+            ),
+            (64, 2, 4, 2)
+        );
+        assert_eq!(config.rope_theta, 10000.0);
+
+        let info = file.tensor_info("token_embd.weight").unwrap();
+        assert_eq!(info.shape, vec![2, 2]);
+        assert_eq!(info.ggml_type, GgmlType::F32);
+        assert_eq!(
+            &data[info.begin..info.end],
+            &[1f32, 2.0, 3.0, 4.0].map(f32::to_le_bytes).concat()
+        );
+
+        assert!(matches!(
+            file.tensor_info("output.weight"),
+            Err(LoadError::MissingTensor { .. })
+        ));
+        assert!(GgufFile::parse("model.gguf", &data[..data.len() - 1]).is_err());
+        assert!(GgufFile::parse("model.gguf", b"GGML").is_err());
+    }
+
+    #[test]
+    fn test_overflowing_tensor_sizes() {
+        for shape in [[1 << 40, 1 << 40], [usize::MAX / 2, 1]] {
+            let data = GgufBuilder::default()
+                .tensor("huge.weight", &shape, GgmlType::F32, Vec::new())
+                .build();
+            assert!(matches!(
+                GgufFile::parse("model.gguf", &data),
+                Err(LoadError::Corrupted { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_tokenizer() {
+        // "Ġ" is the GPT-2 encoding of a space, "Ċ" of a newline.
+        let data = GgufBuilder::default()
+            .string("tokenizer.ggml.model", "gpt2")
+            .strings("tokenizer.ggml.tokens", &["a", "Ġb", "Ċ", "<|eot_id|>"])
+            .i32s("tokenizer.ggml.token_type", &[1, 1, 1, 3])
+            .u32("tokenizer.ggml.eos_token_id", 3)
+            .build();
+
+        let tokenizer = GgufFile::parse("model.gguf", &data)
+            .unwrap()
+            .tokenizer()
+            .unwrap();
+        assert_eq!(tokenizer.eos_token_id, Some(3));
+        assert_eq!(tokenizer.bos_token_id, None);
+        assert_eq!(
+            tokenizer.mergeable_ranks().unwrap(),
+            vec![(b"a".to_vec(), 0), (b" b".to_vec(), 1), (b"\n".to_vec(), 2)]
+        );
+    }
+}
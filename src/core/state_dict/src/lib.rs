@@ -1,3 +1,17 @@
+pub mod cache;
+pub mod download;
+pub mod error;
 pub mod from_state_dict;
+pub mod gguf;
+pub mod manifest;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod mmap_gguf;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod mmap_state_dict;
 pub mod owned_tensor;
+pub mod remote_safetensors;
 pub mod state_dict;
+#[cfg(test)]
+mod test_server;
+pub mod to_state_dict;
+pub mod weights_source;
@@ -0,0 +1,318 @@
+use crate::download::set_expected_total_bytes;
+use crate::error::LoadError;
+use crate::from_state_dict::{convert_dtype, get_file_by_name_unverified};
+use crate::owned_tensor::{Dtype, OwnedTensor};
+use crate::weights_source::weights_source;
+use safetensors::SafeTensors;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+pub const MANIFEST_FILENAME: &str = "manifest.json";
+
+/// Lists every file of a weights source with its size and SHA-256, and the
+/// dtype, shape and SHA-256 of every tensor inside safetensors files.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    pub files: BTreeMap<String, FileEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileEntry {
+    pub size: usize,
+    pub sha256: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tensors: BTreeMap<String, TensorEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TensorEntry {
+    pub dtype: Dtype,
+    pub shape: Vec<usize>,
+    /// Hash of the tensor's own bytes, so that tensors fetched by range can be verified.
+    pub sha256: String,
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+impl Manifest {
+    pub fn from_json(filename: &str, data: &[u8]) -> Result<Self, LoadError> {
+        serde_json::from_slice(data).map_err(|err| LoadError::corrupted(filename, err))
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("manifest is always serializable")
+    }
+
+    /// Records `data` as the contents of `filename`. Safetensors files also get
+    /// an entry per tensor.
+    pub fn add_file(&mut self, filename: &str, data: &[u8]) -> Result<(), LoadError> {
+        let mut tensors = BTreeMap::new();
+
+        if filename.ends_with(".safetensors") {
+            let file = SafeTensors::deserialize(data)
+                .map_err(|err| LoadError::corrupted(filename, err))?;
+
+            for (name, view) in file.tensors() {
+                let entry = TensorEntry {
+                    dtype: convert_dtype(&name, view.dtype())?,
+                    shape: view.shape().to_vec(),
+                    sha256: sha256_hex(view.data()),
+                };
+                tensors.insert(name, entry);
+            }
+        }
+
+        self.files.insert(
+            filename.to_string(),
+            FileEntry {
+                size: data.len(),
+                sha256: sha256_hex(data),
+                tensors,
+            },
+        );
+
+        Ok(())
+    }
+
+    pub fn total_size(&self) -> usize {
+        self.files.values().map(|entry| entry.size).sum()
+    }
+
+    pub fn file_sha256(&self, filename: &str) -> Option<&str> {
+        self.files.get(filename).map(|entry| entry.sha256.as_str())
+    }
+
+    fn file_entry(&self, filename: &str) -> Result<&FileEntry, LoadError> {
+        self.files
+            .get(filename)
+            .ok_or_else(|| LoadError::NotInManifest {
+                name: filename.to_string(),
+            })
+    }
+
+    /// Tensor `name` of `filename`. Files holding a single tensor match any
+    /// name, the same way `read_named_tensor` does.
+    fn tensor_entry(&self, filename: &str, name: &str) -> Result<&TensorEntry, LoadError> {
+        let file_entry = self.file_entry(filename)?;
+
+        if let Some(entry) = file_entry.tensors.get(name) {
+            return Ok(entry);
+        }
+
+        match file_entry.tensors.values().next() {
+            Some(entry) if file_entry.tensors.len() == 1 => Ok(entry),
+            _ => Err(LoadError::NotInManifest {
+                name: format!("{filename}: {name}"),
+            }),
+        }
+    }
+
+    pub fn verify_file(&self, filename: &str, data: &[u8]) -> Result<(), LoadError> {
+        let entry = self.file_entry(filename)?;
+
+        if data.len() != entry.size {
+            return Err(LoadError::corrupted(
+                filename,
+                format!("expected {} bytes, got {}", entry.size, data.len()),
+            ));
+        }
+
+        if sha256_hex(data) != entry.sha256 {
+            return Err(LoadError::corrupted(filename, "sha256 mismatch"));
+        }
+
+        Ok(())
+    }
+
+    /// Checks dtype and shape of a tensor read from `filename`.
+    pub fn verify_tensor_info(
+        &self,
+        filename: &str,
+        tensor: &OwnedTensor,
+    ) -> Result<(), LoadError> {
+        let entry = self.tensor_entry(filename, &tensor.name)?;
+
+        if tensor.dtype != entry.dtype {
+            return Err(LoadError::DtypeMismatch {
+                name: tensor.name.clone(),
+                expected: entry.dtype,
+                actual: tensor.dtype,
+            });
+        }
+
+        if tensor.shape != entry.shape {
+            return Err(LoadError::shape_mismatch(
+                &tensor.name,
+                entry.shape.clone(),
+                tensor.shape.clone(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Checks the raw bytes of tensor `name` fetched out of `filename`.
+    pub fn verify_tensor_data(
+        &self,
+        filename: &str,
+        name: &str,
+        data: &[u8],
+    ) -> Result<(), LoadError> {
+        let entry = self.tensor_entry(filename, name)?;
+
+        if sha256_hex(data) != entry.sha256 {
+            return Err(LoadError::corrupted(name, "sha256 mismatch"));
+        }
+
+        Ok(())
+    }
+}
+
+thread_local! {
+    static MANIFEST_CACHE: RefCell<Option<Rc<Manifest>>> = const { RefCell::new(None) };
+}
+
+pub(crate) fn clear_manifest_cache() {
+    MANIFEST_CACHE.set(None);
+}
+
+/// Manifest of the current weights source, `None` if the source has none.
+pub async fn get_manifest() -> Result<Option<Rc<Manifest>>, LoadError> {
+    let Some(filename) = weights_source().manifest else {
+        return Ok(None);
+    };
+
+    if let Some(manifest) = MANIFEST_CACHE.with_borrow(|cache| cache.clone()) {
+        return Ok(Some(manifest));
+    }
+
+    let data = get_file_by_name_unverified(&filename).await?;
+    let manifest = Rc::new(Manifest::from_json(&filename, &data)?);
+
+    MANIFEST_CACHE.set(Some(manifest.clone()));
+    set_expected_total_bytes(Some(manifest.total_size()));
+
+    Ok(Some(manifest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::from_state_dict::get_file_by_name;
+    use crate::test_server::TestServer;
+    use crate::weights_source::{set_weights_source, WeightsLayout, WeightsSource};
+    use safetensors::tensor::TensorView;
+
+    fn safetensors_file(name: &str, values: &[f32]) -> Vec<u8> {
+        let data: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+        let view = TensorView::new(safetensors::Dtype::F32, vec![values.len()], &data).unwrap();
+        safetensors::serialize([(name, view)], &None).unwrap()
+    }
+
+    fn tensor(name: &str, values: &[f32]) -> OwnedTensor {
+        OwnedTensor {
+            name: name.to_string(),
+            data: values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+            shape: vec![values.len()],
+            dtype: Dtype::F32,
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut manifest = Manifest::default();
+        manifest
+            .add_file("a.safetensors", &safetensors_file("a", &[1.0, 2.0]))
+            .unwrap();
+        manifest.add_file("tokenizer.model", b"tokens").unwrap();
+
+        let parsed = Manifest::from_json(MANIFEST_FILENAME, manifest.to_json().as_bytes()).unwrap();
+        assert_eq!(parsed, manifest);
+        assert_eq!(parsed.files["a.safetensors"].tensors["a"].shape, vec![2]);
+    }
+
+    #[test]
+    fn test_verify_file() {
+        let data = safetensors_file("a", &[1.0, 2.0]);
+        let mut manifest = Manifest::default();
+        manifest.add_file("a.safetensors", &data).unwrap();
+
+        assert_eq!(manifest.verify_file("a.safetensors", &data), Ok(()));
+
+        let mut corrupted = data.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            manifest.verify_file("a.safetensors", &corrupted),
+            Err(LoadError::Corrupted { .. })
+        ));
+        assert!(matches!(
+            manifest.verify_file("a.safetensors", &data[..data.len() - 1]),
+            Err(LoadError::Corrupted { .. })
+        ));
+        assert_eq!(
+            manifest.verify_file("b.safetensors", &data),
+            Err(LoadError::NotInManifest {
+                name: "b.safetensors".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_verify_tensor() {
+        let mut manifest = Manifest::default();
+        manifest
+            .add_file("a.safetensors", &safetensors_file("a", &[1.0, 2.0]))
+            .unwrap();
+
+        let good = tensor("a", &[1.0, 2.0]);
+        assert_eq!(manifest.verify_tensor_info("a.safetensors", &good), Ok(()));
+        assert_eq!(
+            manifest.verify_tensor_data("a.safetensors", "a", &good.data),
+            Ok(())
+        );
+
+        let wrong_shape = tensor("a", &[1.0, 2.0, 3.0]);
+        assert_eq!(
+            manifest.verify_tensor_info("a.safetensors", &wrong_shape),
+            Err(LoadError::shape_mismatch("a", vec![2], vec![3]))
+        );
+
+        let wrong_data = tensor("a", &[1.0, 3.0]);
+        assert!(manifest
+            .verify_tensor_data("a.safetensors", "a", &wrong_data.data)
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unlisted_file_is_not_retried() {
+        let mut manifest = Manifest::default();
+        manifest
+            .add_file("a.safetensors", &safetensors_file("a", &[1.0]))
+            .unwrap();
+
+        let server = TestServer::start();
+        server.insert(MANIFEST_FILENAME, manifest.to_json().into_bytes());
+        server.insert("b.safetensors", safetensors_file("b", &[2.0]));
+        set_weights_source(
+            WeightsSource::new(server.url(), "", WeightsLayout::PerTensorFiles)
+                .with_manifest(MANIFEST_FILENAME),
+        );
+
+        assert_eq!(
+            get_file_by_name("b.safetensors").await,
+            Err(LoadError::NotInManifest {
+                name: "b.safetensors".to_string()
+            })
+        );
+        let requests = server.requests();
+        let downloads = requests
+            .iter()
+            .filter(|request| request.path.ends_with("b.safetensors"));
+        assert_eq!(downloads.count(), 1);
+    }
+}
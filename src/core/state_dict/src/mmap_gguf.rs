@@ -0,0 +1,364 @@
+use crate::error::LoadError;
+use crate::from_state_dict::TensorProvider;
+use crate::gguf::{GgufFile, GgufTensorInfo};
+use crate::owned_tensor::{get_f32_data, Dtype, Tensor};
+use async_trait::async_trait;
+use memmap2::Mmap;
+use nn::llama_config::LlamaConfig;
+use std::borrow::Cow;
+use std::fs::File;
+use std::path::Path;
+
+/// Names of the tensors of a block in llama.cpp's GGUF files, by the names of
+/// the Hugging Face modules they hold.
+const BLOCK_TENSORS: [(&str, &str); 9] = [
+    ("input_layernorm", "attn_norm"),
+    ("self_attn.q_proj", "attn_q"),
+    ("self_attn.k_proj", "attn_k"),
+    ("self_attn.v_proj", "attn_v"),
+    ("self_attn.o_proj", "attn_output"),
+    ("post_attention_layernorm", "ffn_norm"),
+    ("mlp.gate_proj", "ffn_gate"),
+    ("mlp.up_proj", "ffn_up"),
+    ("mlp.down_proj", "ffn_down"),
+];
+
+/// Memory-mapped GGUF model. Through `&MmapGguf`, modules load its tensors by
+/// their Hugging Face names, and quantized tensors are borrowed from the
+/// mapped file and run through `LinearGGUF` as they are.
+pub struct MmapGguf {
+    mmap: Mmap,
+    file: GgufFile,
+}
+
+impl MmapGguf {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, LoadError> {
+        let path = path.as_ref();
+        let name = path.display().to_string();
+        let io_error = |err: std::io::Error| LoadError::io(&name, err);
+
+        let file = File::open(path).map_err(io_error)?;
+        // Safety: weight files must not be modified while they are mapped.
+        let mmap = unsafe { Mmap::map(&file).map_err(io_error)? };
+        let file = GgufFile::parse(&name, &mmap)?;
+
+        Ok(Self { mmap, file })
+    }
+
+    /// Leaks the file, so that loaded modules can live for `'static`.
+    pub fn leak(self) -> &'static Self {
+        Box::leak(Box::new(self))
+    }
+
+    pub fn file(&self) -> &GgufFile {
+        &self.file
+    }
+
+    pub fn llama_config(&self) -> Result<LlamaConfig, LoadError> {
+        self.file.llama_config()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.file.tensors.contains_key(name)
+    }
+
+    pub fn get_raw(&self, name: &str) -> Result<(&[u8], &GgufTensorInfo), LoadError> {
+        let info = self.file.tensor_info(name)?;
+        Ok((&self.mmap[info.begin..info.end], info))
+    }
+
+    /// Borrows f32 tensors; tensors of any other type are dequantized into an owned copy.
+    pub fn get_f32(&self, name: &str) -> Result<(Cow<'_, [f32]>, Vec<usize>), LoadError> {
+        let (data, info) = self.get_raw(name)?;
+        get_f32_data(Tensor {
+            name: name.to_string(),
+            data: Cow::Borrowed(data),
+            shape: info.shape.clone(),
+            dtype: Dtype::from_ggml_type(info.ggml_type),
+        })
+    }
+
+    /// The GGUF name of the tensor with the Hugging Face name `name`.
+    fn gguf_name(&self, name: &str) -> Option<String> {
+        let (module, suffix) = name.rsplit_once('.')?;
+        let module = match module {
+            "model.embed_tokens" => "token_embd".to_string(),
+            "model.norm" => "output_norm".to_string(),
+            // Models with tied embeddings have no separate output matrix.
+            "lm_head" => match self.contains(&format!("output.{suffix}")) {
+                true => "output".to_string(),
+                false => "token_embd".to_string(),
+            },
+            _ => {
+                let (layer_idx, module) = module.strip_prefix("model.layers.")?.split_once('.')?;
+                let (_, gguf_module) = BLOCK_TENSORS.iter().find(|(hf, _)| *hf == module)?;
+                format!("blk.{layer_idx}.{gguf_module}")
+            }
+        };
+        Some(format!("{module}.{suffix}"))
+    }
+
+    /// Looks a tensor up by its Hugging Face name.
+    pub fn get_tensor(&self, name: &str) -> Result<Tensor<'_>, LoadError> {
+        let gguf_name = self
+            .gguf_name(name)
+            .filter(|gguf_name| self.contains(gguf_name))
+            .ok_or_else(|| LoadError::MissingTensor {
+                name: name.to_string(),
+            })?;
+        let n_heads = if name.ends_with(".self_attn.q_proj.weight") {
+            Some(self.llama_config()?.n_heads)
+        } else if name.ends_with(".self_attn.k_proj.weight") {
+            Some(self.llama_config()?.n_kv_heads)
+        } else {
+            None
+        };
+        let (data, info) = self.get_raw(&gguf_name)?;
+
+        let data = match (n_heads, &info.shape[..]) {
+            (None, _) => Cow::Borrowed(data),
+            (Some(n_heads), &[n_rows, _]) if n_heads > 0 && n_rows % (2 * n_heads) == 0 => {
+                Cow::Owned(unpermute_rope_rows(data, n_rows, n_heads))
+            }
+            (Some(n_heads), shape) => {
+                return Err(LoadError::corrupted(
+                    &gguf_name,
+                    format!("shape {shape:?} does not split into {n_heads} heads"),
+                ))
+            }
+        };
+
+        Ok(Tensor {
+            name: name.to_string(),
+            data,
+            shape: info.shape.clone(),
+            dtype: Dtype::from_ggml_type(info.ggml_type),
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl<'a> TensorProvider<'a> for &'a MmapGguf {
+    async fn get_tensor(&self, name: &str) -> Result<Tensor<'a>, LoadError> {
+        MmapGguf::get_tensor(*self, name)
+    }
+}
+
+/// llama.cpp stores the q and k rows of each head with the two halves of every
+/// rotary pair interleaved; the attention here rotates halves, like the
+/// Hugging Face weights. Reorders the rows back.
+fn unpermute_rope_rows(data: &[u8], n_rows: usize, n_heads: usize) -> Vec<u8> {
+    let row_size = data.len() / n_rows;
+    let head_dim = n_rows / n_heads;
+
+    let mut output = Vec::with_capacity(data.len());
+    for head_idx in 0..n_heads {
+        for half_idx in 0..2 {
+            for pair_idx in 0..head_dim / 2 {
+                let row = head_idx * head_dim + 2 * pair_idx + half_idx;
+                output.extend_from_slice(&data[row * row_size..(row + 1) * row_size]);
+            }
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::from_state_dict::tests::pseudo_random;
+    use crate::from_state_dict::{FromStateDict, FromStateDictConf};
+    use crate::gguf::tests::GgufBuilder;
+    use half::f16;
+    use nn::embedding::EmbeddingINT8;
+    use nn::linear_gguf::{GgmlType, LinearGGUF};
+    use nn::llama::{DefaultLlama, Llama};
+    use std::path::PathBuf;
+
+    const DIM: usize = 32;
+    const N_HEADS: usize = 2;
+    const N_KV_HEADS: usize = 1;
+    const HEAD_DIM: usize = DIM / N_HEADS;
+    const FFN_DIM: usize = 64;
+    const N_TOKENS: usize = 5;
+
+    fn f32_bytes(values: &[f32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_le_bytes()).collect()
+    }
+
+    fn quantize_q8_0(values: &[f32]) -> Vec<u8> {
+        values
+            .chunks_exact(32)
+            .flat_map(|block| {
+                let d = block.iter().fold(0f32, |max, v| max.max(v.abs())) / 127.0;
+                let mut bytes = f16::from_f32(d).to_le_bytes().to_vec();
+                let d = f16::from_f32(d).to_f32();
+                bytes.extend(block.iter().map(|v| (v / d).round() as i8 as u8));
+                bytes
+            })
+            .collect()
+    }
+
+    /// The llama.cpp conversion of Hugging Face q and k weights.
+    fn permute_rope_rows(weight: &[f32], n_rows: usize, n_heads: usize) -> Vec<f32> {
+        let row_size = weight.len() / n_rows;
+        let head_dim = n_rows / n_heads;
+
+        let mut output = Vec::with_capacity(weight.len());
+        for head_idx in 0..n_heads {
+            for pair_idx in 0..head_dim / 2 {
+                for half_idx in 0..2 {
+                    let row = head_idx * head_dim + half_idx * head_dim / 2 + pair_idx;
+                    output.extend_from_slice(&weight[row * row_size..(row + 1) * row_size]);
+                }
+            }
+        }
+        output
+    }
+
+    /// The linear weight types `write_model` can encode.
+    #[derive(Debug, Clone, Copy)]
+    enum WeightType {
+        F32,
+        Q8_0,
+    }
+
+    impl WeightType {
+        fn ggml_type(self) -> GgmlType {
+            match self {
+                WeightType::F32 => GgmlType::F32,
+                WeightType::Q8_0 => GgmlType::Q8_0,
+            }
+        }
+
+        fn encode(self, values: &[f32]) -> Vec<u8> {
+            match self {
+                WeightType::F32 => f32_bytes(values),
+                WeightType::Q8_0 => quantize_q8_0(values),
+            }
+        }
+    }
+
+    /// Writes a one-layer model with the given linear weight type, returning
+    /// its path and the Hugging Face q_proj weight.
+    fn write_model(dir: &Path, weight_type: WeightType) -> (PathBuf, Vec<f32>) {
+        let ggml_type = weight_type.ggml_type();
+
+        let q_proj = pseudo_random(DIM * DIM, 1);
+        let k_proj = pseudo_random(N_KV_HEADS * HEAD_DIM * DIM, 2);
+
+        let mut builder = GgufBuilder::default();
+        builder
+            .string("general.architecture", "llama")
+            .u32("llama.embedding_length", DIM as u32)
+            .u32("llama.block_count", 1)
+            .u32("llama.attention.head_count", N_HEADS as u32)
+            .u32("llama.attention.head_count_kv", N_KV_HEADS as u32)
+            .f32("llama.attention.layer_norm_rms_epsilon", 1e-5)
+            .f32("llama.rope.freq_base", 500000.0)
+            .f32_tensor(
+                "token_embd.weight",
+                &[N_TOKENS, DIM],
+                &pseudo_random(N_TOKENS * DIM, 3),
+            )
+            .f32_tensor("output_norm.weight", &[DIM], &[1.0; DIM])
+            .f32_tensor("blk.0.attn_norm.weight", &[DIM], &[1.0; DIM])
+            .f32_tensor("blk.0.ffn_norm.weight", &[DIM], &[1.0; DIM])
+            .tensor(
+                "blk.0.attn_q.weight",
+                &[DIM, DIM],
+                ggml_type,
+                weight_type.encode(&permute_rope_rows(&q_proj, DIM, N_HEADS)),
+            )
+            .tensor(
+                "blk.0.attn_k.weight",
+                &[N_KV_HEADS * HEAD_DIM, DIM],
+                ggml_type,
+                weight_type.encode(&permute_rope_rows(&k_proj, HEAD_DIM, N_KV_HEADS)),
+            );
+
+        for (seed, (name, shape)) in [
+            ("blk.0.attn_v.weight", [N_KV_HEADS * HEAD_DIM, DIM]),
+            ("blk.0.attn_output.weight", [DIM, DIM]),
+            ("blk.0.ffn_gate.weight", [FFN_DIM, DIM]),
+            ("blk.0.ffn_up.weight", [FFN_DIM, DIM]),
+            ("blk.0.ffn_down.weight", [DIM, FFN_DIM]),
+            ("output.weight", [N_TOKENS, DIM]),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let values = pseudo_random(shape[0] * shape[1], seed + 4);
+            builder.tensor(name, &shape, ggml_type, weight_type.encode(&values));
+        }
+
+        let path = dir.join(format!("model-{weight_type:?}.gguf"));
+        std::fs::write(&path, builder.build()).unwrap();
+        (path, q_proj)
+    }
+
+    #[test]
+    fn test_unpermute_rope_rows() {
+        let weight: Vec<f32> = (0..8 * 3).map(|v| v as f32).collect();
+        let permuted = permute_rope_rows(&weight, 8, 2);
+        assert_ne!(permuted, weight);
+
+        let unpermuted = unpermute_rope_rows(&f32_bytes(&permuted), 8, 2);
+        assert_eq!(unpermuted, f32_bytes(&weight));
+    }
+
+    #[tokio::test]
+    async fn test_load_llama() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let (path, q_proj) = write_model(dir.path(), WeightType::F32);
+        let gguf = &MmapGguf::open(path).unwrap();
+        let config = gguf.llama_config().unwrap();
+        assert_eq!((config.dim, config.n_layers), (DIM, 1));
+
+        let loaded_q_proj = LinearGGUF::from_state_dict(&gguf, "model.layers.0.self_attn.q_proj.")
+            .await
+            .unwrap();
+        assert_eq!(
+            loaded_q_proj.dequantize().data().as_ref(),
+            q_proj.as_slice()
+        );
+
+        let mut dense: DefaultLlama<LinearGGUF, LinearGGUF> =
+            Llama::from_state_dict(&gguf, "", config.clone())
+                .await
+                .unwrap();
+
+        let (path, _) = write_model(dir.path(), WeightType::Q8_0);
+        let gguf = &MmapGguf::open(path).unwrap();
+        let mut quantized: DefaultLlama<LinearGGUF, LinearGGUF> =
+            Llama::from_state_dict(&gguf, "", config).await.unwrap();
+
+        for token in [1, 3, 0] {
+            let expected = dense.forward(token).await.unwrap();
+            let actual = quantized.forward(token).await.unwrap();
+            assert_eq!(expected.len(), N_TOKENS);
+            for (a, e) in actual.iter().zip(&expected) {
+                assert!((a - e).abs() < 0.05, "{actual:?} != {expected:?}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_embeddings_are_requantized() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, _) = write_model(dir.path(), WeightType::F32);
+        let gguf = &MmapGguf::open(path).unwrap();
+
+        let (embeddings, _) = gguf.get_f32("token_embd.weight").unwrap();
+        let mut embedding = EmbeddingINT8::from_state_dict(&gguf, "model.embed_tokens.")
+            .await
+            .unwrap();
+        for (token, expected) in embeddings.chunks_exact(DIM).enumerate() {
+            let max_value = expected.iter().fold(0f32, |max, v| max.max(v.abs()));
+            for (a, e) in embedding.forward(token).iter().zip(expected) {
+                assert!((a - e).abs() <= max_value / 254.0 + 1e-6);
+            }
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use crate::error::LoadError;
+use crate::from_state_dict::{convert_dtype, TensorProvider};
+use crate::owned_tensor::{get_f32_data, get_i8_data, get_u8_data, Dtype, Tensor};
+use async_trait::async_trait;
+use memmap2::Mmap;
+use safetensors::tensor::{Metadata, TensorInfo};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+
+struct MmapFile {
+    mmap: Mmap,
+    data_offset: usize,
+    metadata: Metadata,
+}
+
+/// Memory-mapped safetensors weights. Modules loaded from `&MmapStateDict`
+/// borrow their tensors straight from the mapped files instead of copying them.
+pub struct MmapStateDict {
+    files: Vec<MmapFile>,
+    tensor_files: HashMap<String, usize>,
+}
+
+impl MmapStateDict {
+    /// Opens a single `.safetensors` file, a `*.safetensors.index.json` shard
+    /// index or a directory of per-tensor `.safetensors` files.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, LoadError> {
+        let path = path.as_ref();
+        let path_name = path.display().to_string();
+        let io_error = |err: std::io::Error| LoadError::io(&path_name, err);
+
+        let file_paths = if path.is_dir() {
+            let mut file_paths = Vec::new();
+            for entry in path.read_dir().map_err(io_error)? {
+                let entry_path = entry.map_err(io_error)?.path();
+                if entry_path
+                    .extension()
+                    .is_some_and(|ext| ext == "safetensors")
+                {
+                    file_paths.push(entry_path);
+                }
+            }
+            file_paths.sort();
+            file_paths
+        } else if path.to_string_lossy().ends_with(".json") {
+            let index: serde_json::Value =
+                serde_json::from_slice(&std::fs::read(path).map_err(io_error)?)
+                    .map_err(|err| LoadError::corrupted(&path_name, err))?;
+            let weight_map = index["weight_map"]
+                .as_object()
+                .ok_or_else(|| LoadError::corrupted(&path_name, "no weight_map"))?;
+
+            let mut filenames: Vec<&str> = weight_map.values().filter_map(|v| v.as_str()).collect();
+            filenames.sort();
+            filenames.dedup();
+
+            let parent = path.parent().unwrap_or(Path::new("."));
+            filenames.iter().map(|name| parent.join(name)).collect()
+        } else {
+            vec![path.to_path_buf()]
+        };
+
+        let mut state_dict = MmapStateDict {
+            files: Vec::new(),
+            tensor_files: HashMap::new(),
+        };
+
+        for file_path in file_paths {
+            state_dict.add_file(&file_path)?;
+        }
+
+        Ok(state_dict)
+    }
+
+    fn add_file(&mut self, path: &Path) -> Result<(), LoadError> {
+        let name = path.display().to_string();
+        let io_error = |err: std::io::Error| LoadError::io(&name, err);
+
+        let file = File::open(path).map_err(io_error)?;
+        // Safety: weight files must not be modified while they are mapped.
+        let mmap = unsafe { Mmap::map(&file).map_err(io_error)? };
+
+        let (header_size, metadata) = safetensors::SafeTensors::read_metadata(&mmap)
+            .map_err(|err| LoadError::corrupted(&name, err))?;
+
+        let file_idx = self.files.len();
+        let names: Vec<String> = metadata.tensors().into_keys().collect();
+
+        // Per-tensor files are looked up by file name, whatever the tensor inside is called.
+        if names.len() == 1 {
+            if let Some(stem) = path.file_stem() {
+                self.tensor_files
+                    .insert(stem.to_string_lossy().to_string(), file_idx);
+            }
+        }
+        for name in names {
+            self.tensor_files.insert(name, file_idx);
+        }
+
+        self.files.push(MmapFile {
+            mmap,
+            data_offset: 8 + header_size,
+            metadata,
+        });
+
+        Ok(())
+    }
+
+    /// Leaks the state dict, so that loaded modules can live for `'static`.
+    pub fn leak(self) -> &'static Self {
+        Box::leak(Box::new(self))
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.tensor_files.contains_key(name)
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.tensor_files.keys().map(|name| name.as_str()).collect()
+    }
+
+    fn tensor_info(&self, name: &str) -> Result<(&MmapFile, &TensorInfo), LoadError> {
+        let missing = || LoadError::MissingTensor {
+            name: name.to_string(),
+        };
+
+        let file = &self.files[*self.tensor_files.get(name).ok_or_else(missing)?];
+
+        let info = match file.metadata.info(name) {
+            Some(info) => info,
+            None => file
+                .metadata
+                .tensors()
+                .into_values()
+                .next()
+                .ok_or_else(missing)?,
+        };
+
+        Ok((file, info))
+    }
+
+    pub fn get_raw(&self, name: &str) -> Result<(&[u8], Vec<usize>, Dtype), LoadError> {
+        let (file, info) = self.tensor_info(name)?;
+        let (begin, end) = info.data_offsets;
+
+        let data = file
+            .mmap
+            .get(file.data_offset + begin..file.data_offset + end)
+            .ok_or_else(|| LoadError::corrupted(name, "tensor is out of file bounds"))?;
+
+        Ok((data, info.shape.clone(), convert_dtype(name, info.dtype)?))
+    }
+
+    /// Borrows the tensor's bytes from the mapped file.
+    pub fn get_tensor(&self, name: &str) -> Result<Tensor<'_>, LoadError> {
+        let (data, shape, dtype) = self.get_raw(name)?;
+        Ok(Tensor {
+            name: name.to_string(),
+            data: Cow::Borrowed(data),
+            shape,
+            dtype,
+        })
+    }
+
+    /// Borrows f32 tensors; f16 and bf16 tensors are upcast into an owned copy.
+    pub fn get_f32(&self, name: &str) -> Result<(Cow<'_, [f32]>, Vec<usize>), LoadError> {
+        get_f32_data(self.get_tensor(name)?)
+    }
+
+    pub fn get_u8(&self, name: &str) -> Result<(Cow<'_, [u8]>, Vec<usize>), LoadError> {
+        get_u8_data(self.get_tensor(name)?)
+    }
+
+    pub fn get_i8(&self, name: &str) -> Result<(Cow<'_, [i8]>, Vec<usize>), LoadError> {
+        get_i8_data(self.get_tensor(name)?)
+    }
+}
+
+/// Modules loaded through a borrowed state dict borrow their tensors from the
+/// mapped files, as long as the data is aligned, which is the case for files
+/// written by the safetensors library.
+#[async_trait(?Send)]
+impl<'a> TensorProvider<'a> for &'a MmapStateDict {
+    async fn get_tensor(&self, name: &str) -> Result<Tensor<'a>, LoadError> {
+        MmapStateDict::get_tensor(*self, name)
+    }
+}
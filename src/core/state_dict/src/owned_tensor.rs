@@ -1,50 +1,333 @@
-use bytemuck::cast_slice;
+use crate::error::LoadError;
+use bytemuck::Pod;
+use half::{bf16, f16};
+use nn::linear_gguf::{dequantize_row, GgmlType};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::mem::size_of;
 use tensorlib::matrix::OwnedMatrix;
 
-#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Serialize, Deserialize)]
 pub enum Dtype {
     F32,
+    F16,
+    BF16,
     U8,
     I8,
+    U16,
+    I16,
+    /// Blocks of one of ggml's quantization formats, as found in GGUF files.
+    Q4_0,
+    Q8_0,
+    Q4K,
+    Q6K,
 }
 
+impl Dtype {
+    /// Bytes taken by `n_values` values, `None` if they do not fill whole blocks.
+    pub fn data_size(&self, n_values: usize) -> Option<usize> {
+        match self {
+            Dtype::F32 => Some(4 * n_values),
+            Dtype::F16 | Dtype::BF16 | Dtype::U16 | Dtype::I16 => Some(2 * n_values),
+            Dtype::U8 | Dtype::I8 => Some(n_values),
+            Dtype::Q4_0 | Dtype::Q8_0 | Dtype::Q4K | Dtype::Q6K => {
+                self.ggml_type()?.row_size(n_values)
+            }
+        }
+    }
+
+    /// The matching ggml type, for the types `LinearGGUF` can run.
+    pub fn ggml_type(&self) -> Option<GgmlType> {
+        match self {
+            Dtype::F32 => Some(GgmlType::F32),
+            Dtype::F16 => Some(GgmlType::F16),
+            Dtype::Q4_0 => Some(GgmlType::Q4_0),
+            Dtype::Q8_0 => Some(GgmlType::Q8_0),
+            Dtype::Q4K => Some(GgmlType::Q4K),
+            Dtype::Q6K => Some(GgmlType::Q6K),
+            Dtype::BF16 | Dtype::U8 | Dtype::I8 | Dtype::U16 | Dtype::I16 => None,
+        }
+    }
+
+    pub fn from_ggml_type(ggml_type: GgmlType) -> Self {
+        match ggml_type {
+            GgmlType::F32 => Dtype::F32,
+            GgmlType::F16 => Dtype::F16,
+            GgmlType::Q4_0 => Dtype::Q4_0,
+            GgmlType::Q8_0 => Dtype::Q8_0,
+            GgmlType::Q4K => Dtype::Q4K,
+            GgmlType::Q6K => Dtype::Q6K,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct OwnedTensor {
+    pub name: String,
     pub data: Vec<u8>,
     pub shape: Vec<usize>,
     pub dtype: Dtype,
 }
 
-pub fn get_f32_data(tensor: OwnedTensor) -> (Vec<f32>, Vec<usize>) {
-    assert_eq!(tensor.dtype, Dtype::F32);
-    // Suboptimal reallocation.
-    // Can be fixed with unsafe, but I don't want to
-    let raw_slice: &[f32] = cast_slice(&tensor.data);
-    (raw_slice.to_vec(), tensor.shape)
+/// A tensor as handed out by a `TensorProvider`: its data is either owned or
+/// borrowed for `'a` from the provider, e.g. from a memory-mapped file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tensor<'a> {
+    pub name: String,
+    pub data: Cow<'a, [u8]>,
+    pub shape: Vec<usize>,
+    pub dtype: Dtype,
+}
+
+impl From<OwnedTensor> for Tensor<'_> {
+    fn from(tensor: OwnedTensor) -> Self {
+        Self {
+            name: tensor.name,
+            data: Cow::Owned(tensor.data),
+            shape: tensor.shape,
+            dtype: tensor.dtype,
+        }
+    }
 }
 
-pub fn get_u8_data(tensor: OwnedTensor) -> (Vec<u8>, Vec<usize>) {
-    assert_eq!(tensor.dtype, Dtype::U8);
-    // Suboptimal reallocation.
-    // Can be fixed with unsafe, but I don't want to
-    let raw_slice: &[u8] = cast_slice(&tensor.data);
-    (raw_slice.to_vec(), tensor.shape)
+impl Tensor<'_> {
+    pub fn into_owned(self) -> OwnedTensor {
+        OwnedTensor {
+            name: self.name,
+            data: self.data.into_owned(),
+            shape: self.shape,
+            dtype: self.dtype,
+        }
+    }
+
+    fn check_dtype(&self, expected: Dtype) -> Result<(), LoadError> {
+        if self.dtype != expected {
+            return Err(LoadError::DtypeMismatch {
+                name: self.name.clone(),
+                expected,
+                actual: self.dtype,
+            });
+        }
+        self.check_size()
+    }
+
+    pub fn check_size(&self) -> Result<(), LoadError> {
+        let n_values = self.shape.iter().product::<usize>();
+        let expected_size = self.dtype.data_size(n_values);
+        if expected_size != Some(self.data.len()) {
+            return Err(LoadError::corrupted(
+                &self.name,
+                format!(
+                    "expected {n_values} {:?} values, got {} bytes",
+                    self.dtype,
+                    self.data.len()
+                ),
+            ));
+        }
+        Ok(())
+    }
 }
 
-pub fn get_i8_data(tensor: OwnedTensor) -> (Vec<i8>, Vec<usize>) {
-    assert_eq!(tensor.dtype, Dtype::I8);
-    // Suboptimal reallocation.
-    // Can be fixed with unsafe, but I don't want to
-    let raw_slice: &[i8] = cast_slice(&tensor.data);
-    (raw_slice.to_vec(), tensor.shape)
+/// Reinterprets little-endian bytes as `T`, borrowing when they are aligned.
+fn cast_data<T: Pod>(data: Cow<'_, [u8]>) -> Cow<'_, [T]> {
+    if let Cow::Borrowed(data) = data {
+        if let Ok(data) = bytemuck::try_cast_slice(data) {
+            return Cow::Borrowed(data);
+        }
+    }
+    Cow::Owned(
+        data.chunks_exact(size_of::<T>())
+            .map(bytemuck::pod_read_unaligned)
+            .collect(),
+    )
 }
 
-pub fn get_matrix(tensor: OwnedTensor) -> OwnedMatrix {
-    assert_eq!(tensor.shape.len(), 2);
+/// Reads f32 data, borrowing it when possible. Half precision and ggml
+/// quantized tensors are converted into an owned copy.
+pub fn get_f32_data(tensor: Tensor<'_>) -> Result<(Cow<'_, [f32]>, Vec<usize>), LoadError> {
+    tensor.check_size()?;
+    let data = match tensor.dtype {
+        Dtype::F32 => cast_data(tensor.data),
+        dtype => Cow::Owned(
+            bytes_to_f32(&tensor.data, dtype).ok_or(LoadError::DtypeMismatch {
+                name: tensor.name,
+                expected: Dtype::F32,
+                actual: dtype,
+            })?,
+        ),
+    };
+    Ok((data, tensor.shape))
+}
+
+/// Converts the data of a tensor with `data_size` already checked.
+pub fn bytes_to_f32(data: &[u8], dtype: Dtype) -> Option<Vec<f32>> {
+    match dtype {
+        Dtype::F32 => Some(
+            data.chunks_exact(4)
+                .map(|v| f32::from_le_bytes([v[0], v[1], v[2], v[3]]))
+                .collect(),
+        ),
+        Dtype::F16 => Some(
+            data.chunks_exact(2)
+                .map(|v| f16::from_le_bytes([v[0], v[1]]).to_f32())
+                .collect(),
+        ),
+        Dtype::BF16 => Some(
+            data.chunks_exact(2)
+                .map(|v| bf16::from_le_bytes([v[0], v[1]]).to_f32())
+                .collect(),
+        ),
+        Dtype::Q4_0 | Dtype::Q8_0 | Dtype::Q4K | Dtype::Q6K => {
+            let ggml_type = dtype.ggml_type()?;
+            let n_values = data.len() / ggml_type.type_size() * ggml_type.block_size();
+            let mut output = vec![0f32; n_values];
+            dequantize_row(ggml_type, data, &mut output);
+            Some(output)
+        }
+        Dtype::U8 | Dtype::I8 | Dtype::U16 | Dtype::I16 => None,
+    }
+}
+
+pub fn get_u8_data(tensor: Tensor<'_>) -> Result<(Cow<'_, [u8]>, Vec<usize>), LoadError> {
+    tensor.check_dtype(Dtype::U8)?;
+    Ok((tensor.data, tensor.shape))
+}
+
+pub fn get_i8_data(tensor: Tensor<'_>) -> Result<(Cow<'_, [i8]>, Vec<usize>), LoadError> {
+    tensor.check_dtype(Dtype::I8)?;
+    Ok((cast_data(tensor.data), tensor.shape))
+}
+
+pub fn get_u16_data(tensor: Tensor<'_>) -> Result<(Cow<'_, [u16]>, Vec<usize>), LoadError> {
+    tensor.check_dtype(Dtype::U16)?;
+    Ok((cast_data(tensor.data), tensor.shape))
+}
+
+pub fn get_i16_data(tensor: Tensor<'_>) -> Result<(Cow<'_, [i16]>, Vec<usize>), LoadError> {
+    tensor.check_dtype(Dtype::I16)?;
+    Ok((cast_data(tensor.data), tensor.shape))
+}
+
+pub fn get_matrix(tensor: Tensor<'_>) -> Result<OwnedMatrix, LoadError> {
+    if tensor.shape.len() != 2 {
+        return Err(LoadError::corrupted(
+            &tensor.name,
+            format!("expected a matrix, got shape {:?}", tensor.shape),
+        ));
+    }
     let shape = (tensor.shape[0], tensor.shape[1]);
 
-    let data = get_f32_data(tensor).0;
+    let data = get_f32_data(tensor)?.0.into_owned();
+
+    Ok(OwnedMatrix::from_vec(shape, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tensor(data: Vec<u8>, dtype: Dtype) -> Tensor<'static> {
+        let shape = vec![data.len() / dtype.data_size(1).unwrap()];
+        Tensor {
+            name: "tensor".to_string(),
+            data: Cow::Owned(data),
+            shape,
+            dtype,
+        }
+    }
+
+    #[test]
+    fn test_borrows_aligned_data() {
+        let values = [1.0f32, -2.5, 0.125];
+        let tensor = Tensor {
+            name: "tensor".to_string(),
+            data: Cow::Borrowed(bytemuck::cast_slice(&values)),
+            shape: vec![3],
+            dtype: Dtype::F32,
+        };
+
+        let (output, _) = get_f32_data(tensor.clone()).unwrap();
+        assert!(matches!(output, Cow::Borrowed(_)));
+        assert_eq!(output.as_ref(), values);
+
+        let (output, _) = get_f32_data(tensor.into_owned().into()).unwrap();
+        assert!(matches!(output, Cow::Owned(_)));
+        assert_eq!(output.as_ref(), values);
+    }
+
+    #[test]
+    fn test_q8_0_to_f32() {
+        // One block: an f16 scale of 0.5, then 32 int8 values.
+        let mut data = f16::from_f32(0.5).to_le_bytes().to_vec();
+        data.extend((0..32).map(|v| (v as i8 - 16) as u8));
+        let tensor = Tensor {
+            name: "tensor".to_string(),
+            data: Cow::Owned(data),
+            shape: vec![32],
+            dtype: Dtype::Q8_0,
+        };
+
+        let expected: Vec<f32> = (0..32).map(|v| (v - 16) as f32 * 0.5).collect();
+        assert_eq!(get_f32_data(tensor).unwrap().0, expected);
+    }
+
+    #[test]
+    fn test_f16_to_f32() {
+        let values = [1.0f32, -2.5, 0.125];
+        let data = values
+            .iter()
+            .flat_map(|v| f16::from_f32(*v).to_le_bytes())
+            .collect();
+
+        let (output, shape) = get_f32_data(tensor(data, Dtype::F16)).unwrap();
+        assert_eq!(output.as_ref(), values);
+        assert_eq!(shape, vec![3]);
+    }
+
+    #[test]
+    fn test_bf16_to_f32() {
+        let values = [1.0f32, -2.5, 256.0];
+        let data = values
+            .iter()
+            .flat_map(|v| bf16::from_f32(*v).to_le_bytes())
+            .collect();
+
+        assert_eq!(
+            get_f32_data(tensor(data, Dtype::BF16)).unwrap().0.as_ref(),
+            values
+        );
+    }
+
+    #[test]
+    fn test_i16() {
+        let values = [1i16, -300, i16::MAX];
+        let data = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+
+        assert_eq!(
+            get_i16_data(tensor(data, Dtype::I16)).unwrap().0.as_ref(),
+            values
+        );
+    }
 
-    assert_eq!(data.len(), shape.0 * shape.1);
+    #[test]
+    fn test_dtype_mismatch() {
+        assert_eq!(
+            get_f32_data(tensor(vec![1, 2, 3, 4], Dtype::U8)).err(),
+            Some(LoadError::DtypeMismatch {
+                name: "tensor".to_string(),
+                expected: Dtype::F32,
+                actual: Dtype::U8,
+            })
+        );
+    }
 
-    OwnedMatrix::from_vec(shape, data)
+    #[test]
+    fn test_truncated() {
+        let mut tensor = tensor(vec![0; 8], Dtype::I16);
+        tensor.data.to_mut().pop();
+        assert!(matches!(
+            get_i16_data(tensor),
+            Err(LoadError::Corrupted { .. })
+        ));
+    }
 }
@@ -0,0 +1,284 @@
+use crate::error::LoadError;
+use crate::from_state_dict::{
+    convert_dtype, get_file_by_name, get_file_range_by_name, get_verified_file_range,
+};
+use crate::manifest::get_manifest;
+use crate::owned_tensor::OwnedTensor;
+use safetensors::tensor::Metadata;
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Parsed header of a remote safetensors file.
+pub struct SafetensorsHeader {
+    /// Absolute offset of the first data byte in the file.
+    data_offset: usize,
+    metadata: Metadata,
+}
+
+#[derive(Deserialize)]
+struct ShardIndex {
+    weight_map: HashMap<String, String>,
+}
+
+/// Largest header accepted, the same limit the safetensors library uses.
+const MAX_HEADER_SIZE: usize = 100_000_000;
+
+thread_local! {
+    static HEADERS_CACHE: RefCell<HashMap<String, Rc<SafetensorsHeader>>> =
+        RefCell::new(HashMap::new());
+    static INDEX_CACHE: RefCell<HashMap<String, Rc<HashMap<String, String>>>> =
+        RefCell::new(HashMap::new());
+}
+
+pub(crate) fn clear_caches() {
+    HEADERS_CACHE.with_borrow_mut(|cache| cache.clear());
+    INDEX_CACHE.with_borrow_mut(|cache| cache.clear());
+}
+
+fn parse_metadata(filename: &str, header: &[u8]) -> Result<Metadata, LoadError> {
+    serde_json::from_slice(header).map_err(|err| {
+        LoadError::corrupted(
+            filename,
+            format!("failed to parse safetensors header: {err}"),
+        )
+    })
+}
+
+fn read_header_size(filename: &str, data: &[u8]) -> Result<usize, LoadError> {
+    let size_bytes: [u8; 8] = data
+        .get(0..8)
+        .and_then(|data| data.try_into().ok())
+        .ok_or_else(|| LoadError::corrupted(filename, "safetensors file is too short"))?;
+
+    usize::try_from(u64::from_le_bytes(size_bytes))
+        .ok()
+        .filter(|header_size| *header_size <= MAX_HEADER_SIZE)
+        .ok_or_else(|| LoadError::corrupted(filename, "safetensors header is too large"))
+}
+
+async fn get_header(filename: &str) -> Result<Rc<SafetensorsHeader>, LoadError> {
+    if let Some(header) = HEADERS_CACHE.with_borrow(|cache| cache.get(filename).cloned()) {
+        return Ok(header);
+    }
+
+    let header_size = read_header_size(filename, &get_file_range_by_name(filename, 0..8).await?)?;
+    let data_offset = header_size
+        .checked_add(8)
+        .ok_or_else(|| LoadError::corrupted(filename, "safetensors header is too large"))?;
+    let metadata = parse_metadata(
+        filename,
+        &get_file_range_by_name(filename, 8..data_offset).await?,
+    )?;
+
+    let header = Rc::new(SafetensorsHeader {
+        data_offset,
+        metadata,
+    });
+
+    HEADERS_CACHE.with_borrow_mut(|cache| cache.insert(filename.to_string(), header.clone()));
+
+    Ok(header)
+}
+
+async fn get_shard_index(index_filename: &str) -> Result<Rc<HashMap<String, String>>, LoadError> {
+    if let Some(index) = INDEX_CACHE.with_borrow(|cache| cache.get(index_filename).cloned()) {
+        return Ok(index);
+    }
+
+    let data = get_file_by_name(index_filename).await?;
+    let index: ShardIndex =
+        serde_json::from_slice(&data).map_err(|err| LoadError::corrupted(index_filename, err))?;
+    let index = Rc::new(index.weight_map);
+
+    INDEX_CACHE.with_borrow_mut(|cache| cache.insert(index_filename.to_string(), index.clone()));
+
+    Ok(index)
+}
+
+/// Fetches a single tensor out of a (possibly multi-tensor) safetensors file,
+/// downloading only the header and the tensor's own byte range.
+pub async fn get_tensor_from_file(filename: &str, name: &str) -> Result<OwnedTensor, LoadError> {
+    let header = get_header(filename).await?;
+
+    let info = header
+        .metadata
+        .info(name)
+        .ok_or_else(|| LoadError::MissingTensor {
+            name: name.to_string(),
+        })?;
+
+    let (begin, end) = info.data_offsets;
+    let range = header.data_offset + begin..header.data_offset + end;
+
+    let manifest = get_manifest().await?;
+
+    let data = get_verified_file_range(filename, range, |data| match &manifest {
+        Some(manifest) => manifest.verify_tensor_data(filename, name, data),
+        None => Ok(()),
+    })
+    .await?;
+
+    let tensor = OwnedTensor {
+        name: name.to_string(),
+        data,
+        shape: info.shape.clone(),
+        dtype: convert_dtype(name, info.dtype)?,
+    };
+
+    if let Some(manifest) = &manifest {
+        manifest.verify_tensor_info(filename, &tensor)?;
+    }
+
+    Ok(tensor)
+}
+
+pub async fn get_tensor_from_shards(
+    index_filename: &str,
+    name: &str,
+) -> Result<OwnedTensor, LoadError> {
+    let index = get_shard_index(index_filename).await?;
+
+    let filename = index.get(name).ok_or_else(|| LoadError::MissingTensor {
+        name: name.to_string(),
+    })?;
+
+    get_tensor_from_file(filename, name).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::from_state_dict::tests::{block_state_dict, insert_aqlm, pseudo_random, CONFIG};
+    use crate::from_state_dict::{get_tensor, FromStateDictConf, RemoteWeights};
+    use crate::state_dict::StateDict;
+    use crate::test_server::TestServer;
+    use crate::weights_source::{set_weights_source, WeightsLayout, WeightsSource};
+    use nn::linear_aqlm::LinearAQLM;
+    use nn::llama_block::{DefaultLlamaBlock, LlamaBlock};
+
+    const SINGLE_FILE: &str = "model.safetensors";
+    const INDEX_FILE: &str = "model.safetensors.index.json";
+
+    fn subset(state_dict: &StateDict, names: &[&str]) -> StateDict {
+        names
+            .iter()
+            .map(|name| state_dict.get(name).unwrap().clone())
+            .collect()
+    }
+
+    /// Serves `state_dict` in `layout` and makes it the weights source.
+    fn serve(state_dict: &StateDict, layout: WeightsLayout) -> TestServer {
+        let server = TestServer::start();
+        let mut names = state_dict.names();
+        names.sort();
+
+        match &layout {
+            WeightsLayout::PerTensorFiles => {
+                for name in names {
+                    let data = subset(state_dict, &[name]).to_safetensors().unwrap();
+                    server.insert(&format!("{name}.safetensors"), data);
+                }
+            }
+            WeightsLayout::SingleFile(filename) => {
+                server.insert(filename, state_dict.to_safetensors().unwrap());
+            }
+            WeightsLayout::Sharded(index_filename) => {
+                let mut weight_map = HashMap::new();
+                for (shard_idx, shard_names) in names.chunks(names.len().div_ceil(2)).enumerate() {
+                    let filename = format!("model-{:05}-of-00002.safetensors", shard_idx + 1);
+                    let data = subset(state_dict, shard_names).to_safetensors().unwrap();
+                    server.insert(&filename, data);
+                    for name in shard_names {
+                        weight_map.insert(name.to_string(), filename.clone());
+                    }
+                }
+                let index = serde_json::json!({ "weight_map": weight_map });
+                server.insert(index_filename, index.to_string().into_bytes());
+            }
+        }
+
+        set_weights_source(WeightsSource::new(server.url(), "", layout));
+        server
+    }
+
+    #[tokio::test]
+    async fn test_load_block_from_each_layout() {
+        let state_dict = block_state_dict(insert_aqlm);
+        let mut expected: DefaultLlamaBlock<LinearAQLM> =
+            LlamaBlock::from_state_dict(&state_dict, "block.", CONFIG)
+                .await
+                .unwrap();
+        let expected = expected
+            .forward(pseudo_random(CONFIG.dim, 1))
+            .await
+            .unwrap();
+
+        for layout in [
+            WeightsLayout::PerTensorFiles,
+            WeightsLayout::SingleFile(SINGLE_FILE.to_string()),
+            WeightsLayout::Sharded(INDEX_FILE.to_string()),
+        ] {
+            let _server = serve(&state_dict, layout.clone());
+
+            let mut block: DefaultLlamaBlock<LinearAQLM> =
+                LlamaBlock::from_state_dict(&RemoteWeights, "block.", CONFIG)
+                    .await
+                    .unwrap();
+            let output = block.forward(pseudo_random(CONFIG.dim, 1)).await.unwrap();
+            assert_eq!(output, expected, "{layout:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetches_only_the_tensor_range() {
+        let state_dict = block_state_dict(insert_aqlm);
+        let server = serve(
+            &state_dict,
+            WeightsLayout::SingleFile(SINGLE_FILE.to_string()),
+        );
+
+        let name = "block.mlp.down_proj.scales";
+        let tensor = get_tensor(name).await.unwrap();
+        assert_eq!(Some(&tensor), state_dict.get(name));
+
+        let file = state_dict.to_safetensors().unwrap();
+        let header_size = read_header_size(SINGLE_FILE, &file).unwrap();
+        let requests = server.requests();
+        assert!(requests.iter().all(|request| request.range.is_some()));
+        assert_eq!(
+            server.served_bytes(),
+            8 + header_size + tensor.data.len(),
+            "{requests:?}"
+        );
+
+        // The header is fetched once per file.
+        server.clear_requests();
+        get_tensor("block.mlp.up_proj.scales").await.unwrap();
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_rejects_oversized_header() {
+        let header_size = (MAX_HEADER_SIZE as u64 + 1).to_le_bytes();
+        assert!(matches!(
+            read_header_size(SINGLE_FILE, &header_size),
+            Err(LoadError::Corrupted { .. })
+        ));
+        assert!(matches!(
+            read_header_size(SINGLE_FILE, &u64::MAX.to_le_bytes()),
+            Err(LoadError::Corrupted { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_server_without_range_support() {
+        let state_dict = block_state_dict(insert_aqlm);
+        let server = serve(&state_dict, WeightsLayout::Sharded(INDEX_FILE.to_string()));
+        server.set_ignore_ranges(true);
+
+        let name = "block.self_attn.o_proj.codes_120";
+        assert_eq!(get_tensor(name).await.as_ref().ok(), state_dict.get(name));
+    }
+}
@@ -1,4 +1,247 @@
-use crate::owned_tensor::OwnedTensor;
+use crate::error::LoadError;
+use crate::from_state_dict::{tensor_view_to_owned_tensor, to_safetensors_dtype, TensorProvider};
+use crate::owned_tensor::{Dtype, OwnedTensor, Tensor};
+use async_trait::async_trait;
+use safetensors::tensor::TensorView;
+use safetensors::SafeTensors;
 use std::collections::HashMap;
 
-pub type StateDict = HashMap<String, OwnedTensor>;
+/// Tensors held in memory, keyed by name.
+#[derive(Default)]
+pub struct StateDict {
+    tensors: HashMap<String, OwnedTensor>,
+}
+
+impl StateDict {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads every tensor of a safetensors file.
+    pub fn from_safetensors(data: &[u8]) -> Result<Self, LoadError> {
+        let tensors = SafeTensors::deserialize(data)
+            .map_err(|err| LoadError::corrupted("safetensors", err))?;
+
+        tensors
+            .tensors()
+            .into_iter()
+            .map(|(name, view)| tensor_view_to_owned_tensor(&name, view))
+            .collect()
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn from_file(path: impl AsRef<std::path::Path>) -> Result<Self, LoadError> {
+        let path = path.as_ref();
+        let data =
+            std::fs::read(path).map_err(|err| LoadError::io(&path.display().to_string(), err))?;
+        Self::from_safetensors(&data)
+    }
+
+    /// Serializes every tensor into a single safetensors file.
+    pub fn to_safetensors(&self) -> Result<Vec<u8>, LoadError> {
+        safetensors::serialize(self.views()?, &None)
+            .map_err(|err| LoadError::serialization("safetensors", err))
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn save(&self, path: impl AsRef<std::path::Path>) -> Result<(), LoadError> {
+        let path = path.as_ref();
+        safetensors::serialize_to_file(self.views()?, &None, path)
+            .map_err(|err| save_error(&path.display().to_string(), err))
+    }
+
+    /// Writes every tensor to `{dir}/{name}.safetensors`, the per-tensor layout.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn save_per_tensor(&self, dir: impl AsRef<std::path::Path>) -> Result<(), LoadError> {
+        for (name, tensor) in &self.tensors {
+            let path = dir.as_ref().join(format!("{name}.safetensors"));
+            safetensors::serialize_to_file([(name, tensor_view(tensor)?)], &None, &path)
+                .map_err(|err| save_error(name, err))?;
+        }
+        Ok(())
+    }
+
+    fn views(&self) -> Result<Vec<(&String, TensorView<'_>)>, LoadError> {
+        self.tensors
+            .iter()
+            .map(|(name, tensor)| Ok((name, tensor_view(tensor)?)))
+            .collect()
+    }
+
+    pub fn insert(&mut self, tensor: OwnedTensor) {
+        self.tensors.insert(tensor.name.clone(), tensor);
+    }
+
+    pub fn insert_f32(&mut self, name: &str, shape: &[usize], data: &[f32]) {
+        self.insert_bytes(name, shape, Dtype::F32, bytemuck::cast_slice(data));
+    }
+
+    pub fn insert_i8(&mut self, name: &str, shape: &[usize], data: &[i8]) {
+        self.insert_bytes(name, shape, Dtype::I8, bytemuck::cast_slice(data));
+    }
+
+    pub fn insert_u8(&mut self, name: &str, shape: &[usize], data: &[u8]) {
+        self.insert_bytes(name, shape, Dtype::U8, data);
+    }
+
+    pub fn insert_u16(&mut self, name: &str, shape: &[usize], data: &[u16]) {
+        self.insert_bytes(name, shape, Dtype::U16, bytemuck::cast_slice(data));
+    }
+
+    fn insert_bytes(&mut self, name: &str, shape: &[usize], dtype: Dtype, data: &[u8]) {
+        assert_eq!(dtype.data_size(shape.iter().product()), Some(data.len()));
+        self.insert(OwnedTensor {
+            name: name.to_string(),
+            data: data.to_vec(),
+            shape: shape.to_vec(),
+            dtype,
+        });
+    }
+
+    pub fn get(&self, name: &str) -> Option<&OwnedTensor> {
+        self.tensors.get(name)
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<OwnedTensor> {
+        self.tensors.remove(name)
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.tensors.keys().map(|name| name.as_str()).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tensors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tensors.is_empty()
+    }
+}
+
+impl FromIterator<OwnedTensor> for StateDict {
+    fn from_iter<I: IntoIterator<Item = OwnedTensor>>(iter: I) -> Self {
+        let mut state_dict = StateDict::new();
+        for tensor in iter {
+            state_dict.insert(tensor);
+        }
+        state_dict
+    }
+}
+
+fn tensor_view(tensor: &OwnedTensor) -> Result<TensorView<'_>, LoadError> {
+    TensorView::new(
+        to_safetensors_dtype(&tensor.name, tensor.dtype)?,
+        tensor.shape.clone(),
+        &tensor.data,
+    )
+    .map_err(|err| LoadError::serialization(&tensor.name, err))
+}
+
+/// Failing to write is an io error; anything else safetensors rejects is a
+/// problem with the tensors themselves.
+#[cfg(not(target_arch = "wasm32"))]
+fn save_error(name: &str, err: safetensors::SafeTensorError) -> LoadError {
+    match err {
+        safetensors::SafeTensorError::IoError(err) => LoadError::io(name, err),
+        err => LoadError::serialization(name, err),
+    }
+}
+
+/// Hands out copies, so that the modules loaded from a state dict own their
+/// weights and outlive it.
+#[async_trait(?Send)]
+impl<'a> TensorProvider<'a> for StateDict {
+    async fn get_tensor(&self, name: &str) -> Result<Tensor<'a>, LoadError> {
+        self.get(name)
+            .cloned()
+            .map(Tensor::from)
+            .ok_or_else(|| LoadError::MissingTensor {
+                name: name.to_string(),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_safetensors() {
+        let data: Vec<u8> = [1.0f32, 2.0, 3.0, 4.0]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        let codes = [7u8, 8, 9];
+        let file = safetensors::serialize(
+            [
+                (
+                    "weight",
+                    TensorView::new(safetensors::Dtype::F32, vec![2, 2], &data).unwrap(),
+                ),
+                (
+                    "codes",
+                    TensorView::new(safetensors::Dtype::U8, vec![3], &codes).unwrap(),
+                ),
+            ],
+            &None,
+        )
+        .unwrap();
+
+        let state_dict = StateDict::from_safetensors(&file).unwrap();
+        assert_eq!(state_dict.len(), 2);
+
+        let weight = state_dict.get("weight").unwrap();
+        assert_eq!((weight.dtype, &weight.shape), (Dtype::F32, &vec![2, 2]));
+        assert_eq!(weight.data, data);
+        assert_eq!(state_dict.get("codes").unwrap().data, codes);
+
+        let reread = StateDict::from_safetensors(&state_dict.to_safetensors().unwrap()).unwrap();
+        assert_eq!(reread.get("weight"), state_dict.get("weight"));
+        assert_eq!(reread.get("codes"), state_dict.get("codes"));
+    }
+
+    #[test]
+    fn test_save_per_tensor() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut state_dict = StateDict::new();
+        state_dict.insert_f32("model.norm.weight", &[2], &[1.0, 2.0]);
+        state_dict.insert_i8("lm_head.weight_int8", &[1, 2], &[-3, 4]);
+        state_dict.save_per_tensor(dir.path()).unwrap();
+
+        let mut invalid = StateDict::new();
+        invalid.insert(OwnedTensor {
+            name: "invalid".to_string(),
+            data: vec![0; 3],
+            shape: vec![2],
+            dtype: Dtype::F32,
+        });
+        assert!(matches!(
+            invalid.save_per_tensor(dir.path()),
+            Err(LoadError::Serialization { .. })
+        ));
+
+        let path = dir.path().join("lm_head.weight_int8.safetensors");
+        let reread = StateDict::from_file(path).unwrap();
+        assert_eq!(reread.names(), vec!["lm_head.weight_int8"]);
+        assert_eq!(
+            reread.get("lm_head.weight_int8"),
+            state_dict.get("lm_head.weight_int8")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_missing_tensor() {
+        let mut state_dict = StateDict::new();
+        state_dict.insert_f32("weight", &[2], &[1.0, 2.0]);
+
+        assert!(state_dict.get_tensor("weight").await.is_ok());
+        assert_eq!(
+            state_dict.get_tensor("bias").await.err(),
+            Some(LoadError::MissingTensor {
+                name: "bias".to_string()
+            })
+        );
+    }
+}
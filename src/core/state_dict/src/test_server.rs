@@ -0,0 +1,143 @@
+//! A local HTTP server for the loader tests, serving files from memory and
+//! answering Range requests the way Hugging Face does.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ServedRequest {
+    pub path: String,
+    pub range: Option<Range<usize>>,
+    pub n_bytes: usize,
+}
+
+#[derive(Default)]
+struct State {
+    files: HashMap<String, Vec<u8>>,
+    requests: Vec<ServedRequest>,
+    ignore_ranges: bool,
+}
+
+pub(crate) struct TestServer {
+    url: String,
+    state: Arc<Mutex<State>>,
+}
+
+impl TestServer {
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let server_state = state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                handle(stream, &server_state);
+            }
+        });
+
+        Self { url, state }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn insert(&self, filename: &str, data: Vec<u8>) {
+        let mut state = self.state.lock().unwrap();
+        state.files.insert(filename.to_string(), data);
+    }
+
+    /// Answers every request with the whole file, like servers without Range support.
+    pub fn set_ignore_ranges(&self, ignore_ranges: bool) {
+        self.state.lock().unwrap().ignore_ranges = ignore_ranges;
+    }
+
+    pub fn requests(&self) -> Vec<ServedRequest> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    pub fn served_bytes(&self) -> usize {
+        self.requests().iter().map(|request| request.n_bytes).sum()
+    }
+
+    pub fn clear_requests(&self) {
+        self.state.lock().unwrap().requests.clear();
+    }
+}
+
+/// Parses `bytes=0-1023`.
+fn parse_range(header: &str) -> Option<Range<usize>> {
+    let (begin, end) = header.trim().strip_prefix("bytes=")?.split_once('-')?;
+    Some(begin.parse().ok()?..end.parse::<usize>().ok()? + 1)
+}
+
+fn handle(mut stream: TcpStream, state: &Mutex<State>) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).is_err() {
+        return;
+    }
+    let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+    let path = path.split('?').next().unwrap().trim_start_matches('/');
+
+    let mut range = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).is_err() || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("range") {
+                range = parse_range(value);
+            }
+        }
+    }
+
+    let mut state = state.lock().unwrap();
+    if state.ignore_ranges {
+        range = None;
+    }
+
+    let (status, headers, body) = match (state.files.get(path), &range) {
+        (None, _) => ("404 Not Found", String::new(), Vec::new()),
+        (Some(data), Some(range)) if range.start >= data.len() => (
+            "416 Range Not Satisfiable",
+            format!("Content-Range: bytes */{}\r\n", data.len()),
+            Vec::new(),
+        ),
+        (Some(data), Some(range)) => {
+            let end = range.end.min(data.len());
+            (
+                "206 Partial Content",
+                format!(
+                    "Content-Range: bytes {}-{}/{}\r\n",
+                    range.start,
+                    end - 1,
+                    data.len()
+                ),
+                data[range.start..end].to_vec(),
+            )
+        }
+        (Some(data), None) => ("200 OK", String::new(), data.clone()),
+    };
+
+    state.requests.push(ServedRequest {
+        path: path.to_string(),
+        range,
+        n_bytes: body.len(),
+    });
+    drop(state);
+
+    let head = format!(
+        "HTTP/1.1 {status}\r\n{headers}Content-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    let _ = stream.write_all(head.as_bytes());
+    let _ = stream.write_all(&body);
+}
@@ -0,0 +1,240 @@
+use crate::state_dict::StateDict;
+use nn::attention::Attention;
+use nn::embedding::EmbeddingINT8;
+use nn::layernorm::LayerNorm;
+use nn::linear::Module;
+use nn::linear_aqlm::LinearAQLM;
+use nn::linear_int8::LinearINT8;
+use nn::llama::Llama;
+use nn::llama_block::LlamaBlock;
+use nn::matrix_int8::{Int8Scaling, MatrixInt8};
+use nn::mlp::MLP;
+
+/// Writes a module's tensors under the names its `FromStateDict` impl reads.
+pub trait ToStateDict {
+    fn to_state_dict(&self, prefix: &str, state_dict: &mut StateDict);
+}
+
+impl ToStateDict for LayerNorm<'_> {
+    fn to_state_dict(&self, prefix: &str, state_dict: &mut StateDict) {
+        let weight = self.weight();
+        state_dict.insert_f32(&format!("{prefix}weight"), &[weight.len()], weight);
+    }
+}
+
+impl ToStateDict for MatrixInt8<'_> {
+    fn to_state_dict(&self, prefix: &str, state_dict: &mut StateDict) {
+        let (n_rows, n_cols) = self.shape();
+        let max_values_shape = match self.scaling() {
+            Int8Scaling::PerColumn => vec![n_cols],
+            Int8Scaling::PerRow => vec![n_rows, 1],
+        };
+
+        state_dict.insert_f32(
+            &format!("{prefix}weight_max_values"),
+            &max_values_shape,
+            self.max_values(),
+        );
+        state_dict.insert_i8(
+            &format!("{prefix}weight_int8"),
+            &[n_rows, n_cols],
+            self.int8_values(),
+        );
+    }
+}
+
+impl ToStateDict for EmbeddingINT8<'_> {
+    fn to_state_dict(&self, prefix: &str, state_dict: &mut StateDict) {
+        self.weight().to_state_dict(prefix, state_dict);
+    }
+}
+
+impl ToStateDict for LinearINT8<'_> {
+    fn to_state_dict(&self, prefix: &str, state_dict: &mut StateDict) {
+        self.weight().to_state_dict(prefix, state_dict);
+    }
+}
+
+impl ToStateDict for LinearAQLM<'_> {
+    fn to_state_dict(&self, prefix: &str, state_dict: &mut StateDict) {
+        let (out_dim, in_dim) = self.shape();
+
+        state_dict.insert_f32(
+            &format!("{prefix}codebooks"),
+            &[2, 256, 1, 8],
+            self.codebooks(),
+        );
+        state_dict.insert_f32(&format!("{prefix}scales"), &[out_dim], self.scales());
+        state_dict.insert_u8(
+            &format!("{prefix}codes_120"),
+            &[in_dim / 8, 2, out_dim],
+            self.codes(),
+        );
+    }
+}
+
+impl<LinearType> ToStateDict for MLP<LinearType>
+where
+    LinearType: Module + ToStateDict,
+{
+    fn to_state_dict(&self, prefix: &str, state_dict: &mut StateDict) {
+        let submodules = self.submodules();
+        submodules
+            .up_proj
+            .to_state_dict(&format!("{prefix}up_proj."), state_dict);
+        submodules
+            .gate_proj
+            .to_state_dict(&format!("{prefix}gate_proj."), state_dict);
+        submodules
+            .down_proj
+            .to_state_dict(&format!("{prefix}down_proj."), state_dict);
+    }
+}
+
+impl<LinearType> ToStateDict for Attention<LinearType>
+where
+    LinearType: Module + ToStateDict,
+{
+    fn to_state_dict(&self, prefix: &str, state_dict: &mut StateDict) {
+        let submodules = self.submodules();
+        submodules
+            .v_proj
+            .inner()
+            .to_state_dict(&format!("{prefix}v_proj."), state_dict);
+        submodules
+            .q_proj
+            .to_state_dict(&format!("{prefix}q_proj."), state_dict);
+        submodules
+            .k_proj
+            .inner()
+            .to_state_dict(&format!("{prefix}k_proj."), state_dict);
+        submodules
+            .o_proj
+            .to_state_dict(&format!("{prefix}o_proj."), state_dict);
+    }
+}
+
+impl<MLPType, AttentionType> ToStateDict for LlamaBlock<'_, MLPType, AttentionType>
+where
+    MLPType: Module + ToStateDict,
+    AttentionType: Module + ToStateDict,
+{
+    fn to_state_dict(&self, prefix: &str, state_dict: &mut StateDict) {
+        let submodules = self.submodules();
+        submodules
+            .input_layernorm
+            .to_state_dict(&format!("{prefix}input_layernorm."), state_dict);
+        submodules
+            .attention
+            .to_state_dict(&format!("{prefix}self_attn."), state_dict);
+        submodules
+            .post_attention_layernorm
+            .to_state_dict(&format!("{prefix}post_attention_layernorm."), state_dict);
+        submodules
+            .mlp
+            .to_state_dict(&format!("{prefix}mlp."), state_dict);
+    }
+}
+
+impl<HeadLinearType, MLPType, AttentionType> ToStateDict
+    for Llama<'_, HeadLinearType, MLPType, AttentionType>
+where
+    HeadLinearType: Module + ToStateDict,
+    MLPType: Module + ToStateDict,
+    AttentionType: Module + ToStateDict,
+{
+    fn to_state_dict(&self, prefix: &str, state_dict: &mut StateDict) {
+        let submodules = self.submodules();
+        for (layer_idx, block) in submodules.blocks.iter().enumerate() {
+            block.to_state_dict(&format!("{prefix}model.layers.{layer_idx}."), state_dict);
+        }
+        submodules
+            .embed_tokens
+            .to_state_dict(&format!("{prefix}model.embed_tokens."), state_dict);
+        submodules
+            .norm
+            .to_state_dict(&format!("{prefix}model.norm."), state_dict);
+        submodules
+            .lm_head
+            .to_state_dict(&format!("{prefix}lm_head."), state_dict);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::from_state_dict::tests::{
+        block_state_dict, insert_aqlm, insert_block, insert_int8, CONFIG,
+    };
+    use crate::from_state_dict::FromStateDictConf;
+    use crate::mmap_state_dict::MmapStateDict;
+    use nn::llama::DefaultLlama;
+    use nn::llama_block::DefaultLlamaBlock;
+
+    const N_TOKENS: usize = 6;
+
+    fn assert_same_tensors(actual: &StateDict, expected: &StateDict) {
+        let mut names = actual.names();
+        names.sort();
+        let mut expected_names = expected.names();
+        expected_names.sort();
+        assert_eq!(names, expected_names);
+
+        for name in names {
+            assert_eq!(actual.get(name), expected.get(name), "{name}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_block_round_trip() {
+        let expected = block_state_dict(insert_int8);
+        let block: DefaultLlamaBlock<LinearINT8> =
+            LlamaBlock::from_state_dict(&expected, "block.", CONFIG)
+                .await
+                .unwrap();
+        let mut actual = StateDict::new();
+        block.to_state_dict("block.", &mut actual);
+        assert_same_tensors(&actual, &expected);
+
+        let expected = block_state_dict(insert_aqlm);
+        let block: DefaultLlamaBlock<LinearAQLM> =
+            LlamaBlock::from_state_dict(&expected, "block.", CONFIG)
+                .await
+                .unwrap();
+        let mut actual = StateDict::new();
+        block.to_state_dict("block.", &mut actual);
+        assert_same_tensors(&actual, &expected);
+    }
+
+    #[tokio::test]
+    async fn test_llama_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut expected = StateDict::new();
+        insert_block(&mut expected, "model.layers.0.", insert_aqlm);
+        insert_int8(&mut expected, "model.embed_tokens.", N_TOKENS, CONFIG.dim);
+        expected.insert_f32("model.norm.weight", &[CONFIG.dim], &[1.0; CONFIG.dim]);
+        insert_int8(&mut expected, "lm_head.", N_TOKENS, CONFIG.dim);
+        expected.save_per_tensor(dir.path()).unwrap();
+
+        let mmap = &MmapStateDict::open(dir.path()).unwrap();
+        let mut llama: DefaultLlama<LinearAQLM, LinearINT8> =
+            Llama::from_state_dict(&mmap, "", CONFIG).await.unwrap();
+
+        let mut actual = StateDict::new();
+        llama.to_state_dict("", &mut actual);
+        assert_same_tensors(&actual, &expected);
+
+        let path = dir.path().join("model.safetensors");
+        actual.save(&path).unwrap();
+        let mmap = &MmapStateDict::open(&path).unwrap();
+        let mut reloaded: DefaultLlama<LinearAQLM, LinearINT8> =
+            Llama::from_state_dict(&mmap, "", CONFIG).await.unwrap();
+
+        for token in [2, 5] {
+            let expected = llama.forward(token).await.unwrap();
+            assert_eq!(reloaded.forward(token).await.unwrap(), expected);
+            assert!(expected.iter().all(|v| v.is_finite()));
+        }
+    }
+}
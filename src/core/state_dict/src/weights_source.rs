@@ -0,0 +1,82 @@
+use crate::manifest::clear_manifest_cache;
+use crate::remote_safetensors::clear_caches;
+use std::cell::RefCell;
+
+const LOCAL_PREFIX: &str = "http://localhost:8000/aqlm-f32/";
+const LOCAL_SUFFIX: &str = "";
+const PROD_PREFIX: &str =
+    "https://huggingface.co/galqiwi/llama-3.1-aqlm-pv-2x8-f32-int8-emb/resolve/main/";
+const PROD_SUFFIX: &str = "?download=true";
+
+/// How tensors are laid out in the files of a weights source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WeightsLayout {
+    /// One `{tensor_name}.safetensors` file per tensor.
+    PerTensorFiles,
+    /// Every tensor lives in a single safetensors file, e.g. `model.safetensors`.
+    SingleFile(String),
+    /// Tensors are spread over shards listed in an index file,
+    /// e.g. `model.safetensors.index.json`.
+    Sharded(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WeightsSource {
+    pub prefix: String,
+    pub suffix: String,
+    pub layout: WeightsLayout,
+    /// Manifest file to check downloads against, see [`crate::manifest::Manifest`].
+    pub manifest: Option<String>,
+}
+
+impl WeightsSource {
+    pub fn new(prefix: &str, suffix: &str, layout: WeightsLayout) -> Self {
+        Self {
+            prefix: prefix.to_string(),
+            suffix: suffix.to_string(),
+            layout,
+            manifest: None,
+        }
+    }
+
+    pub fn with_manifest(mut self, filename: &str) -> Self {
+        self.manifest = Some(filename.to_string());
+        self
+    }
+
+    pub fn local() -> Self {
+        Self::new(LOCAL_PREFIX, LOCAL_SUFFIX, WeightsLayout::PerTensorFiles)
+    }
+
+    pub fn huggingface(repo: &str, layout: WeightsLayout) -> Self {
+        Self::new(
+            &format!("https://huggingface.co/{repo}/resolve/main/"),
+            PROD_SUFFIX,
+            layout,
+        )
+    }
+
+    pub fn url(&self, filename: &str) -> String {
+        format!("{}{filename}{}", self.prefix, self.suffix)
+    }
+}
+
+impl Default for WeightsSource {
+    fn default() -> Self {
+        Self::new(PROD_PREFIX, PROD_SUFFIX, WeightsLayout::PerTensorFiles)
+    }
+}
+
+thread_local! {
+    static WEIGHTS_SOURCE: RefCell<WeightsSource> = RefCell::new(WeightsSource::default());
+}
+
+pub fn set_weights_source(source: WeightsSource) {
+    clear_caches();
+    clear_manifest_cache();
+    WEIGHTS_SOURCE.set(source);
+}
+
+pub fn weights_source() -> WeightsSource {
+    WEIGHTS_SOURCE.with_borrow(|source| source.clone())
+}
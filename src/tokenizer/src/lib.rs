@@ -24,26 +24,10 @@ pub enum Role {
 }
 
 impl Llama3Tokenizer {
+    /// Parses a tiktoken `tokenizer.model` file.
     pub fn from_data(data: Vec<u8>) -> anyhow::Result<Self> {
-        let mut special_tokens: Vec<String> = vec![
-            "<|begin_of_text|>".to_string(),
-            "<|end_of_text|>".to_string(),
-            "<|reserved_special_token_0|>".to_string(),
-            "<|reserved_special_token_1|>".to_string(),
-            "<|reserved_special_token_2|>".to_string(),
-            "<|reserved_special_token_3|>".to_string(),
-            "<|start_header_id|>".to_string(),
-            "<|end_header_id|>".to_string(),
-            "<|reserved_special_token_4|>".to_string(),
-            "<|eot_id|>".to_string(), // end of turn
-        ];
-
-        for i in 5..251 {
-            special_tokens.push(format!("<|reserved_special_token_{}|>", i));
-        }
-
         let data = from_utf8(&data)?;
-        let mut mergeable_ranks: HashMap<Vec<u8>, usize> = HashMap::default();
+        let mut mergeable_ranks = Vec::new();
 
         for line in data.lines() {
             let parts: Vec<&str> = line.split_whitespace().collect();
@@ -60,9 +44,34 @@ impl Llama3Tokenizer {
                 Err(_) => continue, // Skip lines where the base64 decoding fails
             };
 
-            mergeable_ranks.insert(decoded_bytes, number);
+            mergeable_ranks.push((decoded_bytes, number));
         }
 
+        Self::from_ranks(mergeable_ranks)
+    }
+
+    /// Builds the tokenizer from byte sequences and their ranks, e.g. the
+    /// vocabulary embedded in a GGUF file.
+    pub fn from_ranks(ranks: impl IntoIterator<Item = (Vec<u8>, usize)>) -> anyhow::Result<Self> {
+        let mut special_tokens: Vec<String> = vec![
+            "<|begin_of_text|>".to_string(),
+            "<|end_of_text|>".to_string(),
+            "<|reserved_special_token_0|>".to_string(),
+            "<|reserved_special_token_1|>".to_string(),
+            "<|reserved_special_token_2|>".to_string(),
+            "<|reserved_special_token_3|>".to_string(),
+            "<|start_header_id|>".to_string(),
+            "<|end_header_id|>".to_string(),
+            "<|reserved_special_token_4|>".to_string(),
+            "<|eot_id|>".to_string(), // end of turn
+        ];
+
+        for i in 5..251 {
+            special_tokens.push(format!("<|reserved_special_token_{}|>", i));
+        }
+
+        let mergeable_ranks: HashMap<Vec<u8>, usize> = ranks.into_iter().collect();
+
         let special_tokens_map: HashMap<String, usize> = special_tokens
             .into_iter()
             .enumerate()
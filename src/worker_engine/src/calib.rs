@@ -1,92 +1,101 @@
 use crate::handles::lock_handles;
 use crate::parallel_aqlm::ParallelAQLMLinear;
+use crate::parallel_int8::{INT8Weights, ParallelINT8Linear};
+use crate::registry_rpc::RegistryError;
+use crate::sharding::{alive_workers, Split};
 use log::info;
-use nn::linear::Module;
 use std::cell::Cell;
 use std::collections::HashMap;
+use std::future::Future;
 use tensorlib::functional::argmin;
+use tensorlib::matrix::OwnedMatrix;
 use tokio::sync::Semaphore;
 use web_time::Instant;
 
+/// How a linear layer is sharded: over how many workers, and along which
+/// dimension.
+type Sharding = (usize, Split);
+
 static CALIB_SEMAPHORE: Semaphore = Semaphore::const_new(1);
 thread_local! {
-    static AQLM_N_WORKERS_CACHE: Cell<HashMap<(usize, usize), usize>> = Cell::new(HashMap::new());
+    static SHARDING_CACHE: Cell<HashMap<(&'static str, usize, usize), Sharding>> = Cell::new(HashMap::new());
 }
 
-pub(crate) async fn get_optimal_aqlm_n_workers(
-    codebooks: &[f32],
-    scales: &[f32],
-    codes: &[u8],
+/// Runs `calibrate` unless a layer of the same kind and shape already has.
+async fn get_cached_sharding(
+    kind: &'static str,
     out_dim: usize,
-    in_group_dim: usize,
-) -> anyhow::Result<usize> {
+    in_dim: usize,
+    calibrate: impl Future<Output = anyhow::Result<Sharding>>,
+) -> anyhow::Result<Sharding> {
     let _guard = CALIB_SEMAPHORE.acquire().await.unwrap();
 
-    let cache_entry = (out_dim, in_group_dim);
-    let mut cache = AQLM_N_WORKERS_CACHE.take();
+    let cache_entry = (kind, out_dim, in_dim);
+    let mut cache = SHARDING_CACHE.take();
 
     let output = cache.get(&cache_entry).cloned();
     if let Some(output) = output {
-        AQLM_N_WORKERS_CACHE.set(cache);
+        SHARDING_CACHE.set(cache);
         return Ok(output);
     }
 
-    let output =
-        do_get_optimal_aqlm_n_workers(codebooks, scales, codes, out_dim, in_group_dim).await?;
+    let output = calibrate.await;
 
-    cache.insert(cache_entry, output);
-    AQLM_N_WORKERS_CACHE.set(cache);
+    if let Ok(output) = output {
+        cache.insert(cache_entry, output);
+    }
+    SHARDING_CACHE.set(cache);
 
-    Ok(output)
+    output
 }
 
-pub(crate) async fn do_get_optimal_aqlm_n_workers(
+pub(crate) async fn get_optimal_aqlm_sharding(
     codebooks: &[f32],
     scales: &[f32],
     codes: &[u8],
     out_dim: usize,
     in_group_dim: usize,
-) -> anyhow::Result<usize> {
-    let test_data = vec![3f32; in_group_dim * 8];
-
-    let n_workers = {
-        let handles_guard = lock_handles().await;
-        handles_guard.borrow().len()
-    };
+) -> anyhow::Result<Sharding> {
+    let calibrate = do_get_optimal_aqlm_sharding(codebooks, scales, codes, out_dim, in_group_dim);
+    get_cached_sharding("aqlm", out_dim, in_group_dim * 8, calibrate).await
+}
 
-    assert!(n_workers > 0);
+pub(crate) async fn do_get_optimal_aqlm_sharding(
+    codebooks: &[f32],
+    scales: &[f32],
+    codes: &[u8],
+    out_dim: usize,
+    in_group_dim: usize,
+) -> anyhow::Result<Sharding> {
+    let test_data = vec![3f32; in_group_dim * 8];
 
+    let candidates = candidate_shardings().await?;
     let mut timings: Vec<f32> = Vec::new();
 
-    for layer_n_workers in 1..=n_workers {
+    for &(layer_n_workers, split) in &candidates {
         let mut aqlm = ParallelAQLMLinear::new(
-            format!("calib_{layer_n_workers}"),
+            calib_layer_name((layer_n_workers, split)),
             codebooks,
             scales,
             codes,
             out_dim,
             in_group_dim,
             layer_n_workers,
+            split,
         )
-        .await;
-
-        aqlm.forward(&test_data).await;
-
-        let begin = Instant::now();
-        for _ in 0..10 {
-            aqlm.forward(&test_data).await;
-        }
+        .await?;
 
-        timings.push(begin.elapsed().as_secs_f64() as f32);
-
-        aqlm.async_drop().await;
-        drop(aqlm);
+        // The layer is dropped even if a forward failed.
+        let timing = time_forwards(async || aqlm.try_forward(&test_data).await).await;
+        let dropped = aqlm.async_drop().await;
+        timings.push(timing?);
+        dropped?;
     }
 
-    let output = argmin(&timings) + 1;
+    let output = candidates[argmin(&timings)];
 
     info!(
-        "AQLM Calibration({}x{}): {} {:?}",
+        "AQLM Calibration({}x{}): {:?} {:?}",
         out_dim,
         in_group_dim * 8,
         output,
@@ -95,3 +104,80 @@ pub(crate) async fn do_get_optimal_aqlm_n_workers(
 
     Ok(output)
 }
+
+pub(crate) async fn get_optimal_int8_sharding(weights: &INT8Weights) -> anyhow::Result<Sharding> {
+    let calibrate = do_get_optimal_int8_sharding(weights);
+    get_cached_sharding("int8", weights.out_dim, weights.in_dim, calibrate).await
+}
+
+pub(crate) async fn do_get_optimal_int8_sharding(
+    weights: &INT8Weights,
+) -> anyhow::Result<Sharding> {
+    let test_data = vec![3f32; weights.in_dim];
+
+    let candidates = candidate_shardings().await?;
+    let mut timings: Vec<f32> = Vec::new();
+
+    for &(layer_n_workers, split) in &candidates {
+        let mut int8 = ParallelINT8Linear::from_weights(
+            calib_layer_name((layer_n_workers, split)),
+            weights,
+            layer_n_workers,
+            split,
+        )
+        .await?;
+
+        // The layer is dropped even if a forward failed.
+        let timing = time_forwards(async || int8.try_forward(&test_data).await).await;
+        let dropped = int8.async_drop().await;
+        timings.push(timing?);
+        dropped?;
+    }
+
+    let output = candidates[argmin(&timings)];
+
+    info!(
+        "INT8 Calibration({}x{}): {:?} {:?}",
+        weights.out_dim, weights.in_dim, output, timings
+    );
+
+    Ok(output)
+}
+
+/// Row splits over any number of the alive workers, and column splits over
+/// two or more, which a single worker would run like a row split.
+async fn candidate_shardings() -> anyhow::Result<Vec<Sharding>> {
+    let n_workers = {
+        let handles_guard = lock_handles().await;
+        alive_workers(handles_guard.borrow()).len()
+    };
+
+    anyhow::ensure!(n_workers > 0, "no alive worker to calibrate on");
+
+    let mut candidates = vec![(1, Split::Rows)];
+    for layer_n_workers in 2..=n_workers {
+        candidates.push((layer_n_workers, Split::Rows));
+        candidates.push((layer_n_workers, Split::Columns));
+    }
+    Ok(candidates)
+}
+
+/// Names every candidate differently, so that none can collide with another
+/// one's leftovers.
+fn calib_layer_name((n_workers, split): Sharding) -> String {
+    format!("calib_{split:?}_{n_workers}")
+}
+
+/// The time of 10 forwards, after one to warm up.
+async fn time_forwards(
+    mut forward: impl AsyncFnMut() -> Result<OwnedMatrix, RegistryError>,
+) -> Result<f32, RegistryError> {
+    forward().await?;
+
+    let begin = Instant::now();
+    for _ in 0..10 {
+        forward().await?;
+    }
+
+    Ok(begin.elapsed().as_secs_f64() as f32)
+}
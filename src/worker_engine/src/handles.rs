@@ -1,10 +1,13 @@
 use crate::registry_rpc_handle::RPCLinearRegistryHandle;
+use state_dict::from_state_dict::TensorProvider;
 use std::cell::RefCell;
 use std::mem;
+use std::rc::Rc;
 use tokio::sync::{Semaphore, SemaphorePermit};
 
 thread_local! {
     static HANDLES_G: RefCell<Vec<RPCLinearRegistryHandle>> = const { RefCell::new(Vec::new()) };
+    static WEIGHT_SOURCE_G: RefCell<Option<Rc<dyn TensorProvider<'static>>>> = const { RefCell::new(None) };
 }
 
 static HANDLES_SEMAPHORE_G: Semaphore = Semaphore::const_new(1);
@@ -19,6 +22,14 @@ pub(crate) async fn lock_handles() -> HandlesGuard {
     }
 }
 
+pub(crate) fn set_weight_source(source: Option<Rc<dyn TensorProvider<'static>>>) {
+    WEIGHT_SOURCE_G.set(source);
+}
+
+pub(crate) fn weight_source() -> Option<Rc<dyn TensorProvider<'static>>> {
+    WEIGHT_SOURCE_G.with_borrow(Clone::clone)
+}
+
 pub struct HandlesGuard {
     _guard: SemaphorePermit<'static>,
     inner: Vec<RPCLinearRegistryHandle>,
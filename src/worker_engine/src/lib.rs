@@ -2,7 +2,17 @@ mod calib;
 mod handles;
 pub mod matrix_serde;
 pub mod parallel_aqlm;
+pub mod parallel_attention;
 pub mod parallel_int8;
+pub mod parallel_mlp;
 pub mod registry;
 pub mod registry_rpc;
 pub mod registry_rpc_handle;
+mod sharding;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod tcp_transport;
+#[cfg(test)]
+mod test_weights;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod thread_transport;
+pub mod transport;
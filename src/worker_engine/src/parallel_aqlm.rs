@@ -1,14 +1,28 @@
-use crate::calib::get_optimal_aqlm_n_workers;
-use crate::handles::lock_handles;
+use crate::calib::get_optimal_aqlm_sharding;
+use crate::handles::{self, lock_handles};
+use crate::registry::RegistryLayer;
+use crate::registry_rpc::{ColumnRange, LayerConfig, RegistryError};
 use crate::registry_rpc_handle::RPCLinearRegistryHandle;
+use crate::sharding::{
+    alive_workers, check_handles, forward_error, forward_layers, load_workers, remove_shards,
+    split_groups, split_rows, Columns, Reduce, Shard, ShardWeights,
+};
 use async_trait::async_trait;
 use futures::future::join_all;
-use nn::linear::Module;
-use state_dict::from_state_dict::{load_f32_data, load_u8_data, FromStateDict};
+use nn::linear::{ForwardError, Module};
+use nn::linear_aqlm::LinearAQLM;
+use state_dict::error::LoadError;
+use state_dict::from_state_dict::{
+    check_aqlm_shapes, load_f32_data, load_u8_data, FromStateDict, TensorProvider,
+};
+use state_dict::state_dict::StateDict;
+use std::borrow::Cow;
 use std::mem;
-use tensorlib::functional::cat_row;
+use std::rc::Rc;
 use tensorlib::matrix::{Matrix, OwnedMatrix};
 
+pub use crate::sharding::Split;
+
 pub async fn set_handles(mut new_handles: Vec<RPCLinearRegistryHandle>) {
     new_handles.truncate(8);
 
@@ -16,14 +30,176 @@ pub async fn set_handles(mut new_handles: Vec<RPCLinearRegistryHandle>) {
     let _ = mem::replace(handles.borrow_mut(), new_handles);
 }
 
+/// Where parallel layers re-fetch the shards of dead workers from. Without
+/// one, losing a worker fails every layer that had a shard on it.
+pub fn set_weight_source(source: Option<Rc<dyn TensorProvider<'static>>>) {
+    handles::set_weight_source(source);
+}
+
+/// Pings every worker still considered alive. Workers that don't answer are
+/// marked dead, and the layers move their shards on the next forward.
+/// Returns the number of alive workers.
+pub async fn check_workers() -> usize {
+    let mut handles = lock_handles().await;
+    let futures = handles
+        .borrow_mut()
+        .iter_mut()
+        .filter(|handle| handle.is_alive())
+        .map(|handle| handle.ping());
+
+    for result in join_all(futures).await {
+        if let Err(err) = result {
+            log::warn!("worker failed a health check: {err}");
+        }
+    }
+
+    alive_workers(handles.borrow()).len()
+}
+
 pub struct ParallelAQLMLinear {
     name: String,
     out_dim: usize,
     in_group_dim: usize,
-    n_workers: usize,
+    split: Split,
+    shards: Vec<Shard>,
+}
+
+/// The tensors of a `LinearAQLM`.
+pub(crate) struct AQLMWeights<'a> {
+    codebooks: Cow<'a, [f32]>,
+    scales: Cow<'a, [f32]>,
+    codes: Cow<'a, [u8]>,
+    pub out_dim: usize,
+    pub in_group_dim: usize,
+}
+
+impl AQLMWeights<'_> {
+    /// Adds output rows `begin..end` as the tensors of a `LinearAQLM` under
+    /// `prefix`.
+    pub fn insert_rows(&self, tensors: &mut StateDict, prefix: &str, begin: usize, end: usize) {
+        // codes: [in_group_idx, codebook_idx, out_idx]
+        let shard_codes: Vec<u8> = (0..self.in_group_dim * 2)
+            .flat_map(|idx| {
+                self.codes[idx * self.out_dim + begin..idx * self.out_dim + end]
+                    .iter()
+                    .copied()
+            })
+            .collect();
+
+        tensors.insert_f32(
+            &format!("{prefix}codebooks"),
+            &[2, 256, 1, 8],
+            &self.codebooks,
+        );
+        tensors.insert_f32(
+            &format!("{prefix}scales"),
+            &[end - begin],
+            &self.scales[begin..end],
+        );
+        tensors.insert_u8(
+            &format!("{prefix}codes_120"),
+            &[self.in_group_dim, 2, end - begin],
+            &shard_codes,
+        );
+    }
+
+    /// Adds input columns `begin..end`, multiples of 8, as the tensors of a
+    /// `LinearAQLM` under `prefix`. Its output is the part of the layer
+    /// output that comes from these columns.
+    pub fn insert_columns(&self, tensors: &mut StateDict, prefix: &str, begin: usize, end: usize) {
+        let (group_begin, group_end) = (begin / 8, end / 8);
+
+        tensors.insert_f32(
+            &format!("{prefix}codebooks"),
+            &[2, 256, 1, 8],
+            &self.codebooks,
+        );
+        tensors.insert_f32(&format!("{prefix}scales"), &[self.out_dim], &self.scales);
+        tensors.insert_u8(
+            &format!("{prefix}codes_120"),
+            &[group_end - group_begin, 2, self.out_dim],
+            &self.codes[group_begin * 2 * self.out_dim..group_end * 2 * self.out_dim],
+        );
+    }
+}
+
+#[async_trait(?Send)]
+impl FromStateDict<'static> for AQLMWeights<'static> {
+    async fn from_state_dict(
+        source: &dyn TensorProvider<'static>,
+        prefix: &str,
+    ) -> Result<Self, LoadError> {
+        let codebooks_file = format!("{prefix}codebooks");
+        let scales_file = format!("{prefix}scales");
+        let codes_file = format!("{prefix}codes_120");
+
+        let codebooks_future = load_f32_data(source, &codebooks_file);
+        let scales_future = load_f32_data(source, &scales_file);
+        let codes_future = load_u8_data(source, &codes_file);
+
+        let (codebooks, codebooks_shape) = codebooks_future.await?;
+        let (scales, _) = scales_future.await?;
+        let (codes, codes_shape) = codes_future.await?;
+
+        let (out_dim, in_group_dim) =
+            check_aqlm_shapes(prefix, &codebooks_shape, &scales, &codes_shape)?;
+
+        Ok(Self {
+            codebooks,
+            scales,
+            codes,
+            out_dim,
+            in_group_dim,
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl ShardWeights for AQLMWeights<'static> {
+    async fn upload(
+        &self,
+        handle: &mut RPCLinearRegistryHandle,
+        layer_name: &str,
+        shard: &Shard,
+        reduce: Reduce,
+    ) -> Result<(), RegistryError> {
+        upload_shard(handle, layer_name, self, shard, reduce).await
+    }
+}
+
+async fn upload_shard(
+    handle: &mut RPCLinearRegistryHandle,
+    layer_name: &str,
+    weights: &AQLMWeights<'_>,
+    shard: &Shard,
+    reduce: Reduce,
+) -> Result<(), RegistryError> {
+    let mut tensors = StateDict::new();
+    let (kind, config) = match reduce {
+        Reduce::Concat => {
+            weights.insert_rows(&mut tensors, "", shard.begin, shard.end);
+            (LinearAQLM::KIND, LayerConfig::None)
+        }
+        Reduce::Sum => {
+            weights.insert_columns(&mut tensors, "inner.", shard.begin, shard.end);
+            let range = ColumnRange {
+                begin: shard.begin,
+                in_dim: weights.in_group_dim * 8,
+            };
+            (Columns::<LinearAQLM>::KIND, LayerConfig::Columns(range))
+        }
+    };
+
+    handle
+        .add_layer(shard.name(layer_name), kind, config, &tensors)
+        .await
 }
 
 impl ParallelAQLMLinear {
+    /// Shards the layer over the first `n_workers` alive workers. Column
+    /// shards hold whole groups of 8 columns, so there are at most
+    /// `in_group_dim` of them.
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         name: String,
         codebooks: &[f32],
@@ -32,127 +208,369 @@ impl ParallelAQLMLinear {
         out_dim: usize,
         in_group_dim: usize,
         n_workers: usize,
-    ) -> Self {
+        split: Split,
+    ) -> Result<Self, RegistryError> {
         let mut handles = lock_handles().await;
         let handles = handles.borrow_mut();
-        let handles: &mut [RPCLinearRegistryHandle] = &mut handles[0..n_workers];
-        assert_ne!(handles.len(), 0);
-
-        let chunk_size = out_dim / handles.len();
-        let n_handlers = handles.len();
 
-        for (handler_idx, handler) in handles.iter_mut().enumerate() {
-            let begin = handler_idx * chunk_size;
-            let end = match handler_idx == n_handlers - 1 {
-                true => out_dim,
-                false => (handler_idx + 1) * chunk_size,
-            };
+        let max_workers = match split {
+            Split::Rows => n_workers,
+            Split::Columns => n_workers.min(in_group_dim),
+        };
+        let workers = load_workers(handles, max_workers)?;
 
-            // codes: [in_group_idx, codebook_idx, out_idx]
-            let chunk_codes: Vec<u8> = (0..in_group_dim * 2)
-                .flat_map(|idx| {
-                    codes[idx * out_dim + begin..idx * out_dim + end]
-                        .iter()
-                        .copied()
-                })
-                .collect();
-
-            handler
-                .add_aqlm(
-                    name.clone(),
-                    codebooks,
-                    &scales[begin..end],
-                    &chunk_codes,
-                    end - begin,
-                    in_group_dim,
-                )
-                .await;
+        let weights = AQLMWeights {
+            codebooks: Cow::Borrowed(codebooks),
+            scales: Cow::Borrowed(scales),
+            codes: Cow::Borrowed(codes),
+            out_dim,
+            in_group_dim,
+        };
+        let shards = match split {
+            Split::Rows => split_rows(out_dim, &workers),
+            Split::Columns => split_groups(in_group_dim * 8, 8, &workers),
+        };
+        for shard in &shards {
+            upload_shard(
+                &mut handles[shard.worker],
+                &name,
+                &weights,
+                shard,
+                split.reduce(),
+            )
+            .await?;
         }
 
-        Self {
+        Ok(Self {
             name,
             out_dim,
             in_group_dim,
-            n_workers,
-        }
+            split,
+            shards,
+        })
     }
 }
 
 #[async_trait(?Send)]
-impl FromStateDict for ParallelAQLMLinear {
-    async fn from_state_dict(prefix: &str) -> anyhow::Result<Self> {
-        let codebooks_file = format!("{prefix}codebooks");
-        let scales_file = format!("{prefix}scales");
-        let codes_file = format!("{prefix}codes_120");
-
-        let codebooks_future = load_f32_data(&codebooks_file);
-        let scales_future = load_f32_data(&scales_file);
-        let codes_future = load_u8_data(&codes_file);
-
-        let (codebooks, codebooks_shape) = codebooks_future.await?;
-        assert_eq!(codebooks_shape, vec![2, 256, 1, 8]);
-
-        let (scales, _) = scales_future.await?;
-        let (codes, codes_shape) = codes_future.await?;
-
-        let out_dim = codes_shape[2];
-        let in_group_dim = codes_shape[0];
+impl FromStateDict<'static> for ParallelAQLMLinear {
+    async fn from_state_dict(
+        source: &dyn TensorProvider<'static>,
+        prefix: &str,
+    ) -> Result<Self, LoadError> {
+        let weights = AQLMWeights::from_state_dict(source, prefix).await?;
 
-        let n_layer_workers =
-            get_optimal_aqlm_n_workers(&codebooks, &scales, &codes, out_dim, in_group_dim).await?;
+        let (n_layer_workers, split) = get_optimal_aqlm_sharding(
+            &weights.codebooks,
+            &weights.scales,
+            &weights.codes,
+            weights.out_dim,
+            weights.in_group_dim,
+        )
+        .await
+        .map_err(|err| LoadError::backend(prefix, err))?;
 
-        Ok(Self::new(
+        Self::new(
             prefix.to_string(),
-            &codebooks,
-            &scales,
-            &codes,
-            out_dim,
-            in_group_dim,
+            &weights.codebooks,
+            &weights.scales,
+            &weights.codes,
+            weights.out_dim,
+            weights.in_group_dim,
             n_layer_workers,
+            split,
         )
-        .await)
+        .await
+        .map_err(|err| LoadError::backend(prefix, err))
     }
 }
 
-#[async_trait(?Send)]
-impl Module for ParallelAQLMLinear {
-    async fn forward(&mut self, x: &[f32]) -> OwnedMatrix {
-        let x = Matrix::from_slice((1, x.len()), x);
+impl ParallelAQLMLinear {
+    /// Like `forward`, but keeps the `RegistryError`.
+    pub async fn try_forward(&mut self, x: &[f32]) -> Result<OwnedMatrix, RegistryError> {
+        let mut outputs = Self::try_forward_batch(&mut [self], x).await?;
+        Ok(outputs.remove(0))
+    }
 
-        let n_workers = self.n_workers;
+    /// Like `forward_batch`, but keeps the `RegistryError`.
+    /// Shards of dead workers are moved to the surviving ones first.
+    pub async fn try_forward_batch(
+        modules: &mut [&mut Self],
+        x: &[f32],
+    ) -> Result<Vec<OwnedMatrix>, RegistryError> {
+        let x = Matrix::from_slice((1, x.len()), x);
 
         let mut handles = lock_handles().await;
-        assert_ne!(handles.borrow().len(), 0);
-        let handles: &mut [RPCLinearRegistryHandle] = handles.borrow_mut();
+        check_handles(handles.borrow())?;
 
-        let futures = handles
+        let mut layers: Vec<(&str, &mut [Shard], Reduce)> = modules
             .iter_mut()
-            .take(n_workers)
-            .map(|handler| handler.aqlm_forward(&self.name, &x));
-
-        let output = join_all(futures).await;
+            .map(|module| {
+                let reduce = module.split.reduce();
+                (module.name.as_str(), &mut module.shards[..], reduce)
+            })
+            .collect();
+        forward_layers::<AQLMWeights>(handles.borrow_mut(), &mut layers, &x).await
+    }
+}
 
-        cat_row(&output)
+#[async_trait(?Send)]
+impl Module for ParallelAQLMLinear {
+    async fn forward(&mut self, x: &[f32]) -> Result<OwnedMatrix, ForwardError> {
+        self.try_forward(x)
+            .await
+            .map_err(|err| forward_error(&[&self.name], err))
     }
 
     fn shape(&self) -> (usize, usize) {
         (self.out_dim, self.in_group_dim * 8)
     }
+
+    /// Sends the shards each worker holds in a single request.
+    async fn forward_batch(
+        modules: &mut [&mut Self],
+        x: &[f32],
+    ) -> Result<Vec<OwnedMatrix>, ForwardError> {
+        Self::try_forward_batch(modules, x).await.map_err(|err| {
+            let names: Vec<&str> = modules.iter().map(|module| module.name.as_str()).collect();
+            forward_error(&names, err)
+        })
+    }
 }
 
 impl ParallelAQLMLinear {
-    pub async fn async_drop(&mut self) {
-        let n_workers = self.n_workers;
-
+    /// Removes the shards from the workers that are still alive.
+    pub async fn async_drop(&mut self) -> Result<(), RegistryError> {
         let mut handles = lock_handles().await;
-        assert_ne!(handles.borrow().len(), 0);
-        let handles: &mut [RPCLinearRegistryHandle] = handles.borrow_mut();
+        check_handles(handles.borrow())?;
 
-        let futures = handles
-            .iter_mut()
-            .take(n_workers)
-            .map(|handler| handler.remove_aqlm(self.name.clone()));
+        remove_shards(handles.borrow_mut(), &self.name, &self.shards).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry_rpc::RegistryError;
+    use crate::test_weights::{assert_close, insert_aqlm};
+    use crate::thread_transport::tests::{Fault, FaultyTransport};
+    use crate::thread_transport::ThreadTransport;
+    use crate::transport::Transport;
+    use std::cell::Cell;
+    use std::time::Duration;
+
+    const OUT_DIM: usize = 10;
+    const IN_GROUP_DIM: usize = 4;
+
+    fn aqlm_state_dict() -> StateDict {
+        let mut state_dict = StateDict::new();
+        insert_aqlm(&mut state_dict, "linear.", OUT_DIM, IN_GROUP_DIM * 8);
+        state_dict
+    }
+
+    #[tokio::test]
+    async fn test_matches_local_linear() {
+        let handles = (0..3)
+            .map(|_| RPCLinearRegistryHandle::spawn_thread())
+            .collect();
+        set_handles(handles).await;
+
+        let state_dict = aqlm_state_dict();
+        let mut parallel = ParallelAQLMLinear::from_state_dict(&state_dict, "linear.")
+            .await
+            .unwrap();
+        let mut local = LinearAQLM::from_state_dict(&state_dict, "linear.")
+            .await
+            .unwrap();
+        assert_eq!(parallel.shape(), local.shape());
+
+        let x: Vec<f32> = (0..IN_GROUP_DIM * 8).map(|idx| idx as f32 / 16.0).collect();
+        let expected = local.forward(&x).await.unwrap();
+        let actual = parallel.forward(&x).await.unwrap();
+        assert_eq!(actual.shape(), (1, OUT_DIM));
+        assert_close(&actual, &expected);
+
+        parallel.async_drop().await.unwrap();
+    }
+
+    struct CountingTransport {
+        inner: ThreadTransport,
+        n_requests: Rc<Cell<usize>>,
+    }
+
+    #[async_trait(?Send)]
+    impl Transport for CountingTransport {
+        async fn round_trip(&mut self, request: Vec<u8>) -> Result<Vec<u8>, RegistryError> {
+            self.n_requests.set(self.n_requests.get() + 1);
+            self.inner.round_trip(request).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_forward_batch() {
+        let n_requests = Rc::new(Cell::new(0));
+        let handles = (0..2)
+            .map(|_| {
+                RPCLinearRegistryHandle::new(CountingTransport {
+                    inner: ThreadTransport::spawn(),
+                    n_requests: n_requests.clone(),
+                })
+            })
+            .collect();
+        set_handles(handles).await;
+
+        let state_dict = aqlm_state_dict();
+        let weights = AQLMWeights::from_state_dict(&state_dict, "linear.")
+            .await
+            .unwrap();
+        let mut layers = Vec::new();
+        for (name, n_workers, split) in [
+            ("q.", 1, Split::Rows),
+            ("k.", 2, Split::Rows),
+            ("v.", 2, Split::Columns),
+        ] {
+            let layer = ParallelAQLMLinear::new(
+                name.to_string(),
+                &weights.codebooks,
+                &weights.scales,
+                &weights.codes,
+                OUT_DIM,
+                IN_GROUP_DIM,
+                n_workers,
+                split,
+            )
+            .await
+            .unwrap();
+            layers.push(layer);
+        }
+        let mut local = LinearAQLM::from_state_dict(&state_dict, "linear.")
+            .await
+            .unwrap();
+
+        let x: Vec<f32> = (0..IN_GROUP_DIM * 8).map(|idx| idx as f32 / 16.0).collect();
+        let expected = local.forward(&x).await.unwrap();
+
+        n_requests.set(0);
+        let [q, k, v] = &mut layers[..] else {
+            unreachable!()
+        };
+        let outputs = ParallelAQLMLinear::forward_batch(&mut [q, k, v], &x)
+            .await
+            .unwrap();
+        assert_eq!(n_requests.get(), 2);
+        assert_eq!(outputs.len(), 3);
+        for output in &outputs {
+            assert_close(output, &expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reshard_on_worker_failure() {
+        let mut faults = Vec::new();
+        let mut handles = Vec::new();
+        for _ in 0..3 {
+            let (transport, fault) = FaultyTransport::spawn();
+            let mut handle = RPCLinearRegistryHandle::new(transport);
+            handle.set_timeout(Duration::from_millis(100));
+            handles.push(handle);
+            faults.push(fault);
+        }
+        set_handles(handles).await;
+
+        let state_dict = Rc::new(aqlm_state_dict());
+        set_weight_source(Some(state_dict.clone()));
+
+        let weights = AQLMWeights::from_state_dict(state_dict.as_ref(), "linear.")
+            .await
+            .unwrap();
+        let mut parallel = ParallelAQLMLinear::new(
+            "linear.".to_string(),
+            &weights.codebooks,
+            &weights.scales,
+            &weights.codes,
+            OUT_DIM,
+            IN_GROUP_DIM,
+            3,
+            Split::Rows,
+        )
+        .await
+        .unwrap();
+        let mut local = LinearAQLM::from_state_dict(state_dict.as_ref(), "linear.")
+            .await
+            .unwrap();
+
+        let x: Vec<f32> = (0..IN_GROUP_DIM * 8).map(|idx| idx as f32 / 16.0).collect();
+        let expected = local.forward(&x).await.unwrap();
+
+        // A worker that stops replying times out, and its shard moves.
+        faults[1].set(Fault::Hang);
+        assert_close(&parallel.try_forward(&x).await.unwrap(), &expected);
+        assert!(parallel.shards.iter().all(|shard| shard.worker != 1));
+
+        // A disconnected worker is noticed by the health check.
+        faults[0].set(Fault::Disconnect);
+        assert_eq!(check_workers().await, 1);
+        assert_close(&parallel.try_forward(&x).await.unwrap(), &expected);
+        assert!(parallel.shards.iter().all(|shard| shard.worker == 2));
+
+        faults[2].set(Fault::Disconnect);
+        let err = parallel.try_forward(&x).await.unwrap_err();
+        assert_eq!(err.code, crate::registry_rpc::ErrorCode::NoWorkers);
+        // The model sees the failure through `Module` as well.
+        let err = parallel.forward(&x).await.unwrap_err();
+        assert!(err.to_string().starts_with("linear.: NoWorkers"), "{err}");
+
+        set_weight_source(None);
+    }
+
+    #[tokio::test]
+    async fn test_column_split() {
+        let mut faults = Vec::new();
+        let mut handles = Vec::new();
+        for _ in 0..3 {
+            let (transport, fault) = FaultyTransport::spawn();
+            handles.push(RPCLinearRegistryHandle::new(transport));
+            faults.push(fault);
+        }
+        set_handles(handles).await;
+
+        let state_dict = Rc::new(aqlm_state_dict());
+        set_weight_source(Some(state_dict.clone()));
+
+        let weights = AQLMWeights::from_state_dict(state_dict.as_ref(), "linear.")
+            .await
+            .unwrap();
+        let mut parallel = ParallelAQLMLinear::new(
+            "linear.".to_string(),
+            &weights.codebooks,
+            &weights.scales,
+            &weights.codes,
+            OUT_DIM,
+            IN_GROUP_DIM,
+            3,
+            Split::Columns,
+        )
+        .await
+        .unwrap();
+        let mut local = LinearAQLM::from_state_dict(state_dict.as_ref(), "linear.")
+            .await
+            .unwrap();
+
+        let bounds: Vec<_> = parallel
+            .shards
+            .iter()
+            .map(|shard| (shard.begin, shard.end))
+            .collect();
+        assert_eq!(bounds, [(0, 8), (8, 16), (16, 32)]);
+
+        let x: Vec<f32> = (0..IN_GROUP_DIM * 8).map(|idx| idx as f32 / 16.0).collect();
+        let expected = local.forward(&x).await.unwrap();
+        assert_close(&parallel.try_forward(&x).await.unwrap(), &expected);
+
+        // Column shards move like row shards.
+        faults[1].set(Fault::Disconnect);
+        assert_close(&parallel.try_forward(&x).await.unwrap(), &expected);
+        assert!(parallel.shards.iter().all(|shard| shard.worker != 1));
 
-        join_all(futures).await;
+        set_weight_source(None);
     }
 }
@@ -0,0 +1,488 @@
+use crate::handles::lock_handles;
+use crate::parallel_aqlm::AQLMWeights;
+use crate::registry::RegistryLayer;
+use crate::registry_rpc::{ErrorCode, HeadsConfig, LayerConfig, RegistryError};
+use crate::registry_rpc_handle::RPCLinearRegistryHandle;
+use crate::sharding::{
+    check_handles, clear_caches, forward_error, forward_shards, load_weights_conf, load_workers,
+    recover_shards, remove_shards, split_rows, Reduce, Shard, ShardWeights,
+};
+use async_trait::async_trait;
+use nn::attention::{Attention, AttentionConfig, AttentionSubmodules, CachedAttentionLinear};
+use nn::linear::{ForwardError, Module};
+use nn::linear_aqlm::LinearAQLM;
+use state_dict::error::LoadError;
+use state_dict::from_state_dict::{FromStateDict, FromStateDictConf, TensorProvider};
+use state_dict::state_dict::StateDict;
+use tensorlib::matrix::{Matrix, OwnedMatrix};
+
+/// The worker side of `ParallelAQLMAttention`: attention over some of the
+/// heads, with their KV cache, whose output is their part of `o_proj`'s.
+pub struct AttentionHeads(Attention<LinearAQLM<'static>>);
+
+#[async_trait(?Send)]
+impl Module for AttentionHeads {
+    async fn forward(&mut self, x: &[f32]) -> Result<OwnedMatrix, ForwardError> {
+        self.0.forward(x).await
+    }
+
+    fn shape(&self) -> (usize, usize) {
+        self.0.shape()
+    }
+
+    fn clear_cache(&mut self) {
+        self.0.clear_cache();
+    }
+}
+
+/// Reads the four projections; the head counts follow from their shapes.
+#[async_trait(?Send)]
+impl FromStateDictConf<'static, HeadsConfig> for AttentionHeads {
+    async fn from_state_dict(
+        source: &dyn TensorProvider<'static>,
+        prefix: &str,
+        config: HeadsConfig,
+    ) -> Result<Self, LoadError> {
+        let HeadsConfig {
+            head_dim,
+            rope_theta,
+        } = config;
+
+        let q_proj = LinearAQLM::from_state_dict(source, &format!("{prefix}q_proj.")).await?;
+        let k_proj = LinearAQLM::from_state_dict(source, &format!("{prefix}k_proj.")).await?;
+        let v_proj = LinearAQLM::from_state_dict(source, &format!("{prefix}v_proj.")).await?;
+        let o_proj = LinearAQLM::from_state_dict(source, &format!("{prefix}o_proj.")).await?;
+
+        let ((q_dim, dim), (kv_dim, _)) = (q_proj.shape(), k_proj.shape());
+        check_shapes(
+            prefix,
+            [
+                ("k_proj", &k_proj, [kv_dim, dim]),
+                ("v_proj", &v_proj, [kv_dim, dim]),
+                ("o_proj", &o_proj, [dim, q_dim]),
+            ],
+        )?;
+        if head_dim == 0
+            || q_dim % head_dim != 0
+            || kv_dim % head_dim != 0
+            || kv_dim == 0
+            || q_dim % kv_dim != 0
+        {
+            return Err(LoadError::corrupted(
+                prefix,
+                format!("head_dim {head_dim} doesn't split {q_dim} query and {kv_dim} key rows"),
+            ));
+        }
+
+        let config = AttentionConfig {
+            dim,
+            head_dim,
+            n_heads: q_dim / head_dim,
+            n_kv_heads: kv_dim / head_dim,
+            rope_theta,
+        };
+        let submodules = AttentionSubmodules {
+            v_proj: CachedAttentionLinear::new(v_proj, None),
+            q_proj,
+            k_proj: CachedAttentionLinear::new(k_proj, Some(config.get_emb_config())),
+            o_proj,
+        };
+        Ok(Self(Attention::new(submodules, config)))
+    }
+}
+
+#[async_trait(?Send)]
+impl RegistryLayer for AttentionHeads {
+    const KIND: &'static str = "aqlm_attention";
+    const KERNELS: &'static [&'static str] = &["aqlm_2x8"];
+    type Config = HeadsConfig;
+
+    async fn from_spec(tensors: &StateDict, config: HeadsConfig) -> Result<Self, LoadError> {
+        Self::from_state_dict(tensors, "", config).await
+    }
+}
+
+fn check_shapes<const N: usize>(
+    prefix: &str,
+    layers: [(&str, &dyn Module, [usize; 2]); N],
+) -> Result<(), LoadError> {
+    for (name, layer, expected) in layers {
+        let (out_dim, in_dim) = layer.shape();
+        if [out_dim, in_dim] != expected {
+            return Err(LoadError::shape_mismatch(
+                &format!("{prefix}{name}."),
+                expected.to_vec(),
+                vec![out_dim, in_dim],
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The state of the workers' KV caches.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CacheState {
+    /// Every shard has seen the same tokens.
+    Synced { n_tokens: usize },
+    /// The shards may still hold tokens from before `clear_cache`.
+    Cleared,
+    /// A shard lost its tokens or missed one. Only `clear_cache` helps.
+    Lost,
+}
+
+/// An AQLM `Attention` split by KV heads. Every worker holds the query, key
+/// and value rows of its heads and the matching columns of `o_proj`, and
+/// keeps their KV cache, so only the partial outputs are summed here.
+///
+/// A KV cache can't be moved, so when a worker dies mid-sequence, forwards
+/// fail with `CacheLost` until `clear_cache`. Shards move to the surviving
+/// workers then, like those of other parallel layers.
+pub struct ParallelAQLMAttention {
+    name: String,
+    config: AttentionConfig,
+    shards: Vec<Shard>,
+    cache: CacheState,
+}
+
+struct AttentionWeights {
+    q_proj: AQLMWeights<'static>,
+    k_proj: AQLMWeights<'static>,
+    v_proj: AQLMWeights<'static>,
+    o_proj: AQLMWeights<'static>,
+    config: AttentionConfig,
+}
+
+#[async_trait(?Send)]
+impl FromStateDictConf<'static, AttentionConfig> for AttentionWeights {
+    async fn from_state_dict(
+        source: &dyn TensorProvider<'static>,
+        prefix: &str,
+        config: AttentionConfig,
+    ) -> Result<Self, LoadError> {
+        let q_proj = AQLMWeights::from_state_dict(source, &format!("{prefix}q_proj.")).await?;
+        let k_proj = AQLMWeights::from_state_dict(source, &format!("{prefix}k_proj.")).await?;
+        let v_proj = AQLMWeights::from_state_dict(source, &format!("{prefix}v_proj.")).await?;
+        let o_proj = AQLMWeights::from_state_dict(source, &format!("{prefix}o_proj.")).await?;
+
+        let q_dim = config.n_heads * config.head_dim;
+        let kv_dim = config.n_kv_heads * config.head_dim;
+        for (name, weights, expected) in [
+            ("q_proj", &q_proj, [q_dim, config.dim]),
+            ("k_proj", &k_proj, [kv_dim, config.dim]),
+            ("v_proj", &v_proj, [kv_dim, config.dim]),
+            ("o_proj", &o_proj, [config.dim, q_dim]),
+        ] {
+            let actual = [weights.out_dim, weights.in_group_dim * 8];
+            if actual != expected {
+                return Err(LoadError::shape_mismatch(
+                    &format!("{prefix}{name}."),
+                    expected.to_vec(),
+                    actual.to_vec(),
+                ));
+            }
+        }
+
+        Ok(Self {
+            q_proj,
+            k_proj,
+            v_proj,
+            o_proj,
+            config,
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl ShardWeights for AttentionWeights {
+    /// `shard` holds KV heads `begin..end`, and the query heads that use them.
+    async fn upload(
+        &self,
+        handle: &mut RPCLinearRegistryHandle,
+        layer_name: &str,
+        shard: &Shard,
+        _reduce: Reduce,
+    ) -> Result<(), RegistryError> {
+        let config = &self.config;
+        let head_dim = config.head_dim;
+        let q_heads_per_kv_head = config.n_heads / config.n_kv_heads;
+        let (kv_begin, kv_end) = (shard.begin * head_dim, shard.end * head_dim);
+        let (q_begin, q_end) = (kv_begin * q_heads_per_kv_head, kv_end * q_heads_per_kv_head);
+
+        let mut tensors = StateDict::new();
+        self.q_proj
+            .insert_rows(&mut tensors, "q_proj.", q_begin, q_end);
+        self.k_proj
+            .insert_rows(&mut tensors, "k_proj.", kv_begin, kv_end);
+        self.v_proj
+            .insert_rows(&mut tensors, "v_proj.", kv_begin, kv_end);
+        self.o_proj
+            .insert_columns(&mut tensors, "o_proj.", q_begin, q_end);
+        let heads_config = HeadsConfig {
+            head_dim,
+            rope_theta: config.rope_theta,
+        };
+
+        handle
+            .add_layer(
+                shard.name(layer_name),
+                AttentionHeads::KIND,
+                LayerConfig::Attention(heads_config),
+                &tensors,
+            )
+            .await
+    }
+}
+
+#[async_trait(?Send)]
+impl FromStateDictConf<'static, AttentionConfig> for ParallelAQLMAttention {
+    async fn from_state_dict(
+        source: &dyn TensorProvider<'static>,
+        prefix: &str,
+        config: AttentionConfig,
+    ) -> Result<Self, LoadError> {
+        let weights = AttentionWeights::from_state_dict(source, prefix, config.clone()).await?;
+
+        let mut handles = lock_handles().await;
+        let handles = handles.borrow_mut();
+
+        let workers = load_workers(handles, config.n_kv_heads)
+            .map_err(|err| LoadError::backend(prefix, err))?;
+
+        let shards = split_rows(config.n_kv_heads, &workers);
+        for shard in &shards {
+            weights
+                .upload(&mut handles[shard.worker], prefix, shard, Reduce::Sum)
+                .await
+                .map_err(|err| LoadError::backend(prefix, err))?;
+        }
+
+        Ok(Self {
+            name: prefix.to_string(),
+            config,
+            shards,
+            cache: CacheState::Synced { n_tokens: 0 },
+        })
+    }
+}
+
+impl ParallelAQLMAttention {
+    /// Like `forward`, but keeps the `RegistryError`.
+    pub async fn try_forward(&mut self, x: &[f32]) -> Result<OwnedMatrix, RegistryError> {
+        let x = Matrix::from_slice((1, x.len()), x);
+
+        let mut handles = lock_handles().await;
+        let handles = handles.borrow_mut();
+        check_handles(handles)?;
+
+        let dead_shards = self
+            .shards
+            .iter()
+            .any(|shard| !handles[shard.worker].is_alive());
+        let n_tokens = match self.cache {
+            CacheState::Synced { n_tokens } if n_tokens > 0 && dead_shards => {
+                self.cache = CacheState::Lost;
+                None
+            }
+            CacheState::Synced { n_tokens } => Some(n_tokens),
+            CacheState::Cleared => Some(0),
+            CacheState::Lost => None,
+        };
+        let Some(n_tokens) = n_tokens else {
+            return Err(RegistryError::new(
+                ErrorCode::CacheLost,
+                format!("{}: KV cache lost with a worker", self.name),
+            ));
+        };
+
+        // Moved shards start with an empty cache, so this only happens
+        // before the first token.
+        let weights = load_weights_conf::<AttentionWeights, _>(&self.name, self.config.clone());
+        recover_shards(handles, &self.name, &mut self.shards, Reduce::Sum, weights).await?;
+
+        if self.cache == CacheState::Cleared {
+            clear_caches(handles, &self.name, &self.shards).await?;
+            self.cache = CacheState::Synced { n_tokens: 0 };
+        }
+
+        let layers = [(self.name.as_str(), &self.shards[..], Reduce::Sum)];
+        match forward_shards(handles, &layers, &x).await {
+            Ok(mut outputs) => {
+                self.cache = CacheState::Synced {
+                    n_tokens: n_tokens + 1,
+                };
+                Ok(outputs.remove(0))
+            }
+            Err(err) => {
+                // Shards that answered have cached the token.
+                self.cache = CacheState::Lost;
+                Err(err)
+            }
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Module for ParallelAQLMAttention {
+    async fn forward(&mut self, x: &[f32]) -> Result<OwnedMatrix, ForwardError> {
+        self.try_forward(x)
+            .await
+            .map_err(|err| forward_error(&[&self.name], err))
+    }
+
+    fn shape(&self) -> (usize, usize) {
+        (self.config.dim, self.config.dim)
+    }
+
+    /// The workers' caches are cleared with the next forward.
+    fn clear_cache(&mut self) {
+        if self.cache != (CacheState::Synced { n_tokens: 0 }) {
+            self.cache = CacheState::Cleared;
+        }
+    }
+}
+
+impl ParallelAQLMAttention {
+    /// Removes the shards from the workers that are still alive.
+    pub async fn async_drop(&mut self) -> Result<(), RegistryError> {
+        let mut handles = lock_handles().await;
+        check_handles(handles.borrow())?;
+
+        remove_shards(handles.borrow_mut(), &self.name, &self.shards).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parallel_aqlm::{check_workers, set_handles, set_weight_source};
+    use crate::test_weights::{assert_close, insert_aqlm};
+    use crate::thread_transport::tests::{Fault, FaultyTransport};
+    use std::rc::Rc;
+
+    fn config() -> AttentionConfig {
+        AttentionConfig {
+            dim: 32,
+            head_dim: 8,
+            n_heads: 4,
+            n_kv_heads: 2,
+            rope_theta: 10000.0,
+        }
+    }
+
+    fn attention_state_dict() -> StateDict {
+        let mut state_dict = StateDict::new();
+        insert_aqlm(&mut state_dict, "attn.q_proj.", 32, 32);
+        insert_aqlm(&mut state_dict, "attn.k_proj.", 16, 32);
+        insert_aqlm(&mut state_dict, "attn.v_proj.", 16, 32);
+        insert_aqlm(&mut state_dict, "attn.o_proj.", 32, 32);
+        state_dict
+    }
+
+    fn token(idx: usize) -> Vec<f32> {
+        (0..32)
+            .map(|dim_idx| ((dim_idx + 3 * idx) % 7) as f32 / 7.0 - 0.5)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_matches_local_attention() {
+        let handles = (0..3)
+            .map(|_| RPCLinearRegistryHandle::spawn_thread())
+            .collect();
+        set_handles(handles).await;
+
+        let state_dict = attention_state_dict();
+        let mut parallel = ParallelAQLMAttention::from_state_dict(&state_dict, "attn.", config())
+            .await
+            .unwrap();
+        let mut local = Attention::<LinearAQLM>::from_state_dict(&state_dict, "attn.", config())
+            .await
+            .unwrap();
+        // Two KV heads can't be split over three workers.
+        assert_eq!(parallel.shards.len(), 2);
+
+        for idx in 0..3 {
+            let x = token(idx);
+            assert_close(
+                &parallel.forward(&x).await.unwrap(),
+                &local.forward(&x).await.unwrap(),
+            );
+        }
+
+        parallel.clear_cache();
+        local.clear_cache();
+        let x = token(5);
+        assert_close(
+            &parallel.forward(&x).await.unwrap(),
+            &local.forward(&x).await.unwrap(),
+        );
+
+        parallel.async_drop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cache_lost_with_worker() {
+        let mut faults = Vec::new();
+        let mut handles = Vec::new();
+        for _ in 0..2 {
+            let (transport, fault) = FaultyTransport::spawn();
+            handles.push(RPCLinearRegistryHandle::new(transport));
+            faults.push(fault);
+        }
+        set_handles(handles).await;
+
+        let state_dict = Rc::new(attention_state_dict());
+        let mut parallel =
+            ParallelAQLMAttention::from_state_dict(state_dict.as_ref(), "attn.", config())
+                .await
+                .unwrap();
+        let mut local =
+            Attention::<LinearAQLM>::from_state_dict(state_dict.as_ref(), "attn.", config())
+                .await
+                .unwrap();
+        parallel.try_forward(&token(0)).await.unwrap();
+
+        faults[1].set(Fault::Disconnect);
+        parallel.try_forward(&token(1)).await.unwrap_err();
+        let err = parallel.try_forward(&token(1)).await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::CacheLost);
+
+        // A new sequence can start on the surviving worker.
+        set_weight_source(Some(state_dict.clone()));
+        parallel.clear_cache();
+        for idx in 0..2 {
+            let x = token(idx);
+            let output = parallel.try_forward(&x).await.unwrap();
+            assert_close(&output, &local.forward(&x).await.unwrap());
+        }
+        assert!(parallel.shards.iter().all(|shard| shard.worker == 0));
+        set_weight_source(None);
+
+        // With every worker dead, loading fails instead of panicking.
+        faults[0].set(Fault::Disconnect);
+        assert_eq!(check_workers().await, 0);
+        let err = ParallelAQLMAttention::from_state_dict(state_dict.as_ref(), "attn.", config())
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, LoadError::Backend { .. }), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_rejects_bad_head_dim() {
+        let state_dict = attention_state_dict();
+        let load = |head_dim| {
+            let config = HeadsConfig {
+                head_dim,
+                rope_theta: 10000.0,
+            };
+            AttentionHeads::from_state_dict(&state_dict, "attn.", config)
+        };
+
+        assert_eq!(load(8).await.unwrap().shape(), (32, 32));
+        for head_dim in [0, 3, 64] {
+            let err = load(head_dim).await.err().unwrap();
+            assert!(matches!(err, LoadError::Corrupted { .. }), "{err}");
+        }
+    }
+}
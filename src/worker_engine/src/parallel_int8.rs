@@ -1,79 +1,349 @@
+use crate::calib::get_optimal_int8_sharding;
 use crate::handles::lock_handles;
+use crate::registry::RegistryLayer;
+use crate::registry_rpc::{ColumnRange, LayerConfig, RegistryError};
+use crate::registry_rpc_handle::RPCLinearRegistryHandle;
+use crate::sharding::{
+    check_handles, forward_error, forward_layers, load_workers, remove_shards, split_rows, Columns,
+    Reduce, Shard, ShardWeights, Split,
+};
 use async_trait::async_trait;
-use futures::future::join_all;
-use nn::linear::Module;
-use state_dict::from_state_dict::{load_f32_data, load_i8_data, FromStateDict};
-use tensorlib::functional::cat_row;
+use nn::linear::{ForwardError, Module};
+use nn::linear_int8::LinearINT8;
+use nn::matrix_int8::Int8Scaling;
+use state_dict::error::LoadError;
+use state_dict::from_state_dict::{
+    check_int8_shape, load_f32_data, load_i8_data, FromStateDict, TensorProvider,
+};
+use state_dict::state_dict::StateDict;
 use tensorlib::matrix::{Matrix, OwnedMatrix};
 
 pub struct ParallelINT8Linear {
     name: String,
     out_dim: usize,
     in_dim: usize,
+    split: Split,
+    shards: Vec<Shard>,
+}
+
+/// The tensors of a `LinearINT8`.
+pub(crate) struct INT8Weights {
+    max_values: Vec<f32>,
+    int8_values: Vec<i8>,
+    scaling: Int8Scaling,
+    pub out_dim: usize,
+    pub in_dim: usize,
+}
+
+impl INT8Weights {
+    /// Adds output rows `begin..end` as the tensors of a `LinearINT8` under
+    /// `prefix`.
+    fn insert_rows(&self, tensors: &mut StateDict, prefix: &str, begin: usize, end: usize) {
+        let (max_values, max_values_shape) = match self.scaling {
+            Int8Scaling::PerColumn => (&self.max_values[..], vec![self.in_dim]),
+            Int8Scaling::PerRow => (&self.max_values[begin..end], vec![end - begin, 1]),
+        };
+
+        tensors.insert_f32(
+            &format!("{prefix}weight_max_values"),
+            &max_values_shape,
+            max_values,
+        );
+        tensors.insert_i8(
+            &format!("{prefix}weight_int8"),
+            &[end - begin, self.in_dim],
+            &self.int8_values[begin * self.in_dim..end * self.in_dim],
+        );
+    }
+
+    /// Adds input columns `begin..end` as the tensors of a `LinearINT8` under
+    /// `prefix`.
+    fn insert_columns(&self, tensors: &mut StateDict, prefix: &str, begin: usize, end: usize) {
+        let (max_values, max_values_shape) = match self.scaling {
+            Int8Scaling::PerColumn => (&self.max_values[begin..end], vec![end - begin]),
+            Int8Scaling::PerRow => (&self.max_values[..], vec![self.out_dim, 1]),
+        };
+        let int8_values: Vec<i8> = self
+            .int8_values
+            .chunks(self.in_dim)
+            .flat_map(|row| row[begin..end].iter().copied())
+            .collect();
+
+        tensors.insert_f32(
+            &format!("{prefix}weight_max_values"),
+            &max_values_shape,
+            max_values,
+        );
+        tensors.insert_i8(
+            &format!("{prefix}weight_int8"),
+            &[self.out_dim, end - begin],
+            &int8_values,
+        );
+    }
 }
 
 #[async_trait(?Send)]
-impl FromStateDict for ParallelINT8Linear {
-    async fn from_state_dict(prefix: &str) -> anyhow::Result<Self> {
-        let max_values = load_f32_data(&format!("{prefix}weight_max_values"))
+impl FromStateDict<'static> for INT8Weights {
+    async fn from_state_dict(
+        source: &dyn TensorProvider<'static>,
+        prefix: &str,
+    ) -> Result<Self, LoadError> {
+        let (max_values, max_values_shape) =
+            load_f32_data(source, &format!("{prefix}weight_max_values")).await?;
+        let int8_values = load_i8_data(source, &format!("{prefix}weight_int8"))
             .await?
             .0;
-        let int8_values = load_i8_data(&format!("{prefix}weight_int8")).await?.0;
 
-        assert_eq!(int8_values.len() % max_values.len(), 0);
+        let scaling = check_int8_shape(prefix, &max_values, &max_values_shape, &int8_values)?;
 
-        let in_dim = max_values.len();
-        let out_dim = int8_values.len() / max_values.len();
+        let (out_dim, in_dim) = match scaling {
+            Int8Scaling::PerColumn => (int8_values.len() / max_values.len(), max_values.len()),
+            Int8Scaling::PerRow => (max_values.len(), int8_values.len() / max_values.len()),
+        };
 
-        let mut handles = lock_handles().await;
+        Ok(Self {
+            max_values: max_values.into_owned(),
+            int8_values: int8_values.into_owned(),
+            scaling,
+            out_dim,
+            in_dim,
+        })
+    }
+}
 
-        let handlers = handles.borrow_mut();
-        assert_ne!(handlers.len(), 0);
+#[async_trait(?Send)]
+impl ShardWeights for INT8Weights {
+    async fn upload(
+        &self,
+        handle: &mut RPCLinearRegistryHandle,
+        layer_name: &str,
+        shard: &Shard,
+        reduce: Reduce,
+    ) -> Result<(), RegistryError> {
+        let mut tensors = StateDict::new();
+        let (kind, config) = match reduce {
+            Reduce::Concat => {
+                self.insert_rows(&mut tensors, "", shard.begin, shard.end);
+                (LinearINT8::KIND, LayerConfig::None)
+            }
+            Reduce::Sum => {
+                self.insert_columns(&mut tensors, "inner.", shard.begin, shard.end);
+                let range = ColumnRange {
+                    begin: shard.begin,
+                    in_dim: self.in_dim,
+                };
+                (Columns::<LinearINT8>::KIND, LayerConfig::Columns(range))
+            }
+        };
 
-        let chunk_size = out_dim / handlers.len();
-        let n_handlers = handlers.len();
+        handle
+            .add_layer(shard.name(layer_name), kind, config, &tensors)
+            .await
+    }
+}
 
-        for (handler_idx, handler) in handlers.iter_mut().enumerate() {
-            let begin = handler_idx * chunk_size;
-            let end = match handler_idx == n_handlers - 1 {
-                true => out_dim,
-                false => (handler_idx + 1) * chunk_size,
-            };
+impl ParallelINT8Linear {
+    /// Shards the layer over the first `n_workers` alive workers.
+    pub(crate) async fn from_weights(
+        name: String,
+        weights: &INT8Weights,
+        n_workers: usize,
+        split: Split,
+    ) -> Result<Self, RegistryError> {
+        let mut handles = lock_handles().await;
+        let handles = handles.borrow_mut();
 
-            let chunk_int8_values = &int8_values[begin * in_dim..end * in_dim];
+        let workers = load_workers(handles, n_workers)?;
 
-            handler
-                .add_int8(prefix.to_string(), &max_values, chunk_int8_values)
-                .await;
+        let shards = match split {
+            Split::Rows => split_rows(weights.out_dim, &workers),
+            Split::Columns => split_rows(weights.in_dim, &workers),
+        };
+        for shard in &shards {
+            weights
+                .upload(&mut handles[shard.worker], &name, shard, split.reduce())
+                .await?;
         }
 
         Ok(ParallelINT8Linear {
-            name: prefix.to_string(),
-            out_dim,
-            in_dim,
+            name,
+            out_dim: weights.out_dim,
+            in_dim: weights.in_dim,
+            split,
+            shards,
         })
     }
 }
 
 #[async_trait(?Send)]
-impl Module for ParallelINT8Linear {
-    async fn forward(&mut self, x: &[f32]) -> OwnedMatrix {
+impl FromStateDict<'static> for ParallelINT8Linear {
+    async fn from_state_dict(
+        source: &dyn TensorProvider<'static>,
+        prefix: &str,
+    ) -> Result<Self, LoadError> {
+        let weights = INT8Weights::from_state_dict(source, prefix).await?;
+
+        let (n_layer_workers, split) = get_optimal_int8_sharding(&weights)
+            .await
+            .map_err(|err| LoadError::backend(prefix, err))?;
+
+        Self::from_weights(prefix.to_string(), &weights, n_layer_workers, split)
+            .await
+            .map_err(|err| LoadError::backend(prefix, err))
+    }
+}
+
+impl ParallelINT8Linear {
+    /// Like `forward`, but keeps the `RegistryError`.
+    pub async fn try_forward(&mut self, x: &[f32]) -> Result<OwnedMatrix, RegistryError> {
+        let mut outputs = Self::try_forward_batch(&mut [self], x).await?;
+        Ok(outputs.remove(0))
+    }
+
+    /// Like `forward_batch`, but keeps the `RegistryError`.
+    /// Shards of dead workers are moved to the surviving ones first.
+    pub async fn try_forward_batch(
+        modules: &mut [&mut Self],
+        x: &[f32],
+    ) -> Result<Vec<OwnedMatrix>, RegistryError> {
         let x = Matrix::from_slice((1, x.len()), x);
 
-        let mut handlers = lock_handles().await;
-        assert_ne!(handlers.borrow().len(), 0);
+        let mut handles = lock_handles().await;
+        check_handles(handles.borrow())?;
 
-        let futures = handlers
-            .borrow_mut()
+        let mut layers: Vec<(&str, &mut [Shard], Reduce)> = modules
             .iter_mut()
-            .map(|handler| handler.int8_forward(&self.name, &x));
-
-        let output = join_all(futures).await;
+            .map(|module| {
+                let reduce = module.split.reduce();
+                (module.name.as_str(), &mut module.shards[..], reduce)
+            })
+            .collect();
+        forward_layers::<INT8Weights>(handles.borrow_mut(), &mut layers, &x).await
+    }
+}
 
-        cat_row(&output)
+#[async_trait(?Send)]
+impl Module for ParallelINT8Linear {
+    async fn forward(&mut self, x: &[f32]) -> Result<OwnedMatrix, ForwardError> {
+        self.try_forward(x)
+            .await
+            .map_err(|err| forward_error(&[&self.name], err))
     }
 
     fn shape(&self) -> (usize, usize) {
         (self.out_dim, self.in_dim)
     }
+
+    /// Sends the shards each worker holds in a single request.
+    async fn forward_batch(
+        modules: &mut [&mut Self],
+        x: &[f32],
+    ) -> Result<Vec<OwnedMatrix>, ForwardError> {
+        Self::try_forward_batch(modules, x).await.map_err(|err| {
+            let names: Vec<&str> = modules.iter().map(|module| module.name.as_str()).collect();
+            forward_error(&names, err)
+        })
+    }
+}
+
+impl ParallelINT8Linear {
+    /// Removes the shards from the workers that are still alive.
+    pub async fn async_drop(&mut self) -> Result<(), RegistryError> {
+        let mut handles = lock_handles().await;
+        check_handles(handles.borrow())?;
+
+        remove_shards(handles.borrow_mut(), &self.name, &self.shards).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parallel_aqlm::{set_handles, set_weight_source};
+    use crate::test_weights::assert_close;
+    use crate::thread_transport::tests::{Fault, FaultyTransport};
+
+    #[tokio::test]
+    async fn test_matches_local_linear() {
+        let handles = (0..3)
+            .map(|_| RPCLinearRegistryHandle::spawn_thread())
+            .collect();
+        set_handles(handles).await;
+
+        let (out_dim, in_dim) = (7, 16);
+        let int8_values: Vec<i8> = (0..out_dim * in_dim)
+            .map(|idx| (idx * 37 % 255) as i8)
+            .collect();
+        let x: Vec<f32> = (0..in_dim).map(|idx| idx as f32 / 8.0 - 1.0).collect();
+
+        let mut state_dict = StateDict::new();
+        state_dict.insert_i8("per_row.weight_int8", &[out_dim, in_dim], &int8_values);
+        state_dict.insert_f32("per_row.weight_max_values", &[out_dim, 1], &[0.5; 7]);
+        state_dict.insert_i8("per_column.weight_int8", &[out_dim, in_dim], &int8_values);
+        state_dict.insert_f32("per_column.weight_max_values", &[in_dim], &[2.0; 16]);
+
+        for prefix in ["per_row.", "per_column."] {
+            let mut parallel = ParallelINT8Linear::from_state_dict(&state_dict, prefix)
+                .await
+                .unwrap();
+            let mut local = LinearINT8::from_state_dict(&state_dict, prefix)
+                .await
+                .unwrap();
+            assert_eq!(parallel.shape(), local.shape());
+
+            let expected = local.forward(&x).await.unwrap();
+            assert_close(&parallel.forward(&x).await.unwrap(), &expected);
+            parallel.async_drop().await.unwrap();
+
+            let weights = INT8Weights::from_state_dict(&state_dict, prefix)
+                .await
+                .unwrap();
+            for split in [Split::Rows, Split::Columns] {
+                let mut parallel =
+                    ParallelINT8Linear::from_weights(prefix.to_string(), &weights, 3, split)
+                        .await
+                        .unwrap();
+                assert_eq!(parallel.shape(), local.shape());
+                assert_close(&parallel.forward(&x).await.unwrap(), &expected);
+                parallel.async_drop().await.unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_recover_from_disconnect() {
+        let mut faults = Vec::new();
+        let mut handles = Vec::new();
+        for _ in 0..2 {
+            let (transport, fault) = FaultyTransport::spawn();
+            handles.push(RPCLinearRegistryHandle::new(transport));
+            faults.push(fault);
+        }
+        set_handles(handles).await;
+
+        let (out_dim, in_dim) = (5, 8);
+        let int8_values: Vec<i8> = (0..out_dim * in_dim).map(|idx| idx as i8).collect();
+        let mut state_dict = StateDict::new();
+        state_dict.insert_i8("head.weight_int8", &[out_dim, in_dim], &int8_values);
+        state_dict.insert_f32("head.weight_max_values", &[out_dim, 1], &[1.0; 5]);
+        let state_dict = std::rc::Rc::new(state_dict);
+
+        let mut parallel = ParallelINT8Linear::from_state_dict(state_dict.as_ref(), "head.")
+            .await
+            .unwrap();
+        let mut local = LinearINT8::from_state_dict(state_dict.as_ref(), "head.")
+            .await
+            .unwrap();
+        let x = [0.25; 8];
+        let expected = local.forward(&x).await.unwrap();
+
+        // Without a weight source the shard can't be moved.
+        faults[0].set(Fault::Disconnect);
+        let err = parallel.try_forward(&x).await.unwrap_err();
+        assert_eq!(err.code, crate::registry_rpc::ErrorCode::RecoveryFailed);
+
+        set_weight_source(Some(state_dict.clone()));
+        assert_close(&parallel.try_forward(&x).await.unwrap(), &expected);
+        set_weight_source(None);
+    }
 }
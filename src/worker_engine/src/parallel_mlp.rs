@@ -0,0 +1,252 @@
+use crate::handles::lock_handles;
+use crate::parallel_aqlm::AQLMWeights;
+use crate::registry::RegistryLayer;
+use crate::registry_rpc::{LayerConfig, RegistryError};
+use crate::registry_rpc_handle::RPCLinearRegistryHandle;
+use crate::sharding::{
+    check_handles, forward_error, forward_layers, load_workers, remove_shards, split_groups,
+    Reduce, Shard, ShardWeights,
+};
+use async_trait::async_trait;
+use nn::linear::{ForwardError, Module};
+use nn::linear_aqlm::LinearAQLM;
+use nn::mlp::MLP;
+use state_dict::error::LoadError;
+use state_dict::from_state_dict::{FromStateDict, TensorProvider};
+use state_dict::state_dict::StateDict;
+use tensorlib::matrix::{Matrix, OwnedMatrix};
+
+/// An AQLM `MLP` split by hidden units. Every worker holds the matching rows
+/// of `gate_proj` and `up_proj` and columns of `down_proj`, so it runs the
+/// whole block on its slice and only the partial outputs are summed here.
+pub struct ParallelAQLMMLP {
+    name: String,
+    dim: usize,
+    shards: Vec<Shard>,
+}
+
+struct MLPWeights {
+    gate_proj: AQLMWeights<'static>,
+    up_proj: AQLMWeights<'static>,
+    down_proj: AQLMWeights<'static>,
+}
+
+#[async_trait(?Send)]
+impl FromStateDict<'static> for MLPWeights {
+    async fn from_state_dict(
+        source: &dyn TensorProvider<'static>,
+        prefix: &str,
+    ) -> Result<Self, LoadError> {
+        let gate_proj =
+            AQLMWeights::from_state_dict(source, &format!("{prefix}gate_proj.")).await?;
+        let up_proj = AQLMWeights::from_state_dict(source, &format!("{prefix}up_proj.")).await?;
+        let down_proj =
+            AQLMWeights::from_state_dict(source, &format!("{prefix}down_proj.")).await?;
+
+        let (hidden_dim, dim) = (gate_proj.out_dim, gate_proj.in_group_dim * 8);
+        for (name, weights, expected) in [
+            ("up_proj", &up_proj, [hidden_dim, dim]),
+            ("down_proj", &down_proj, [dim, hidden_dim]),
+        ] {
+            let actual = [weights.out_dim, weights.in_group_dim * 8];
+            if actual != expected {
+                return Err(LoadError::shape_mismatch(
+                    &format!("{prefix}{name}."),
+                    expected.to_vec(),
+                    actual.to_vec(),
+                ));
+            }
+        }
+
+        Ok(Self {
+            gate_proj,
+            up_proj,
+            down_proj,
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl ShardWeights for MLPWeights {
+    async fn upload(
+        &self,
+        handle: &mut RPCLinearRegistryHandle,
+        layer_name: &str,
+        shard: &Shard,
+        _reduce: Reduce,
+    ) -> Result<(), RegistryError> {
+        let (begin, end) = (shard.begin, shard.end);
+
+        let mut tensors = StateDict::new();
+        self.gate_proj
+            .insert_rows(&mut tensors, "gate_proj.", begin, end);
+        self.up_proj
+            .insert_rows(&mut tensors, "up_proj.", begin, end);
+        self.down_proj
+            .insert_columns(&mut tensors, "down_proj.", begin, end);
+
+        handle
+            .add_layer(
+                shard.name(layer_name),
+                MLP::<LinearAQLM>::KIND,
+                LayerConfig::None,
+                &tensors,
+            )
+            .await
+    }
+}
+
+#[async_trait(?Send)]
+impl FromStateDict<'static> for ParallelAQLMMLP {
+    async fn from_state_dict(
+        source: &dyn TensorProvider<'static>,
+        prefix: &str,
+    ) -> Result<Self, LoadError> {
+        let weights = MLPWeights::from_state_dict(source, prefix).await?;
+        let hidden_dim = weights.gate_proj.out_dim;
+
+        let mut handles = lock_handles().await;
+        let handles = handles.borrow_mut();
+
+        // down_proj columns can only be split between groups of 8.
+        let workers =
+            load_workers(handles, hidden_dim / 8).map_err(|err| LoadError::backend(prefix, err))?;
+
+        let shards = split_groups(hidden_dim, 8, &workers);
+        for shard in &shards {
+            weights
+                .upload(&mut handles[shard.worker], prefix, shard, Reduce::Sum)
+                .await
+                .map_err(|err| LoadError::backend(prefix, err))?;
+        }
+
+        Ok(Self {
+            name: prefix.to_string(),
+            dim: weights.down_proj.out_dim,
+            shards,
+        })
+    }
+}
+
+impl ParallelAQLMMLP {
+    /// Like `forward`, but keeps the `RegistryError`.
+    /// Shards of dead workers are moved to the surviving ones first.
+    pub async fn try_forward(&mut self, x: &[f32]) -> Result<OwnedMatrix, RegistryError> {
+        let x = Matrix::from_slice((1, x.len()), x);
+
+        let mut handles = lock_handles().await;
+        check_handles(handles.borrow())?;
+
+        let mut layers = [(self.name.as_str(), &mut self.shards[..], Reduce::Sum)];
+        let mut outputs =
+            forward_layers::<MLPWeights>(handles.borrow_mut(), &mut layers, &x).await?;
+        Ok(outputs.remove(0))
+    }
+}
+
+#[async_trait(?Send)]
+impl Module for ParallelAQLMMLP {
+    async fn forward(&mut self, x: &[f32]) -> Result<OwnedMatrix, ForwardError> {
+        self.try_forward(x)
+            .await
+            .map_err(|err| forward_error(&[&self.name], err))
+    }
+
+    fn shape(&self) -> (usize, usize) {
+        (self.dim, self.dim)
+    }
+}
+
+impl ParallelAQLMMLP {
+    /// Removes the shards from the workers that are still alive.
+    pub async fn async_drop(&mut self) -> Result<(), RegistryError> {
+        let mut handles = lock_handles().await;
+        check_handles(handles.borrow())?;
+
+        remove_shards(handles.borrow_mut(), &self.name, &self.shards).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parallel_aqlm::{set_handles, set_weight_source};
+    use crate::registry_rpc::ErrorCode;
+    use crate::test_weights::{assert_close, insert_aqlm};
+    use crate::thread_transport::tests::{Fault, FaultyTransport};
+    use std::rc::Rc;
+
+    const DIM: usize = 16;
+    const HIDDEN_DIM: usize = 48;
+
+    fn mlp_state_dict() -> StateDict {
+        let mut state_dict = StateDict::new();
+        insert_aqlm(&mut state_dict, "mlp.gate_proj.", HIDDEN_DIM, DIM);
+        insert_aqlm(&mut state_dict, "mlp.up_proj.", HIDDEN_DIM, DIM);
+        insert_aqlm(&mut state_dict, "mlp.down_proj.", DIM, HIDDEN_DIM);
+        state_dict
+    }
+
+    #[tokio::test]
+    async fn test_matches_local_mlp() {
+        let handles = (0..4)
+            .map(|_| RPCLinearRegistryHandle::spawn_thread())
+            .collect();
+        set_handles(handles).await;
+
+        let state_dict = mlp_state_dict();
+        let mut parallel = ParallelAQLMMLP::from_state_dict(&state_dict, "mlp.")
+            .await
+            .unwrap();
+        let mut local = MLP::<LinearAQLM>::from_state_dict(&state_dict, "mlp.")
+            .await
+            .unwrap();
+        assert_eq!(parallel.shape(), local.shape());
+
+        let bounds: Vec<_> = parallel
+            .shards
+            .iter()
+            .map(|shard| (shard.begin, shard.end))
+            .collect();
+        assert_eq!(bounds, [(0, 8), (8, 16), (16, 24), (24, 48)]);
+
+        let x: Vec<f32> = (0..DIM).map(|idx| idx as f32 / 8.0 - 1.0).collect();
+        assert_close(
+            &parallel.forward(&x).await.unwrap(),
+            &local.forward(&x).await.unwrap(),
+        );
+
+        parallel.async_drop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_recover_from_disconnect() {
+        let mut faults = Vec::new();
+        let mut handles = Vec::new();
+        for _ in 0..3 {
+            let (transport, fault) = FaultyTransport::spawn();
+            handles.push(RPCLinearRegistryHandle::new(transport));
+            faults.push(fault);
+        }
+        set_handles(handles).await;
+
+        let state_dict = Rc::new(mlp_state_dict());
+        let mut parallel = ParallelAQLMMLP::from_state_dict(state_dict.as_ref(), "mlp.")
+            .await
+            .unwrap();
+        let mut local = MLP::<LinearAQLM>::from_state_dict(state_dict.as_ref(), "mlp.")
+            .await
+            .unwrap();
+        let x = [0.25; DIM];
+        let expected = local.forward(&x).await.unwrap();
+
+        faults[1].set(Fault::Disconnect);
+        let err = parallel.try_forward(&x).await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::RecoveryFailed);
+
+        set_weight_source(Some(state_dict.clone()));
+        assert_close(&parallel.try_forward(&x).await.unwrap(), &expected);
+        assert!(parallel.shards.iter().all(|shard| shard.worker != 1));
+        set_weight_source(None);
+    }
+}
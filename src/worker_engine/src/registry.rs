@@ -1,15 +1,145 @@
+use crate::parallel_attention::AttentionHeads;
+use crate::registry_rpc::{ColumnRange, ErrorCode, LayerConfig, LayerInfo, RegistryError};
+use crate::sharding::Columns;
+use async_trait::async_trait;
+use futures::future::LocalBoxFuture;
 use nn::linear::Module;
 use nn::linear_aqlm::LinearAQLM;
 use nn::linear_int8::LinearINT8;
-use nn::matrix_int8::MatrixInt8;
-use std::borrow::Cow;
+use nn::mlp::MLP;
+use state_dict::error::LoadError;
+use state_dict::from_state_dict::{FromStateDict, FromStateDictConf};
+use state_dict::state_dict::StateDict;
 use std::collections::HashMap;
-use tensorlib::matrix::Matrix;
+use tensorlib::matrix::{Matrix, OwnedMatrix};
+
+/// A layer type the registry can host. `KIND` names the type in layer
+/// specs, and layers are built from the spec's tensors, named without a
+/// prefix, and its `LayerConfig`.
+#[async_trait(?Send)]
+pub trait RegistryLayer: Module + Sized + 'static {
+    const KIND: &'static str;
+    /// The kernels the type's forward runs, as reported in `Capabilities`.
+    const KERNELS: &'static [&'static str];
+
+    /// The variant of `LayerConfig` the kind takes.
+    type Config: TryFrom<LayerConfig, Error = LayerConfig>;
+
+    async fn from_spec(tensors: &StateDict, config: Self::Config) -> Result<Self, LoadError>;
+}
+
+#[async_trait(?Send)]
+impl RegistryLayer for LinearAQLM<'static> {
+    const KIND: &'static str = "aqlm";
+    const KERNELS: &'static [&'static str] = &["aqlm_2x8"];
+    type Config = ();
+
+    async fn from_spec(tensors: &StateDict, _: ()) -> Result<Self, LoadError> {
+        Self::from_state_dict(tensors, "").await
+    }
+}
+
+#[async_trait(?Send)]
+impl RegistryLayer for LinearINT8<'static> {
+    const KIND: &'static str = "int8";
+    const KERNELS: &'static [&'static str] = &["int8_per_column", "int8_per_row"];
+    type Config = ();
+
+    async fn from_spec(tensors: &StateDict, _: ()) -> Result<Self, LoadError> {
+        Self::from_state_dict(tensors, "").await
+    }
+}
+
+#[async_trait(?Send)]
+impl RegistryLayer for Columns<LinearAQLM<'static>> {
+    const KIND: &'static str = "aqlm_columns";
+    const KERNELS: &'static [&'static str] = &["aqlm_2x8"];
+    type Config = ColumnRange;
+
+    async fn from_spec(tensors: &StateDict, range: ColumnRange) -> Result<Self, LoadError> {
+        Self::from_state_dict(tensors, "", range).await
+    }
+}
+
+#[async_trait(?Send)]
+impl RegistryLayer for Columns<LinearINT8<'static>> {
+    const KIND: &'static str = "int8_columns";
+    const KERNELS: &'static [&'static str] = &["int8_per_column", "int8_per_row"];
+    type Config = ColumnRange;
+
+    async fn from_spec(tensors: &StateDict, range: ColumnRange) -> Result<Self, LoadError> {
+        Self::from_state_dict(tensors, "", range).await
+    }
+}
+
+#[async_trait(?Send)]
+impl RegistryLayer for MLP<LinearAQLM<'static>> {
+    const KIND: &'static str = "aqlm_mlp";
+    const KERNELS: &'static [&'static str] = &["aqlm_2x8"];
+    type Config = ();
+
+    async fn from_spec(tensors: &StateDict, _: ()) -> Result<Self, LoadError> {
+        Self::from_state_dict(tensors, "").await
+    }
+}
+
+type Builder =
+    fn(StateDict, LayerConfig) -> LocalBoxFuture<'static, Result<Box<dyn Module>, RegistryError>>;
+
+fn build<L: RegistryLayer>(
+    tensors: StateDict,
+    config: LayerConfig,
+) -> LocalBoxFuture<'static, Result<Box<dyn Module>, RegistryError>> {
+    Box::pin(async move {
+        let config = L::Config::try_from(config).map_err(|config| {
+            RegistryError::new(
+                ErrorCode::InvalidSpec,
+                format!("{} layers don't take {config:?}", L::KIND),
+            )
+        })?;
+        let layer = L::from_spec(&tensors, config).await.map_err(|err| {
+            let code = match err {
+                LoadError::ShapeMismatch { .. } | LoadError::DtypeMismatch { .. } => {
+                    ErrorCode::ShapeMismatch
+                }
+                _ => ErrorCode::InvalidSpec,
+            };
+            RegistryError::new(code, err)
+        })?;
+        Ok(Box::new(layer) as Box<dyn Module>)
+    })
+}
+
+/// How to build a registered kind, and what that needs.
+struct Kind {
+    build: Builder,
+    kernels: &'static [&'static str],
+}
+
+struct Entry {
+    kind: &'static str,
+    layer: Box<dyn Module>,
+}
 
-#[derive(Default)]
 pub struct LocalLinearRegistry {
-    aqlm_storage: HashMap<String, LinearAQLM<'static>>,
-    int8_storage: HashMap<String, LinearINT8<'static>>,
+    kinds: HashMap<&'static str, Kind>,
+    layers: HashMap<String, Entry>,
+}
+
+impl Default for LocalLinearRegistry {
+    fn default() -> Self {
+        let mut registry = Self {
+            kinds: HashMap::new(),
+            layers: HashMap::new(),
+        };
+        registry.register::<LinearAQLM>();
+        registry.register::<LinearINT8>();
+        registry.register::<Columns<LinearAQLM>>();
+        registry.register::<Columns<LinearINT8>>();
+        registry.register::<MLP<LinearAQLM>>();
+        registry.register::<AttentionHeads>();
+        registry
+    }
 }
 
 impl LocalLinearRegistry {
@@ -17,55 +147,280 @@ impl LocalLinearRegistry {
         data
     }
 
-    pub async fn add_aqlm(
+    /// Lets clients add layers of type `L`.
+    pub fn register<L: RegistryLayer>(&mut self) {
+        let kind = Kind {
+            build: build::<L>,
+            kernels: L::KERNELS,
+        };
+        self.kinds.insert(L::KIND, kind);
+    }
+
+    /// The layer kinds that can be added, sorted.
+    pub fn kinds(&self) -> Vec<String> {
+        let mut kinds: Vec<String> = self.kinds.keys().map(|kind| kind.to_string()).collect();
+        kinds.sort();
+        kinds
+    }
+
+    /// The kernels the registered kinds run, sorted and without duplicates.
+    pub fn kernels(&self) -> Vec<String> {
+        let mut kernels: Vec<String> = self
+            .kinds
+            .values()
+            .flat_map(|kind| kind.kernels)
+            .map(|kernel| kernel.to_string())
+            .collect();
+        kernels.sort();
+        kernels.dedup();
+        kernels
+    }
+
+    /// Builds a layer of the given kind, replacing any layer of the same name.
+    pub async fn add(
         &mut self,
         name: String,
-        codebooks: Vec<f32>,
-        scales: Vec<f32>,
-        codes: Vec<u8>,
-        out_dim: usize,
-        in_group_dim: usize,
-    ) {
-        // info!("add_aqlm {}", name);
-        self.aqlm_storage.insert(
-            name,
-            LinearAQLM::new(
-                Cow::Owned(codebooks),
-                Cow::Owned(scales),
-                Cow::Owned(codes),
-                out_dim,
-                in_group_dim,
+        kind: &str,
+        config: LayerConfig,
+        tensors: StateDict,
+    ) -> Result<(), RegistryError> {
+        // info!("add {} {}", kind, name);
+        let (&kind, Kind { build, .. }) = self.kinds.get_key_value(kind).ok_or_else(|| {
+            RegistryError::new(
+                ErrorCode::UnknownKind,
+                format!("{name}: no layer kind {kind}"),
+            )
+        })?;
+
+        let layer = build(tensors, config)
+            .await
+            .map_err(|err| RegistryError::new(err.code, format!("{name}: {}", err.message)))?;
+
+        self.layers.insert(name, Entry { kind, layer });
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<(), RegistryError> {
+        // info!("remove {}", name);
+        self.layers
+            .remove(name)
+            .map(|_| ())
+            .ok_or_else(|| RegistryError::unknown_layer(name))
+    }
+
+    pub async fn forward(
+        &mut self,
+        name: &str,
+        other: &Matrix<'_>,
+    ) -> Result<OwnedMatrix, RegistryError> {
+        // info!("forward {}", name);
+        let layer = &mut self
+            .layers
+            .get_mut(name)
+            .ok_or_else(|| RegistryError::unknown_layer(name))?
+            .layer;
+        check_input(name, layer.as_ref(), other)?;
+        layer
+            .forward(other.data())
+            .await
+            .map_err(|err| RegistryError::new(ErrorCode::ForwardFailed, format!("{name}: {err}")))
+    }
+
+    /// Runs several layers on the same input, returning the outputs in order.
+    pub async fn forward_batch(
+        &mut self,
+        names: &[impl AsRef<str>],
+        other: &Matrix<'_>,
+    ) -> Result<Vec<OwnedMatrix>, RegistryError> {
+        let mut outputs = Vec::with_capacity(names.len());
+        for name in names {
+            outputs.push(self.forward(name.as_ref(), other).await?);
+        }
+        Ok(outputs)
+    }
+
+    /// Clears the caches of several layers. Fails without clearing anything
+    /// if any of them doesn't exist.
+    pub fn clear_cache(&mut self, names: &[impl AsRef<str>]) -> Result<(), RegistryError> {
+        if let Some(name) = names
+            .iter()
+            .find(|name| !self.layers.contains_key(name.as_ref()))
+        {
+            return Err(RegistryError::unknown_layer(name.as_ref()));
+        }
+
+        for name in names {
+            self.layers
+                .get_mut(name.as_ref())
+                .unwrap()
+                .layer
+                .clear_cache();
+        }
+        Ok(())
+    }
+
+    /// Every hosted layer, sorted by name.
+    pub fn list(&self) -> Vec<LayerInfo> {
+        let mut layers: Vec<LayerInfo> = self
+            .layers
+            .iter()
+            .map(|(name, entry)| {
+                let (out_dim, in_dim) = entry.layer.shape();
+                LayerInfo {
+                    name: name.clone(),
+                    kind: entry.kind.to_string(),
+                    out_dim,
+                    in_dim,
+                }
+            })
+            .collect();
+        layers.sort_by(|a, b| a.name.cmp(&b.name));
+        layers
+    }
+}
+
+/// Layers take a single row of `in_dim` values.
+fn check_input(name: &str, layer: &dyn Module, other: &Matrix) -> Result<(), RegistryError> {
+    let in_dim = layer.shape().1;
+    if other.shape() != (1, in_dim) {
+        return Err(RegistryError::new(
+            ErrorCode::ShapeMismatch,
+            format!(
+                "{name}: expected a (1, {in_dim}) input, got {:?}",
+                other.shape()
             ),
-        );
+        ));
     }
+    Ok(())
+}
 
-    pub async fn add_int8(&mut self, name: String, max_values: Vec<f32>, int8_values: Vec<i8>) {
-        // info!("add_int8");
-        self.int8_storage.insert(
-            name,
-            LinearINT8::new(MatrixInt8::new(
-                Cow::Owned(max_values),
-                Cow::Owned(int8_values),
-            )),
-        );
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nn::linear::ForwardError;
+    use state_dict::from_state_dict::load_f32_data;
+
+    /// Multiplies its input by a scalar.
+    struct Scale {
+        factor: f32,
+        dim: usize,
+    }
+
+    #[async_trait(?Send)]
+    impl Module for Scale {
+        async fn forward(&mut self, x: &[f32]) -> Result<OwnedMatrix, ForwardError> {
+            let data: Vec<f32> = x.iter().map(|v| v * self.factor).collect();
+            Ok(Matrix::from_slice((1, self.dim), &data).into_owned())
+        }
+
+        fn shape(&self) -> (usize, usize) {
+            (self.dim, self.dim)
+        }
     }
 
-    pub async fn remove_aqlm(&mut self, name: String) {
-        // info!("remove_aqlm {}", name);
-        self.aqlm_storage.remove(&name).unwrap();
+    #[async_trait(?Send)]
+    impl RegistryLayer for Scale {
+        const KIND: &'static str = "scale";
+        const KERNELS: &'static [&'static str] = &["scale_f32"];
+        type Config = ();
+
+        async fn from_spec(tensors: &StateDict, _: ()) -> Result<Self, LoadError> {
+            let (factor, shape) = load_f32_data(tensors, "factor").await?;
+            Ok(Self {
+                factor: factor[0],
+                dim: shape[0],
+            })
+        }
     }
 
-    pub async fn aqlm_forward(&mut self, name: &str, other: &Matrix<'_>) -> Matrix<'_> {
-        // info!("aqlm_forward {}", name);
-        let linear = self.aqlm_storage.get_mut(name).unwrap();
-        assert_eq!(other.n_rows(), 1);
-        linear.forward(other.data()).await
+    fn scale_tensors(dim: usize, factor: f32) -> StateDict {
+        let mut tensors = StateDict::new();
+        tensors.insert_f32("factor", &[dim], &vec![factor; dim]);
+        tensors
     }
 
-    pub async fn int8_forward(&mut self, name: &str, other: &Matrix<'_>) -> Matrix<'_> {
-        // info!("int8_forward");
-        let linear = self.int8_storage.get_mut(name).unwrap();
-        assert_eq!(other.n_rows(), 1);
-        linear.forward(other.data()).await
+    #[tokio::test]
+    async fn test_custom_layer_kind() {
+        let mut registry = LocalLinearRegistry::default();
+        let err = registry
+            .add(
+                "double".to_string(),
+                "scale",
+                LayerConfig::None,
+                scale_tensors(3, 2.0),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::UnknownKind);
+
+        registry.register::<Scale>();
+        assert_eq!(
+            registry.kinds(),
+            [
+                "aqlm",
+                "aqlm_attention",
+                "aqlm_columns",
+                "aqlm_mlp",
+                "int8",
+                "int8_columns",
+                "scale"
+            ]
+        );
+        assert_eq!(
+            registry.kernels(),
+            ["aqlm_2x8", "int8_per_column", "int8_per_row", "scale_f32"]
+        );
+        registry
+            .add(
+                "double".to_string(),
+                "scale",
+                LayerConfig::None,
+                scale_tensors(3, 2.0),
+            )
+            .await
+            .unwrap();
+
+        let x = Matrix::from_slice((1, 3), &[1.0, 2.0, 3.0]);
+        let output = registry.forward("double", &x).await.unwrap();
+        assert_eq!(output.data()[..], [2.0, 4.0, 6.0]);
+
+        let err = registry
+            .add(
+                "broken".to_string(),
+                "int8",
+                LayerConfig::None,
+                scale_tensors(3, 2.0),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidSpec);
+
+        // A config for another kind is rejected before reading any tensor.
+        let range = ColumnRange {
+            begin: 0,
+            in_dim: 3,
+        };
+        let err = registry
+            .add(
+                "broken".to_string(),
+                "scale",
+                LayerConfig::Columns(range),
+                scale_tensors(3, 2.0),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidSpec);
+
+        assert_eq!(
+            registry.list(),
+            [LayerInfo {
+                name: "double".to_string(),
+                kind: "scale".to_string(),
+                out_dim: 3,
+                in_dim: 3,
+            }]
+        );
+        registry.remove("double").unwrap();
+        assert!(registry.list().is_empty());
     }
 }
@@ -2,6 +2,7 @@ use crate::matrix_serde::SerdeMatrix;
 use crate::registry::LocalLinearRegistry;
 use serde::{Deserialize, Serialize};
 use speedy::{Readable, Writable};
+use state_dict::state_dict::StateDict;
 use std::borrow::Cow;
 use wasm_bindgen::prelude::wasm_bindgen;
 
@@ -21,142 +22,362 @@ impl RPCLinearRegistryServer {
 #[wasm_bindgen]
 impl RPCLinearRegistryServer {
     pub async fn serve_serialized(&mut self, request: &[u8]) -> Vec<u8> {
-        let request: Request = Request::read_from_buffer(request).unwrap();
-        let response = self.serve(request).await;
-        response.write_to_vec().unwrap()
+        let response = match Request::read_from_buffer(request) {
+            Ok(request) => self.serve(request).await,
+            Err(err) => Response::Error(RegistryError::new(
+                ErrorCode::DecodeFailed,
+                format!("failed to decode request: {err}"),
+            )),
+        };
+        encode_response(&response)
     }
 }
 
+/// Encodes `response`, or an `EncodeFailed` error in its place. Should that
+/// fail too, the empty frame still fails to decode on the client.
+fn encode_response(response: &Response) -> Vec<u8> {
+    response.write_to_vec().unwrap_or_else(|err| {
+        log::error!("failed to encode a response: {err}");
+        Response::Error(RegistryError::new(
+            ErrorCode::EncodeFailed,
+            format!("failed to encode response: {err}"),
+        ))
+        .write_to_vec()
+        .unwrap_or_default()
+    })
+}
+
 impl RPCLinearRegistryServer {
     async fn serve(&mut self, request: Request<'_>) -> Response {
-        match request {
-            Request::AddAQLMRequest(request) => {
-                self.serve_add_aqlm(request).await;
-                Response::AddAQLMResponse
-            }
-            Request::AddINT8Request(request) => {
-                self.serve_add_int8(request).await;
-                Response::AddINT8Response
-            }
-            Request::RemoveAQLMRequest(request) => {
-                self.serve_remove_aqlm(request).await;
-                Response::RemoveAQLMResponse
-            }
-            Request::AQLMForwardRequest(request) => {
-                Response::AQLMForwardResponse(self.serve_aqlm_forward(request).await)
-            }
-            Request::INT8ForwardRequest(request) => {
-                Response::INT8ForwardResponse(self.serve_int8_forward(request).await)
+        let response = match request {
+            Request::HelloRequest(request) => {
+                Ok(Response::HelloResponse(self.serve_hello(request)))
             }
-        }
+            Request::AddLayerRequest(request) => self
+                .serve_add_layer(request)
+                .await
+                .map(|()| Response::AddLayerResponse),
+            Request::RemoveLayerRequest(request) => self
+                .inner
+                .remove(&request.name)
+                .map(|()| Response::RemoveLayerResponse),
+            Request::ForwardRequest(request) => self
+                .serve_forward(request)
+                .await
+                .map(Response::ForwardResponse),
+            Request::ForwardBatchRequest(request) => self
+                .serve_forward_batch(request)
+                .await
+                .map(Response::ForwardBatchResponse),
+            Request::ListLayersRequest => Ok(Response::ListLayersResponse(ListLayersResponse {
+                layers: self.inner.list(),
+            })),
+            Request::PingRequest => Ok(Response::PingResponse),
+            Request::ClearCacheRequest(request) => self
+                .inner
+                .clear_cache(&request.names)
+                .map(|()| Response::ClearCacheResponse),
+        };
+        response.unwrap_or_else(Response::Error)
     }
 
-    async fn serve_add_aqlm(&mut self, request: AddAQLMRequest<'_>) {
-        self.inner
-            .add_aqlm(
-                request.name,
-                request.codebooks.into_owned(),
-                request.scales.into_owned(),
-                request.codes.into_owned(),
-                request.out_dim,
-                request.in_group_dim,
-            )
-            .await;
+    fn serve_hello(&self, request: HelloRequest) -> HelloResponse {
+        if request.version != PROTOCOL_VERSION {
+            log::warn!(
+                "client speaks protocol version {}, this worker speaks {PROTOCOL_VERSION}",
+                request.version
+            );
+        }
+
+        HelloResponse {
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities {
+                layer_kinds: self.inner.kinds(),
+                kernels: self.inner.kernels(),
+            },
+        }
     }
 
-    async fn serve_add_int8(&mut self, request: AddINT8Request<'_>) {
+    async fn serve_add_layer(&mut self, request: AddLayerRequest<'_>) -> Result<(), RegistryError> {
+        let tensors = StateDict::from_safetensors(&request.spec.tensors).map_err(|err| {
+            RegistryError::new(ErrorCode::DecodeFailed, format!("{}: {err}", request.name))
+        })?;
+
         self.inner
-            .add_int8(
+            .add(
                 request.name,
-                request.max_values.into_owned(),
-                request.int8_values.into_owned(),
+                &request.spec.kind,
+                request.spec.config,
+                tensors,
             )
-            .await;
-    }
-
-    async fn serve_remove_aqlm(&mut self, request: RemoveAQLMRequest) {
-        self.inner.remove_aqlm(request.name).await;
+            .await
     }
 
-    async fn serve_aqlm_forward(&mut self, request: AQLMForwardRequest<'_>) -> AQLMForwardResponse {
+    async fn serve_forward(
+        &mut self,
+        request: ForwardRequest<'_>,
+    ) -> Result<ForwardResponse<'static>, RegistryError> {
         let output = self
             .inner
-            .aqlm_forward(&request.name, &request.other.into())
-            .await;
-        AQLMForwardResponse {
+            .forward(&request.name, &request.other.into())
+            .await?;
+        Ok(ForwardResponse {
             output: output.into(),
-        }
+        })
     }
 
-    async fn serve_int8_forward(&mut self, request: INT8ForwardRequest<'_>) -> INT8ForwardResponse {
-        let output = self
+    async fn serve_forward_batch(
+        &mut self,
+        request: ForwardBatchRequest<'_>,
+    ) -> Result<ForwardBatchResponse<'static>, RegistryError> {
+        let outputs = self
             .inner
-            .int8_forward(&request.name, &request.other.into())
-            .await;
-        INT8ForwardResponse {
-            output: output.into(),
-        }
+            .forward_batch(&request.names, &request.other.into())
+            .await?;
+        Ok(ForwardBatchResponse {
+            outputs: outputs.into_iter().map(SerdeMatrix::from).collect(),
+        })
     }
 }
 
+/// Bumped on every change to the encoding of `Request` or `Response`.
+pub const PROTOCOL_VERSION: u32 = 9;
+
+// The handshake has to decode the same way in every protocol version, so
+// `HelloRequest` and `HelloResponse` stay the first variants and keep their
+// fields.
 #[derive(Serialize, Deserialize, Readable, Writable)]
 pub enum Request<'a> {
-    AddAQLMRequest(AddAQLMRequest<'a>),
-    AddINT8Request(AddINT8Request<'a>),
-    RemoveAQLMRequest(RemoveAQLMRequest),
-    AQLMForwardRequest(AQLMForwardRequest<'a>),
-    INT8ForwardRequest(INT8ForwardRequest<'a>),
+    HelloRequest(HelloRequest),
+    AddLayerRequest(AddLayerRequest<'a>),
+    RemoveLayerRequest(RemoveLayerRequest),
+    ForwardRequest(ForwardRequest<'a>),
+    ForwardBatchRequest(ForwardBatchRequest<'a>),
+    ListLayersRequest,
+    PingRequest,
+    ClearCacheRequest(ClearCacheRequest<'a>),
 }
 
 #[derive(Serialize, Deserialize, Readable, Writable)]
-pub struct AddAQLMRequest<'a> {
-    pub name: String,
-    pub codebooks: Cow<'a, [f32]>,
-    pub scales: Cow<'a, [f32]>,
-    pub codes: Cow<'a, [u8]>,
-    pub out_dim: usize,
-    pub in_group_dim: usize,
+pub struct HelloRequest {
+    pub version: u32,
 }
 
 #[derive(Serialize, Deserialize, Readable, Writable)]
-pub struct RemoveAQLMRequest {
+pub struct AddLayerRequest<'a> {
     pub name: String,
+    pub spec: LayerSpec<'a>,
+}
+
+/// A layer to build on the worker: its registered kind, the settings that
+/// aren't weights, and its tensors as a safetensors file, named the way the
+/// kind's `RegistryLayer::from_spec` reads them.
+#[derive(Serialize, Deserialize, Readable, Writable)]
+pub struct LayerSpec<'a> {
+    pub kind: Cow<'a, str>,
+    pub config: LayerConfig,
+    pub tensors: Cow<'a, [u8]>,
+}
+
+/// The settings of a layer that aren't weights. Each kind takes one variant.
+#[derive(Serialize, Deserialize, Readable, Writable, Debug, Clone, Copy, PartialEq)]
+pub enum LayerConfig {
+    /// For kinds built from their tensors alone.
+    None,
+    Columns(ColumnRange),
+    Attention(HeadsConfig),
+}
+
+/// Input columns `begin..` of `in_dim`, held by a column shard.
+#[derive(Serialize, Deserialize, Readable, Writable, Debug, Clone, Copy, PartialEq)]
+pub struct ColumnRange {
+    pub begin: usize,
+    pub in_dim: usize,
+}
+
+/// The settings of attention heads whose counts follow from the projection
+/// shapes.
+#[derive(Serialize, Deserialize, Readable, Writable, Debug, Clone, Copy, PartialEq)]
+pub struct HeadsConfig {
+    pub head_dim: usize,
+    pub rope_theta: f32,
+}
+
+impl TryFrom<LayerConfig> for () {
+    type Error = LayerConfig;
+
+    fn try_from(config: LayerConfig) -> Result<Self, LayerConfig> {
+        match config {
+            LayerConfig::None => Ok(()),
+            config => Err(config),
+        }
+    }
+}
+
+impl TryFrom<LayerConfig> for ColumnRange {
+    type Error = LayerConfig;
+
+    fn try_from(config: LayerConfig) -> Result<Self, LayerConfig> {
+        match config {
+            LayerConfig::Columns(range) => Ok(range),
+            config => Err(config),
+        }
+    }
+}
+
+impl TryFrom<LayerConfig> for HeadsConfig {
+    type Error = LayerConfig;
+
+    fn try_from(config: LayerConfig) -> Result<Self, LayerConfig> {
+        match config {
+            LayerConfig::Attention(config) => Ok(config),
+            config => Err(config),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Readable, Writable)]
-pub struct AddINT8Request<'a> {
+pub struct RemoveLayerRequest {
     pub name: String,
-    pub max_values: Cow<'a, [f32]>,
-    pub int8_values: Cow<'a, [i8]>,
 }
 
 #[derive(Serialize, Deserialize, Readable, Writable)]
-pub struct AQLMForwardRequest<'a> {
+pub struct ForwardRequest<'a> {
     pub name: Cow<'a, str>,
     pub other: SerdeMatrix<'a>,
 }
 
+/// Runs several layers on the same input in one round trip.
 #[derive(Serialize, Deserialize, Readable, Writable)]
-pub struct INT8ForwardRequest<'a> {
-    pub name: Cow<'a, str>,
+pub struct ForwardBatchRequest<'a> {
+    pub names: Vec<Cow<'a, str>>,
     pub other: SerdeMatrix<'a>,
 }
 
+/// Makes stateful layers forget the tokens seen so far.
+#[derive(Serialize, Deserialize, Readable, Writable)]
+pub struct ClearCacheRequest<'a> {
+    pub names: Vec<Cow<'a, str>>,
+}
+
 #[derive(Serialize, Deserialize, Readable, Writable)]
 pub enum Response<'a> {
-    AddAQLMResponse,
-    AddINT8Response,
-    RemoveAQLMResponse,
-    AQLMForwardResponse(AQLMForwardResponse<'a>),
-    INT8ForwardResponse(INT8ForwardResponse<'a>),
+    HelloResponse(HelloResponse),
+    AddLayerResponse,
+    RemoveLayerResponse,
+    ForwardResponse(ForwardResponse<'a>),
+    ForwardBatchResponse(ForwardBatchResponse<'a>),
+    ListLayersResponse(ListLayersResponse),
+    PingResponse,
+    ClearCacheResponse,
+    Error(RegistryError),
+}
+
+#[derive(Serialize, Deserialize, Readable, Writable, Debug, Clone, PartialEq)]
+pub struct HelloResponse {
+    pub version: u32,
+    pub capabilities: Capabilities,
+}
+
+/// What a worker can serve, by name so that any version can decode it.
+#[derive(Serialize, Deserialize, Readable, Writable, Debug, Clone, PartialEq)]
+pub struct Capabilities {
+    pub layer_kinds: Vec<String>,
+    pub kernels: Vec<String>,
+}
+
+impl Capabilities {
+    /// The capabilities of this build's default registry.
+    pub fn local() -> Self {
+        let registry = LocalLinearRegistry::default();
+        Self {
+            layer_kinds: registry.kinds(),
+            kernels: registry.kernels(),
+        }
+    }
+
+    /// Everything in `self` that `other` lacks.
+    pub fn missing_from(&self, other: &Capabilities) -> Vec<String> {
+        let layer_kinds = self
+            .layer_kinds
+            .iter()
+            .filter(|kind| !other.layer_kinds.contains(kind));
+        let kernels = self
+            .kernels
+            .iter()
+            .filter(|kernel| !other.kernels.contains(kernel));
+
+        layer_kinds.chain(kernels).cloned().collect()
+    }
 }
 
 #[derive(Serialize, Deserialize, Readable, Writable)]
-pub struct AQLMForwardResponse<'a> {
+pub struct ForwardResponse<'a> {
     pub output: SerdeMatrix<'a>,
 }
 
 #[derive(Serialize, Deserialize, Readable, Writable)]
-pub struct INT8ForwardResponse<'a> {
-    pub output: SerdeMatrix<'a>,
+pub struct ForwardBatchResponse<'a> {
+    pub outputs: Vec<SerdeMatrix<'a>>,
+}
+
+#[derive(Serialize, Deserialize, Readable, Writable)]
+pub struct ListLayersResponse {
+    pub layers: Vec<LayerInfo>,
+}
+
+#[derive(Serialize, Deserialize, Readable, Writable, Debug, Clone, PartialEq)]
+pub struct LayerInfo {
+    pub name: String,
+    pub kind: String,
+    pub out_dim: usize,
+    pub in_dim: usize,
+}
+
+#[derive(Serialize, Deserialize, Readable, Writable, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    UnknownLayer,
+    ShapeMismatch,
+    DecodeFailed,
+    UnexpectedResponse,
+    Disconnected,
+    Timeout,
+    NoWorkers,
+    RecoveryFailed,
+    Incompatible,
+    UnknownKind,
+    InvalidSpec,
+    CacheLost,
+    ForwardFailed,
+    EncodeFailed,
+}
+
+/// A failed request, as reported by the worker or by the transport to it.
+#[derive(Serialize, Deserialize, Readable, Writable, Debug, Clone, PartialEq, thiserror::Error)]
+#[error("{code:?}: {message}")]
+pub struct RegistryError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl RegistryError {
+    pub fn new(code: ErrorCode, message: impl ToString) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+        }
+    }
+
+    pub fn unknown_layer(name: &str) -> Self {
+        Self::new(ErrorCode::UnknownLayer, format!("no layer named {name}"))
+    }
+
+    pub fn disconnected(message: impl ToString) -> Self {
+        Self::new(ErrorCode::Disconnected, message)
+    }
+
+    /// Whether the worker that failed can't be used anymore.
+    pub fn is_fatal(&self) -> bool {
+        matches!(self.code, ErrorCode::Disconnected | ErrorCode::Timeout)
+    }
 }
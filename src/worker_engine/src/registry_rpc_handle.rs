@@ -1,112 +1,392 @@
 use crate::registry_rpc::{
-    AQLMForwardRequest, AddAQLMRequest, AddINT8Request, INT8ForwardRequest, RemoveAQLMRequest,
-    Request, Response,
+    AddLayerRequest, Capabilities, ClearCacheRequest, ErrorCode, ForwardBatchRequest,
+    ForwardRequest, HelloRequest, HelloResponse, LayerConfig, LayerInfo, LayerSpec, RegistryError,
+    RemoveLayerRequest, Request, Response, PROTOCOL_VERSION,
 };
+use crate::transport::Transport;
+use futures::future::{select, Either};
 use speedy::{Readable, Writable};
+use state_dict::download::sleep_ms;
+use state_dict::state_dict::StateDict;
 use std::borrow::Cow;
+use std::time::Duration;
 use tensorlib::matrix::{Matrix, OwnedMatrix};
-use tokio::sync::mpsc;
-use web_sys::Worker;
 
+/// How long a worker may take to answer a single request.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Workers from before the handshake may never answer it.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A connection to one worker's registry. Once a request times out or the
+/// transport fails, the worker is considered dead and every later request
+/// fails right away.
 pub struct RPCLinearRegistryHandle {
-    worker: Worker,
-    response_rx: mpsc::Receiver<Vec<u8>>,
-    response_tx: mpsc::Sender<Vec<u8>>,
+    transport: Box<dyn Transport>,
+    timeout: Duration,
+    alive: bool,
 }
 
 impl RPCLinearRegistryHandle {
-    pub fn new(worker: Worker) -> Self {
-        let (response_tx, response_rx) = mpsc::channel(1);
-
+    pub fn new(transport: impl Transport + 'static) -> Self {
         Self {
-            worker,
-            response_rx,
-            response_tx,
+            transport: Box::new(transport),
+            timeout: DEFAULT_TIMEOUT,
+            alive: true,
         }
     }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn is_alive(&self) -> bool {
+        self.alive
+    }
+
+    /// A registry served from a new OS thread.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn spawn_thread() -> Self {
+        Self::new(crate::thread_transport::ThreadTransport::spawn())
+    }
+
+    /// A registry hosted by a `linear_worker` server.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn connect(addr: impl tokio::net::ToSocketAddrs) -> std::io::Result<Self> {
+        Ok(Self::new(
+            crate::tcp_transport::TcpTransport::connect(addr).await?,
+        ))
+    }
 }
 
 impl RPCLinearRegistryHandle {
-    pub fn get_sender(&self) -> mpsc::Sender<Vec<u8>> {
-        self.response_tx.clone()
+    async fn send_serialized(&mut self, request: Request<'_>) -> Result<Vec<u8>, RegistryError> {
+        if !self.alive {
+            return Err(RegistryError::disconnected("worker is dead"));
+        }
+
+        let round_trip = self.transport.round_trip(request.write_to_vec().unwrap());
+        let timeout_ms = self.timeout.as_millis().min(u32::MAX as u128) as u32;
+        let response = match select(round_trip, Box::pin(sleep_ms(timeout_ms))).await {
+            Either::Left((response, _)) => response,
+            Either::Right(_) => Err(RegistryError::new(
+                ErrorCode::Timeout,
+                format!("no response within {:?}", self.timeout),
+            )),
+        };
+
+        if let Err(err) = &response {
+            if err.is_fatal() {
+                self.alive = false;
+            }
+        }
+        response
     }
 }
 
-impl RPCLinearRegistryHandle {
-    async fn send_serialized(&mut self, request: Request<'_>) -> Vec<u8> {
-        self.worker
-            .post_message(&request.write_to_vec().unwrap().into())
-            .unwrap();
-        self.response_rx.recv().await.unwrap()
+/// Decodes a response, turning a reported `Response::Error` into an `Err`.
+fn decode_response(response: &[u8]) -> Result<Response<'_>, RegistryError> {
+    match Response::read_from_buffer(response) {
+        Ok(Response::Error(err)) => Err(err),
+        Ok(response) => Ok(response),
+        Err(err) => Err(RegistryError::new(
+            ErrorCode::DecodeFailed,
+            format!("failed to decode response: {err}"),
+        )),
     }
 }
 
+fn unexpected_response(request: &str) -> RegistryError {
+    RegistryError::new(
+        ErrorCode::UnexpectedResponse,
+        format!("unexpected response to {request}"),
+    )
+}
+
 impl RPCLinearRegistryHandle {
-    pub async fn add_aqlm(
+    pub async fn hello(&mut self) -> Result<HelloResponse, RegistryError> {
+        let response = self
+            .send_serialized(Request::HelloRequest(HelloRequest {
+                version: PROTOCOL_VERSION,
+            }))
+            .await?;
+        match decode_response(&response)? {
+            Response::HelloResponse(response) => Ok(response),
+            _ => Err(unexpected_response("HelloRequest")),
+        }
+    }
+
+    /// Checks that the worker speaks this protocol version and serves every
+    /// layer kind and kernel this build uses. Returns the worker's
+    /// capabilities.
+    pub async fn handshake(&mut self) -> Result<Capabilities, RegistryError> {
+        let timeout = self.timeout;
+        self.timeout = timeout.min(HANDSHAKE_TIMEOUT);
+        let hello = self.hello().await;
+        self.timeout = timeout;
+
+        let hello = match hello {
+            Ok(hello) => hello,
+            Err(err) if err.is_fatal() => return Err(err),
+            Err(err) => {
+                return Err(RegistryError::new(
+                    ErrorCode::Incompatible,
+                    format!("worker doesn't speak protocol version {PROTOCOL_VERSION}: {err}"),
+                ))
+            }
+        };
+
+        if hello.version != PROTOCOL_VERSION {
+            return Err(RegistryError::new(
+                ErrorCode::Incompatible,
+                format!(
+                    "worker speaks protocol version {}, expected {PROTOCOL_VERSION}",
+                    hello.version
+                ),
+            ));
+        }
+
+        let missing = Capabilities::local().missing_from(&hello.capabilities);
+        if !missing.is_empty() {
+            return Err(RegistryError::new(
+                ErrorCode::Incompatible,
+                format!("worker doesn't support {}", missing.join(", ")),
+            ));
+        }
+
+        Ok(hello.capabilities)
+    }
+
+    /// Checks that the worker still answers.
+    pub async fn ping(&mut self) -> Result<(), RegistryError> {
+        let response = self.send_serialized(Request::PingRequest).await?;
+        match decode_response(&response)? {
+            Response::PingResponse => Ok(()),
+            _ => Err(unexpected_response("PingRequest")),
+        }
+    }
+
+    /// Builds a layer of a kind the worker has registered from `config` and
+    /// `tensors`, replacing any layer of the same name.
+    pub async fn add_layer(
         &mut self,
         name: String,
-        codebooks: &[f32],
-        scales: &[f32],
-        codes: &[u8],
-        out_dim: usize,
-        in_group_dim: usize,
-    ) {
-        self.send_serialized(Request::AddAQLMRequest(AddAQLMRequest {
-            name,
-            codebooks: Cow::Borrowed(codebooks),
-            scales: Cow::Borrowed(scales),
-            codes: Cow::Borrowed(codes),
-            out_dim,
-            in_group_dim,
-        }))
-        .await;
-    }
-
-    pub async fn add_int8(&mut self, name: String, max_values: &[f32], int8_values: &[i8]) {
-        self.send_serialized(Request::AddINT8Request(AddINT8Request {
-            name,
-            max_values: Cow::Borrowed(max_values),
-            int8_values: Cow::Borrowed(int8_values),
-        }))
-        .await;
-    }
-
-    pub async fn remove_aqlm(&mut self, name: String) {
-        self.send_serialized(Request::RemoveAQLMRequest(RemoveAQLMRequest { name }))
-            .await;
-    }
-
-    pub async fn aqlm_forward(&mut self, name: &str, other: &Matrix<'_>) -> OwnedMatrix {
-        if let Response::AQLMForwardResponse(response) = Response::read_from_buffer(
-            &self
-                .send_serialized(Request::AQLMForwardRequest(AQLMForwardRequest {
-                    name: Cow::Borrowed(name),
-                    other: other.into(),
-                }))
-                .await,
-        )
-        .unwrap()
-        {
-            let output: Matrix = response.output.into();
-            return output.into_owned();
+        kind: &str,
+        config: LayerConfig,
+        tensors: &StateDict,
+    ) -> Result<(), RegistryError> {
+        let tensors = tensors
+            .to_safetensors()
+            .map_err(|err| RegistryError::new(ErrorCode::InvalidSpec, format!("{name}: {err}")))?;
+
+        let response = self
+            .send_serialized(Request::AddLayerRequest(AddLayerRequest {
+                name,
+                spec: LayerSpec {
+                    kind: Cow::Borrowed(kind),
+                    config,
+                    tensors: Cow::Owned(tensors),
+                },
+            }))
+            .await?;
+        match decode_response(&response)? {
+            Response::AddLayerResponse => Ok(()),
+            _ => Err(unexpected_response("AddLayerRequest")),
+        }
+    }
+
+    pub async fn remove_layer(&mut self, name: String) -> Result<(), RegistryError> {
+        let response = self
+            .send_serialized(Request::RemoveLayerRequest(RemoveLayerRequest { name }))
+            .await?;
+        match decode_response(&response)? {
+            Response::RemoveLayerResponse => Ok(()),
+            _ => Err(unexpected_response("RemoveLayerRequest")),
         }
-        panic!()
-    }
-
-    pub async fn int8_forward(&mut self, name: &str, other: &Matrix<'_>) -> OwnedMatrix {
-        if let Response::INT8ForwardResponse(response) = Response::read_from_buffer(
-            &self
-                .send_serialized(Request::INT8ForwardRequest(INT8ForwardRequest {
-                    name: Cow::Borrowed(name),
-                    other: other.into(),
-                }))
-                .await,
-        )
-        .unwrap()
-        {
-            let output: Matrix = response.output.into();
-            return output.into_owned();
+    }
+
+    pub async fn forward(
+        &mut self,
+        name: &str,
+        other: &Matrix<'_>,
+    ) -> Result<OwnedMatrix, RegistryError> {
+        let response = self
+            .send_serialized(Request::ForwardRequest(ForwardRequest {
+                name: Cow::Borrowed(name),
+                other: other.into(),
+            }))
+            .await?;
+        match decode_response(&response)? {
+            Response::ForwardResponse(response) => {
+                let output: Matrix = response.output.into();
+                Ok(output.into_owned())
+            }
+            _ => Err(unexpected_response("ForwardRequest")),
+        }
+    }
+
+    /// Runs several layers on the same input in one round trip.
+    pub async fn forward_batch(
+        &mut self,
+        names: &[String],
+        other: &Matrix<'_>,
+    ) -> Result<Vec<OwnedMatrix>, RegistryError> {
+        let response = self
+            .send_serialized(Request::ForwardBatchRequest(ForwardBatchRequest {
+                names: names
+                    .iter()
+                    .map(|name| Cow::Borrowed(name.as_str()))
+                    .collect(),
+                other: other.into(),
+            }))
+            .await?;
+        match decode_response(&response)? {
+            Response::ForwardBatchResponse(response) if response.outputs.len() == names.len() => {
+                Ok(response
+                    .outputs
+                    .into_iter()
+                    .map(|output| Matrix::from(output).into_owned())
+                    .collect())
+            }
+            _ => Err(unexpected_response("ForwardBatchRequest")),
+        }
+    }
+
+    /// Makes stateful layers forget the tokens seen so far.
+    pub async fn clear_cache(&mut self, names: &[String]) -> Result<(), RegistryError> {
+        let response = self
+            .send_serialized(Request::ClearCacheRequest(ClearCacheRequest {
+                names: names
+                    .iter()
+                    .map(|name| Cow::Borrowed(name.as_str()))
+                    .collect(),
+            }))
+            .await?;
+        match decode_response(&response)? {
+            Response::ClearCacheResponse => Ok(()),
+            _ => Err(unexpected_response("ClearCacheRequest")),
         }
-        panic!()
+    }
+
+    pub async fn list_layers(&mut self) -> Result<Vec<LayerInfo>, RegistryError> {
+        let response = self.send_serialized(Request::ListLayersRequest).await?;
+        match decode_response(&response)? {
+            Response::ListLayersResponse(response) => Ok(response.layers),
+            _ => Err(unexpected_response("ListLayersRequest")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::thread_transport::ThreadTransport;
+    use crate::transport::Transport;
+
+    fn int8_tensors(max_values: &[f32]) -> StateDict {
+        let mut tensors = StateDict::new();
+        tensors.insert_i8("weight_int8", &[2, 4], &[1; 8]);
+        tensors.insert_f32("weight_max_values", &[max_values.len()], max_values);
+        tensors
+    }
+
+    #[tokio::test]
+    async fn test_error_responses() {
+        let mut handle = RPCLinearRegistryHandle::spawn_thread();
+        let x = Matrix::from_slice((1, 4), &[1.0; 4]);
+
+        let err = handle.forward("missing", &x).await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::UnknownLayer);
+        let err = handle
+            .remove_layer("missing".to_string())
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::UnknownLayer);
+
+        let tensors = int8_tensors(&[1.0; 4]);
+        let err = handle
+            .add_layer("linear".to_string(), "int4", LayerConfig::None, &tensors)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::UnknownKind);
+        let err = handle
+            .add_layer(
+                "bad".to_string(),
+                "int8",
+                LayerConfig::None,
+                &int8_tensors(&[1.0; 3]),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidSpec);
+
+        handle
+            .add_layer("linear".to_string(), "int8", LayerConfig::None, &tensors)
+            .await
+            .unwrap();
+        let wide = Matrix::from_slice((1, 8), &[1.0; 8]);
+        let err = handle.forward("linear", &wide).await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::ShapeMismatch);
+
+        // The worker survives all of the above.
+        let output = handle.forward("linear", &x).await.unwrap();
+        assert_eq!(output.shape(), (1, 2));
+        assert_eq!(
+            handle.list_layers().await.unwrap(),
+            [LayerInfo {
+                name: "linear".to_string(),
+                kind: "int8".to_string(),
+                out_dim: 2,
+                in_dim: 4,
+            }]
+        );
+    }
+
+    /// A worker that answers every request with the same bytes.
+    struct CannedTransport(Vec<u8>);
+
+    #[async_trait::async_trait(?Send)]
+    impl Transport for CannedTransport {
+        async fn round_trip(&mut self, _request: Vec<u8>) -> Result<Vec<u8>, RegistryError> {
+            Ok(self.0.clone())
+        }
+    }
+
+    fn hello_from(version: u32, capabilities: Capabilities) -> RPCLinearRegistryHandle {
+        let response = Response::HelloResponse(HelloResponse {
+            version,
+            capabilities,
+        });
+        RPCLinearRegistryHandle::new(CannedTransport(response.write_to_vec().unwrap()))
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let mut handle = RPCLinearRegistryHandle::spawn_thread();
+        assert_eq!(handle.handshake().await.unwrap(), Capabilities::local());
+
+        let mut handle = hello_from(PROTOCOL_VERSION + 1, Capabilities::local());
+        let err = handle.handshake().await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::Incompatible);
+        assert!(err.message.contains("protocol version"), "{err}");
+
+        let mut capabilities = Capabilities::local();
+        capabilities.kernels.retain(|kernel| kernel != "aqlm_2x8");
+        let mut handle = hello_from(PROTOCOL_VERSION, capabilities);
+        let err = handle.handshake().await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::Incompatible);
+        assert!(err.message.ends_with("aqlm_2x8"), "{err}");
+
+        let mut handle = RPCLinearRegistryHandle::new(CannedTransport(vec![0xff; 3]));
+        let err = handle.handshake().await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::Incompatible);
+    }
+
+    #[tokio::test]
+    async fn test_undecodable_request() {
+        let mut transport = ThreadTransport::spawn();
+        let response = transport.round_trip(vec![0xff; 3]).await.unwrap();
+        let err = decode_response(&response).map(|_| ()).unwrap_err();
+        assert_eq!(err.code, ErrorCode::DecodeFailed);
     }
 }
@@ -0,0 +1,454 @@
+//! Parallel layers are split into shards, one or more per worker, whose
+//! outputs are concatenated or summed into the layer output. When a worker
+//! dies, its shards are re-fetched from the weight source and moved to the
+//! surviving workers.
+
+use crate::handles::weight_source;
+use crate::registry_rpc::{ColumnRange, ErrorCode, RegistryError};
+use crate::registry_rpc_handle::RPCLinearRegistryHandle;
+use async_trait::async_trait;
+use futures::future::join_all;
+use nn::linear::{ForwardError, Module};
+use state_dict::error::LoadError;
+use state_dict::from_state_dict::{FromStateDict, FromStateDictConf, TensorProvider};
+use std::future::Future;
+use std::rc::Rc;
+use tensorlib::functional::{add_rows, cat_row};
+use tensorlib::matrix::{Matrix, OwnedMatrix};
+
+/// Rows `begin..end` of a layer, held by the worker at `worker`. Rows are
+/// output rows, or input columns and hidden units for layers that sum their
+/// shards.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Shard {
+    pub worker: usize,
+    pub begin: usize,
+    pub end: usize,
+}
+
+impl Shard {
+    /// The name the shard is registered under on its worker.
+    pub fn name(&self, layer_name: &str) -> String {
+        format!("{layer_name}[{}..{}]", self.begin, self.end)
+    }
+}
+
+/// How the outputs of a layer's shards make up the layer output.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Reduce {
+    /// Each shard computes its rows of the output.
+    Concat,
+    /// Each shard computes a partial output of full width.
+    Sum,
+}
+
+impl Reduce {
+    fn apply(self, outputs: &[OwnedMatrix]) -> OwnedMatrix {
+        match self {
+            Reduce::Concat => cat_row(outputs),
+            Reduce::Sum => {
+                let sum = outputs[1..]
+                    .iter()
+                    .fold(outputs[0].data().to_vec(), |sum, output| {
+                        add_rows(sum, output.data())
+                    });
+                OwnedMatrix::from_vec(outputs[0].shape(), sum)
+            }
+        }
+    }
+}
+
+/// Which dimension the shards of a linear layer split.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Split {
+    /// Each shard holds some output rows, and gets the whole input.
+    Rows,
+    /// Each shard holds some input columns, and computes their part of the
+    /// whole output. Suits layers with a small output and a large input.
+    Columns,
+}
+
+impl Split {
+    pub(crate) fn reduce(self) -> Reduce {
+        match self {
+            Split::Rows => Reduce::Concat,
+            Split::Columns => Reduce::Sum,
+        }
+    }
+}
+
+/// The weights of a whole layer, from which any shard can be uploaded.
+#[async_trait(?Send)]
+pub(crate) trait ShardWeights: Sized {
+    /// `reduce` is how the layer combines its shards, which tells linears
+    /// whether `shard` holds rows or columns.
+    async fn upload(
+        &self,
+        handle: &mut RPCLinearRegistryHandle,
+        layer_name: &str,
+        shard: &Shard,
+        reduce: Reduce,
+    ) -> Result<(), RegistryError>;
+}
+
+/// The worker side of a column shard: a layer over input columns
+/// `begin..begin + layer.in_dim` that takes the whole input row.
+pub struct Columns<L> {
+    begin: usize,
+    in_dim: usize,
+    layer: L,
+}
+
+#[async_trait(?Send)]
+impl<L: Module> Module for Columns<L> {
+    async fn forward(&mut self, x: &[f32]) -> Result<OwnedMatrix, ForwardError> {
+        let end = self.begin + self.layer.shape().1;
+        self.layer.forward(&x[self.begin..end]).await
+    }
+
+    fn shape(&self) -> (usize, usize) {
+        (self.layer.shape().0, self.in_dim)
+    }
+}
+
+/// Reads the inner layer under `inner.`; the range comes with the spec.
+#[async_trait(?Send)]
+impl<'a, L: Module + FromStateDict<'a>> FromStateDictConf<'a, ColumnRange> for Columns<L> {
+    async fn from_state_dict(
+        source: &dyn TensorProvider<'a>,
+        prefix: &str,
+        range: ColumnRange,
+    ) -> Result<Self, LoadError> {
+        let ColumnRange { begin, in_dim } = range;
+
+        let inner_prefix = format!("{prefix}inner.");
+        let layer = L::from_state_dict(source, &inner_prefix).await?;
+        let end = begin.checked_add(layer.shape().1);
+        if end.is_none_or(|end| end > in_dim) {
+            return Err(LoadError::corrupted(
+                &inner_prefix,
+                format!("{} columns from {begin} out of {in_dim}", layer.shape().1),
+            ));
+        }
+
+        Ok(Self {
+            begin,
+            in_dim,
+            layer,
+        })
+    }
+}
+
+pub(crate) fn alive_workers(handles: &[RPCLinearRegistryHandle]) -> Vec<usize> {
+    (0..handles.len())
+        .filter(|&worker| handles[worker].is_alive())
+        .collect()
+}
+
+/// Fails instead of running a layer without any worker, as happens before
+/// `set_handles`.
+pub(crate) fn check_handles(handles: &[RPCLinearRegistryHandle]) -> Result<(), RegistryError> {
+    if handles.is_empty() {
+        return Err(RegistryError::new(ErrorCode::NoWorkers, "no worker set up"));
+    }
+    Ok(())
+}
+
+/// The first `max_workers` alive workers, to load a layer onto. Fails instead
+/// of returning none.
+pub(crate) fn load_workers(
+    handles: &[RPCLinearRegistryHandle],
+    max_workers: usize,
+) -> Result<Vec<usize>, RegistryError> {
+    let mut workers = alive_workers(handles);
+    workers.truncate(max_workers);
+    if workers.is_empty() {
+        return Err(RegistryError::new(
+            ErrorCode::NoWorkers,
+            "no alive worker to load onto",
+        ));
+    }
+    Ok(workers)
+}
+
+/// Splits `out_dim` rows evenly over `workers`; the last one takes the rest.
+pub(crate) fn split_rows(out_dim: usize, workers: &[usize]) -> Vec<Shard> {
+    split_groups(out_dim, 1, workers)
+}
+
+/// Like `split_rows`, but shards start at multiples of `group_size`, which
+/// must divide `out_dim`.
+pub(crate) fn split_groups(out_dim: usize, group_size: usize, workers: &[usize]) -> Vec<Shard> {
+    assert_eq!(out_dim % group_size, 0);
+    let chunk_size = out_dim / group_size / workers.len() * group_size;
+
+    workers
+        .iter()
+        .enumerate()
+        .map(|(idx, &worker)| Shard {
+            worker,
+            begin: idx * chunk_size,
+            end: match idx == workers.len() - 1 {
+                true => out_dim,
+                false => (idx + 1) * chunk_size,
+            },
+        })
+        .collect()
+}
+
+/// A worker failure as seen by the model, naming the layers it hit.
+pub(crate) fn forward_error(names: &[&str], err: RegistryError) -> ForwardError {
+    format!("{}: {err}", names.join(", ")).into()
+}
+
+/// Runs `x` through the shards of several layers, with a single request per
+/// worker, and reduces each layer's outputs in row order.
+pub(crate) async fn forward_shards(
+    handles: &mut [RPCLinearRegistryHandle],
+    layers: &[(&str, &[Shard], Reduce)],
+    x: &Matrix<'_>,
+) -> Result<Vec<OwnedMatrix>, RegistryError> {
+    let futures = handles.iter_mut().enumerate().map(|(worker, handle)| {
+        // The (layer, shard) index of every shard this worker holds.
+        let mut owners = Vec::new();
+        let mut names = Vec::new();
+        for (layer_idx, (layer_name, shards, _)) in layers.iter().enumerate() {
+            for (shard_idx, shard) in shards.iter().enumerate() {
+                if shard.worker == worker {
+                    owners.push((layer_idx, shard_idx));
+                    names.push(shard.name(layer_name));
+                }
+            }
+        }
+
+        async move {
+            if names.is_empty() {
+                return Ok(Vec::new());
+            }
+            let outputs = handle.forward_batch(&names, x).await?;
+            Ok::<_, RegistryError>(owners.into_iter().zip(outputs).collect::<Vec<_>>())
+        }
+    });
+
+    let mut outputs = Vec::new();
+    for worker_outputs in join_all(futures).await {
+        outputs.extend(worker_outputs?);
+    }
+    outputs.sort_by_key(|(owner, _)| *owner);
+
+    let mut layer_outputs: Vec<Vec<OwnedMatrix>> = layers.iter().map(|_| Vec::new()).collect();
+    for ((layer_idx, _), output) in outputs {
+        layer_outputs[layer_idx].push(output);
+    }
+    Ok(layers
+        .iter()
+        .zip(layer_outputs)
+        .map(|((_, _, reduce), outputs)| reduce.apply(&outputs))
+        .collect())
+}
+
+/// Runs `x` through several layers of the same type. Shards of dead workers
+/// are moved to the surviving ones first, and again whenever a worker dies
+/// mid-request.
+pub(crate) async fn forward_layers<Weights: ShardWeights + FromStateDict<'static>>(
+    handles: &mut [RPCLinearRegistryHandle],
+    layers: &mut [(&str, &mut [Shard], Reduce)],
+    x: &Matrix<'_>,
+) -> Result<Vec<OwnedMatrix>, RegistryError> {
+    loop {
+        for (layer_name, shards, reduce) in layers.iter_mut() {
+            let weights = load_weights::<Weights>(layer_name);
+            recover_shards(handles, layer_name, shards, *reduce, weights).await?;
+        }
+
+        let shards: Vec<(&str, &[Shard], Reduce)> = layers
+            .iter()
+            .map(|(layer_name, shards, reduce)| (*layer_name, &shards[..], *reduce))
+            .collect();
+        match forward_shards(handles, &shards, x).await {
+            Err(err) if err.is_fatal() => log::warn!("{err}"),
+            outputs => return outputs,
+        }
+    }
+}
+
+/// Assigns the shards of dead workers to the alive workers holding the fewest
+/// shards of this layer. Returns the indices of the moved shards.
+fn reassign_dead_shards(
+    handles: &[RPCLinearRegistryHandle],
+    shards: &mut [Shard],
+) -> Result<Vec<usize>, RegistryError> {
+    let alive = alive_workers(handles);
+    let mut moved = Vec::new();
+
+    for shard_idx in 0..shards.len() {
+        if handles[shards[shard_idx].worker].is_alive() {
+            continue;
+        }
+
+        let worker = alive
+            .iter()
+            .copied()
+            .min_by_key(|&worker| shards.iter().filter(|s| s.worker == worker).count())
+            .ok_or_else(|| RegistryError::new(ErrorCode::NoWorkers, "all workers are dead"))?;
+
+        shards[shard_idx].worker = worker;
+        moved.push(shard_idx);
+    }
+
+    Ok(moved)
+}
+
+/// Moves the shards of dead workers to alive ones. `weights` fetches the
+/// whole layer, and is only awaited if anything has to move.
+pub(crate) async fn recover_shards<Weights: ShardWeights>(
+    handles: &mut [RPCLinearRegistryHandle],
+    layer_name: &str,
+    shards: &mut [Shard],
+    reduce: Reduce,
+    weights: impl Future<Output = Result<Weights, RegistryError>>,
+) -> Result<(), RegistryError> {
+    if shards.iter().all(|shard| handles[shard.worker].is_alive()) {
+        return Ok(());
+    }
+
+    // Fetched before any shard is reassigned, so that a failed fetch leaves
+    // the layer as it was.
+    let weights = weights.await?;
+
+    loop {
+        let moved = reassign_dead_shards(handles, shards)?;
+        if moved.is_empty() {
+            return Ok(());
+        }
+        log::warn!(
+            "{layer_name}: moving {} shards off dead workers",
+            moved.len()
+        );
+
+        for shard_idx in moved {
+            let shard = &shards[shard_idx];
+            let upload = weights.upload(&mut handles[shard.worker], layer_name, shard, reduce);
+            if let Err(err) = upload.await {
+                // A fatal error kills the worker, so the shard moves again on
+                // the next pass.
+                if !err.is_fatal() {
+                    return Err(err);
+                }
+            }
+        }
+    }
+}
+
+/// Removes the shards from the workers that are still alive.
+pub(crate) async fn remove_shards(
+    handles: &mut [RPCLinearRegistryHandle],
+    layer_name: &str,
+    shards: &[Shard],
+) -> Result<(), RegistryError> {
+    for shard in shards {
+        let handle = &mut handles[shard.worker];
+        if handle.is_alive() {
+            handle.remove_layer(shard.name(layer_name)).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Clears the caches of the shards, with a single request per worker.
+pub(crate) async fn clear_caches(
+    handles: &mut [RPCLinearRegistryHandle],
+    layer_name: &str,
+    shards: &[Shard],
+) -> Result<(), RegistryError> {
+    let futures = handles.iter_mut().enumerate().map(|(worker, handle)| {
+        let names: Vec<String> = shards
+            .iter()
+            .filter(|shard| shard.worker == worker)
+            .map(|shard| shard.name(layer_name))
+            .collect();
+
+        async move {
+            if names.is_empty() {
+                return Ok(());
+            }
+            handle.clear_cache(&names).await
+        }
+    });
+
+    join_all(futures).await.into_iter().collect()
+}
+
+fn weight_source_or_err() -> Result<Rc<dyn TensorProvider<'static>>, RegistryError> {
+    weight_source().ok_or_else(|| {
+        RegistryError::new(
+            ErrorCode::RecoveryFailed,
+            "no weight source to re-fetch from",
+        )
+    })
+}
+
+/// Fetches a whole layer from the weight source.
+pub(crate) async fn load_weights<Weights: FromStateDict<'static>>(
+    prefix: &str,
+) -> Result<Weights, RegistryError> {
+    let source = weight_source_or_err()?;
+    Weights::from_state_dict(source.as_ref(), prefix)
+        .await
+        .map_err(|err| RegistryError::new(ErrorCode::RecoveryFailed, err))
+}
+
+/// Like `load_weights`, for layers that need a config to load.
+pub(crate) async fn load_weights_conf<Weights: FromStateDictConf<'static, Config>, Config>(
+    prefix: &str,
+    config: Config,
+) -> Result<Weights, RegistryError> {
+    let source = weight_source_or_err()?;
+    Weights::from_state_dict(source.as_ref(), prefix, config)
+        .await
+        .map_err(|err| RegistryError::new(ErrorCode::RecoveryFailed, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_weights::insert_aqlm;
+    use nn::linear_aqlm::LinearAQLM;
+    use state_dict::state_dict::StateDict;
+
+    #[test]
+    fn test_split_rows() {
+        let shards = split_rows(10, &[0, 2, 3]);
+        let bounds: Vec<_> = shards
+            .iter()
+            .map(|shard| (shard.worker, shard.begin, shard.end))
+            .collect();
+        assert_eq!(bounds, [(0, 0, 3), (2, 3, 6), (3, 6, 10)]);
+        assert_eq!(shards[1].name("mlp.up_proj."), "mlp.up_proj.[3..6]");
+
+        let bounds: Vec<_> = split_groups(48, 8, &[0, 1, 2, 3])
+            .iter()
+            .map(|shard| (shard.begin, shard.end))
+            .collect();
+        assert_eq!(bounds, [(0, 8), (8, 16), (16, 24), (24, 48)]);
+    }
+
+    #[tokio::test]
+    async fn test_columns_range() {
+        let mut state_dict = StateDict::new();
+        insert_aqlm(&mut state_dict, "inner.", 4, 16);
+        let load = |begin, in_dim| {
+            Columns::<LinearAQLM>::from_state_dict(&state_dict, "", ColumnRange { begin, in_dim })
+        };
+
+        let columns = load(8, 32).await.unwrap();
+        assert_eq!(columns.shape(), (4, 32));
+        assert_eq!(load(1 << 20, 1 << 21).await.unwrap().shape(), (4, 1 << 21));
+
+        // Columns past `in_dim` are rejected.
+        for (begin, in_dim) in [(24, 32), (usize::MAX, 32)] {
+            let err = load(begin, in_dim).await.err().unwrap();
+            assert!(matches!(err, LoadError::Corrupted { .. }), "{err}");
+        }
+    }
+}
@@ -0,0 +1,223 @@
+//! Registry requests over TCP. Every message is framed as a little-endian
+//! `u64` byte count followed by the speedy-encoded `Request` or `Response`.
+
+use crate::registry_rpc::{RPCLinearRegistryServer, RegistryError};
+use crate::transport::Transport;
+use async_trait::async_trait;
+use futures::executor::block_on;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Frames above this size are rejected. The largest real frame is an
+/// `AddLayer` with a whole int8 lm_head, about 0.5 GiB for Llama 3 8B.
+const MAX_FRAME_SIZE: u64 = 1 << 30;
+
+fn check_frame_size(size: u64) -> io::Result<u64> {
+    if size > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {size} bytes is too large"),
+        ));
+    }
+    Ok(size)
+}
+
+/// Errors unless `data` holds the whole frame of `size` bytes.
+fn check_frame_read(data: &[u8], size: u64) -> io::Result<()> {
+    if data.len() as u64 != size {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("frame ended after {} of {size} bytes", data.len()),
+        ));
+    }
+    Ok(())
+}
+
+/// Reads into a buffer that grows with the data received, so a length prefix
+/// alone cannot make us allocate the whole frame. Returns `None` if the
+/// stream ends between frames; ending anywhere else is an error.
+fn read_frame(stream: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut size = [0u8; 8];
+    let n_read = loop {
+        match stream.read(&mut size) {
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            n_read => break n_read?,
+        }
+    };
+    if n_read == 0 {
+        return Ok(None);
+    }
+    stream.read_exact(&mut size[n_read..])?;
+    let size = check_frame_size(u64::from_le_bytes(size))?;
+
+    let mut data = Vec::new();
+    stream.take(size).read_to_end(&mut data)?;
+    check_frame_read(&data, size)?;
+    Ok(Some(data))
+}
+
+fn write_frame(stream: &mut impl Write, data: &[u8]) -> io::Result<()> {
+    stream.write_all(&(data.len() as u64).to_le_bytes())?;
+    stream.write_all(data)?;
+    stream.flush()
+}
+
+/// Serves one client with a registry of its own until it disconnects.
+pub fn serve_connection(mut stream: TcpStream) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut server = RPCLinearRegistryServer::default();
+
+    loop {
+        let Some(request) = read_frame(&mut stream)? else {
+            return Ok(());
+        };
+        let response = block_on(server.serve_serialized(&request));
+        write_frame(&mut stream, &response)?;
+    }
+}
+
+/// Accepts clients forever, serving each on its own thread.
+pub fn serve(listener: TcpListener) -> io::Result<()> {
+    for stream in listener.incoming() {
+        // One bad client must not take down the others.
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                log::warn!("accept failed: {err}");
+                continue;
+            }
+        };
+        let peer = match stream.peer_addr() {
+            Ok(peer) => peer,
+            Err(err) => {
+                log::warn!("client left before it was served: {err}");
+                continue;
+            }
+        };
+        log::info!("{peer} connected");
+
+        thread::spawn(move || match serve_connection(stream) {
+            Ok(()) => log::info!("{peer} disconnected"),
+            Err(err) => log::warn!("{peer}: {err}"),
+        });
+    }
+    Ok(())
+}
+
+/// A connection to a `serve` worker.
+pub struct TcpTransport {
+    stream: tokio::net::TcpStream,
+}
+
+impl TcpTransport {
+    pub async fn connect(addr: impl tokio::net::ToSocketAddrs) -> io::Result<Self> {
+        let stream = tokio::net::TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        Ok(Self { stream })
+    }
+
+    async fn try_round_trip(&mut self, request: &[u8]) -> io::Result<Vec<u8>> {
+        self.stream
+            .write_all(&(request.len() as u64).to_le_bytes())
+            .await?;
+        self.stream.write_all(request).await?;
+        self.stream.flush().await?;
+
+        let size = check_frame_size(self.stream.read_u64_le().await?)?;
+        let mut response = Vec::new();
+        (&mut self.stream)
+            .take(size)
+            .read_to_end(&mut response)
+            .await?;
+        check_frame_read(&response, size)?;
+        Ok(response)
+    }
+}
+
+#[async_trait(?Send)]
+impl Transport for TcpTransport {
+    async fn round_trip(&mut self, request: Vec<u8>) -> Result<Vec<u8>, RegistryError> {
+        self.try_round_trip(&request)
+            .await
+            .map_err(RegistryError::disconnected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parallel_aqlm::set_handles;
+    use crate::parallel_int8::ParallelINT8Linear;
+    use crate::registry_rpc_handle::RPCLinearRegistryHandle;
+    use nn::linear::Module;
+    use nn::linear_int8::LinearINT8;
+    use state_dict::from_state_dict::FromStateDict;
+    use state_dict::state_dict::StateDict;
+
+    #[test]
+    fn test_frames() {
+        let mut buffer = Vec::new();
+        write_frame(&mut buffer, b"hello").unwrap();
+        write_frame(&mut buffer, b"").unwrap();
+
+        let mut reader = buffer.as_slice();
+        assert_eq!(read_frame(&mut reader).unwrap().unwrap(), b"hello");
+        assert_eq!(read_frame(&mut reader).unwrap().unwrap(), b"");
+        assert_eq!(read_frame(&mut reader).unwrap(), None);
+
+        // Ending inside the length prefix or the frame is not a clean close.
+        let prefix = 5u64.to_le_bytes();
+        assert_eq!(
+            read_frame(&mut &prefix[..3]).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+
+        let oversized = (MAX_FRAME_SIZE + 1).to_le_bytes();
+        assert_eq!(
+            read_frame(&mut oversized.as_slice()).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        let mut truncated = 100u64.to_le_bytes().to_vec();
+        truncated.extend_from_slice(b"short");
+        assert_eq!(
+            read_frame(&mut truncated.as_slice()).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[tokio::test]
+    async fn test_localhost_workers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve(listener));
+
+        let mut handles = Vec::new();
+        for _ in 0..2 {
+            let transport = TcpTransport::connect(addr).await.unwrap();
+            handles.push(RPCLinearRegistryHandle::new(transport));
+        }
+        set_handles(handles).await;
+
+        let (out_dim, in_dim) = (5, 8);
+        let int8_values: Vec<i8> = (0..out_dim * in_dim)
+            .map(|idx| (idx * 37 % 255) as i8)
+            .collect();
+        let mut state_dict = StateDict::new();
+        state_dict.insert_i8("linear.weight_int8", &[out_dim, in_dim], &int8_values);
+        state_dict.insert_f32("linear.weight_max_values", &[in_dim], &[0.5; 8]);
+
+        let mut remote = ParallelINT8Linear::from_state_dict(&state_dict, "linear.")
+            .await
+            .unwrap();
+        let mut local = LinearINT8::from_state_dict(&state_dict, "linear.")
+            .await
+            .unwrap();
+
+        let x: Vec<f32> = (0..in_dim).map(|idx| idx as f32 - 3.0).collect();
+        let expected = local.forward(&x).await.unwrap();
+        assert_eq!(remote.forward(&x).await.unwrap(), expected);
+    }
+}
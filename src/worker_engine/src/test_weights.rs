@@ -0,0 +1,34 @@
+//! Weights and checks shared by the parallel layer tests.
+
+use state_dict::state_dict::StateDict;
+use tensorlib::matrix::OwnedMatrix;
+
+/// Adds a 2x8 AQLM linear under `prefix`. The codes are seeded from the
+/// prefix, so layers of the same shape still get different weights.
+pub(crate) fn insert_aqlm(state_dict: &mut StateDict, prefix: &str, out_dim: usize, in_dim: usize) {
+    let seed = prefix.bytes().fold(0usize, |seed, byte| {
+        seed.wrapping_mul(31).wrapping_add(byte as usize)
+    });
+    let codebooks: Vec<f32> = (0..2 * 256 * 8)
+        .map(|idx| (idx * 7919 % 201) as f32 / 100.0 - 1.0)
+        .collect();
+    let scales: Vec<f32> = (0..out_dim).map(|idx| 0.1 + idx as f32 / 100.0).collect();
+    let codes: Vec<u8> = (0..in_dim / 8 * 2 * out_dim)
+        .map(|idx| (idx.wrapping_add(seed).wrapping_mul(37) % 256) as u8)
+        .collect();
+
+    state_dict.insert_f32(&format!("{prefix}codebooks"), &[2, 256, 1, 8], &codebooks);
+    state_dict.insert_f32(&format!("{prefix}scales"), &[out_dim], &scales);
+    state_dict.insert_u8(
+        &format!("{prefix}codes_120"),
+        &[in_dim / 8, 2, out_dim],
+        &codes,
+    );
+}
+
+pub(crate) fn assert_close(actual: &OwnedMatrix, expected: &OwnedMatrix) {
+    assert_eq!(actual.shape(), expected.shape());
+    for (a, e) in actual.data().iter().zip(expected.data().iter()) {
+        assert!((a - e).abs() < 1e-4, "{a} != {e}");
+    }
+}
@@ -0,0 +1,89 @@
+use crate::registry_rpc::{RPCLinearRegistryServer, RegistryError};
+use crate::transport::Transport;
+use async_trait::async_trait;
+use futures::executor::block_on;
+use std::sync::mpsc;
+use std::thread;
+use tokio::sync::oneshot;
+
+type Job = (Vec<u8>, oneshot::Sender<Vec<u8>>);
+
+/// An `RPCLinearRegistryServer` on its own OS thread. The thread exits once
+/// the transport is dropped.
+pub struct ThreadTransport {
+    request_tx: mpsc::Sender<Job>,
+}
+
+impl ThreadTransport {
+    pub fn spawn() -> Self {
+        let (request_tx, request_rx) = mpsc::channel::<Job>();
+
+        thread::spawn(move || {
+            let mut server = RPCLinearRegistryServer::default();
+            while let Ok((request, response_tx)) = request_rx.recv() {
+                let response = block_on(server.serve_serialized(&request));
+                let _ = response_tx.send(response);
+            }
+        });
+
+        Self { request_tx }
+    }
+}
+
+#[async_trait(?Send)]
+impl Transport for ThreadTransport {
+    async fn round_trip(&mut self, request: Vec<u8>) -> Result<Vec<u8>, RegistryError> {
+        let (response_tx, response_rx) = oneshot::channel();
+        if self.request_tx.send((request, response_tx)).is_err() {
+            return Err(RegistryError::disconnected("worker thread exited"));
+        }
+        response_rx
+            .await
+            .map_err(|_| RegistryError::disconnected("worker thread exited"))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::future;
+    use std::rc::Rc;
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub(crate) enum Fault {
+        None,
+        /// Requests are never answered.
+        Hang,
+        /// Requests fail as if the connection was lost.
+        Disconnect,
+    }
+
+    /// A `ThreadTransport` that misbehaves on demand.
+    pub(crate) struct FaultyTransport {
+        inner: ThreadTransport,
+        fault: Rc<Cell<Fault>>,
+    }
+
+    impl FaultyTransport {
+        pub(crate) fn spawn() -> (Self, Rc<Cell<Fault>>) {
+            let fault = Rc::new(Cell::new(Fault::None));
+            let transport = Self {
+                inner: ThreadTransport::spawn(),
+                fault: fault.clone(),
+            };
+            (transport, fault)
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl Transport for FaultyTransport {
+        async fn round_trip(&mut self, request: Vec<u8>) -> Result<Vec<u8>, RegistryError> {
+            match self.fault.get() {
+                Fault::None => self.inner.round_trip(request).await,
+                Fault::Hang => future::pending().await,
+                Fault::Disconnect => Err(RegistryError::disconnected("fault injected")),
+            }
+        }
+    }
+}
@@ -0,0 +1,49 @@
+use crate::registry_rpc::RegistryError;
+use async_trait::async_trait;
+use tokio::sync::mpsc;
+use web_sys::Worker;
+
+/// Carries serialized registry requests to a worker and returns its serialized
+/// responses, one request at a time. Fails only when the worker can't be
+/// reached; errors reported by the worker itself come back as responses.
+#[async_trait(?Send)]
+pub trait Transport {
+    async fn round_trip(&mut self, request: Vec<u8>) -> Result<Vec<u8>, RegistryError>;
+}
+
+/// A browser worker running `linear_worker.js`. Responses come back through the
+/// sender handed out by `get_sender`, which JS feeds from `onmessage`.
+pub struct WebWorkerTransport {
+    worker: Worker,
+    response_rx: mpsc::Receiver<Vec<u8>>,
+    response_tx: mpsc::Sender<Vec<u8>>,
+}
+
+impl WebWorkerTransport {
+    pub fn new(worker: Worker) -> Self {
+        let (response_tx, response_rx) = mpsc::channel(1);
+
+        Self {
+            worker,
+            response_rx,
+            response_tx,
+        }
+    }
+
+    pub fn get_sender(&self) -> mpsc::Sender<Vec<u8>> {
+        self.response_tx.clone()
+    }
+}
+
+#[async_trait(?Send)]
+impl Transport for WebWorkerTransport {
+    async fn round_trip(&mut self, request: Vec<u8>) -> Result<Vec<u8>, RegistryError> {
+        self.worker
+            .post_message(&request.into())
+            .map_err(|err| RegistryError::disconnected(format!("{err:?}")))?;
+        self.response_rx
+            .recv()
+            .await
+            .ok_or_else(|| RegistryError::disconnected("response channel closed"))
+    }
+}